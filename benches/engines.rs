@@ -0,0 +1,174 @@
+//! Order flow through running engines, each on its own thread and logging
+//! every order, on one market alone and on two markets at once.
+//!
+//! Run with `cargo bench --bench engines`. The markets share the log only for
+//! as long as it takes to write a record, so two markets taking the same flow
+//! each should finish in about the time one takes, not twice that.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use orderbook::dispatch;
+use orderbook::orderbook::Orderbook;
+use orderbook::persistence::Wal;
+use orderbook::types::{
+    Account, Feeds, MarketConfig, Order, OrderSide, OrderStatus, OrderType, OrderbookCommand,
+    SelfTradePrevention,
+};
+use rust_decimal::Decimal;
+use tokio::sync::{mpsc, oneshot};
+
+/// Symbol and base asset of each market an engine is started for.
+const MARKETS: [(&str, &str); 2] = [("BTC-USD", "BTC"), ("ETH-USD", "ETH")];
+
+const MAKER: &str = "maker";
+const TAKER: &str = "taker";
+
+/// An engine per market sharing one log in a temporary directory, and the
+/// runtime the benchmark sends orders from. The maker rests an ask and the
+/// taker lifts it, so every book is empty between pairs; both are funded far
+/// beyond what any benchmark spends.
+struct Engines {
+    runtime: tokio::runtime::Runtime,
+    markets: Vec<(&'static str, mpsc::Sender<OrderbookCommand>)>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Engines {
+    fn new() -> Self {
+        let funded = Account {
+            balance: Decimal::from(i64::MAX),
+            assets: MARKETS
+                .iter()
+                .map(|(_, asset)| (asset.to_string(), Decimal::from(i64::MAX)))
+                .collect(),
+            ..Account::default()
+        };
+        let accounts = Arc::new(Mutex::new(HashMap::from([
+            (MAKER.to_string(), funded.clone()),
+            (TAKER.to_string(), funded),
+        ])));
+
+        let dir = std::env::temp_dir().join(format!("orderbook-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let (wal, _) = Wal::open(&path, 0).unwrap();
+        let wal = Arc::new(Mutex::new(wal));
+        let order_sequence = Arc::new(AtomicU64::new(0));
+
+        let markets = MARKETS
+            .iter()
+            .map(|(symbol, asset)| {
+                let market = MarketConfig::new(symbol, asset, Decimal::new(1, 2));
+                let book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(1_024));
+                let (tx, rx) = mpsc::channel(1_024);
+                let engine = book.run_orderbook_engine(rx, wal.clone(), order_sequence.clone());
+                dispatch::spawn_engine(symbol, engine).unwrap();
+                (*symbol, tx)
+            })
+            .collect();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        Self {
+            runtime,
+            markets,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Trades `pairs` times on each of the first `markets` markets, all of
+    /// them at once, and returns how long the slowest took.
+    fn trade(&self, markets: usize, pairs: u64) -> Duration {
+        self.runtime.block_on(async {
+            let start = Instant::now();
+            let flows: Vec<_> = self.markets[..markets]
+                .iter()
+                .map(|(symbol, tx)| {
+                    let (symbol, tx) = (*symbol, tx.clone());
+                    let next_id = Arc::clone(&self.next_id);
+                    tokio::spawn(async move {
+                        for _ in 0..pairs {
+                            for (user_id, side) in
+                                [(MAKER, OrderSide::Sell), (TAKER, OrderSide::Buy)]
+                            {
+                                let id = next_id.fetch_add(1, Ordering::Relaxed);
+                                place(&tx, order(id, symbol, user_id, side)).await;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for flow in flows {
+                flow.await.unwrap();
+            }
+            start.elapsed()
+        })
+    }
+}
+
+/// A limit order for one unit at 100.
+fn order(id: u64, symbol: &str, user_id: &str, side: OrderSide) -> Order {
+    Order {
+        id: format!("bench-{id}"),
+        user_id: user_id.to_string(),
+        symbol: symbol.to_string(),
+        side,
+        order_type: OrderType::LimitOrder,
+        price: Some(Decimal::ONE_HUNDRED),
+        quantity: Decimal::ONE,
+        remaining_quantity: Decimal::ONE,
+        quote_quantity: None,
+        visible_quantity: None,
+        linked_order_id: None,
+        client_order_id: None,
+        expires_at: None,
+        activates_at: None,
+        worst_average_price: None,
+        timestamp: 0,
+        sequence: 0,
+        self_trade_prevention: SelfTradePrevention::default(),
+        reduce_only: false,
+        status: OrderStatus::Open,
+    }
+}
+
+async fn place(tx: &mpsc::Sender<OrderbookCommand>, order: Order) {
+    let (response, reply) = oneshot::channel();
+    let command = OrderbookCommand::AddOrder {
+        order: Box::new(order),
+        ack: None,
+        response,
+    };
+    tx.send(command).await.unwrap();
+    let response = reply.await.unwrap();
+    assert!(
+        response.accepted(),
+        "benchmark order rejected: {response:?}"
+    );
+}
+
+fn concurrent_markets(c: &mut Criterion) {
+    let engines = Engines::new();
+    let mut group = c.benchmark_group("concurrent_markets");
+    group.sample_size(10);
+    for markets in 1..=MARKETS.len() {
+        group.bench_with_input(BenchmarkId::from_parameter(markets), &markets, |b, _| {
+            b.iter_custom(|iters| engines.trade(markets, iters));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_markets);
+criterion_main!(benches);
@@ -0,0 +1,248 @@
+//! Matching engine throughput against books of increasing depth.
+//!
+//! Run with `cargo bench`. Each benchmark leaves the book as deep as it found
+//! it, so every iteration sees the same depth; restoring it is not timed.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use orderbook::orderbook::Orderbook;
+use orderbook::types::{
+    Account, Feeds, MarketConfig, Order, OrderResponse, OrderSide, OrderStatus, OrderType,
+    SelfTradePrevention,
+};
+use rust_decimal::Decimal;
+
+/// Price levels on each side of the book.
+const DEPTHS: [usize; 3] = [10, 100, 1_000];
+
+/// Resting orders at each price level.
+const ORDERS_PER_LEVEL: usize = 4;
+
+const SYMBOL: &str = "BENCH-USD";
+
+/// Midpoint of the fixture book, in cents.
+const MID: i64 = 1_000_000;
+
+const MAKER: &str = "maker";
+const TAKER: &str = "taker";
+
+/// A two-sided book around `MID`, one tick per level, with a few orders of
+/// varying size queued at each price. The maker owns every resting order and
+/// the taker trades against them; both are funded far beyond what any
+/// benchmark spends.
+struct Fixture {
+    book: Orderbook,
+    depth: usize,
+    next_id: u64,
+    now: u64,
+}
+
+impl Fixture {
+    fn new(depth: usize) -> Self {
+        let funded = Account {
+            balance: Decimal::from(i64::MAX),
+            assets: HashMap::from([("BENCH".to_string(), Decimal::from(i64::MAX))]),
+            ..Account::default()
+        };
+        let accounts = Arc::new(Mutex::new(HashMap::from([
+            (MAKER.to_string(), funded.clone()),
+            (TAKER.to_string(), funded),
+        ])));
+        let market = MarketConfig::new(SYMBOL, "BENCH", Decimal::new(1, 2));
+        let mut fixture = Self {
+            book: Orderbook::new(market, accounts, Feeds::new(1_024)),
+            depth,
+            next_id: 0,
+            now: 1,
+        };
+        fixture.fill(OrderSide::Buy);
+        fixture.fill(OrderSide::Sell);
+        fixture
+    }
+
+    /// Price `level` ticks away from the mid on `side`'s side of the book,
+    /// starting at the best price.
+    fn price(side: &OrderSide, level: usize) -> Decimal {
+        let ticks = level as i64 + 1;
+        match side {
+            OrderSide::Buy => Decimal::new(MID - ticks, 2),
+            OrderSide::Sell => Decimal::new(MID + ticks, 2),
+        }
+    }
+
+    /// Sizes between 0.1 and 5.0, so levels hold different amounts.
+    fn quantity(level: usize, position: usize) -> Decimal {
+        Decimal::new(((level * 31 + position * 17) % 50 + 1) as i64, 1)
+    }
+
+    /// Rests `ORDERS_PER_LEVEL` maker orders at each of `depth` levels of `side`.
+    fn fill(&mut self, side: OrderSide) {
+        for level in 0..self.depth {
+            for position in 0..ORDERS_PER_LEVEL {
+                let order = self.order(
+                    MAKER,
+                    side.clone(),
+                    OrderType::LimitOrder,
+                    Some(Self::price(&side, level)),
+                    Self::quantity(level, position),
+                );
+                self.place(order);
+            }
+        }
+    }
+
+    /// Total quantity resting on `side`.
+    fn side_quantity(&self) -> Decimal {
+        (0..self.depth)
+            .flat_map(|level| (0..ORDERS_PER_LEVEL).map(move |position| (level, position)))
+            .map(|(level, position)| Self::quantity(level, position))
+            .sum()
+    }
+
+    fn order(
+        &mut self,
+        user_id: &str,
+        side: OrderSide,
+        order_type: OrderType,
+        price: Option<Decimal>,
+        quantity: Decimal,
+    ) -> Order {
+        self.next_id += 1;
+        self.now += 1;
+        Order {
+            id: format!("bench-{}", self.next_id),
+            user_id: user_id.to_string(),
+            symbol: SYMBOL.to_string(),
+            side,
+            order_type,
+            price,
+            quantity,
+            remaining_quantity: quantity,
+            quote_quantity: None,
+            visible_quantity: None,
+            linked_order_id: None,
+            client_order_id: None,
+            expires_at: None,
+            activates_at: None,
+            worst_average_price: None,
+            timestamp: self.now,
+            sequence: 0,
+            self_trade_prevention: SelfTradePrevention::default(),
+            reduce_only: false,
+            status: OrderStatus::Open,
+        }
+    }
+
+    fn place(&mut self, order: Order) -> OrderResponse {
+        let response = self.book.add_order(order, self.now);
+        assert!(
+            !matches!(response, OrderResponse::Error { .. }),
+            "fixture order rejected"
+        );
+        response
+    }
+
+    /// A bid that joins the back of the queue at one of the book's levels.
+    fn resting_bid(&mut self, iteration: u64) -> Order {
+        let level = iteration as usize % self.depth;
+        let price = Self::price(&OrderSide::Buy, level);
+        self.order(
+            MAKER,
+            OrderSide::Buy,
+            OrderType::LimitOrder,
+            Some(price),
+            Decimal::ONE,
+        )
+    }
+}
+
+fn limit_insertion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("limit_insertion");
+    for depth in DEPTHS {
+        let mut fixture = Fixture::new(depth);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter_custom(|iters| {
+                let orders: Vec<Order> = (0..iters).map(|i| fixture.resting_bid(i)).collect();
+                let ids: Vec<String> = orders.iter().map(|order| order.id.clone()).collect();
+
+                let start = Instant::now();
+                for order in orders {
+                    fixture.place(order);
+                }
+                let elapsed = start.elapsed();
+
+                for id in &ids {
+                    fixture.book.cancel_order(id);
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn market_sweep(c: &mut Criterion) {
+    let mut group = c.benchmark_group("market_sweep");
+    for depth in DEPTHS {
+        let mut fixture = Fixture::new(depth);
+        let everything = fixture.side_quantity();
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let sweep = fixture.order(
+                        TAKER,
+                        OrderSide::Buy,
+                        OrderType::MarketOrder {
+                            protection_price: None,
+                        },
+                        None,
+                        everything,
+                    );
+
+                    let start = Instant::now();
+                    fixture.place(sweep);
+                    elapsed += start.elapsed();
+
+                    fixture.fill(OrderSide::Sell);
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn cancellation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cancellation");
+    for depth in DEPTHS {
+        let mut fixture = Fixture::new(depth);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter_custom(|iters| {
+                let ids: Vec<String> = (0..iters)
+                    .map(|i| {
+                        let order = fixture.resting_bid(i);
+                        let id = order.id.clone();
+                        fixture.place(order);
+                        id
+                    })
+                    .collect();
+
+                let start = Instant::now();
+                for id in &ids {
+                    fixture.book.cancel_order(id);
+                }
+                start.elapsed()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, limit_insertion, market_sweep, cancellation);
+criterion_main!(benches);
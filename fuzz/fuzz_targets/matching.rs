@@ -0,0 +1,228 @@
+//! Drives arbitrary orders and deposits through `Orderbook::add_order` and
+//! `Account::deposit` and checks the book and the accounts after each one.
+//!
+//! The input is read as a run of 18-byte commands; a shorter tail is ignored:
+//!
+//! | byte  | meaning                                                      |
+//! |-------|--------------------------------------------------------------|
+//! | 0     | bit 0 picks one of two traders, bits 1-3 the action: 0-4     |
+//! |       | place an order, 5 cancel one placed earlier, 6 deposit quote |
+//! |       | and 7 deposit base                                           |
+//! | 1     | bit 0 is the side (set for sell), the rest the order type,   |
+//! |       | an index into `order_type` modulo its variants               |
+//! | 2-9   | price as a little-endian `f64`                               |
+//! | 10-17 | quantity as a little-endian `f64`                            |
+//!
+//! Prices and quantities go through `Decimal::from_f64`, so NaN and the
+//! infinities arrive as a missing price or a zero quantity and values out of
+//! `Decimal`'s range are dropped the same way. Quantities are then rounded to
+//! the market's `base_decimals`, as the engine refuses anything finer. A
+//! cancel takes the price bits as an index into the orders placed so far. A
+//! deposit brings in the quantity, refused as `/onramp` refuses it when it is
+//! over `MAX_DEPOSIT` or would overflow the holding.
+//!
+//! After every command the book must not be crossed, its levels must be
+//! sorted and non-empty, no holding may go negative, and, as the market
+//! charges no fees and settles whole cents, the quote and base held across
+//! both traders must be what they were funded with plus what was deposited.
+
+#![no_main]
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use libfuzzer_sys::fuzz_target;
+use orderbook::orderbook::Orderbook;
+use orderbook::types::{
+    Account, Accounts, Feeds, MarketConfig, Order, OrderSide, OrderStatus, OrderType, PegReference,
+    SelfTradePrevention, DEFAULT_BASE_DECIMALS, MAX_DEPOSIT,
+};
+use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+const SYMBOL: &str = "FUZZ-USD";
+const BASE: &str = "FUZZ";
+const TRADERS: [&str; 2] = ["maker", "taker"];
+const COMMAND_LEN: usize = 18;
+
+/// Quote and base each trader starts with: plenty for ordinary orders, while
+/// huge prices and quantities still run out of funds.
+fn funding() -> Decimal {
+    Decimal::from(1_000_000_000u64)
+}
+
+fn order_type(index: u8, price: Option<Decimal>, quantity: Decimal) -> OrderType {
+    let trigger = price.unwrap_or_default();
+    match index % 12 {
+        0 => OrderType::LimitOrder,
+        1 => OrderType::MarketOrder {
+            protection_price: None,
+        },
+        2 => OrderType::MarketOrder {
+            protection_price: price,
+        },
+        3 => OrderType::ImmediateOrCancel,
+        4 => OrderType::FillOrKill,
+        5 => OrderType::PostOnly,
+        6 => OrderType::StopMarket {
+            stop_price: trigger,
+        },
+        7 => OrderType::TrailingStop {
+            trail_amount: trigger,
+            stop_price: None,
+        },
+        8 => OrderType::MarketIfTouched {
+            touch_price: trigger,
+        },
+        9 => OrderType::Iceberg {
+            display_quantity: quantity / Decimal::TWO,
+        },
+        10 => OrderType::Peg {
+            reference: PegReference::Primary,
+            offset: Decimal::ZERO,
+        },
+        _ => OrderType::Peg {
+            reference: PegReference::Market,
+            offset: -trigger,
+        },
+    }
+}
+
+fn decimal(bytes: &[u8]) -> Option<Decimal> {
+    let value = f64::from_le_bytes(bytes.try_into().unwrap());
+    Decimal::from_f64(value)
+}
+
+/// Quote and base brought in across both traders, funding included.
+struct Supply {
+    quote: Decimal,
+    base: Decimal,
+}
+
+fn check(book: &Orderbook, accounts: &Accounts, supply: &Supply) {
+    if let (Some(bid), Some(ask)) = (book.best_bid(), book.best_ask()) {
+        assert!(bid < ask, "book crossed: bid {bid} >= ask {ask}");
+    }
+
+    let snapshot = book.get_snapshot();
+    for level in snapshot.bids.iter().chain(&snapshot.asks) {
+        assert!(
+            level.quantity > Decimal::ZERO,
+            "empty level at {}",
+            level.price
+        );
+        assert!(
+            level.order_count > 0,
+            "level without orders at {}",
+            level.price
+        );
+    }
+    assert!(snapshot.bids.windows(2).all(|w| w[0].price > w[1].price));
+    assert!(snapshot.asks.windows(2).all(|w| w[0].price < w[1].price));
+
+    let accounts = accounts.lock().unwrap();
+    let mut quote = Decimal::ZERO;
+    let mut base = Decimal::ZERO;
+    for account in accounts.values() {
+        let held = account.assets.get(BASE).copied().unwrap_or_default();
+        let reserved = account
+            .reserved_assets
+            .get(BASE)
+            .copied()
+            .unwrap_or_default();
+        for amount in [account.balance, account.reserved, held, reserved] {
+            assert!(amount >= Decimal::ZERO, "negative holding: {account:?}");
+        }
+        quote += account.balance + account.reserved;
+        base += held + reserved;
+    }
+    assert_eq!(quote, supply.quote, "quote not conserved");
+    assert_eq!(base, supply.base, "base not conserved");
+}
+
+fuzz_target!(|data: &[u8]| {
+    let funded = Account {
+        balance: funding(),
+        assets: HashMap::from([(BASE.to_string(), funding())]),
+        ..Account::default()
+    };
+    let accounts: Accounts = Arc::new(Mutex::new(
+        TRADERS
+            .iter()
+            .map(|trader| (trader.to_string(), funded.clone()))
+            .collect(),
+    ));
+    let market = MarketConfig::new(SYMBOL, BASE, Decimal::new(1, 2));
+    let mut book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(16));
+    let mut placed: Vec<String> = Vec::new();
+    let funded = funding() * Decimal::from(TRADERS.len());
+    let mut supply = Supply {
+        quote: funded,
+        base: funded,
+    };
+
+    for (n, command) in data.chunks_exact(COMMAND_LEN).enumerate() {
+        let now = n as u64 + 1;
+        let trader = TRADERS[usize::from(command[0] & 1)];
+        let price = decimal(&command[2..10]);
+        let quantity = decimal(&command[10..18])
+            .unwrap_or_default()
+            .round_dp(DEFAULT_BASE_DECIMALS);
+
+        let action = (command[0] >> 1) & 7;
+        if action >= 6 {
+            let asset = (action == 7).then_some(BASE);
+            let mut accounts = accounts.lock().unwrap();
+            let account = accounts.get_mut(trader).unwrap();
+            if quantity > Decimal::ZERO && quantity <= MAX_DEPOSIT {
+                match account.deposit(asset, quantity, now) {
+                    Ok(_) if asset.is_some() => supply.base += quantity,
+                    Ok(_) => supply.quote += quantity,
+                    Err(_) => assert!(!account.can_credit(asset, quantity)),
+                }
+            }
+        } else if action == 5 {
+            if !placed.is_empty() {
+                let index = u64::from_le_bytes(command[2..10].try_into().unwrap());
+                let id = &placed[(index % placed.len() as u64) as usize];
+                book.cancel_order(id);
+            }
+        } else {
+            let side = if command[1] & 1 == 0 {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            };
+            let order_type = order_type(command[1] >> 1, price, quantity);
+            let id = format!("fuzz-{n}");
+            let order = Order {
+                id: id.clone(),
+                user_id: trader.to_string(),
+                symbol: SYMBOL.to_string(),
+                side,
+                // Only the order types that take a price are given one.
+                price: price.filter(|_| order_type.requires_price()),
+                order_type,
+                quantity,
+                remaining_quantity: quantity,
+                quote_quantity: None,
+                visible_quantity: None,
+                linked_order_id: None,
+                client_order_id: None,
+                expires_at: None,
+                activates_at: None,
+                worst_average_price: None,
+                timestamp: now,
+                sequence: now,
+                self_trade_prevention: SelfTradePrevention::default(),
+                reduce_only: false,
+                status: OrderStatus::Open,
+            };
+            book.add_order(order, now);
+            placed.push(id);
+        }
+
+        check(&book, &accounts, &supply);
+    }
+});
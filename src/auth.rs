@@ -0,0 +1,56 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::HttpRequest;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+/// How long a token issued by `signin` stays valid.
+pub const TOKEN_TTL_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// Username the token was issued to.
+    pub sub: String,
+    /// Expiry as seconds since the Unix epoch.
+    pub exp: u64,
+}
+
+pub fn issue_token(secret: &str, username: &str) -> jsonwebtoken::errors::Result<String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: now + TOKEN_TTL_SECS,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+}
+
+/// Returns the claims of a token signed with `secret` that has not expired.
+pub fn verify_token(secret: &str, token: &str) -> Option<Claims> {
+    let mut validation = Validation::default();
+    validation.leeway = 0;
+
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &validation,
+    )
+    .ok()
+    .map(|data| data.claims)
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|t| t.strip_prefix("Bearer "))
+        .map(|s| s.to_string())
+}
@@ -0,0 +1,94 @@
+use rust_decimal::Decimal;
+
+use ::orderbook::types::{OrderActivity, ORDER_ACTIVITY_RETENTION_MILLIS};
+
+use crate::config::{env_or, invalid};
+
+/// Cancels per placed order above which a user is flagged, unless
+/// `CANCEL_RATIO_LIMIT` is set.
+pub const DEFAULT_LIMIT: Decimal = Decimal::from_parts(9, 0, 0, false, 1);
+
+/// Orders a user must have placed in the window before they can be flagged,
+/// unless `CANCEL_RATIO_MIN_ORDERS` is set.
+pub const DEFAULT_MIN_ORDERS: u64 = 50;
+
+/// Span the ratio is taken over unless `CANCEL_RATIO_WINDOW_SECS` is set.
+pub const DEFAULT_WINDOW_SECS: u64 = 5 * 60;
+
+/// What happens to a flagged user's new orders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Only reported on `/admin/users`.
+    Flag,
+    /// Also refused until the ratio falls back under the limit.
+    Reject,
+}
+
+/// Flags users who cancel most of what they place, a sign of orders meant to
+/// move the book rather than trade. A user is flagged while their orders
+/// placed over the last `window_millis` number at least `min_orders` and
+/// their cancels exceed `limit` times that.
+#[derive(Debug, Clone)]
+pub struct CancelRatioPolicy {
+    limit: Decimal,
+    min_orders: u64,
+    window_millis: u64,
+    action: Action,
+}
+
+impl CancelRatioPolicy {
+    /// Reads the policy from the environment; `CANCEL_RATIO_ACTION` is `flag`,
+    /// the default, or `reject`. A value that does not parse stops startup,
+    /// as does a window longer than the activity accounts keep.
+    pub fn from_env() -> std::io::Result<Self> {
+        let limit = env_or("CANCEL_RATIO_LIMIT", DEFAULT_LIMIT)?;
+        let min_orders = env_or("CANCEL_RATIO_MIN_ORDERS", DEFAULT_MIN_ORDERS)?;
+        let window_secs = env_or("CANCEL_RATIO_WINDOW_SECS", DEFAULT_WINDOW_SECS)?;
+        if limit < Decimal::ZERO {
+            return Err(invalid("CANCEL_RATIO_LIMIT", &limit.to_string()));
+        }
+        let window_millis = window_secs.saturating_mul(1000);
+        if window_millis == 0 || window_millis > ORDER_ACTIVITY_RETENTION_MILLIS {
+            return Err(invalid(
+                "CANCEL_RATIO_WINDOW_SECS",
+                &window_secs.to_string(),
+            ));
+        }
+        let action = match std::env::var("CANCEL_RATIO_ACTION") {
+            Err(_) => Action::Flag,
+            Ok(action) => match action.trim().to_lowercase().as_str() {
+                "flag" => Action::Flag,
+                "reject" => Action::Reject,
+                _ => return Err(invalid("CANCEL_RATIO_ACTION", &action)),
+            },
+        };
+
+        Ok(Self {
+            limit,
+            min_orders,
+            window_millis,
+            action,
+        })
+    }
+
+    pub fn window_millis(&self) -> u64 {
+        self.window_millis
+    }
+
+    pub fn limit(&self) -> Decimal {
+        self.limit
+    }
+
+    /// Whether `activity`, taken over `window_millis`, gets a user flagged.
+    pub fn flags(&self, activity: &OrderActivity) -> bool {
+        activity.placed >= self.min_orders
+            && activity
+                .cancel_ratio()
+                .is_some_and(|ratio| ratio > self.limit)
+    }
+
+    /// Whether `activity` gets a user's new orders refused.
+    pub fn rejects(&self, activity: &OrderActivity) -> bool {
+        self.action == Action::Reject && self.flags(activity)
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::types::Trade;
+
+/// Candle widths the engine aggregates, as accepted by `/candles/{symbol}`.
+pub const INTERVALS: [(&str, u64); 4] = [("1m", 60), ("5m", 300), ("15m", 900), ("1h", 3600)];
+
+/// Completed candles kept per interval, in addition to the one in progress.
+const CANDLE_HISTORY_LIMIT: usize = 500;
+
+/// Seconds in an interval name such as "5m", if it is one of `INTERVALS`.
+pub fn interval_secs(name: &str) -> Option<u64> {
+    INTERVALS
+        .iter()
+        .find(|(interval, _)| *interval == name)
+        .map(|(_, secs)| *secs)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Candle {
+    /// Start of the bucket, in milliseconds since the Unix epoch.
+    pub open_time: u64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+}
+
+/// Candles of one width for one market, oldest first. The last entry is the
+/// bucket currently being filled. Buckets without trades are skipped.
+pub struct CandleSeries {
+    pub interval_secs: u64,
+    candles: VecDeque<Candle>,
+}
+
+impl CandleSeries {
+    pub fn new(interval_secs: u64) -> Self {
+        Self {
+            interval_secs,
+            candles: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, trade: &Trade) {
+        let interval_millis = self.interval_secs * 1000;
+        let open_time = trade.timestamp - trade.timestamp % interval_millis;
+
+        if let Some(candle) = self.candles.back_mut() {
+            if candle.open_time == open_time {
+                candle.high = candle.high.max(trade.price);
+                candle.low = candle.low.min(trade.price);
+                candle.close = trade.price;
+                candle.volume += trade.quantity;
+                return;
+            }
+        }
+
+        if self.candles.len() > CANDLE_HISTORY_LIMIT {
+            self.candles.pop_front();
+        }
+        self.candles.push_back(Candle {
+            open_time,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.quantity,
+        });
+    }
+
+    /// The last `limit` candles, oldest first.
+    pub fn latest(&self, limit: usize) -> Vec<Candle> {
+        let skip = self.candles.len().saturating_sub(limit);
+        self.candles.iter().skip(skip).cloned().collect()
+    }
+}
@@ -0,0 +1,38 @@
+use std::str::FromStr;
+
+/// The environment variable `name` parsed as a `T`, or `default` when unset.
+/// A value that does not parse stops startup rather than being ignored.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> std::io::Result<T> {
+    Ok(env_parsed(name)?.unwrap_or(default))
+}
+
+/// The environment variable `name` parsed as a `T`, or `None` when unset.
+pub fn env_parsed<T: FromStr>(name: &str) -> std::io::Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid(name, &value)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Comma-separated entries of the environment variable `name`, or of
+/// `default` when it is unset. Entries are trimmed and empty ones skipped.
+pub fn env_list(name: &str, default: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// The error startup fails with when `name` is set to `value` it cannot use.
+pub fn invalid(name: &str, value: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{name} has an invalid value: {value:?}"),
+    )
+}
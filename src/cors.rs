@@ -0,0 +1,93 @@
+use std::str::FromStr;
+
+use actix_cors::Cors;
+use actix_web::http::{header::HeaderName, Method, Uri};
+
+use crate::config::{env_list, env_or, invalid};
+
+/// Methods allowed from other origins unless `CORS_ALLOWED_METHODS` is set:
+/// every method a route answers to.
+pub const DEFAULT_ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+
+/// Request headers allowed from other origins unless `CORS_ALLOWED_HEADERS`
+/// is set.
+pub const DEFAULT_ALLOWED_HEADERS: &str = "Authorization, Content-Type, Idempotency-Key";
+
+/// How long a browser may reuse a preflight answer unless `CORS_MAX_AGE_SECS`
+/// is set.
+pub const DEFAULT_MAX_AGE_SECS: usize = 60 * 60;
+
+/// Which other origins browsers let call the API. None are allowed unless
+/// `CORS_ALLOWED_ORIGINS` lists them, or is `*` for any. Requests from an
+/// origin not listed are still served, only without the headers a browser
+/// needs to hand the response to the page; their preflights are refused.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    /// Empty when any origin is allowed.
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age_secs: usize,
+}
+
+impl CorsPolicy {
+    /// Reads the policy from the environment. A value that does not parse
+    /// stops startup rather than quietly narrowing or widening the policy.
+    pub fn from_env() -> std::io::Result<Self> {
+        let origins = env_list("CORS_ALLOWED_ORIGINS", "");
+        let any_origin = origins.iter().any(|origin| origin == "*");
+        let origins = match any_origin {
+            true => Vec::new(),
+            false => origins
+                .into_iter()
+                .map(|origin| match Uri::from_str(&origin) {
+                    Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => Ok(origin),
+                    _ => Err(invalid("CORS_ALLOWED_ORIGINS", &origin)),
+                })
+                .collect::<std::io::Result<_>>()?,
+        };
+        let methods = env_list("CORS_ALLOWED_METHODS", DEFAULT_ALLOWED_METHODS)
+            .iter()
+            .map(|method| {
+                Method::from_str(&method.to_uppercase())
+                    .map_err(|_| invalid("CORS_ALLOWED_METHODS", method))
+            })
+            .collect::<std::io::Result<_>>()?;
+        let headers = env_list("CORS_ALLOWED_HEADERS", DEFAULT_ALLOWED_HEADERS)
+            .iter()
+            .map(|header| {
+                HeaderName::from_str(header).map_err(|_| invalid("CORS_ALLOWED_HEADERS", header))
+            })
+            .collect::<std::io::Result<_>>()?;
+        let max_age_secs = env_or("CORS_MAX_AGE_SECS", DEFAULT_MAX_AGE_SECS)?;
+
+        Ok(Self {
+            origins,
+            any_origin,
+            methods,
+            headers,
+            max_age_secs,
+        })
+    }
+
+    /// The middleware enforcing the policy. `Cors` cannot be cloned, so each
+    /// worker builds its own.
+    pub fn middleware(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.methods.clone())
+            .allowed_headers(self.headers.clone())
+            .max_age(self.max_age_secs);
+        if self.any_origin {
+            cors = cors.allow_any_origin();
+        }
+        for origin in &self.origins {
+            cors = cors.allowed_origin(origin);
+        }
+        cors
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.any_origin
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::HashMap, future::Future, sync::OnceLock, time::Duration};
+
+use actix_web::{http::StatusCode, HttpResponse};
+use tokio::sync::{
+    mpsc::{self, error::SendTimeoutError},
+    oneshot,
+};
+
+use crate::error::ApiError;
+use crate::types::{Order, OrderbookCommand};
+
+/// Command channel of each market's engine task, keyed by symbol. Commands to
+/// one market are handled in the order they are sent; markets run
+/// independently of each other.
+pub type Shards = HashMap<String, mpsc::Sender<OrderbookCommand>>;
+
+/// How long a request waits for an engine unless `ENGINE_TIMEOUT_MS` is set.
+pub const DEFAULT_ENGINE_TIMEOUT_MS: u64 = 5_000;
+
+/// How long a request waits for room in a full engine queue unless
+/// `ENGINE_QUEUE_TIMEOUT_MS` is set.
+pub const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 250;
+
+static ENGINE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+static QUEUE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Runs a market's engine on a thread of its own. An engine waiting on the
+/// log, the disk or the balances then holds up only its market, never the
+/// runtime workers serving requests.
+pub fn spawn_engine(
+    symbol: &str,
+    engine: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<std::thread::JoinHandle<()>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name(format!("engine-{symbol}"))
+        .spawn(move || runtime.block_on(engine))
+}
+
+/// Sets how long requests wait for an engine. Only the first call has any
+/// effect; it is made once at startup.
+pub fn set_timeout(timeout: Duration) {
+    let _ = ENGINE_TIMEOUT.set(timeout);
+}
+
+/// Sets how long requests wait for room in a full engine queue. Only the
+/// first call has any effect; it is made once at startup.
+pub fn set_queue_timeout(timeout: Duration) {
+    let _ = QUEUE_TIMEOUT.set(timeout);
+}
+
+/// Why an engine task gave no answer.
+#[derive(Debug)]
+pub enum EngineError {
+    NotRunning,
+    Dropped,
+    /// The engine's queue stayed full for the whole queue timeout.
+    Busy,
+    TimedOut,
+}
+
+impl EngineError {
+    pub fn response(&self) -> HttpResponse {
+        let unavailable = |code, message| {
+            ApiError::new(StatusCode::SERVICE_UNAVAILABLE, code, message).response()
+        };
+        match self {
+            Self::NotRunning => {
+                unavailable("engine_not_running", "orderbook engine is not running")
+            }
+            Self::Dropped => ApiError::internal("orderbook engine dropped the request").response(),
+            Self::Busy => {
+                let mut response =
+                    unavailable("engine_busy", "orderbook engine is busy, retry shortly");
+                response.headers_mut().insert(
+                    actix_web::http::header::RETRY_AFTER,
+                    actix_web::http::header::HeaderValue::from_static("1"),
+                );
+                response
+            }
+            Self::TimedOut => {
+                unavailable("engine_timeout", "orderbook engine did not answer in time")
+            }
+        }
+    }
+}
+
+/// Gives up on `exchange` once the engine timeout passes. An order command
+/// still queued by then is skipped by the engine when it gets to it, as
+/// nobody is waiting for the answer.
+async fn timed<T>(
+    exchange: impl Future<Output = Result<T, EngineError>>,
+) -> Result<T, EngineError> {
+    let timeout = ENGINE_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_millis(DEFAULT_ENGINE_TIMEOUT_MS));
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .unwrap_or(Err(EngineError::TimedOut))
+}
+
+/// Queues `command` on an engine. Rather than wait indefinitely behind a full
+/// queue, gives up once the queue timeout passes without room opening up.
+async fn enqueue(
+    orderbook_tx: &mpsc::Sender<OrderbookCommand>,
+    command: OrderbookCommand,
+) -> Result<(), EngineError> {
+    let timeout = QUEUE_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_millis(DEFAULT_QUEUE_TIMEOUT_MS));
+    orderbook_tx
+        .send_timeout(command, timeout)
+        .await
+        .map_err(|error| match error {
+            SendTimeoutError::Timeout(_) => {
+                tracing::warn!(capacity = orderbook_tx.max_capacity(), "engine queue full");
+                EngineError::Busy
+            }
+            SendTimeoutError::Closed(_) => EngineError::NotRunning,
+        })
+}
+
+/// Sends the command built by `command` to one engine and waits for its reply.
+pub async fn request<T>(
+    orderbook_tx: &mpsc::Sender<OrderbookCommand>,
+    command: impl FnOnce(oneshot::Sender<T>) -> OrderbookCommand,
+) -> Result<T, EngineError> {
+    timed(async {
+        let (response_tx, response_rx) = oneshot::channel();
+        enqueue(orderbook_tx, command(response_tx)).await?;
+        response_rx.await.map_err(|_| EngineError::Dropped)
+    })
+    .await
+}
+
+/// What an engine first answered a command that can be acknowledged with.
+pub enum Answer<A, T> {
+    /// The command was acknowledged; its reply is still to come on the receiver.
+    Acknowledged(A, oneshot::Receiver<T>),
+    /// The engine replied without acknowledging first.
+    Replied(T),
+}
+
+/// Like `request`, for commands the engine may acknowledge before it has
+/// worked out its reply. Returns at the acknowledgement, leaving the caller
+/// to collect the reply, or with the reply if no acknowledgement was sent.
+pub async fn request_acknowledged<A, T>(
+    orderbook_tx: &mpsc::Sender<OrderbookCommand>,
+    command: impl FnOnce(oneshot::Sender<A>, oneshot::Sender<T>) -> OrderbookCommand,
+) -> Result<Answer<A, T>, EngineError> {
+    timed(async {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let (response_tx, response_rx) = oneshot::channel();
+        enqueue(orderbook_tx, command(ack_tx, response_tx)).await?;
+        match ack_rx.await {
+            Ok(ack) => Ok(Answer::Acknowledged(ack, response_rx)),
+            Err(_) => response_rx
+                .await
+                .map(Answer::Replied)
+                .map_err(|_| EngineError::Dropped),
+        }
+    })
+    .await
+}
+
+/// Sends a command to every engine before waiting on any, so the markets work
+/// on it at the same time. Replies come back in the iteration order of `shards`.
+pub async fn request_all<T>(
+    shards: &Shards,
+    command: impl Fn(oneshot::Sender<T>) -> OrderbookCommand,
+) -> Result<Vec<T>, EngineError> {
+    timed(async {
+        let mut pending = Vec::with_capacity(shards.len());
+        for orderbook_tx in shards.values() {
+            let (response_tx, response_rx) = oneshot::channel();
+            enqueue(orderbook_tx, command(response_tx)).await?;
+            pending.push(response_rx);
+        }
+
+        let mut replies = Vec::with_capacity(pending.len());
+        for response_rx in pending {
+            replies.push(response_rx.await.map_err(|_| EngineError::Dropped)?);
+        }
+        Ok(replies)
+    })
+    .await
+}
+
+/// The engine holding `order_id`, open or recently closed, and the order
+/// itself. Orders never move between markets, so the engine found stays the
+/// right one to send later commands for the order to.
+pub async fn find_order<'a>(
+    shards: &'a Shards,
+    order_id: &str,
+) -> Result<Option<(&'a mpsc::Sender<OrderbookCommand>, Order)>, EngineError> {
+    let replies = request_all(shards, |response| OrderbookCommand::GetOrder {
+        order_id: order_id.to_string(),
+        response,
+    })
+    .await?;
+
+    Ok(shards
+        .values()
+        .zip(replies)
+        .find_map(|(orderbook_tx, order)| order.map(|order| (orderbook_tx, order))))
+}
@@ -0,0 +1,11 @@
+//! The matching engine and its persistence, shared by the HTTP server in
+//! `main.rs` and the benchmarks under `benches/`.
+
+pub mod candles;
+pub mod dispatch;
+pub mod error;
+pub mod metrics;
+pub mod orderbook;
+pub mod persistence;
+pub mod stats;
+pub mod types;
@@ -0,0 +1,73 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Failed signins allowed within the window unless `LOGIN_MAX_FAILURES` is set.
+pub const DEFAULT_MAX_FAILURES: usize = 5;
+
+/// Length of the sliding window unless `LOGIN_WINDOW_SECS` is set.
+pub const DEFAULT_WINDOW_SECS: u64 = 15 * 60;
+
+/// Failed signins per username over a sliding window. Once `max_failures`
+/// of them fall within `window` the username is locked, even to the right
+/// password, until the oldest of them leaves the window.
+pub struct LoginAttempts {
+    max_failures: usize,
+    window: Duration,
+    failures: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl LoginAttempts {
+    pub fn new(max_failures: usize, window: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            window,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads `LOGIN_MAX_FAILURES` and `LOGIN_WINDOW_SECS`; a value that does
+    /// not parse stops startup.
+    pub fn from_env() -> std::io::Result<Self> {
+        let max_failures = crate::config::env_or("LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILURES)?;
+        let window = crate::config::env_or("LOGIN_WINDOW_SECS", DEFAULT_WINDOW_SECS)?;
+        Ok(Self::new(max_failures, Duration::from_secs(window)))
+    }
+
+    /// How long `username` stays locked, if it is.
+    pub fn locked_for(&self, username: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let recent = failures.get_mut(username)?;
+        self.forget_expired(recent, now);
+        if recent.len() < self.max_failures {
+            return None;
+        }
+        let oldest = recent[recent.len() - self.max_failures];
+        Some(self.window.saturating_sub(now - oldest))
+    }
+
+    pub fn record_failure(&self, username: &str) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let recent = failures.entry(username.to_string()).or_default();
+        self.forget_expired(recent, now);
+        recent.push_back(now);
+        // Only the latest `max_failures` decide when the lock lifts.
+        while recent.len() > self.max_failures {
+            recent.pop_front();
+        }
+    }
+
+    pub fn clear(&self, username: &str) {
+        self.failures.lock().unwrap().remove(username);
+    }
+
+    fn forget_expired(&self, recent: &mut VecDeque<Instant>, now: Instant) {
+        while recent.front().is_some_and(|&at| now - at >= self.window) {
+            recent.pop_front();
+        }
+    }
+}
@@ -1,14 +1,41 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::Duration,
+};
 
-use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use actix_web::{
+    delete, dev::Payload, get, http::StatusCode, middleware, patch, post, put, web, App,
+    FromRequest, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::types::{OrderbookCommand, User};
+use ::orderbook::{candles, dispatch, error, metrics, orderbook, persistence, types};
 
-mod orderbook;
-mod types;
+use crate::auth::bearer_token;
+use crate::config::{env_list, env_or};
+use crate::dispatch::Shards;
+use crate::error::ApiError;
+use crate::persistence::{Recovery, SharedWal, Wal, WalEntry};
+use crate::types::{
+    Account, Accounts, Bracket, Feeds, MarketFees, MarketStatus, MatchingMode, Order,
+    OrderActivity, OrderError, OrderResponse, OrderSide, OrderStatus, OrderType, OrderbookCommand,
+    PriceLevel, SelfTradePrevention, User, UserEvent, MAX_DEPOSIT,
+};
+
+mod auth;
+mod cancel_ratio;
+mod config;
+mod cors;
+mod lockout;
+mod password;
+#[cfg(feature = "test-endpoints")]
+mod test_endpoints;
+mod ws;
 
 #[get("/hello/{name}")]
 async fn greet(name: web::Path<String>) -> impl Responder {
@@ -17,8 +44,35 @@ async fn greet(name: web::Path<String>) -> impl Responder {
 
 struct AppState {
     users: Mutex<HashMap<String, types::User>>,
-    sessions: Mutex<HashMap<String, String>>,
-    orderbook_tx: tokio::sync::mpsc::Sender<OrderbookCommand>,
+    /// Secret used to sign and verify session tokens.
+    jwt_secret: String,
+    /// Tokens invalidated by `/logout`, mapped to their expiry so they can be
+    /// dropped once they would have been rejected anyway.
+    revoked_tokens: Mutex<HashMap<String, u64>>,
+    /// Failed signins per username; see `lockout::LoginAttempts::from_env`.
+    login_attempts: lockout::LoginAttempts,
+    /// Which users cancel too much of what they place, and what is done
+    /// about it; see `cancel_ratio::CancelRatioPolicy::from_env`.
+    cancel_ratio: cancel_ratio::CancelRatioPolicy,
+    /// Hashes new passwords; see `password::from_env`.
+    password_hasher: Box<dyn password::PasswordHasher>,
+    /// What new passwords must satisfy.
+    password_policy: password::PasswordPolicy,
+    /// Usernames given `User::is_admin` at signup, from `ADMIN_USERS`.
+    admins: HashSet<String>,
+    accounts: Accounts,
+    /// Engine task of each market.
+    shards: Shards,
+    /// Last `Order::sequence` handed out by any market.
+    order_sequence: Arc<AtomicU64>,
+    /// Market data published by the engines; each WebSocket client subscribes.
+    feeds: Feeds,
+    /// Shared with the engines, which log order changes to it. Deposits,
+    /// withdrawals and signups are logged by their handlers.
+    wal: SharedWal,
+    /// Where `save_state` writes.
+    state_file: PathBuf,
+    metrics: metrics::Metrics,
 }
 
 #[derive(Serialize)]
@@ -35,53 +89,268 @@ struct AuthRequest {
 
 #[derive(Deserialize)]
 struct OnRampRequest {
-    amount: f64,
+    amount: Decimal,
+    /// Base asset to credit or debit instead of the quote balance, e.g. "BTC".
+    asset: Option<String>,
 }
 
 #[derive(Serialize)]
 struct OnRampResponse {
     success: bool,
     message: String,
-    new_balance: f64,
+    new_balance: Decimal,
+}
+
+#[derive(Deserialize)]
+struct OrderRequest {
+    symbol: String,
+    side: OrderSide,
+    #[serde(flatten)]
+    order_type: OrderType,
+    price: Option<Decimal>,
+    /// Left out by market buys that give `quote_quantity` instead.
+    #[serde(default)]
+    quantity: Decimal,
+    quote_quantity: Option<Decimal>,
+    /// Good-till-date: milliseconds since the Unix epoch after which the order
+    /// is taken off the book. Omitted means good-till-cancelled.
+    expires_at: Option<u64>,
+    /// Good-after-time: milliseconds since the Unix epoch before which the
+    /// order is held back from the book. A time already passed places it at once.
+    activates_at: Option<u64>,
+    /// Worst average price to accept for whatever the order takes from the
+    /// book: the most a buy pays, the least a sell receives.
+    worst_average_price: Option<Decimal>,
+    #[serde(default)]
+    self_trade_prevention: SelfTradePrevention,
+    #[serde(default)]
+    reduce_only: bool,
+    /// Also accepted as an `Idempotency-Key` header on `POST /order`.
+    client_order_id: Option<String>,
+}
+
+/// An entry order with the exits to place once it fills.
+#[derive(Deserialize)]
+struct BracketRequest {
+    entry: OrderRequest,
+    /// Limit price the take-profit exit rests at.
+    take_profit_price: Decimal,
+    /// Trigger of the stop exit.
+    stop_price: Decimal,
+}
+
+/// The entry's outcome, with the ids its exits will have once placed.
+#[derive(Serialize)]
+struct BracketResponse {
+    #[serde(flatten)]
+    entry: OrderResponse,
+    take_profit_order_id: String,
+    stop_order_id: String,
+}
+
+/// Two orders on one market, placed as a one-cancels-the-other pair.
+#[derive(Deserialize)]
+struct OcoRequest {
+    first: OrderRequest,
+    second: OrderRequest,
+}
+
+#[derive(Deserialize)]
+struct AmendRequest {
+    price: Option<Decimal>,
+    /// New total quantity, including whatever has already filled.
+    quantity: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct ReduceRequest {
+    /// New total quantity, including whatever has already filled.
+    quantity: Decimal,
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    depth: Option<usize>,
+}
+
+/// Samples returned by `/midprice/{symbol}` when no `limit` is given.
+const DEFAULT_MID_PRICE_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct MidPriceQuery {
+    limit: Option<usize>,
+}
+
+/// Trades returned by `/trades/{symbol}` when no `limit` is given.
+const DEFAULT_TRADES_LIMIT: usize = 100;
+
+/// Orders returned by `/orders` when no `limit` is given.
+const DEFAULT_ORDERS_LIMIT: usize = 100;
+
+/// Largest `limit` the paginated listings accept; larger ones are cut to it.
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Header carrying the cursor for the next page of a paginated listing. It
+/// is left out once the listing has run out.
+const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+#[derive(Deserialize)]
+struct TradesQuery {
+    limit: Option<usize>,
+    /// Id of the last trade of the previous page.
+    before: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OrdersQuery {
+    limit: Option<usize>,
+    /// `sequence` of the last order of the previous page.
+    after: Option<u64>,
+}
+
+/// A full page of `items`, with the cursor for the page after it taken from
+/// its last item. A short page is the last one.
+fn page<T: Serialize>(items: Vec<T>, limit: usize, cursor: impl Fn(&T) -> String) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(last) = items.last().filter(|_| items.len() == limit) {
+        response.insert_header((NEXT_CURSOR_HEADER, cursor(last)));
+    }
+    response.json(items)
+}
+
+/// Ledger entries returned by `/ledger` when no `limit` is given.
+const DEFAULT_LEDGER_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct LedgerQuery {
+    limit: Option<usize>,
+}
+
+/// Candles returned by `/candles/{symbol}` when no `limit` is given.
+const DEFAULT_CANDLES_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct CandlesQuery {
+    interval: String,
+    limit: Option<usize>,
+}
+
+/// Verifies `token` and checks it has not been revoked by a logout.
+fn session_claims(data: &AppState, token: &str) -> Option<auth::Claims> {
+    if data.revoked_tokens.lock().unwrap().contains_key(token) {
+        return None;
+    }
+    auth::verify_token(&data.jwt_secret, token)
+}
+
+fn authenticated_user(data: &AppState, req: &HttpRequest) -> Option<User> {
+    let token = bearer_token(req)?;
+    let claims = session_claims(data, &token)?;
+    // Tokens issued before names were normalized carry the name as typed.
+    let username = types::canonical_username(&claims.sub);
+    data.users.lock().unwrap().get(&username).cloned()
+}
+
+/// The user a request's bearer token was issued to. Taking one as a handler
+/// argument answers 401 before the handler runs when the token is missing,
+/// malformed, expired, revoked or names no known user.
+struct AuthenticatedUser(User);
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = std::future::Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let user = req
+            .app_data::<web::Data<AppState>>()
+            .and_then(|data| authenticated_user(data, req));
+        std::future::ready(user.map(Self).ok_or_else(unauthorized))
+    }
+}
+
+fn forbidden() -> HttpResponse {
+    ApiError::new(StatusCode::FORBIDDEN, "forbidden", "admin access required").response()
+}
+
+fn unauthorized() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "missing or invalid authorization token",
+    )
+}
+
+fn unknown_symbol() -> HttpResponse {
+    ApiError::new(StatusCode::NOT_FOUND, "unknown_symbol", "unknown symbol").response()
+}
+
+/// Answers requests that match no route.
+async fn not_found() -> HttpResponse {
+    ApiError::new(StatusCode::NOT_FOUND, "not_found", "no such endpoint").response()
+}
+
+/// Turns a body, query or path that fails to deserialize into a 400 in the
+/// usual envelope rather than actix's plain-text message.
+fn invalid_request(code: &'static str, error: impl std::fmt::Display) -> actix_web::Error {
+    ApiError::new(StatusCode::BAD_REQUEST, code, error.to_string()).into()
 }
 
 #[post("/signup")]
 async fn signup(data: web::Data<AppState>, body: web::Json<AuthRequest>) -> impl Responder {
-    let username = body.username.to_string();
+    let username = types::canonical_username(&body.username);
     let password = body.password.to_string();
 
     if username.is_empty() || password.is_empty() {
-        return HttpResponse::BadRequest().json(AuthResponse {
-            success: false,
-            message: "user and password cannot be empty".into(),
-            token: None,
-        });
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "empty_credentials",
+            "user and password cannot be empty",
+        )
+        .response();
+    }
+
+    let unmet = data.password_policy.unmet(&password);
+    if !unmet.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "weak_password",
+            format!("password must {}", unmet.join(", ")),
+        )
+        .response();
     }
 
     let mut users = data.users.lock().unwrap();
 
     if users.contains_key(&username) {
-        return HttpResponse::Conflict().json(AuthResponse {
-            success: false,
-            message: "user already exists".into(),
-            token: None,
-        });
+        return ApiError::new(StatusCode::CONFLICT, "user_exists", "user already exists")
+            .response();
     }
 
-    let password_hash = match hash(&password, DEFAULT_COST) {
-        Ok(h) => h,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(AuthResponse {
-                success: false,
-                message: format!("failed to hash the password"),
-                token: None,
-            });
+    let password_hash = match data.password_hasher.hash(&password) {
+        Some(h) => h,
+        None => {
+            return ApiError::internal("failed to hash the password").response();
         }
     };
 
     let id = Uuid::new_v4().to_string();
-    let user = User::new(id, username.clone(), password_hash);
+    let mut user = User::new(id.clone(), body.username.clone(), password_hash);
+    user.is_admin = data.admins.contains(&username);
+    if data
+        .wal
+        .lock()
+        .unwrap()
+        .append(orderbook::now_millis(), WalEntry::Signup(user.clone()))
+        .is_err()
+    {
+        return ApiError::internal("failed to record the new user").response();
+    }
     users.insert(username.clone(), user);
+    data.accounts
+        .lock()
+        .unwrap()
+        .insert(id.clone(), Account::default());
+    tracing::info!(username, user_id = id, "signup");
 
     HttpResponse::Ok().json(AuthResponse {
         success: true,
@@ -92,7 +361,7 @@ async fn signup(data: web::Data<AppState>, body: web::Json<AuthRequest>) -> impl
 
 #[post("/signin")]
 async fn signin(data: web::Data<AppState>, body: web::Json<AuthRequest>) -> impl Responder {
-    let username = body.username.to_string();
+    let username = types::canonical_username(&body.username);
     let password = body.password.to_string();
 
     let user = {
@@ -100,91 +369,2970 @@ async fn signin(data: web::Data<AppState>, body: web::Json<AuthRequest>) -> impl
         match users.get(&username) {
             Some(u) => u.clone(),
             None => {
-                return HttpResponse::Unauthorized().json(AuthResponse {
-                    success: false,
-                    message: "not registerd user".into(),
-                    token: None,
-                });
+                return ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "unknown_user",
+                    "not registerd user",
+                )
+                .response();
             }
         }
     };
 
+    if let Some(wait) = data.login_attempts.locked_for(&username) {
+        tracing::warn!(username, "signin refused: too many failures");
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", wait.as_secs().max(1).to_string()))
+            .json(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "locked_out",
+                "too many failed signins, try again later",
+            ));
+    }
+
     //verify
 
-    match verify(password, &user.password_hash) {
-        Ok(true) => {
-            let token = Uuid::new_v4().to_string();
-            let mut sessions = data.sessions.lock().unwrap();
-            sessions.insert(token.clone(), user.username.clone());
-
-            HttpResponse::Ok().json(AuthResponse {
-                success: true,
-                message: "login in successfully".into(),
-                token: Some(token),
-            })
-        }
-        _ => {
-            return HttpResponse::Unauthorized().json(AuthResponse {
-                success: false,
-                message: "wrong credentials".into(),
-                token: None,
-            });
+    if !password::verify(&password, &user.password_hash) {
+        data.login_attempts.record_failure(&username);
+        tracing::warn!(username, "signin failed: wrong password");
+        return ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "wrong_credentials",
+            "wrong credentials",
+        )
+        .response();
+    }
+    data.login_attempts.clear(&username);
+    tracing::info!(username, "signin");
+    rehash_if_outdated(&data, &user, &password);
+
+    match auth::issue_token(&data.jwt_secret, &user.username) {
+        Ok(token) => HttpResponse::Ok().json(AuthResponse {
+            success: true,
+            message: "login in successfully".into(),
+            token: Some(token),
+        }),
+        Err(_) => ApiError::internal("failed to issue token").response(),
+    }
+}
+
+/// Replaces a hash made with an older hasher or cost once the password has
+/// been checked against it. The next state save persists the new hash; until
+/// then the old one still verifies.
+fn rehash_if_outdated(data: &AppState, user: &User, password: &str) {
+    if data.password_hasher.is_current(&user.password_hash) {
+        return;
+    }
+    if let Some(hash) = data.password_hasher.hash(password) {
+        if let Some(stored) = data.users.lock().unwrap().get_mut(&user.username) {
+            stored.password_hash = hash;
         }
     }
 }
 
 #[get("/whoami")]
-async fn whoami(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
-    let token_opt = req
-        .headers()
-        .get("Authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|t| t.strip_prefix("Bearer "))
-        .map(|s| s.to_string());
-
-    if token_opt.is_none() {
-        return HttpResponse::Unauthorized().json(AuthResponse {
-            success: false,
-            message: "missing authorization token".into(),
-            token: None,
+async fn whoami(AuthenticatedUser(user): AuthenticatedUser) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({"username": user.username}))
+}
+
+#[post("/logout")]
+async fn logout(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let token = match bearer_token(&req) {
+        Some(t) => t,
+        None => return unauthorized().response(),
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut revoked = data.revoked_tokens.lock().unwrap();
+    revoked.retain(|_, exp| *exp > now);
+    if let Some(claims) = auth::verify_token(&data.jwt_secret, &token) {
+        revoked.insert(token, claims.exp);
+    }
+
+    HttpResponse::Ok().json(AuthResponse {
+        success: true,
+        message: "logged out".into(),
+        token: None,
+    })
+}
+
+#[derive(Deserialize)]
+struct PlaceOrderQuery {
+    /// Answer with an acknowledgement once the order is sequenced, before it
+    /// is matched, and send the outcome over `/ws/user` instead.
+    #[serde(default)]
+    ack: bool,
+}
+
+#[post("/order")]
+async fn place_order(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    AuthenticatedUser(user): AuthenticatedUser,
+    query: web::Query<PlaceOrderQuery>,
+    body: web::Json<OrderRequest>,
+) -> impl Responder {
+    if let Some(refusal) = cancel_ratio_refusal(&data, &user.id) {
+        return refusal;
+    }
+    let mut order = new_order(user.id, body.into_inner());
+    if order.client_order_id.is_none() {
+        order.client_order_id = req
+            .headers()
+            .get("Idempotency-Key")
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string);
+    }
+
+    let Some(orderbook_tx) = data.shards.get(&order.symbol) else {
+        return HttpResponse::BadRequest().json(OrderResponse::Error {
+            code: OrderError::UnknownSymbol,
+            message: format!("unknown symbol {}", order.symbol),
         });
+    };
+
+    if query.ack {
+        return place_acknowledged(data.clone(), orderbook_tx, order).await;
     }
 
-    let token = token_opt.unwrap();
+    let timer = data.metrics.order_latency.start_timer();
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::AddOrder {
+        order: Box::new(order),
+        ack: None,
+        response,
+    })
+    .await;
+    timer.observe_duration();
 
-    println!("token: {}", token);
+    if let Ok(response) = &result {
+        data.metrics.record_order(response);
+    }
+    order_reply(result)
+}
 
-    let sessions = data.sessions.lock().unwrap();
+/// Places `order` asking the engine for an early acknowledgement, which is
+/// answered with 202. The outcome is then published on the user feed. A
+/// retry the engine already has an outcome for is answered with it directly.
+async fn place_acknowledged(
+    data: web::Data<AppState>,
+    orderbook_tx: &tokio::sync::mpsc::Sender<OrderbookCommand>,
+    order: Order,
+) -> HttpResponse {
+    let (user_id, symbol, order_id) = (
+        order.user_id.clone(),
+        order.symbol.clone(),
+        order.id.clone(),
+    );
+    let timer = data.metrics.order_latency.start_timer();
+    let answer =
+        dispatch::request_acknowledged(orderbook_tx, |ack, response| OrderbookCommand::AddOrder {
+            order: Box::new(order),
+            ack: Some(ack),
+            response,
+        })
+        .await;
+    timer.observe_duration();
 
-    match sessions.get(&token) {
-        Some(user) => HttpResponse::Ok().json(serde_json::json!({"username": user})),
-        None => return HttpResponse::Unauthorized().body("invalid token"),
+    match answer {
+        Ok(dispatch::Answer::Acknowledged(ack, response_rx)) => {
+            actix_web::rt::spawn(async move {
+                let Ok(response) = response_rx.await else {
+                    return;
+                };
+                data.metrics.record_order(&response);
+                let _ = data.feeds.user_events.send(UserEvent::Result {
+                    user_id,
+                    symbol,
+                    order_id,
+                    response,
+                });
+            });
+            HttpResponse::Accepted().json(ack)
+        }
+        Ok(dispatch::Answer::Replied(response)) => {
+            data.metrics.record_order(&response);
+            order_reply(Ok(response))
+        }
+        Err(e) => e.response(),
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let (tx, rx) = tokio::sync::mpsc::channel::<OrderbookCommand>(100);
+/// Matches an order against a copy of the book and answers as `POST /order`
+/// would have, with the trades it would make. Nothing is placed: the book and
+/// balances are left as they were.
+#[post("/order/simulate")]
+async fn simulate_order(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    body: web::Json<OrderRequest>,
+) -> impl Responder {
+    if let Some(refusal) = cancel_ratio_refusal(&data, &user.id) {
+        return refusal;
+    }
+    let order = new_order(user.id, body.into_inner());
+    let Some(orderbook_tx) = data.shards.get(&order.symbol) else {
+        return HttpResponse::BadRequest().json(OrderResponse::Error {
+            code: OrderError::UnknownSymbol,
+            message: format!("unknown symbol {}", order.symbol),
+        });
+    };
 
-    tokio::spawn(async move {
-        orderbook::Orderbook::run_orderbook_engine(rx).await;
-    });
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::SimulateOrder {
+        order: Box::new(order),
+        response,
+    })
+    .await;
+    order_reply(result)
+}
 
-    let state = web::Data::new(AppState {
-        users: Mutex::new(HashMap::new()),
-        sessions: Mutex::new(HashMap::new()),
-        orderbook_tx: tx,
-    });
+/// The answer to a new order from `user_id` while the cancel-ratio policy
+/// refuses their orders, or `None` to go ahead.
+fn cancel_ratio_refusal(data: &AppState, user_id: &str) -> Option<HttpResponse> {
+    let policy = &data.cancel_ratio;
+    let activity = data
+        .accounts
+        .lock()
+        .unwrap()
+        .get(user_id)?
+        .recent_activity(orderbook::now_millis(), policy.window_millis());
+    if !policy.rejects(&activity) {
+        return None;
+    }
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(state.clone())
-            .service(greet)
-            .service(signup)
-            .service(whoami)
-            .service(signin)
+    tracing::warn!(
+        user_id,
+        placed = activity.placed,
+        cancelled = activity.cancelled,
+        "order refused: cancel ratio over the limit"
+    );
+    Some(
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "cancel_ratio_exceeded",
+            format!(
+                "{} of {} orders placed recently were cancelled, over the limit of {}; try again later",
+                activity.cancelled,
+                activity.placed,
+                policy.limit()
+            ),
+        )
+        .response(),
+    )
+}
+
+fn new_order(user_id: String, body: OrderRequest) -> Order {
+    Order {
+        id: Uuid::new_v4().to_string(),
+        user_id,
+        symbol: body.symbol,
+        side: body.side,
+        order_type: body.order_type,
+        price: body.price,
+        quantity: body.quantity,
+        remaining_quantity: body.quantity,
+        quote_quantity: body.quote_quantity,
+        visible_quantity: None,
+        linked_order_id: None,
+        client_order_id: body.client_order_id,
+        expires_at: body.expires_at,
+        activates_at: body.activates_at,
+        worst_average_price: body.worst_average_price,
+        timestamp: orderbook::now_millis(),
+        // Assigned by the engine.
+        sequence: 0,
+        self_trade_prevention: body.self_trade_prevention,
+        reduce_only: body.reduce_only,
+        status: OrderStatus::Open,
+    }
+}
+
+/// Places two linked orders; once one trades or its stop triggers, the other
+/// is cancelled. Fails with 400 if either leg is rejected.
+#[post("/order/oco")]
+async fn place_oco(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    body: web::Json<OcoRequest>,
+) -> impl Responder {
+    if let Some(refusal) = cancel_ratio_refusal(&data, &user.id) {
+        return refusal;
+    }
+    let body = body.into_inner();
+    let first = new_order(user.id.clone(), body.first);
+    let second = new_order(user.id, body.second);
+    if first.symbol != second.symbol {
+        return HttpResponse::BadRequest().json(OrderResponse::Error {
+            code: OrderError::InvalidOco,
+            message: "both legs of an OCO must be on the same market".to_string(),
+        });
+    }
+
+    let Some(orderbook_tx) = data.shards.get(&first.symbol) else {
+        return HttpResponse::BadRequest().json(OrderResponse::Error {
+            code: OrderError::UnknownSymbol,
+            message: format!("unknown symbol {}", first.symbol),
+        });
+    };
+
+    let timer = data.metrics.order_latency.start_timer();
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::AddOco {
+        first: Box::new(first),
+        second: Box::new(second),
+        response,
     })
-    .bind(("0.0.0.0", 8000))?
-    .run()
-    .await
+    .await;
+    timer.observe_duration();
+
+    match result {
+        Ok(result) => {
+            data.metrics.record_order(&result.first);
+            data.metrics.record_order(&result.second);
+            let rejected = [&result.first, &result.second]
+                .iter()
+                .any(|leg| matches!(leg, OrderResponse::Error { .. }));
+            match rejected {
+                true => HttpResponse::BadRequest().json(result),
+                false => HttpResponse::Ok().json(result),
+            }
+        }
+        Err(e) => e.response(),
+    }
+}
+
+/// Places an entry order. Once it is done trading, a take-profit limit and a
+/// stop for what it filled go in on the other side as an OCO pair, so either
+/// exit cancels the other.
+#[post("/order/bracket")]
+async fn place_bracket(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    body: web::Json<BracketRequest>,
+) -> impl Responder {
+    if let Some(refusal) = cancel_ratio_refusal(&data, &user.id) {
+        return refusal;
+    }
+    let body = body.into_inner();
+    let entry = new_order(user.id, body.entry);
+    let exits = Bracket::new(&entry, body.take_profit_price, body.stop_price);
+
+    let Some(orderbook_tx) = data.shards.get(&entry.symbol) else {
+        return HttpResponse::BadRequest().json(OrderResponse::Error {
+            code: OrderError::UnknownSymbol,
+            message: format!("unknown symbol {}", entry.symbol),
+        });
+    };
+
+    let timer = data.metrics.order_latency.start_timer();
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::AddBracket {
+        entry: Box::new(entry),
+        exits: Box::new(exits),
+        response,
+    })
+    .await;
+    timer.observe_duration();
+
+    match result {
+        Ok(response) => {
+            data.metrics.record_order(&response);
+            match response.order_id().map(str::to_string) {
+                Some(entry_id) => HttpResponse::Ok().json(BracketResponse {
+                    entry: response,
+                    take_profit_order_id: Bracket::take_profit_id(&entry_id),
+                    stop_order_id: Bracket::stop_id(&entry_id),
+                }),
+                None => HttpResponse::BadRequest().json(response),
+            }
+        }
+        Err(e) => e.response(),
+    }
+}
+
+/// Maps the engine's answer to an order command onto an HTTP response.
+fn order_reply(result: Result<OrderResponse, dispatch::EngineError>) -> HttpResponse {
+    match result {
+        Ok(result @ OrderResponse::Error { .. }) => HttpResponse::BadRequest().json(result),
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => e.response(),
+    }
+}
+
+/// Reply for a command naming an order that no market knows.
+fn unknown_order() -> HttpResponse {
+    HttpResponse::BadRequest().json(OrderResponse::Error {
+        code: OrderError::OrderNotFound,
+        message: "order not found or already filled".to_string(),
+    })
+}
+
+#[post("/onramp")]
+async fn onramp(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    body: web::Json<OnRampRequest>,
+) -> impl Responder {
+    let body = body.into_inner();
+
+    if body.amount <= Decimal::ZERO {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "amount must be positive",
+        )
+        .response();
+    }
+
+    // As for a withdrawal, the log is held from the check until the credit,
+    // so only a deposit that will apply is ever logged.
+    let mut wal = data.wal.lock().unwrap();
+    wal.settle();
+    let mut accounts = data.accounts.lock().unwrap();
+    let account = accounts.entry(user.id.clone()).or_default();
+    if let Some(refused) = deposit_refusal(account, body.asset.as_deref(), body.amount) {
+        return refused.response();
+    }
+
+    let entry = WalEntry::Deposit {
+        user_id: user.id.clone(),
+        asset: body.asset.clone(),
+        amount: body.amount,
+    };
+    let now = orderbook::now_millis();
+    if let Err(e) = wal.append(now, entry) {
+        tracing::error!(user_id = %user.id, error = %e, "deposit not applied");
+        return ApiError::internal(format!("failed to write the order log: {e}")).response();
+    }
+    let new_balance = account
+        .deposit(body.asset.as_deref(), body.amount, now)
+        .unwrap_or_else(|available| available);
+    tracing::info!(
+        user_id = %user.id,
+        asset = body.asset.as_deref().unwrap_or("quote"),
+        amount = %body.amount,
+        "deposit"
+    );
+
+    HttpResponse::Ok().json(OnRampResponse {
+        success: true,
+        message: "balance credited".into(),
+        new_balance,
+    })
+}
+
+/// Why a deposit of `amount` into `account` is refused: it is larger than
+/// `MAX_DEPOSIT`, or the holding could not represent the result.
+fn deposit_refusal(account: &Account, asset: Option<&str>, amount: Decimal) -> Option<ApiError> {
+    let message = if amount > MAX_DEPOSIT {
+        format!("amount must be at most {MAX_DEPOSIT}")
+    } else if !account.can_credit(asset, amount) {
+        "deposit would take the balance past what an account can hold".to_string()
+    } else {
+        return None;
+    };
+    Some(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "amount_too_large",
+        message,
+    ))
+}
+
+/// Debits available funds. Whatever resting orders have reserved stays put
+/// until they fill or are cancelled.
+#[post("/withdraw")]
+async fn withdraw(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    body: web::Json<OnRampRequest>,
+) -> impl Responder {
+    let body = body.into_inner();
+
+    // The log is held from the balance check until the debit, so no order
+    // can reserve the same funds in between.
+    let mut wal = data.wal.lock().unwrap();
+    wal.settle();
+    let mut accounts = data.accounts.lock().unwrap();
+    let account = accounts.entry(user.id.clone()).or_default();
+
+    let available = match &body.asset {
+        Some(asset) => account.assets.get(asset).copied().unwrap_or_default(),
+        None => account.balance,
+    };
+    let rejected = match body.amount {
+        amount if amount <= Decimal::ZERO => Some(("invalid_amount", "amount must be positive")),
+        amount if amount > available => {
+            Some(("insufficient_funds", "insufficient available funds"))
+        }
+        _ => None,
+    };
+    if let Some((code, message)) = rejected {
+        return ApiError::new(StatusCode::BAD_REQUEST, code, message).response();
+    }
+
+    let entry = WalEntry::Withdraw {
+        user_id: user.id.clone(),
+        asset: body.asset.clone(),
+        amount: body.amount,
+    };
+    let now = orderbook::now_millis();
+    if let Err(e) = wal.append(now, entry) {
+        tracing::error!(user_id = %user.id, error = %e, "withdrawal not applied");
+        return ApiError::internal(format!("failed to write the order log: {e}")).response();
+    }
+    let new_balance = account
+        .withdraw(body.asset.as_deref(), body.amount, now)
+        .unwrap_or_else(|available| available);
+    tracing::info!(
+        user_id = %user.id,
+        asset = body.asset.as_deref().unwrap_or("quote"),
+        amount = %body.amount,
+        "withdrawal"
+    );
+
+    HttpResponse::Ok().json(OnRampResponse {
+        success: true,
+        message: "balance debited".into(),
+        new_balance,
+    })
+}
+
+#[delete("/order/{id}")]
+async fn cancel_order(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    order_id: web::Path<String>,
+) -> impl Responder {
+    let order_id = order_id.into_inner();
+    let orderbook_tx = match dispatch::find_order(&data.shards, &order_id).await {
+        Ok(Some((orderbook_tx, _))) => orderbook_tx,
+        Ok(None) => return unknown_order(),
+        Err(e) => return e.response(),
+    };
+
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::CancelOrder {
+        order_id,
+        user_id: user.id.clone(),
+        cancelled_by: user.id,
+        response,
+    })
+    .await;
+
+    if let Ok(OrderResponse::Cancelled { .. }) = result {
+        data.metrics.orders_cancelled.inc();
+    }
+    order_reply(result)
+}
+
+/// One user as listed by `/admin/users`.
+#[derive(Serialize)]
+struct UserSummary {
+    id: String,
+    username: String,
+    display_name: String,
+    is_admin: bool,
+    #[serde(flatten)]
+    balance: BalanceResponse,
+    /// Over the cancel-ratio policy's window.
+    order_activity: OrderActivity,
+    cancel_ratio: Option<Decimal>,
+    /// Whether the cancel-ratio policy flags the user.
+    flagged: bool,
+}
+
+/// Every user, by username, with their holdings. Password hashes are left out.
+#[get("/admin/users")]
+async fn list_users(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> impl Responder {
+    if !user.is_admin {
+        return forbidden();
+    }
+
+    let now = orderbook::now_millis();
+    let users = data.users.lock().unwrap();
+    let accounts = data.accounts.lock().unwrap();
+    let mut summaries: Vec<UserSummary> = users
+        .values()
+        .map(|user| {
+            let account = accounts.get(&user.id).cloned().unwrap_or_default();
+            let activity = account.recent_activity(now, data.cancel_ratio.window_millis());
+            UserSummary {
+                id: user.id.clone(),
+                username: user.username.clone(),
+                display_name: user.display_name.clone(),
+                is_admin: user.is_admin,
+                balance: account.into(),
+                order_activity: activity,
+                cancel_ratio: activity.cancel_ratio().map(|ratio| ratio.round_dp(4)),
+                flagged: data.cancel_ratio.flags(&activity),
+            }
+        })
+        .collect();
+    drop(accounts);
+    drop(users);
+
+    summaries.sort_by(|a, b| a.username.cmp(&b.username));
+    HttpResponse::Ok().json(summaries)
+}
+
+/// Cancels any user's order. Logged like the owner's own cancel, so replay
+/// needs nothing new.
+#[delete("/admin/order/{id}")]
+async fn force_cancel_order(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    order_id: web::Path<String>,
+) -> impl Responder {
+    if !user.is_admin {
+        return forbidden();
+    }
+
+    let order_id = order_id.into_inner();
+    let (orderbook_tx, order) = match dispatch::find_order(&data.shards, &order_id).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return unknown_order(),
+        Err(e) => return e.response(),
+    };
+
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::CancelOrder {
+        order_id: order_id.clone(),
+        user_id: order.user_id.clone(),
+        cancelled_by: user.id.clone(),
+        response,
+    })
+    .await;
+
+    if let Ok(OrderResponse::Cancelled { .. }) = result {
+        data.metrics.orders_cancelled.inc();
+        tracing::warn!(
+            admin = %user.username,
+            order_id,
+            owner = order.user_id,
+            "order force-cancelled"
+        );
+    }
+    order_reply(result)
+}
+
+#[patch("/order/{id}")]
+async fn amend_order(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    order_id: web::Path<String>,
+    body: web::Json<AmendRequest>,
+) -> impl Responder {
+    if body.price.is_none() && body.quantity.is_none() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "nothing_to_amend",
+            "nothing to amend: give a price or a quantity",
+        )
+        .response();
+    }
+
+    let order_id = order_id.into_inner();
+    let orderbook_tx = match dispatch::find_order(&data.shards, &order_id).await {
+        Ok(Some((orderbook_tx, _))) => orderbook_tx,
+        Ok(None) => return unknown_order(),
+        Err(e) => return e.response(),
+    };
+
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::AmendOrder {
+        order_id,
+        user_id: user.id,
+        new_price: body.price,
+        new_quantity: body.quantity,
+        response,
+    })
+    .await;
+    order_reply(result)
+}
+
+/// Cuts an order's quantity without losing its place in the queue.
+#[patch("/order/{id}/reduce")]
+async fn reduce_order(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    order_id: web::Path<String>,
+    body: web::Json<ReduceRequest>,
+) -> impl Responder {
+    let order_id = order_id.into_inner();
+    let orderbook_tx = match dispatch::find_order(&data.shards, &order_id).await {
+        Ok(Some((orderbook_tx, _))) => orderbook_tx,
+        Ok(None) => return unknown_order(),
+        Err(e) => return e.response(),
+    };
+
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::ReduceOrder {
+        order_id,
+        user_id: user.id,
+        quantity: body.quantity,
+        response,
+    })
+    .await;
+    order_reply(result)
+}
+
+#[derive(Serialize)]
+struct CancelAllResponse {
+    cancelled: usize,
+    order_ids: Vec<String>,
+}
+
+#[delete("/orders")]
+async fn cancel_all_orders(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> impl Responder {
+    let replies = dispatch::request_all(&data.shards, |response| {
+        OrderbookCommand::CancelAllForUser {
+            user_id: user.id.clone(),
+            response,
+        }
+    })
+    .await;
+    let replies = match replies {
+        Ok(replies) => replies,
+        Err(e) => return e.response(),
+    };
+
+    // A market that fails to log cancels nothing; the others still do.
+    let mut order_ids = Vec::new();
+    for reply in replies {
+        match reply {
+            Ok(ids) => order_ids.extend(ids),
+            Err(message) => return ApiError::internal(message).response(),
+        }
+    }
+    data.metrics.orders_cancelled.inc_by(order_ids.len() as u64);
+    HttpResponse::Ok().json(CancelAllResponse {
+        cancelled: order_ids.len(),
+        order_ids,
+    })
+}
+
+#[get("/order/{id}")]
+async fn get_order(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    order_id: web::Path<String>,
+) -> impl Responder {
+    match dispatch::find_order(&data.shards, &order_id).await {
+        Ok(Some((_, order))) if order.user_id == user.id => HttpResponse::Ok().json(order),
+        Ok(_) => ApiError::new(StatusCode::NOT_FOUND, "unknown_order", "unknown order").response(),
+        Err(e) => e.response(),
+    }
+}
+
+/// The caller's open orders across all markets, oldest first.
+#[get("/orders")]
+async fn get_user_orders(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    query: web::Query<OrdersQuery>,
+) -> impl Responder {
+    let replies = dispatch::request_all(&data.shards, |response| OrderbookCommand::GetUserOrders {
+        user_id: user.id.clone(),
+        response,
+    })
+    .await;
+
+    match replies {
+        Ok(replies) => {
+            let limit = query
+                .limit
+                .unwrap_or(DEFAULT_ORDERS_LIMIT)
+                .min(MAX_PAGE_SIZE);
+            let after = query.after.unwrap_or(0);
+            let mut orders: Vec<Order> = replies
+                .into_iter()
+                .flatten()
+                .filter(|o| o.sequence > after)
+                .collect();
+            orders.sort_by_key(|o| o.sequence);
+            orders.truncate(limit);
+            page(orders, limit, |order| order.sequence.to_string())
+        }
+        Err(e) => e.response(),
+    }
+}
+
+/// Available and reserved amounts of one currency or asset.
+#[derive(Default, Serialize)]
+struct Holding {
+    available: Decimal,
+    reserved: Decimal,
+}
+
+#[derive(Serialize)]
+struct BalanceResponse {
+    /// Quote currency.
+    #[serde(flatten)]
+    balance: Holding,
+    assets: HashMap<String, Holding>,
+}
+
+impl From<Account> for BalanceResponse {
+    fn from(account: Account) -> Self {
+        let mut assets: HashMap<String, Holding> = HashMap::new();
+        for (asset, available) in account.assets {
+            assets.entry(asset).or_default().available = available;
+        }
+        for (asset, reserved) in account.reserved_assets {
+            assets.entry(asset).or_default().reserved = reserved;
+        }
+
+        Self {
+            balance: Holding {
+                available: account.balance,
+                reserved: account.reserved,
+            },
+            assets,
+        }
+    }
+}
+
+#[get("/balance")]
+async fn get_balance(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> impl Responder {
+    let accounts = data.accounts.lock().unwrap();
+    let account = accounts.get(&user.id).cloned().unwrap_or_default();
+    drop(accounts);
+
+    HttpResponse::Ok().json(BalanceResponse::from(account))
+}
+
+/// The caller's trailing volume and the fee tier it puts them in on each market.
+#[derive(Serialize)]
+struct FeesResponse {
+    volume: Decimal,
+    window_days: u64,
+    markets: Vec<MarketFees>,
+}
+
+#[get("/fees")]
+async fn get_fees(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> impl Responder {
+    let markets = dispatch::request_all(&data.shards, |response| OrderbookCommand::GetFees {
+        user_id: user.id.clone(),
+        response,
+    })
+    .await;
+    let mut markets = match markets {
+        Ok(markets) => markets,
+        Err(e) => return e.response(),
+    };
+    markets.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+    let volume = data
+        .accounts
+        .lock()
+        .unwrap()
+        .get(&user.id)
+        .map_or(Decimal::ZERO, |a| {
+            a.trailing_volume(orderbook::now_millis())
+        });
+    HttpResponse::Ok().json(FeesResponse {
+        volume,
+        window_days: types::VOLUME_WINDOW_DAYS,
+        markets,
+    })
+}
+
+/// The caller's latest balance changes, oldest first.
+#[get("/ledger")]
+async fn get_ledger(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    query: web::Query<LedgerQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(DEFAULT_LEDGER_LIMIT);
+    let accounts = data.accounts.lock().unwrap();
+    let entries: Vec<_> = accounts.get(&user.id).map_or(Vec::new(), |account| {
+        let skip = account.ledger.len().saturating_sub(limit);
+        account.ledger.iter().skip(skip).cloned().collect()
+    });
+    drop(accounts);
+
+    HttpResponse::Ok().json(entries)
+}
+
+#[get("/orderbook/{symbol}")]
+async fn get_orderbook(
+    data: web::Data<AppState>,
+    symbol: web::Path<String>,
+    query: web::Query<SnapshotQuery>,
+) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    match dispatch::request(orderbook_tx, |response| OrderbookCommand::GetSnapshot {
+        response,
+    })
+    .await
+    {
+        Ok(mut snapshot) => {
+            if let Some(depth) = query.depth {
+                snapshot.bids.truncate(depth);
+                snapshot.asks.truncate(depth);
+            }
+            HttpResponse::Ok().json(snapshot)
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DepthQuery {
+    levels: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DepthLevel {
+    price: Decimal,
+    quantity: Decimal,
+    /// Quantity from the best price up to and including this level.
+    cumulative: Decimal,
+}
+
+#[derive(Serialize)]
+struct DepthResponse {
+    sequence: u64,
+    bids: Vec<DepthLevel>,
+    asks: Vec<DepthLevel>,
+}
+
+/// Running totals over `levels`, best price first.
+fn cumulative(levels: Vec<PriceLevel>, limit: Option<usize>) -> Vec<DepthLevel> {
+    let mut total = Decimal::ZERO;
+    levels
+        .into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|level| {
+            total += level.quantity;
+            DepthLevel {
+                price: level.price,
+                quantity: level.quantity,
+                cumulative: total,
+            }
+        })
+        .collect()
+}
+
+/// Each level of the book with the quantity resting from the best price to
+/// it, for depth charts.
+#[get("/orderbook/{symbol}/depth")]
+async fn get_depth(
+    data: web::Data<AppState>,
+    symbol: web::Path<String>,
+    query: web::Query<DepthQuery>,
+) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    match dispatch::request(orderbook_tx, |response| OrderbookCommand::GetSnapshot {
+        response,
+    })
+    .await
+    {
+        Ok(snapshot) => HttpResponse::Ok().json(DepthResponse {
+            sequence: snapshot.sequence,
+            bids: cumulative(snapshot.bids, query.levels),
+            asks: cumulative(snapshot.asks, query.levels),
+        }),
+        Err(e) => e.response(),
+    }
+}
+
+/// Every resting order per level, in queue order. User ids are only shown on
+/// the caller's own orders.
+#[get("/orderbook/{symbol}/l3")]
+async fn get_full_orderbook(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    symbol: web::Path<String>,
+    query: web::Query<SnapshotQuery>,
+) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+    let caller = authenticated_user(&data, &req).map(|u| u.id);
+
+    match dispatch::request(orderbook_tx, |response| OrderbookCommand::GetFullSnapshot {
+        response,
+    })
+    .await
+    {
+        Ok(mut snapshot) => {
+            if let Some(depth) = query.depth {
+                snapshot.bids.truncate(depth);
+                snapshot.asks.truncate(depth);
+            }
+            let levels = snapshot.bids.iter_mut().chain(snapshot.asks.iter_mut());
+            for entry in levels.flat_map(|(_, entries)| entries) {
+                if entry.user_id != caller {
+                    entry.user_id = None;
+                }
+            }
+            HttpResponse::Ok().json(snapshot)
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[get("/ticker/{symbol}")]
+async fn get_ticker(data: web::Data<AppState>, symbol: web::Path<String>) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    match dispatch::request(orderbook_tx, |response| OrderbookCommand::GetTicker {
+        response,
+    })
+    .await
+    {
+        Ok(ticker) => HttpResponse::Ok().json(ticker),
+        Err(e) => e.response(),
+    }
+}
+
+/// Volume, price range and price change over the last 24 hours.
+#[get("/stats/{symbol}")]
+async fn get_stats(data: web::Data<AppState>, symbol: web::Path<String>) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    match dispatch::request(orderbook_tx, |response| OrderbookCommand::GetStats {
+        response,
+    })
+    .await
+    {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => e.response(),
+    }
+}
+
+/// Every market, by symbol, with its trading rules and status.
+#[get("/symbols")]
+async fn get_symbols(data: web::Data<AppState>) -> impl Responder {
+    let markets = dispatch::request_all(&data.shards, |response| OrderbookCommand::GetMarketInfo {
+        response,
+    })
+    .await;
+
+    match markets {
+        Ok(mut markets) => {
+            markets.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+            HttpResponse::Ok().json(markets)
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[get("/candles/{symbol}")]
+async fn get_candles(
+    data: web::Data<AppState>,
+    symbol: web::Path<String>,
+    query: web::Query<CandlesQuery>,
+) -> impl Responder {
+    let interval_secs = match candles::interval_secs(&query.interval) {
+        Some(secs) => secs,
+        None => {
+            let supported: Vec<&str> = candles::INTERVALS.iter().map(|(name, _)| *name).collect();
+            return ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "unsupported_interval",
+                format!(
+                    "unsupported interval, expected one of {}",
+                    supported.join(", ")
+                ),
+            )
+            .response();
+        }
+    };
+
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::GetCandles {
+        interval_secs,
+        limit: query.limit.unwrap_or(DEFAULT_CANDLES_LIMIT),
+        response,
+    })
+    .await;
+
+    match result {
+        Ok(Some(candles)) => HttpResponse::Ok().json(candles),
+        Ok(None) => ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unsupported_interval",
+            "unsupported interval",
+        )
+        .response(),
+        Err(e) => e.response(),
+    }
+}
+
+/// Mid-price and spread over time, oldest first, with a sample each time a
+/// trade or book change moved either.
+#[get("/midprice/{symbol}")]
+async fn get_mid_prices(
+    data: web::Data<AppState>,
+    symbol: web::Path<String>,
+    query: web::Query<MidPriceQuery>,
+) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::GetMidPrices {
+        limit: query.limit.unwrap_or(DEFAULT_MID_PRICE_LIMIT),
+        response,
+    })
+    .await;
+
+    match result {
+        Ok(samples) => HttpResponse::Ok().json(samples),
+        Err(e) => e.response(),
+    }
+}
+
+#[get("/trades/{symbol}")]
+async fn get_recent_trades(
+    data: web::Data<AppState>,
+    symbol: web::Path<String>,
+    query: web::Query<TradesQuery>,
+) -> impl Responder {
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TRADES_LIMIT)
+        .min(MAX_PAGE_SIZE);
+    let result = dispatch::request(orderbook_tx, |response| OrderbookCommand::GetRecentTrades {
+        limit,
+        before: query.before.clone(),
+        response,
+    })
+    .await;
+
+    match result {
+        Ok(trades) => page(trades, limit, |trade| trade.id.clone()),
+        Err(e) => e.response(),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct MarketStatusRequest {
+    status: MarketStatus,
+}
+
+#[put("/admin/market/{symbol}/status")]
+async fn set_market_status(
+    data: web::Data<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    symbol: web::Path<String>,
+    body: web::Json<MarketStatusRequest>,
+) -> impl Responder {
+    if !user.is_admin {
+        return forbidden();
+    }
+
+    let Some(orderbook_tx) = data.shards.get(symbol.as_str()) else {
+        return unknown_symbol();
+    };
+
+    let status = body.status;
+    match dispatch::request(orderbook_tx, |response| OrderbookCommand::SetStatus {
+        status,
+        response,
+    })
+    .await
+    {
+        Ok(Ok(())) => HttpResponse::Ok().json(MarketStatusRequest { status }),
+        Ok(Err(message)) => ApiError::internal(message).response(),
+        Err(e) => e.response(),
+    }
+}
+
+/// Book depths are only refreshed if every engine answers, but a busy engine
+/// still gets its queue depth reported.
+#[get("/metrics")]
+async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    for (symbol, orderbook_tx) in &data.shards {
+        let queued = orderbook_tx.max_capacity() - orderbook_tx.capacity();
+        data.metrics
+            .engine_queue_depth
+            .with_label_values(&[symbol])
+            .set(queued as i64);
+    }
+
+    let snapshots = dispatch::request_all(&data.shards, |response| OrderbookCommand::GetSnapshot {
+        response,
+    })
+    .await;
+    match snapshots {
+        Ok(snapshots) => {
+            for (symbol, snapshot) in data.shards.keys().zip(&snapshots) {
+                data.metrics.record_depth(symbol, snapshot);
+            }
+        }
+        Err(e) => tracing::warn!(error = ?e, "book depth not refreshed"),
+    }
+
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(data.metrics.render())
+}
+
+/// Saves users, balances and open orders to `state_file`, then drops the log
+/// records the saved state already covers.
+async fn save_state(data: &AppState) -> std::io::Result<()> {
+    let engine = persistence::export_engine(
+        &data.shards,
+        &data.accounts,
+        &data.wal,
+        &data.order_sequence,
+    )
+    .await?;
+    // Users are read after the export, so any signup logged before
+    // `wal_sequence` is guaranteed to be included.
+    let users = data.users.lock().unwrap().values().cloned().collect();
+
+    persistence::save(
+        &data.state_file,
+        &persistence::SavedState {
+            users,
+            accounts: engine.accounts,
+            books: engine.books,
+            wal_sequence: engine.wal_sequence,
+            order_sequence: engine.order_sequence,
+        },
+    )?;
+    data.wal
+        .lock()
+        .unwrap()
+        .truncate_through(engine.wal_sequence)
+}
+
+/// Address the server listens on unless `HOST` is set.
+const DEFAULT_HOST: &str = "0.0.0.0";
+
+/// Port the server listens on unless `PORT` is set.
+const DEFAULT_PORT: u16 = 8000;
+
+/// Commands each engine queues before senders wait, unless
+/// `ENGINE_CHANNEL_CAPACITY` is set.
+const DEFAULT_ENGINE_CHANNEL_CAPACITY: usize = 100;
+
+#[cfg(feature = "test-endpoints")]
+fn test_routes(config: &mut web::ServiceConfig) {
+    config
+        .service(test_endpoints::reset)
+        .service(test_endpoints::seed);
+}
+
+#[cfg(not(feature = "test-endpoints"))]
+fn test_routes(_: &mut web::ServiceConfig) {}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let host: String = env_or("HOST", DEFAULT_HOST.to_string())?;
+    let port: u16 = env_or("PORT", DEFAULT_PORT)?;
+    let channel_capacity: usize =
+        env_or("ENGINE_CHANNEL_CAPACITY", DEFAULT_ENGINE_CHANNEL_CAPACITY)?;
+    if channel_capacity == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "ENGINE_CHANNEL_CAPACITY must be at least 1",
+        ));
+    }
+
+    // `RUST_LOG` picks what is logged, e.g. `RUST_LOG=orderbook=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+    #[cfg(feature = "test-endpoints")]
+    tracing::warn!("test endpoints enabled: /admin/reset and /admin/seed need no token");
+    let cors = cors::CorsPolicy::from_env()?;
+    if cors.allows_any_origin() {
+        tracing::warn!("CORS allows any origin");
+    }
+    let cancel_ratio = cancel_ratio::CancelRatioPolicy::from_env()?;
+
+    let state_file = PathBuf::from(
+        std::env::var("STATE_FILE").unwrap_or_else(|_| persistence::DEFAULT_STATE_FILE.into()),
+    );
+    let wal_file = PathBuf::from(
+        std::env::var("WAL_FILE").unwrap_or_else(|_| persistence::DEFAULT_WAL_FILE.into()),
+    );
+    let saved = persistence::load(&state_file)?;
+    let (wal, records) = Wal::open(&wal_file, saved.wal_sequence)?;
+    let wal: SharedWal = Arc::new(Mutex::new(wal));
+
+    let admins: HashSet<String> = env_list("ADMIN_USERS", "")
+        .iter()
+        .map(|name| types::canonical_username(name))
+        .collect();
+    let mut users: HashMap<String, User> = HashMap::new();
+    let signups = records.iter().filter_map(|record| match &record.entry {
+        WalEntry::Signup(user) => Some(user.clone()),
+        _ => None,
+    });
+    for user in saved.users.into_iter().chain(signups) {
+        let mut user = user.canonicalize();
+        user.is_admin = admins.contains(&user.username);
+        match users.get(&user.username) {
+            // Only possible for names taken before they were normalized.
+            Some(taken) if taken.id != user.id => tracing::warn!(
+                username = %user.display_name,
+                existing = %taken.display_name,
+                "username clashes with an existing one once normalized; keeping the earlier signup"
+            ),
+            _ => {
+                users.insert(user.username.clone(), user);
+            }
+        }
+    }
+
+    let accounts: Accounts = Arc::new(Mutex::new(saved.accounts));
+    let feeds = Feeds::new(1024);
+
+    let recovery = Recovery {
+        books: saved.books,
+        records,
+        order_sequence: saved.order_sequence,
+    };
+    let engine_timeout = env_or("ENGINE_TIMEOUT_MS", dispatch::DEFAULT_ENGINE_TIMEOUT_MS)?;
+    dispatch::set_timeout(std::time::Duration::from_millis(engine_timeout));
+    let queue_timeout = env_or(
+        "ENGINE_QUEUE_TIMEOUT_MS",
+        dispatch::DEFAULT_QUEUE_TIMEOUT_MS,
+    )?;
+    dispatch::set_queue_timeout(std::time::Duration::from_millis(queue_timeout));
+
+    // Must stay the same across restarts, or replaying the log would match
+    // differently than the first time.
+    let pro_rata: HashSet<String> = env_list("PRO_RATA_MARKETS", "").into_iter().collect();
+    let markets = orderbook::default_markets()
+        .into_iter()
+        .map(|m| match pro_rata.contains(&m.symbol) {
+            true => m.with_matching_mode(MatchingMode::ProRata),
+            false => m,
+        })
+        .collect();
+    let (books, order_sequence) =
+        orderbook::Orderbook::recover(markets, &accounts, &feeds, recovery);
+    let order_sequence = Arc::new(AtomicU64::new(order_sequence));
+
+    // One engine per market, so markets never wait on each other's order flow.
+    let metrics = metrics::Metrics::new();
+    let mut shards = Shards::new();
+    let mut engines = Vec::new();
+    for (symbol, mut book) in books {
+        book.count_fills(metrics.orders_filled.clone());
+        let (tx, rx) = tokio::sync::mpsc::channel::<OrderbookCommand>(channel_capacity);
+        let span = tracing::info_span!("engine", market = %symbol);
+        let engine = book
+            .run_orderbook_engine(rx, wal.clone(), order_sequence.clone())
+            .instrument(span);
+        engines.push(dispatch::spawn_engine(&symbol, engine)?);
+        shards.insert(symbol, tx);
+    }
+
+    let state = web::Data::new(AppState {
+        users: Mutex::new(users),
+        jwt_secret: std::env::var("JWT_SECRET").unwrap_or_else(|_| Uuid::new_v4().to_string()),
+        revoked_tokens: Mutex::new(HashMap::new()),
+        login_attempts: lockout::LoginAttempts::from_env()?,
+        cancel_ratio,
+        password_hasher: password::from_env()?,
+        password_policy: password::PasswordPolicy::from_env()?,
+        admins,
+        accounts,
+        shards,
+        order_sequence,
+        feeds,
+        wal,
+        state_file,
+        metrics,
+    });
+
+    let saver = {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(persistence::SAVE_INTERVAL_SECS));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = save_state(&state).await {
+                    tracing::error!(error = %e, "failed to save state");
+                }
+            }
+        })
+    };
+
+    let server_state = state.clone();
+    HttpServer::new(move || {
+        App::new()
+            .app_data(server_state.clone())
+            .wrap(cors.middleware())
+            // Logs the request line only, so tokens in headers stay out of the log.
+            .wrap(middleware::Logger::new("%a \"%r\" %s %b %Dms"))
+            .configure(routes)
+            .default_service(web::to(not_found))
+    })
+    .bind((host.as_str(), port))?
+    .run()
+    .await?;
+
+    // The server stops accepting connections on Ctrl-C or SIGTERM and waits
+    // for in-flight requests. Save once more, then drop the last `shards` so
+    // each engine answers the commands still queued and exits.
+    saver.abort();
+    let _ = saver.await;
+    if let Err(e) = save_state(&state).await {
+        tracing::error!(error = %e, "failed to save state");
+    }
+    drop(state);
+
+    for engine in engines {
+        let joined = actix_web::rt::task::spawn_blocking(move || engine.join()).await;
+        if !matches!(joined, Ok(Ok(()))) {
+            tracing::error!("orderbook engine panicked");
+        }
+    }
+    Ok(())
+}
+
+/// Every route, and the extractor settings they share.
+fn routes(config: &mut web::ServiceConfig) {
+    config
+        .app_data(
+            web::JsonConfig::default()
+                .error_handler(|error, _| invalid_request("invalid_body", error)),
+        )
+        .app_data(
+            web::QueryConfig::default()
+                .error_handler(|error, _| invalid_request("invalid_query", error)),
+        )
+        .app_data(
+            web::PathConfig::default()
+                .error_handler(|error, _| invalid_request("invalid_path", error)),
+        )
+        .service(greet)
+        .service(signup)
+        .service(whoami)
+        .service(signin)
+        .service(logout)
+        .service(onramp)
+        .service(withdraw)
+        .service(place_order)
+        .service(simulate_order)
+        .service(place_oco)
+        .service(place_bracket)
+        .service(cancel_order)
+        .service(cancel_all_orders)
+        .service(amend_order)
+        .service(reduce_order)
+        .service(get_order)
+        .service(get_user_orders)
+        .service(get_balance)
+        .service(get_ledger)
+        .service(get_fees)
+        .service(get_orderbook)
+        .service(get_full_orderbook)
+        .service(get_depth)
+        .service(get_ticker)
+        .service(get_stats)
+        .service(get_symbols)
+        .service(get_recent_trades)
+        .service(get_mid_prices)
+        .service(get_candles)
+        .service(get_metrics)
+        .service(set_market_status)
+        .service(force_cancel_order)
+        .service(list_users)
+        .service(ws::orderbook_feed)
+        .service(ws::user_feed)
+        .configure(test_routes);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::MessageBody,
+        dev::{Service, ServiceResponse},
+        test::{call_service, init_service, read_body, TestRequest},
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Server state with every default market running on an empty log in a
+    /// temporary directory. Passwords are hashed at bcrypt's lowest cost.
+    fn state() -> web::Data<AppState> {
+        let dir = std::env::temp_dir().join(format!("orderbook-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (wal, records) = Wal::open(&dir.join("wal.jsonl"), 0).unwrap();
+        let wal: SharedWal = Arc::new(Mutex::new(wal));
+        let accounts: Accounts = Arc::new(Mutex::new(HashMap::new()));
+        let feeds = Feeds::new(1024);
+        let recovery = Recovery {
+            books: Vec::new(),
+            records,
+            order_sequence: 0,
+        };
+        let (books, order_sequence) = orderbook::Orderbook::recover(
+            orderbook::default_markets(),
+            &accounts,
+            &feeds,
+            recovery,
+        );
+        let order_sequence = Arc::new(AtomicU64::new(order_sequence));
+
+        let metrics = metrics::Metrics::new();
+        let mut shards = Shards::new();
+        for (symbol, mut book) in books {
+            book.count_fills(metrics.orders_filled.clone());
+            let (tx, rx) = tokio::sync::mpsc::channel(DEFAULT_ENGINE_CHANNEL_CAPACITY);
+            let engine = book.run_orderbook_engine(rx, wal.clone(), order_sequence.clone());
+            dispatch::spawn_engine(&symbol, engine).unwrap();
+            shards.insert(symbol, tx);
+        }
+
+        web::Data::new(AppState {
+            users: Mutex::new(HashMap::new()),
+            jwt_secret: Uuid::new_v4().to_string(),
+            revoked_tokens: Mutex::new(HashMap::new()),
+            login_attempts: lockout::LoginAttempts::new(
+                lockout::DEFAULT_MAX_FAILURES,
+                Duration::from_secs(lockout::DEFAULT_WINDOW_SECS),
+            ),
+            cancel_ratio: cancel_ratio::CancelRatioPolicy::from_env().unwrap(),
+            password_hasher: Box::new(password::Bcrypt { cost: 4 }),
+            // No character classes on purpose, so tests can sign up with any
+            // eight characters; `signup_under_the_default_policy_*` covers the default.
+            password_policy: password::PasswordPolicy {
+                min_length: password::DEFAULT_MIN_LENGTH,
+                required: Vec::new(),
+            },
+            admins: HashSet::from(["admin".to_string()]),
+            accounts,
+            shards,
+            order_sequence,
+            feeds,
+            wal,
+            state_file: dir.join("state.json"),
+            metrics,
+        })
+    }
+
+    macro_rules! app {
+        ($state:expr) => {
+            init_service(
+                App::new()
+                    .app_data($state.clone())
+                    .configure(routes)
+                    .default_service(web::to(not_found)),
+            )
+            .await
+        };
+    }
+
+    fn decimal(value: &Value) -> Decimal {
+        serde_json::from_value(value.clone()).unwrap()
+    }
+
+    fn get(uri: &str) -> TestRequest {
+        TestRequest::get().uri(uri)
+    }
+
+    fn post(uri: &str) -> TestRequest {
+        TestRequest::post().uri(uri)
+    }
+
+    fn delete(uri: &str) -> TestRequest {
+        TestRequest::delete().uri(uri)
+    }
+
+    fn put(uri: &str) -> TestRequest {
+        TestRequest::put().uri(uri)
+    }
+
+    /// Signs up `username`, an admin if it is `admin`, and returns a token.
+    async fn user<S, B>(app: &S, username: &str) -> String
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let credentials = json!({ "username": username, "password": "password" });
+        let (status, _) = call(app, post("/signup"), None, credentials.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = call(app, post("/signin"), None, credentials).await;
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    /// Sends `request` as `token`, with `body` as JSON unless it is null, and
+    /// returns the status and the JSON body, or null if there is none.
+    async fn call<S, B>(
+        app: &S,
+        request: TestRequest,
+        token: Option<&str>,
+        body: Value,
+    ) -> (StatusCode, Value)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let mut request = request;
+        if let Some(token) = token {
+            request = request.insert_header(("Authorization", format!("Bearer {token}")));
+        }
+        if !body.is_null() {
+            request = request.set_json(body);
+        }
+        let response = call_service(app, request.to_request()).await;
+        let status = response.status();
+        let bytes = read_body(response).await;
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    /// Deposits `amount` of `asset`, or of the quote currency if it is `None`.
+    async fn fund<S, B>(app: &S, token: &str, asset: Option<&str>, amount: i64)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let body = json!({ "asset": asset, "amount": amount });
+        let (status, _) = call(app, post("/onramp"), Some(token), body).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Places `order`, on BTC-USD unless it names another market.
+    async fn place<S, B>(app: &S, token: &str, order: Value) -> (StatusCode, Value)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let mut order = order;
+        if order.get("symbol").is_none() {
+            order["symbol"] = json!("BTC-USD");
+        }
+        call(app, post("/order"), Some(token), order).await
+    }
+
+    #[actix_web::test]
+    async fn order_rejected_by_the_market_rules_is_not_logged() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        fund(&app, &token, None, 10_000).await;
+        let logged = state.wal.lock().unwrap().sequence();
+
+        let too_fine =
+            json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 0.000000001 });
+        let (status, body) = place(&app, &token, too_fine).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "quantity_precision");
+        assert_eq!(state.wal.lock().unwrap().sequence(), logged);
+
+        let resting = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        let (status, body) = place(&app, &token, resting).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "placed");
+        assert_eq!(state.wal.lock().unwrap().sequence(), logged + 1);
+    }
+
+    #[actix_web::test]
+    async fn deposit_that_would_overflow_is_refused_before_it_is_logged() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        let huge = serde_json::from_str(r#"{ "amount": 79228162514264337593543950335 }"#).unwrap();
+
+        let (status, body) = call(&app, post("/onramp"), Some(&token), huge).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "amount_too_large");
+
+        let max = json!({ "amount": MAX_DEPOSIT });
+        for _ in 0..2 {
+            let (status, _) = call(&app, post("/onramp"), Some(&token), max.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, body) = call(&app, get("/balance"), Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["available"], json!(MAX_DEPOSIT * Decimal::TWO));
+
+        assert_eq!(
+            state.wal.lock().unwrap().sequence(),
+            3,
+            "signup and two deposits"
+        );
+
+        // Deposits small enough on their own still cannot take a holding past
+        // what `Decimal` holds.
+        for account in state.accounts.lock().unwrap().values_mut() {
+            account.balance = Decimal::MAX - Decimal::ONE;
+        }
+        let (status, body) =
+            call(&app, post("/onramp"), Some(&token), json!({ "amount": 2 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "amount_too_large");
+        assert_eq!(state.wal.lock().unwrap().sequence(), 3);
+    }
+
+    /// What `username` has placed and cancelled over the last two minutes.
+    fn activity(state: &AppState, username: &str) -> OrderActivity {
+        let user_id = state.users.lock().unwrap()[username].id.clone();
+        state.accounts.lock().unwrap()[&user_id].recent_activity(orderbook::now_millis(), 120_000)
+    }
+
+    #[actix_web::test]
+    async fn admin_force_cancel_does_not_count_as_the_owner_cancelling() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let admin = user(&app, "admin").await;
+        fund(&app, &alice, None, 10_000).await;
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+
+        let (_, placed) = place(&app, &alice, bid.clone()).await;
+        let uri = format!("/admin/order/{}", placed["order_id"].as_str().unwrap());
+        let (status, body) = call(&app, delete(&uri), Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "cancelled");
+        assert_eq!(activity(&state, "alice").cancelled, 0);
+
+        let (_, placed) = place(&app, &alice, bid).await;
+        let uri = format!("/order/{}", placed["order_id"].as_str().unwrap());
+        let (status, _) = call(&app, delete(&uri), Some(&alice), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(activity(&state, "alice").cancelled, 1);
+        assert_eq!(activity(&state, "alice").placed, 2);
+    }
+
+    #[actix_web::test]
+    async fn onramp_credits_positive_amounts_to_signed_in_users_only() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+
+        let (status, body) = call(
+            &app,
+            post("/onramp"),
+            Some(&token),
+            json!({ "amount": 250 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(decimal(&body["new_balance"]), Decimal::from(250));
+        let btc = json!({ "asset": "BTC", "amount": 2 });
+        let (_, body) = call(&app, post("/onramp"), Some(&token), btc).await;
+        assert_eq!(decimal(&body["new_balance"]), Decimal::TWO);
+
+        for amount in [-5, 0] {
+            let (status, body) = call(
+                &app,
+                post("/onramp"),
+                Some(&token),
+                json!({ "amount": amount }),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], "invalid_amount");
+        }
+
+        let (status, _) = call(&app, post("/onramp"), None, json!({ "amount": 250 })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(
+            &app,
+            post("/onramp"),
+            Some("not-a-token"),
+            json!({ "amount": 250 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (_, body) = call(&app, get("/balance"), Some(&token), Value::Null).await;
+        assert_eq!(decimal(&body["available"]), Decimal::from(250));
+        assert_eq!(decimal(&body["assets"]["BTC"]["available"]), Decimal::TWO);
+    }
+
+    /// Available and reserved quote and BTC of `token`'s user.
+    async fn holdings<S, B>(app: &S, token: &str) -> [Decimal; 4]
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let (_, body) = call(app, get("/balance"), Some(token), Value::Null).await;
+        let btc = &body["assets"]["BTC"];
+        [
+            decimal(&body["available"]),
+            decimal(&body["reserved"]),
+            serde_json::from_value(btc["available"].clone()).unwrap_or_default(),
+            serde_json::from_value(btc["reserved"].clone()).unwrap_or_default(),
+        ]
+    }
+
+    #[actix_web::test]
+    async fn crossing_orders_settle_both_sides_net_of_fees() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        fund(&app, &alice, Some("BTC"), 3).await;
+        fund(&app, &bob, None, 1_000).await;
+
+        let ask = json!({ "side": "sell", "order_type": "limit", "price": 100, "quantity": 2 });
+        assert_eq!(place(&app, &alice, ask).await.1["status"], "placed");
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 2 });
+        assert_eq!(place(&app, &bob, bid).await.1["status"], "filled");
+
+        // 200 of quote changes hands; the maker pays 5 bps and the taker 10.
+        let zero = Decimal::ZERO;
+        let alice_quote = Decimal::new(19_990, 2);
+        assert_eq!(
+            holdings(&app, &alice).await,
+            [alice_quote, zero, Decimal::ONE, zero]
+        );
+        let bob_quote = Decimal::new(79_980, 2);
+        assert_eq!(
+            holdings(&app, &bob).await,
+            [bob_quote, zero, Decimal::TWO, zero]
+        );
+    }
+
+    #[actix_web::test]
+    async fn orders_on_one_market_never_match_another() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        fund(&app, &alice, Some("ETH"), 1).await;
+        fund(&app, &bob, None, 1_000).await;
+
+        let eth_ask = json!({ "symbol": "ETH-USD", "side": "sell", "order_type": "limit", "price": 100, "quantity": 1 });
+        assert_eq!(place(&app, &alice, eth_ask).await.1["status"], "placed");
+        let btc_bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        assert_eq!(place(&app, &bob, btc_bid).await.1["status"], "placed");
+
+        let (status, btc) = call(&app, get("/orderbook/BTC-USD"), None, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(btc["bids"].as_array().unwrap().len(), 1);
+        assert!(btc["asks"].as_array().unwrap().is_empty());
+        let (_, eth) = call(&app, get("/orderbook/ETH-USD"), None, Value::Null).await;
+        assert!(eth["bids"].as_array().unwrap().is_empty());
+        assert_eq!(eth["asks"].as_array().unwrap().len(), 1);
+
+        let (status, body) = call(&app, get("/orderbook/DOGE-USD"), None, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "unknown_symbol");
+    }
+
+    #[test]
+    fn token_verifies_until_it_expires_and_not_once_tampered_with() {
+        let secret = "secret";
+        let token = auth::issue_token(secret, "alice").unwrap();
+        assert_eq!(auth::verify_token(secret, &token).unwrap().sub, "alice");
+        assert!(auth::verify_token("other secret", &token).is_none());
+
+        let expired = auth::Claims {
+            sub: "alice".to_string(),
+            exp: orderbook::now_millis() / 1000 - 1,
+        };
+        let expired = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &expired,
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap();
+        assert!(auth::verify_token(secret, &expired).is_none());
+
+        // Swap in a payload naming someone else, keeping the signature.
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &auth::Claims {
+                sub: "mallory".to_string(),
+                exp: u64::MAX / 2,
+            },
+            &jsonwebtoken::EncodingKey::from_secret(b"guess"),
+        )
+        .unwrap();
+        let payload = forged.split('.').nth(1).unwrap();
+        let tampered = format!("{}.{payload}.{}", parts[0], parts[2]);
+        assert!(auth::verify_token(secret, &tampered).is_none());
+    }
+
+    #[actix_web::test]
+    async fn token_is_refused_once_logged_out() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        let (status, body) = call(&app, get("/whoami"), Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["username"], "alice");
+
+        let (status, _) = call(&app, post("/logout"), Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&app, get("/whoami"), Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&app, get("/balance"), Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn non_finite_zero_and_negative_numbers_are_rejected() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        fund(&app, &token, None, 10_000).await;
+
+        for quantity in [json!(0), json!(-1)] {
+            let bid =
+                json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": quantity });
+            let (status, body) = place(&app, &token, bid).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], "invalid_quantity", "{quantity}");
+        }
+        for price in [json!(0), json!(-5)] {
+            let bid =
+                json!({ "side": "buy", "order_type": "limit", "price": price, "quantity": 1 });
+            let (status, body) = place(&app, &token, bid).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], "invalid_price", "{price}");
+        }
+
+        // JSON has no NaN or infinity; neither as a bare word nor as a
+        // number too large for `Decimal` gets past the body extractor.
+        for quantity in ["NaN", "Infinity", "1e400", "\"NaN\""] {
+            let body = format!(
+                r#"{{ "symbol": "BTC-USD", "side": "buy", "order_type": "limit", "price": 100, "quantity": {quantity} }}"#
+            );
+            let request = post("/order")
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .insert_header(("Content-Type", "application/json"))
+                .set_payload(body);
+            let response = call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{quantity}");
+        }
+
+        let (_, book) = call(&app, get("/orderbook/BTC-USD"), None, Value::Null).await;
+        assert!(book["bids"].as_array().unwrap().is_empty());
+    }
+
+    /// `state` served on a local port, for the websocket feeds.
+    fn serve(state: &web::Data<AppState>) -> actix_test::TestServer {
+        let state = state.clone();
+        actix_test::start(move || App::new().app_data(state.clone()).configure(routes))
+    }
+
+    /// Signs up `username` on a running server and returns a token.
+    async fn live_user(server: &actix_test::TestServer, username: &str) -> String {
+        let credentials = json!({ "username": username, "password": "password" });
+        let registered = server
+            .post("/signup")
+            .send_json(&credentials)
+            .await
+            .unwrap();
+        assert_eq!(registered.status(), StatusCode::OK);
+        let mut session = server
+            .post("/signin")
+            .send_json(&credentials)
+            .await
+            .unwrap();
+        let body: Value = session.json().await.unwrap();
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    /// Sends `request` as `token` with `body` and returns the JSON reply.
+    async fn live_call(request: actix_test::ClientRequest, token: &str, body: Value) -> Value {
+        let mut response = request.bearer_auth(token).send_json(&body).await.unwrap();
+        response.json().await.unwrap()
+    }
+
+    /// The next text message on `socket`, as JSON.
+    async fn next_message<S>(socket: &mut S) -> Value
+    where
+        S: futures_util::Stream<
+                Item = Result<actix_http::ws::Frame, actix_http::ws::ProtocolError>,
+            > + Unpin,
+    {
+        use futures_util::StreamExt;
+
+        let frame = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no message within 5 seconds");
+        match frame {
+            Some(Ok(actix_http::ws::Frame::Text(text))) => serde_json::from_slice(&text).unwrap(),
+            other => panic!("expected a text message, got {other:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn book_feed_sends_a_snapshot_then_the_update_for_a_new_order() {
+        let state = state();
+        let mut server = serve(&state);
+        let token = live_user(&server, "alice").await;
+        let deposit = json!({ "amount": 1_000 });
+        live_call(server.post("/onramp"), &token, deposit).await;
+
+        let mut socket = server.ws_at("/ws/orderbook/BTC-USD").await.unwrap();
+        let snapshot = next_message(&mut socket).await;
+        assert_eq!(snapshot["type"], "snapshot");
+        assert!(snapshot["bids"].as_array().unwrap().is_empty());
+
+        let bid = json!({ "symbol": "BTC-USD", "side": "buy", "order_type": "limit", "price": 100, "quantity": 2 });
+        let placed = live_call(server.post("/order"), &token, bid).await;
+        assert_eq!(placed["status"], "placed");
+
+        let update = next_message(&mut socket).await;
+        assert_eq!(update["type"], "update");
+        assert_eq!(update["symbol"], "BTC-USD");
+        assert_eq!(
+            update["sequence"].as_u64().unwrap(),
+            snapshot["sequence"].as_u64().unwrap() + 1
+        );
+        let level = &update["bids"][0];
+        assert_eq!(decimal(&level[0]), Decimal::ONE_HUNDRED);
+        assert_eq!(decimal(&level[1]), Decimal::TWO);
+    }
+
+    #[actix_web::test]
+    async fn user_feeds_carry_each_side_of_a_fill_to_its_own_user() {
+        let state = state();
+        let server = serve(&state);
+        let alice = live_user(&server, "alice").await;
+        let bob = live_user(&server, "bob").await;
+        let btc = json!({ "asset": "BTC", "amount": 1 });
+        live_call(server.post("/onramp"), &alice, btc).await;
+        live_call(server.post("/onramp"), &bob, json!({ "amount": 1_000 })).await;
+
+        let mut sockets = Vec::new();
+        for token in [&alice, &bob] {
+            let (_, socket) = actix_test::Client::new()
+                .ws(server.url("/ws/user"))
+                .bearer_auth(token)
+                .connect()
+                .await
+                .unwrap();
+            sockets.push(socket);
+        }
+
+        let ask = json!({ "symbol": "BTC-USD", "side": "sell", "order_type": "limit", "price": 100, "quantity": 1 });
+        let sold = live_call(server.post("/order"), &alice, ask).await;
+        let bid = json!({ "symbol": "BTC-USD", "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        let bought = live_call(server.post("/order"), &bob, bid).await;
+        assert_eq!(bought["status"], "filled");
+
+        for (socket, (side, placed)) in sockets.iter_mut().zip([("sell", &sold), ("buy", &bought)])
+        {
+            let fill = next_message(socket).await;
+            assert_eq!(fill["type"], "fill");
+            assert_eq!(fill["side"], side);
+            assert_eq!(fill["order_id"], placed["order_id"]);
+            assert_eq!(decimal(&fill["trade"]["price"]), Decimal::ONE_HUNDRED);
+        }
+    }
+
+    #[actix_web::test]
+    async fn resting_bids_reserve_funds_until_cancelled() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        fund(&app, &token, None, 1_000).await;
+        let zero = Decimal::ZERO;
+
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 6 });
+        let (_, placed) = place(&app, &token, bid).await;
+        assert_eq!(placed["status"], "placed");
+        // The notional and the 10 bps it would pay if it took liquidity.
+        let reserved = Decimal::new(60_060, 2);
+        assert_eq!(
+            holdings(&app, &token).await,
+            [Decimal::ONE_THOUSAND - reserved, reserved, zero, zero]
+        );
+
+        // Within the 1,000 held but not the 399.40 still available.
+        let second = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 5 });
+        let (status, body) = place(&app, &token, second).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "insufficient_funds");
+
+        let uri = format!("/order/{}", placed["order_id"].as_str().unwrap());
+        let (status, _) = call(&app, delete(&uri), Some(&token), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            holdings(&app, &token).await,
+            [Decimal::from(1_000), zero, zero, zero]
+        );
+    }
+
+    #[actix_web::test]
+    async fn balance_lists_an_asset_bought_alongside_the_quote_left() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        fund(&app, &alice, Some("BTC"), 1).await;
+        fund(&app, &bob, None, 500).await;
+
+        let (_, before) = call(&app, get("/balance"), Some(&bob), Value::Null).await;
+        assert!(before["assets"].as_object().unwrap().is_empty());
+
+        let ask = json!({ "side": "sell", "order_type": "limit", "price": 100, "quantity": 1 });
+        place(&app, &alice, ask).await;
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        assert_eq!(place(&app, &bob, bid).await.1["status"], "filled");
+
+        let (status, body) = call(&app, get("/balance"), Some(&bob), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        // 100 spent and 10 bps of it paid as the taker fee.
+        assert_eq!(decimal(&body["available"]), Decimal::new(39_990, 2));
+        let assets = body["assets"].as_object().unwrap();
+        assert_eq!(assets.keys().collect::<Vec<_>>(), ["BTC"]);
+        assert_eq!(decimal(&assets["BTC"]["available"]), Decimal::ONE);
+        assert_eq!(decimal(&assets["BTC"]["reserved"]), Decimal::ZERO);
+    }
+
+    /// The value `/metrics` reports for the unlabelled metric `name`.
+    async fn metric<S, B>(app: &S, name: &str) -> f64
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let response = call_service(app, get("/metrics").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{name} missing from {text}"))
+            .parse()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn placing_an_order_counts_towards_orders_placed_total() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        fund(&app, &token, None, 1_000).await;
+        let placed = metric(&app, "orders_placed_total").await;
+
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        assert_eq!(place(&app, &token, bid).await.1["status"], "placed");
+        assert_eq!(metric(&app, "orders_placed_total").await, placed + 1.0);
+
+        let refused = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 0 });
+        assert_eq!(
+            place(&app, &token, refused).await.0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(metric(&app, "orders_placed_total").await, placed + 1.0);
+    }
+
+    #[actix_web::test]
+    async fn paused_market_refuses_orders_but_not_cancels_until_resumed() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let admin = user(&app, "admin").await;
+        fund(&app, &alice, None, 1_000).await;
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        let (_, resting) = place(&app, &alice, bid.clone()).await;
+
+        let uri = "/admin/market/BTC-USD/status";
+        let paused = json!({ "status": "paused" });
+        let (status, _) = call(&app, put(uri), Some(&alice), paused.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call(&app, put(uri), Some(&admin), paused).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "paused");
+
+        let (status, body) = place(&app, &alice, bid.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "market_closed");
+        let order = format!("/order/{}", resting["order_id"].as_str().unwrap());
+        let (_, body) = call(&app, delete(&order), Some(&alice), Value::Null).await;
+        assert_eq!(body["status"], "cancelled");
+
+        let open = json!({ "status": "open" });
+        let (status, _) = call(&app, put(uri), Some(&admin), open).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(place(&app, &alice, bid).await.1["status"], "placed");
+    }
+
+    #[actix_web::test]
+    async fn request_to_an_engine_that_never_answers_times_out() {
+        let Ok(mut stalled) = Arc::try_unwrap(state().into_inner()) else {
+            unreachable!("the state is not shared yet")
+        };
+        // Commands queue up, but nothing ever reads them.
+        let (tx, _never_read) = tokio::sync::mpsc::channel(DEFAULT_ENGINE_CHANNEL_CAPACITY);
+        stalled.shards.insert("BTC-USD".to_string(), tx);
+        let app = app!(web::Data::new(stalled));
+
+        let wait = Duration::from_millis(dispatch::DEFAULT_ENGINE_TIMEOUT_MS);
+        let answered = tokio::time::timeout(
+            wait * 2,
+            call(&app, get("/orderbook/BTC-USD"), None, Value::Null),
+        );
+        let (status, body) = answered.await.expect("the request hung");
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["code"], "engine_timeout");
+    }
+
+    #[test]
+    fn each_hasher_verifies_its_own_hashes() {
+        use password::PasswordHasher as _;
+
+        let hashers: [Box<dyn password::PasswordHasher>; 2] = [
+            Box::new(password::Bcrypt { cost: 4 }),
+            Box::new(password::Argon2id::new()),
+        ];
+        for hasher in hashers {
+            let hash = hasher.hash("correct horse").unwrap();
+            assert!(hasher.is_current(&hash));
+            assert!(password::verify("correct horse", &hash));
+            assert!(!password::verify("wrong horse", &hash));
+        }
+        let cheaper = password::Bcrypt { cost: 4 }.hash("correct horse").unwrap();
+        assert!(!password::Bcrypt { cost: 5 }.is_current(&cheaper));
+        assert!(!password::Argon2id::new().is_current(&cheaper));
+    }
+
+    #[actix_web::test]
+    async fn signin_upgrades_a_hash_made_by_an_older_hasher() {
+        let data = state();
+        let app = app!(data);
+        user(&app, "alice").await;
+        drop(app);
+
+        let Ok(mut upgraded) = Arc::try_unwrap(data.into_inner()) else {
+            unreachable!("the state is no longer shared")
+        };
+        upgraded.password_hasher = Box::new(password::Argon2id::new());
+        let data = web::Data::new(upgraded);
+        let app = app!(data);
+        let stored = || data.users.lock().unwrap()["alice"].password_hash.clone();
+        assert!(stored().starts_with("$2"));
+
+        let credentials = json!({ "username": "alice", "password": "password" });
+        let (status, _) = call(&app, post("/signin"), None, credentials.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let upgraded = stored();
+        assert!(upgraded.starts_with("$argon2id"));
+
+        // A current hash is left as it is.
+        let (status, _) = call(&app, post("/signin"), None, credentials).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stored(), upgraded);
+    }
+
+    #[actix_web::test]
+    async fn repeated_wrong_passwords_lock_the_username() {
+        let data = state();
+        let app = app!(data);
+        user(&app, "alice").await;
+        let wrong = json!({ "username": "alice", "password": "not it" });
+        for _ in 0..lockout::DEFAULT_MAX_FAILURES {
+            let (status, body) = call(&app, post("/signin"), None, wrong.clone()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["code"], "wrong_credentials");
+        }
+
+        let right = json!({ "username": "alice", "password": "password" });
+        let response = call_service(&app, post("/signin").set_json(right).to_request()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("Retry-After"));
+        let body: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["code"], "locked_out");
+
+        // Nobody else is locked out.
+        user(&app, "bob").await;
+    }
+
+    #[test]
+    fn lockout_lifts_once_the_failures_leave_the_window() {
+        let attempts = lockout::LoginAttempts::new(2, Duration::from_millis(50));
+        attempts.record_failure("alice");
+        assert_eq!(attempts.locked_for("alice"), None);
+        attempts.record_failure("alice");
+        assert!(attempts.locked_for("alice").is_some());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(attempts.locked_for("alice"), None);
+
+        attempts.record_failure("alice");
+        attempts.record_failure("alice");
+        attempts.clear("alice");
+        assert_eq!(attempts.locked_for("alice"), None);
+    }
+
+    #[actix_web::test]
+    async fn usernames_differing_in_case_or_padding_are_one_account() {
+        let data = state();
+        let app = app!(data);
+        let credentials = |username| json!({ "username": username, "password": "password" });
+        let (status, _) = call(&app, post("/signup"), None, credentials("Alice ")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(&app, post("/signin"), None, credentials("alice")).await;
+        assert_eq!(status, StatusCode::OK);
+        let token = body["token"].as_str().unwrap();
+        let (_, body) = call(&app, get("/whoami"), Some(token), Value::Null).await;
+        assert_eq!(body["username"], "alice");
+        assert_eq!(data.users.lock().unwrap()["alice"].display_name, "Alice");
+
+        for duplicate in ["alice", "ALICE", " aLiCe"] {
+            let (status, body) = call(&app, post("/signup"), None, credentials(duplicate)).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{duplicate:?}");
+            assert_eq!(body["code"], "user_exists");
+        }
+        assert_eq!(data.users.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn env_or_falls_back_when_unset_and_refuses_what_does_not_parse() {
+        // Names of their own, as other tests read the real ones in parallel.
+        let (host, port) = ("ORDERBOOK_TEST_HOST", "ORDERBOOK_TEST_PORT");
+        std::env::remove_var(host);
+        std::env::remove_var(port);
+        assert_eq!(
+            env_or(host, DEFAULT_HOST.to_string()).unwrap(),
+            DEFAULT_HOST
+        );
+        assert_eq!(env_or(port, DEFAULT_PORT).unwrap(), DEFAULT_PORT);
+
+        std::env::set_var(host, "127.0.0.1");
+        std::env::set_var(port, " 9000 ");
+        assert_eq!(env_or(host, DEFAULT_HOST.to_string()).unwrap(), "127.0.0.1");
+        assert_eq!(env_or(port, DEFAULT_PORT).unwrap(), 9000);
+
+        for invalid in ["eighty", "65536", "-1", ""] {
+            std::env::set_var(port, invalid);
+            let error = env_or(port, DEFAULT_PORT).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(error.to_string().contains(port), "{error}");
+        }
+        std::env::remove_var(host);
+        std::env::remove_var(port);
+    }
+
+    #[actix_web::test]
+    async fn withdrawals_take_only_available_funds() {
+        let state = state();
+        let app = app!(state);
+        let token = user(&app, "alice").await;
+        fund(&app, &token, None, 1_000).await;
+        let amount_out = |amount: i64| json!({ "asset": null, "amount": amount });
+
+        let (status, body) = call(&app, post("/withdraw"), Some(&token), amount_out(300)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(decimal(&body["new_balance"]), Decimal::from(700));
+
+        for (amount, code) in [(701, "insufficient_funds"), (0, "invalid_amount")] {
+            let (status, body) =
+                call(&app, post("/withdraw"), Some(&token), amount_out(amount)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], code);
+        }
+
+        // 600.60 of the 700 now backs a bid, leaving 99.40 to take out.
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 6 });
+        let (_, placed) = place(&app, &token, bid).await;
+        assert_eq!(placed["status"], "placed");
+        let (status, body) = call(&app, post("/withdraw"), Some(&token), amount_out(100)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "insufficient_funds");
+        let (status, body) = call(&app, post("/withdraw"), Some(&token), amount_out(99)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(decimal(&body["new_balance"]), Decimal::new(40, 2));
+
+        let (status, _) = call(&app, post("/withdraw"), None, amount_out(1)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn ledger_adds_up_to_what_the_account_holds() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        fund(&app, &alice, None, 1_000).await;
+        fund(&app, &bob, Some("BTC"), 1).await;
+        let ask = json!({ "side": "sell", "order_type": "limit", "price": 100, "quantity": 1 });
+        place(&app, &bob, ask).await;
+        let buy = json!({ "side": "buy", "order_type": "market", "quantity": 1 });
+        let (_, filled) = place(&app, &alice, buy).await;
+        assert_eq!(filled["status"], "filled");
+        let out = json!({ "asset": null, "amount": 50 });
+        let (status, _) = call(&app, post("/withdraw"), Some(&alice), out).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, ledger) = call(&app, get("/ledger"), Some(&alice), Value::Null).await;
+        let [quote, _, btc, _] = holdings(&app, &alice).await;
+        for (asset, held) in [(Value::Null, quote), (json!("BTC"), btc)] {
+            let mut running = Decimal::ZERO;
+            for entry in ledger.as_array().unwrap() {
+                if entry.get("asset").unwrap_or(&Value::Null) == &asset {
+                    running += decimal(&entry["amount"]);
+                    assert_eq!(decimal(&entry["balance"]), running, "{entry}");
+                }
+            }
+            assert_eq!(running, held, "{asset}");
+        }
+        // 1,000 in, 100 and a 0.10 fee for the BTC, 50 out.
+        assert_eq!(quote, Decimal::new(84_990, 2));
+        assert_eq!(btc, Decimal::ONE);
+    }
+
+    #[actix_web::test]
+    async fn trade_pages_follow_on_without_gaps_or_repeats() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        fund(&app, &alice, None, 10_000).await;
+        fund(&app, &bob, Some("BTC"), 50).await;
+        let mut traded = Vec::new();
+        for _ in 0..50 {
+            let ask = json!({ "side": "sell", "order_type": "limit", "price": 100, "quantity": 1 });
+            place(&app, &bob, ask).await;
+            let buy = json!({ "side": "buy", "order_type": "market", "quantity": 1 });
+            let (_, filled) = place(&app, &alice, buy).await;
+            traded.push(filled["trades"][0]["id"].clone());
+        }
+
+        let mut paged = Vec::new();
+        let mut pages = Vec::new();
+        let mut uri = "/trades/BTC-USD?limit=20".to_string();
+        loop {
+            let response = call_service(&app, get(&uri).to_request()).await;
+            let cursor = response
+                .headers()
+                .get(NEXT_CURSOR_HEADER)
+                .map(|cursor| cursor.to_str().unwrap().to_string());
+            let trades: Vec<Value> = serde_json::from_slice(&read_body(response).await).unwrap();
+            pages.push(trades.len());
+            paged.extend(trades.into_iter().map(|trade| trade["id"].clone()));
+            match cursor {
+                Some(cursor) => uri = format!("/trades/BTC-USD?limit=20&before={cursor}"),
+                None => break,
+            }
+        }
+        assert_eq!(pages, [20, 20, 10]);
+        traded.reverse();
+        assert_eq!(paged, traded);
+    }
+
+    #[actix_web::test]
+    async fn depth_runs_a_total_from_the_best_price_inward() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        fund(&app, &alice, None, 10_000).await;
+        for (price, quantity) in [(99, 2), (101, 1), (100, 3)] {
+            let bid = json!({ "side": "buy", "order_type": "limit", "price": price, "quantity": quantity });
+            place(&app, &alice, bid).await;
+        }
+
+        let (status, depth) = call(&app, get("/orderbook/BTC-USD/depth"), None, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let column = |key: &str| -> Vec<Decimal> {
+            depth["bids"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|level| decimal(&level[key]))
+                .collect()
+        };
+        assert_eq!(column("price"), [101, 100, 99].map(Decimal::from));
+        assert_eq!(column("quantity"), [1, 3, 2].map(Decimal::from));
+        assert_eq!(column("cumulative"), [1, 4, 6].map(Decimal::from));
+        assert_eq!(depth["asks"], json!([]));
+
+        let uri = "/orderbook/BTC-USD/depth?levels=2";
+        let (_, depth) = call(&app, get(uri), None, Value::Null).await;
+        assert_eq!(depth["bids"].as_array().unwrap().len(), 2);
+        assert_eq!(decimal(&depth["bids"][1]["cumulative"]), Decimal::from(4));
+    }
+
+    #[actix_web::test]
+    async fn signup_lists_every_password_rule_it_fails() {
+        let Ok(mut strict) = Arc::try_unwrap(state().into_inner()) else {
+            unreachable!("the state is not shared yet")
+        };
+        strict.password_policy = password::PasswordPolicy {
+            min_length: 8,
+            required: vec![password::CharClass::Upper, password::CharClass::Digit],
+        };
+        let app = app!(web::Data::new(strict));
+        let signing_up = |password| json!({ "username": "alice", "password": password });
+
+        for (password, message) in [
+            ("Ab1", "password must be at least 8 characters long"),
+            (
+                "abcdefgh",
+                "password must contain an uppercase letter, contain a digit",
+            ),
+            ("Abcdefgh", "password must contain a digit"),
+        ] {
+            let (status, body) = call(&app, post("/signup"), None, signing_up(password)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{password}");
+            assert_eq!(body["code"], "weak_password");
+            assert_eq!(body["message"], message);
+        }
+        let (status, _) = call(&app, post("/signup"), None, signing_up("Abcdefg1")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn password_policy_refuses_an_unknown_character_class() {
+        assert_eq!(
+            password::parse_classes("Lower, digit,").unwrap(),
+            [password::CharClass::Lower, password::CharClass::Digit]
+        );
+        let error = password::parse_classes("lower,emoji").unwrap_err();
+        assert!(
+            error.to_string().contains("PASSWORD_CHAR_CLASSES"),
+            "{error}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn signup_under_the_default_policy_needs_mixed_case_and_a_digit() {
+        let Ok(mut defaults) = Arc::try_unwrap(state().into_inner()) else {
+            unreachable!("the state is not shared yet")
+        };
+        defaults.password_policy = password::PasswordPolicy::default();
+        let app = app!(web::Data::new(defaults));
+        let signing_up = |password| json!({ "username": "alice", "password": password });
+
+        let (status, body) = call(&app, post("/signup"), None, signing_up("abcdefgh")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["message"],
+            "password must contain an uppercase letter, contain a digit"
+        );
+        let (status, _) = call(&app, post("/signup"), None, signing_up("Abcdefg1")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn symbols_lists_every_market_with_its_rules_and_status() {
+        let state = state();
+        let app = app!(state);
+        let admin = user(&app, "admin").await;
+        let paused = json!({ "status": "paused" });
+        let uri = "/admin/market/ETH-USD/status";
+        let (status, _) = call(&app, put(uri), Some(&admin), paused).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, markets) = call(&app, get("/symbols"), None, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let markets = markets.as_array().unwrap();
+        assert_eq!(markets.len(), 2);
+        for (market, symbol, base, min_quantity, base_decimals, status) in [
+            (&markets[0], "BTC-USD", "BTC", Decimal::new(1, 5), 8, "open"),
+            (
+                &markets[1],
+                "ETH-USD",
+                "ETH",
+                Decimal::new(1, 4),
+                6,
+                "paused",
+            ),
+        ] {
+            assert_eq!(market["symbol"], symbol);
+            assert_eq!(market["base_asset"], base);
+            assert_eq!(market["quote_asset"], types::QUOTE_ASSET);
+            assert_eq!(decimal(&market["tick_size"]), orderbook::DEFAULT_TICK_SIZE);
+            assert_eq!(decimal(&market["min_quantity"]), min_quantity);
+            assert_eq!(decimal(&market["min_notional"]), Decimal::ONE);
+            assert_eq!(market["base_decimals"], base_decimals);
+            assert_eq!(market["status"], status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_an_admin_can_cancel_someone_elses_order() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        let admin = user(&app, "admin").await;
+        fund(&app, &alice, None, 1_000).await;
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        let (_, placed) = place(&app, &alice, bid).await;
+        let uri = format!("/admin/order/{}", placed["order_id"].as_str().unwrap());
+
+        let (status, body) = call(&app, delete(&uri), Some(&bob), Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "forbidden");
+        let (status, _) = call(&app, delete(&uri), None, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_ne!(holdings(&app, &alice).await[1], Decimal::ZERO);
+
+        let (status, body) = call(&app, delete(&uri), Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "cancelled");
+        assert_eq!(
+            holdings(&app, &alice).await,
+            [
+                Decimal::ONE_THOUSAND,
+                Decimal::ZERO,
+                Decimal::ZERO,
+                Decimal::ZERO
+            ]
+        );
+        let (_, book) = call(&app, get("/orderbook/BTC-USD"), None, Value::Null).await;
+        assert_eq!(book["bids"], json!([]));
+    }
+
+    #[actix_web::test]
+    async fn admin_lists_users_and_holdings_without_password_hashes() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let admin = user(&app, "admin").await;
+        fund(&app, &alice, None, 250).await;
+        fund(&app, &alice, Some("BTC"), 2).await;
+
+        let (status, body) = call(&app, get("/admin/users"), Some(&alice), Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "forbidden");
+
+        let response = call_service(
+            &app,
+            get("/admin/users")
+                .insert_header(("Authorization", format!("Bearer {admin}")))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = read_body(response).await;
+        let stored = state.users.lock().unwrap()["alice"].password_hash.clone();
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(!text.contains("password_hash") && !text.contains(&stored));
+
+        let users: Value = serde_json::from_slice(&bytes).unwrap();
+        let names: Vec<&Value> = users
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| &u["username"])
+            .collect();
+        assert_eq!(names, [&json!("admin"), &json!("alice")]);
+        let listed = &users[1];
+        assert_eq!(listed["id"], state.users.lock().unwrap()["alice"].id);
+        assert_eq!(listed["is_admin"], false);
+        assert_eq!(decimal(&listed["available"]), Decimal::from(250));
+        assert_eq!(
+            decimal(&listed["assets"]["BTC"]["available"]),
+            Decimal::from(2)
+        );
+        assert_eq!(users[0]["is_admin"], true);
+    }
+
+    #[cfg(feature = "test-endpoints")]
+    #[actix_web::test]
+    async fn reset_empties_a_seeded_book() {
+        let state = state();
+        let app = app!(state);
+        let fixture = json!({
+            "users": [
+                { "username": "alice", "password": "password", "balance": 1000 },
+                { "username": "bob", "password": "password", "assets": { "BTC": 2 } }
+            ],
+            "orders": [
+                { "username": "alice", "order": { "symbol": "BTC-USD", "side": "buy", "order_type": "limit", "price": 99, "quantity": 1 } },
+                { "username": "bob", "order": { "symbol": "BTC-USD", "side": "sell", "order_type": "limit", "price": 101, "quantity": 2 } }
+            ]
+        });
+        let (status, seeded) = call(&app, post("/admin/seed"), None, fixture).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(seeded["orders"][0]["status"], "placed");
+        assert_eq!(seeded["orders"][1]["status"], "placed");
+        let (_, book) = call(&app, get("/orderbook/BTC-USD"), None, Value::Null).await;
+        assert_eq!(decimal(&book["bids"][0]["price"]), Decimal::from(99));
+        assert_eq!(decimal(&book["asks"][0]["quantity"]), Decimal::from(2));
+
+        let (status, _) = call(&app, post("/admin/reset"), None, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, book) = call(&app, get("/orderbook/BTC-USD"), None, Value::Null).await;
+        assert_eq!((&book["bids"], &book["asks"]), (&json!([]), &json!([])));
+        assert!(state.users.lock().unwrap().is_empty());
+        assert!(state.accounts.lock().unwrap().is_empty());
+        let credentials = json!({ "username": "alice", "password": "password" });
+        let (status, _) = call(&app, post("/signin"), None, credentials).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[cfg(not(feature = "test-endpoints"))]
+    #[actix_web::test]
+    async fn reset_and_seed_are_left_out_without_the_feature() {
+        let state = state();
+        let app = app!(state);
+        for uri in ["/admin/reset", "/admin/seed"] {
+            let (status, _) = call(&app, post(uri), None, json!({})).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn full_engine_queue_is_refused_as_busy_rather_than_waited_on() {
+        let Ok(mut stalled) = Arc::try_unwrap(state().into_inner()) else {
+            unreachable!("the state is not shared yet")
+        };
+        let (tx, _never_read) = tokio::sync::mpsc::channel(1);
+        let (response, _) = tokio::sync::oneshot::channel();
+        tx.try_send(OrderbookCommand::GetSnapshot { response })
+            .unwrap();
+        stalled.shards.insert("BTC-USD".to_string(), tx);
+        let app = app!(web::Data::new(stalled));
+
+        // Well short of the engine timeout: only the wait for room counts.
+        let wait = Duration::from_millis(dispatch::DEFAULT_ENGINE_TIMEOUT_MS / 2);
+        let request = get("/orderbook/BTC-USD").to_request();
+        let response = tokio::time::timeout(wait, call_service(&app, request))
+            .await
+            .expect("the request waited for the queue");
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+        let body: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["code"], "engine_busy");
+    }
+
+    #[actix_web::test]
+    async fn errors_share_one_envelope() {
+        let state = state();
+        let app = app!(state);
+        let malformed = post("/signup")
+            .insert_header(actix_web::http::header::ContentType::json())
+            .set_payload("{\"username\": ");
+        let unauthenticated = get("/whoami");
+        for (request, status, code) in [
+            (get("/no/such/route"), StatusCode::NOT_FOUND, "not_found"),
+            (malformed, StatusCode::BAD_REQUEST, "invalid_body"),
+            (unauthenticated, StatusCode::UNAUTHORIZED, "unauthorized"),
+        ] {
+            let response = call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), status);
+            let body: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+            let fields: Vec<&String> = body.as_object().unwrap().keys().collect();
+            assert_eq!(fields, ["code", "message"], "{body}");
+            assert_eq!(body["code"], code);
+            assert!(!body["message"].as_str().unwrap().is_empty());
+        }
+    }
+
+    #[actix_web::test]
+    async fn bearer_token_resolves_to_its_user_or_401() {
+        let state = state();
+        let app = app!(state);
+        user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        let ghost = auth::issue_token(&state.jwt_secret, "ghost").unwrap();
+        let foreign = auth::issue_token("some other secret", "bob").unwrap();
+
+        for authorization in [
+            None,
+            Some(bob.clone()),
+            Some("Bearer".to_string()),
+            Some(format!("Basic {bob}")),
+            Some("Bearer not-a-token".to_string()),
+            Some(format!("Bearer {foreign}")),
+            Some(format!("Bearer {ghost}")),
+        ] {
+            let mut request = get("/whoami");
+            if let Some(authorization) = &authorization {
+                request = request.insert_header(("Authorization", authorization.as_str()));
+            }
+            let response = call_service(&app, request.to_request()).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{authorization:?}"
+            );
+        }
+
+        let (status, body) = call(&app, get("/whoami"), Some(&bob), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["username"], "bob");
+    }
+
+    #[actix_web::test]
+    async fn cors_headers_are_only_sent_to_allowed_origins() {
+        // Only this test reads the CORS settings.
+        std::env::set_var("CORS_ALLOWED_ORIGINS", "https://app.example.com");
+        let policy = cors::CorsPolicy::from_env().unwrap();
+        std::env::remove_var("CORS_ALLOWED_ORIGINS");
+        let state = state();
+        let app = init_service(
+            App::new()
+                .wrap(policy.middleware())
+                .app_data(state.clone())
+                .configure(routes)
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        let allow_origin = |response: &ServiceResponse<_>| {
+            response
+                .headers()
+                .get("Access-Control-Allow-Origin")
+                .map(|origin| origin.to_str().unwrap().to_string())
+        };
+
+        for (origin, allowed) in [
+            ("https://app.example.com", true),
+            ("https://evil.example.com", false),
+        ] {
+            let request = get("/orderbook/BTC-USD").insert_header(("Origin", origin));
+            let response = call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(allow_origin(&response), allowed.then(|| origin.to_string()));
+
+            for uri in ["/order", "/orderbook/BTC-USD"] {
+                let preflight = TestRequest::default()
+                    .method(actix_web::http::Method::OPTIONS)
+                    .uri(uri)
+                    .insert_header(("Origin", origin))
+                    .insert_header(("Access-Control-Request-Method", "POST"))
+                    .insert_header((
+                        "Access-Control-Request-Headers",
+                        "authorization, content-type",
+                    ));
+                let response = call_service(&app, preflight.to_request()).await;
+                assert_eq!(response.status().is_success(), allowed, "{origin} {uri}");
+                assert_eq!(allow_origin(&response), allowed.then(|| origin.to_string()));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn user_cancelling_most_orders_is_flagged_and_a_normal_user_is_not() {
+        let state = state();
+        let app = app!(state);
+        let admin = user(&app, "admin").await;
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 1 });
+        for (username, cancels) in [("alice", 50), ("bob", 10)] {
+            let token = user(&app, username).await;
+            fund(&app, &token, None, 10_000).await;
+            for placed in 0..cancel_ratio::DEFAULT_MIN_ORDERS {
+                let (status, body) = place(&app, &token, bid.clone()).await;
+                assert_eq!(status, StatusCode::OK, "{body}");
+                if placed < cancels {
+                    let uri = format!("/order/{}", body["order_id"].as_str().unwrap());
+                    let (status, _) = call(&app, delete(&uri), Some(&token), Value::Null).await;
+                    assert_eq!(status, StatusCode::OK);
+                }
+            }
+        }
+
+        let (status, users) = call(&app, get("/admin/users"), Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let listed = |username: &str| {
+            users
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|u| u["username"] == username)
+                .unwrap()
+                .clone()
+        };
+        let (alice, bob) = (listed("alice"), listed("bob"));
+        assert_eq!(alice["flagged"], true);
+        assert_eq!(decimal(&alice["cancel_ratio"]), Decimal::ONE);
+        assert_eq!(bob["flagged"], false);
+        assert_eq!(decimal(&bob["cancel_ratio"]), Decimal::new(2, 1));
+        assert_eq!(listed("admin")["flagged"], false);
+    }
+
+    #[test]
+    fn order_sides_and_types_parse_in_any_case() {
+        let side = |text: &str| serde_json::from_value::<types::OrderSide>(json!(text));
+        assert!(matches!(side("Buy"), Ok(types::OrderSide::Buy)));
+        assert!(matches!(side("SELL"), Ok(types::OrderSide::Sell)));
+        let error = side("foo").unwrap_err().to_string();
+        assert!(error.contains("foo") && error.contains("buy"), "{error}");
+
+        let order_type = |tag: &str| {
+            serde_json::from_value::<types::OrderType>(
+                json!({ "order_type": tag, "stop_price": 90 }),
+            )
+        };
+        assert!(matches!(
+            order_type("limit"),
+            Ok(types::OrderType::LimitOrder)
+        ));
+        assert!(matches!(
+            order_type("LIMIT"),
+            Ok(types::OrderType::LimitOrder)
+        ));
+        assert!(matches!(
+            order_type("Stop_Market"),
+            Ok(types::OrderType::StopMarket { .. })
+        ));
+        assert!(order_type("StopMarket").is_err());
+        let error = order_type("foo").unwrap_err().to_string();
+        assert!(error.contains("foo") && error.contains("limit"), "{error}");
+    }
+
+    #[actix_web::test]
+    async fn order_with_an_unknown_side_gets_a_400_naming_it() {
+        let app = app!(state());
+        let token = user(&app, "alice").await;
+        let order = json!({ "side": "foo", "order_type": "limit", "price": 100, "quantity": 1 });
+
+        let (status, body) = place(&app, &token, order).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_body");
+        let message = body["message"].as_str().unwrap();
+        assert!(
+            message.contains("foo") && message.contains("sell"),
+            "{message}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn resting_order_filling_later_counts_towards_orders_filled_total() {
+        let state = state();
+        let app = app!(state);
+        let alice = user(&app, "alice").await;
+        let bob = user(&app, "bob").await;
+        fund(&app, &alice, None, 1_000).await;
+        fund(&app, &bob, Some("BTC"), 2).await;
+
+        let bid = json!({ "side": "buy", "order_type": "limit", "price": 100, "quantity": 2 });
+        assert_eq!(place(&app, &alice, bid).await.1["status"], "placed");
+        let ask = json!({ "side": "sell", "order_type": "limit", "price": 100, "quantity": 1 });
+        assert_eq!(place(&app, &bob, ask.clone()).await.1["status"], "filled");
+        assert_eq!(metric(&app, "orders_filled_total").await, 1.0);
+
+        // Bob's second sell fills, and so does what was left of alice's bid.
+        assert_eq!(place(&app, &bob, ask).await.1["status"], "filled");
+        assert_eq!(metric(&app, "orders_filled_total").await, 3.0);
+    }
 }
@@ -0,0 +1,127 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::types::{OrderResponse, OrderbookSnapshot};
+
+/// Counters and gauges served by `/metrics` in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    pub orders_placed: IntCounter,
+    pub orders_filled: IntCounter,
+    pub orders_cancelled: IntCounter,
+    pub orders_rejected: IntCounter,
+    /// Time from handing an order to its engine until the reply arrives.
+    pub order_latency: Histogram,
+    /// Price levels on each side of each book, refreshed on every scrape.
+    pub book_depth: IntGaugeVec,
+    /// Commands waiting in each engine's queue, refreshed on every scrape.
+    pub engine_queue_depth: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+
+        let orders_placed = counter("orders_placed_total", "Orders accepted by the engine.");
+        let orders_filled = counter(
+            "orders_filled_total",
+            "Orders filled in full, on arrival or while resting.",
+        );
+        let orders_cancelled = counter(
+            "orders_cancelled_total",
+            "Orders cancelled by their owner or left unfilled by their time in force.",
+        );
+        let orders_rejected = counter("orders_rejected_total", "Orders refused by the engine.");
+
+        let order_latency = Histogram::with_opts(HistogramOpts::new(
+            "order_processing_seconds",
+            "Time the engine took to answer an order.",
+        ))
+        .unwrap();
+        registry.register(Box::new(order_latency.clone())).unwrap();
+
+        let book_depth = IntGaugeVec::new(
+            Opts::new(
+                "book_depth_levels",
+                "Price levels resting on one side of a book.",
+            ),
+            &["symbol", "side"],
+        )
+        .unwrap();
+        registry.register(Box::new(book_depth.clone())).unwrap();
+
+        let engine_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "engine_queue_depth",
+                "Commands waiting for a market's engine.",
+            ),
+            &["symbol"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(engine_queue_depth.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            orders_placed,
+            orders_filled,
+            orders_cancelled,
+            orders_rejected,
+            order_latency,
+            book_depth,
+            engine_queue_depth,
+        }
+    }
+
+    /// Counts the outcome of a newly placed order. Fills are left to the
+    /// engines, which see resting orders fill as well; see
+    /// `Orderbook::count_fills`.
+    pub fn record_order(&self, response: &OrderResponse) {
+        if let OrderResponse::Error { .. } = response {
+            self.orders_rejected.inc();
+            return;
+        }
+
+        self.orders_placed.inc();
+        if matches!(
+            response,
+            OrderResponse::Cancelled { .. }
+                | OrderResponse::PartiallyFilled {
+                    cancelled_quantity: Some(_),
+                    ..
+                }
+        ) {
+            self.orders_cancelled.inc();
+        }
+    }
+
+    pub fn record_depth(&self, symbol: &str, snapshot: &OrderbookSnapshot) {
+        for (side, levels) in [("bid", &snapshot.bids), ("ask", &snapshot.asks)] {
+            self.book_depth
+                .with_label_values(&[symbol, side])
+                .set(levels.len() as i64);
+        }
+    }
+
+    /// Everything registered, in the text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
@@ -1,281 +1,5781 @@
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use uuid::Uuid;
+use prometheus::IntCounter;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
 
+use crate::candles::{self, Candle, CandleSeries};
+use crate::persistence::{self, Recovery, SharedWal, WalEntry};
+use crate::stats::{DailyStats, RollingStats};
 use crate::types::{
-    Order, OrderResponse, OrderSide, OrderType, OrderbookCommand, OrderbookSnapshot, Trade,
+    Account, Accounts, BookEntry, BookEvent, BookState, BookUpdate, Bracket, FeeTier, Feeds,
+    FullSnapshot, LedgerKind, MarketConfig, MarketFees, MarketInfo, MarketStatus, MatchingMode,
+    MidPriceSample, OcoResponse, Order, OrderAck, OrderError, OrderResponse, OrderSide,
+    OrderStatus, OrderType, OrderbookCommand, OrderbookSnapshot, PegReference, PriceLevel,
+    SelfTradePrevention, SequencedEvent, Ticker, Trade, UserEvent, QUOTE_ASSET,
 };
 
+/// Price increment used by the markets the engine starts with.
+pub const DEFAULT_TICK_SIZE: Decimal = Decimal::from_parts(1, 0, 0, false, 5);
+
+/// Maker fee, in basis points, of the markets the engine starts with.
+pub const DEFAULT_MAKER_FEE_BPS: Decimal = Decimal::from_parts(5, 0, 0, false, 0);
+
+/// Taker fee, in basis points, of the markets the engine starts with.
+pub const DEFAULT_TAKER_FEE_BPS: Decimal = Decimal::from_parts(10, 0, 0, false, 0);
+
+/// Price band, in basis points, of the markets the engine starts with.
+pub const DEFAULT_PRICE_BAND_BPS: Decimal = Decimal::from_parts(1_000, 0, 0, false, 0);
+
+/// Open orders each user may have on a market the engine starts with.
+pub const DEFAULT_MAX_OPEN_ORDERS: usize = 200;
+
+/// Milliseconds since the Unix epoch, the unit of every order and trade timestamp.
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Volume discounts of the markets the engine starts with.
+pub fn default_fee_tiers() -> Vec<FeeTier> {
+    vec![
+        FeeTier::new(Decimal::from(100_000), Decimal::from(3), Decimal::from(8)),
+        FeeTier::new(Decimal::from(1_000_000), Decimal::ONE, Decimal::from(5)),
+    ]
+}
+
+/// Markets available when the engine is started without explicit configuration.
+pub fn default_markets() -> Vec<MarketConfig> {
+    vec![
+        MarketConfig::new("BTC-USD", "BTC", DEFAULT_TICK_SIZE)
+            .with_fees(DEFAULT_MAKER_FEE_BPS, DEFAULT_TAKER_FEE_BPS)
+            .with_fee_tiers(default_fee_tiers())
+            .with_decimals(8, 2)
+            .with_minimums(Decimal::new(1, 5), Decimal::ONE)
+            .with_maximums(Decimal::new(1_000, 0), DEFAULT_MAX_OPEN_ORDERS)
+            .with_price_band(DEFAULT_PRICE_BAND_BPS),
+        MarketConfig::new("ETH-USD", "ETH", DEFAULT_TICK_SIZE)
+            .with_fees(DEFAULT_MAKER_FEE_BPS, DEFAULT_TAKER_FEE_BPS)
+            .with_fee_tiers(default_fee_tiers())
+            .with_decimals(6, 2)
+            .with_minimums(Decimal::new(1, 4), Decimal::ONE)
+            .with_maximums(Decimal::new(10_000, 0), DEFAULT_MAX_OPEN_ORDERS)
+            .with_price_band(DEFAULT_PRICE_BAND_BPS),
+    ]
+}
+
+/// Levels of each side covered by `book_checksum`.
+pub const CHECKSUM_DEPTH: usize = 25;
+
+/// CRC-32 (IEEE) of the best `CHECKSUM_DEPTH` levels of each side, given
+/// best price first as `(price, visible quantity)`. Each level is written as
+/// `price:quantity` with trailing zeros dropped, levels are joined by `,`
+/// and the bids come before the asks, separated by `|`. A book bidding 2 at
+/// 100 and 0.25 at 99.5 and offering 1 at 101 hashes `"100:2,99.5:0.25|101:1"`;
+/// an empty one hashes `"|"`.
+pub fn book_checksum(bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) -> u32 {
+    let side = |levels: &[(Decimal, Decimal)]| {
+        levels
+            .iter()
+            .take(CHECKSUM_DEPTH)
+            .map(|(price, quantity)| format!("{}:{}", price.normalize(), quantity.normalize()))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    crc32fast::hash(format!("{}|{}", side(bids), side(asks)).as_bytes())
+}
+
+/// How many filled or cancelled orders each book remembers for status queries.
+const ORDER_HISTORY_LIMIT: usize = 10_000;
+
+/// How often the engine looks for good-till-date orders that have expired.
+const EXPIRY_SWEEP_INTERVAL_MS: u64 = 1_000;
+
+/// How many trades each book keeps for the public trade tape.
+const RECENT_TRADES_LIMIT: usize = 1_000;
+
+/// How many mid-price samples each book keeps.
+const MID_PRICE_HISTORY_LIMIT: usize = 1_000;
+
+/// How many `client_order_id`s each book remembers the response to.
+const CLIENT_ORDER_ID_LIMIT: usize = 10_000;
+
+/// Longest `client_order_id` accepted, in bytes.
+pub const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
+
+/// Recently closed orders, oldest evicted first once `ORDER_HISTORY_LIMIT` is reached.
+#[derive(Default)]
+struct OrderHistory {
+    orders: HashMap<String, Order>,
+    ids: VecDeque<String>,
+    /// Counts the orders recorded as filled, if set.
+    filled: Option<IntCounter>,
+}
+
+/// Responses to recently placed orders that carried a `client_order_id`,
+/// keyed by user and id, oldest evicted first once `CLIENT_ORDER_ID_LIMIT`
+/// is reached.
+#[derive(Default)]
+struct ClientOrderIds {
+    responses: HashMap<(String, String), OrderResponse>,
+    keys: VecDeque<(String, String)>,
+}
+
+impl ClientOrderIds {
+    fn record(&mut self, key: (String, String), response: OrderResponse) {
+        if self.keys.len() >= CLIENT_ORDER_ID_LIMIT {
+            if let Some(oldest) = self.keys.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.keys.push_back(key.clone());
+        self.responses.insert(key, response);
+    }
+
+    fn get(&self, user_id: &str, client_order_id: &str) -> Option<&OrderResponse> {
+        self.responses
+            .get(&(user_id.to_string(), client_order_id.to_string()))
+    }
+}
+
+/// How many orders each user has in `Orderbook::orders`, kept up to date as
+/// orders are added and removed so the open-order limit needs no scan.
+#[derive(Default)]
+struct OpenOrderCounts {
+    counts: HashMap<String, usize>,
+}
+
+impl OpenOrderCounts {
+    fn opened(&mut self, user_id: &str) {
+        *self.counts.entry(user_id.to_string()).or_default() += 1;
+    }
+
+    fn closed(&mut self, user_id: &str) {
+        if let Some(count) = self.counts.get_mut(user_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(user_id);
+            }
+        }
+    }
+
+    fn get(&self, user_id: &str) -> usize {
+        self.counts.get(user_id).copied().unwrap_or(0)
+    }
+}
+
+impl OrderHistory {
+    fn record(&mut self, mut order: Order, status: OrderStatus) {
+        if let (OrderStatus::Filled, Some(filled)) = (&status, &self.filled) {
+            filled.inc();
+        }
+        order.status = status;
+        if self.ids.len() >= ORDER_HISTORY_LIMIT {
+            if let Some(oldest) = self.ids.pop_front() {
+                self.orders.remove(&oldest);
+            }
+        }
+        self.ids.push_back(order.id.clone());
+        self.orders.insert(order.id.clone(), order);
+    }
+
+    fn get(&self, order_id: &str) -> Option<&Order> {
+        self.orders.get(order_id)
+    }
+}
+
+/// Result of running an incoming order against the book.
+pub struct MatchOutcome {
+    pub trades: Vec<Trade>,
+    /// Set when self-trade prevention cancelled the rest of the incoming order.
+    pub taker_cancelled: bool,
+}
+
+/// Numbers one market's `BookEvent`s and sends them on `Feeds::book_events`.
+struct EventPublisher {
+    symbol: String,
+    sequence: u64,
+    sender: tokio::sync::broadcast::Sender<SequencedEvent>,
+}
+
+impl EventPublisher {
+    fn publish(&mut self, event: BookEvent) {
+        self.sequence += 1;
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.sender.send(SequencedEvent {
+            symbol: self.symbol.clone(),
+            sequence: self.sequence,
+            event,
+        });
+    }
+}
+
 pub struct Orderbook {
+    market: MarketConfig,
+    accounts: Accounts,
     bids: BTreeMap<u64, VecDeque<Order>>,
     asks: BTreeMap<u64, VecDeque<Order>>,
+    /// Every order currently resting in `bids`/`asks`, keyed by id. Entries are
+    /// copies of the queued orders, so matching keeps `remaining_quantity` in
+    /// sync whenever a resting order is partially filled.
     orders: HashMap<String, Order>,
+    /// Stop and market-if-touched orders waiting for `last_trade_price` to
+    /// reach their trigger. They are also tracked in `orders` so they can be
+    /// cancelled by id.
+    stops: Vec<Order>,
+    /// `(activates_at, sequence, order id)` of good-after-time orders waiting
+    /// to go in, soonest first. The orders are kept in `orders` meanwhile, like
+    /// stops, so they can be looked up and cancelled by id.
+    delayed: BTreeSet<(u64, u64, String)>,
+    /// Exits of bracket entries that are still open, oldest first.
+    brackets: Vec<Bracket>,
+    open_orders: OpenOrderCounts,
+    history: OrderHistory,
+    client_order_ids: ClientOrderIds,
+    /// `(expires_at, order id)` of resting good-till-date orders, soonest
+    /// first. Entries for orders that have since filled or been cancelled are
+    /// left behind and skipped when they come due.
+    expiries: BTreeSet<(u64, String)>,
+    /// `(sequence, order id)` of resting peg orders, oldest first. Entries for
+    /// orders that have since left the book are dropped when next repegged.
+    pegs: BTreeSet<(u64, String)>,
+    /// Trade tape, oldest first, capped at `RECENT_TRADES_LIMIT`.
+    recent_trades: VecDeque<Trade>,
+    /// Mid-price and spread each time the book changed them, oldest first,
+    /// capped at `MID_PRICE_HISTORY_LIMIT`.
+    mid_prices: VecDeque<MidPriceSample>,
+    /// One series per entry in `candles::INTERVALS`.
+    candles: Vec<CandleSeries>,
+    /// Trades of the last 24 hours, aged out on the sweep tick.
+    daily: RollingStats,
+    last_trade_price: Option<Decimal>,
+    status: MarketStatus,
+    /// Time of the command being processed, stamped on the trades it produces.
+    /// Set by the engine so that replaying the log reproduces the same trades.
+    now: u64,
+    /// Trade ids are `{symbol}-{n}`; counting them keeps replays identical.
+    next_trade_id: u64,
+    /// Bid and ask levels touched since the last `BookUpdate` was published.
+    changed_bids: BTreeSet<u64>,
+    changed_asks: BTreeSet<u64>,
+    /// Sequence number of the last published `BookUpdate`.
+    sequence: u64,
+    events: EventPublisher,
+    feeds: Feeds,
 }
 
 impl Orderbook {
-    pub fn new() -> Self {
+    pub fn new(market: MarketConfig, accounts: Accounts, feeds: Feeds) -> Self {
         Self {
+            events: EventPublisher {
+                symbol: market.symbol.clone(),
+                sequence: 0,
+                sender: feeds.book_events.clone(),
+            },
+            market,
+            accounts,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             orders: HashMap::new(),
+            stops: Vec::new(),
+            delayed: BTreeSet::new(),
+            brackets: Vec::new(),
+            open_orders: OpenOrderCounts::default(),
+            history: OrderHistory::default(),
+            client_order_ids: ClientOrderIds::default(),
+            expiries: BTreeSet::new(),
+            pegs: BTreeSet::new(),
+            recent_trades: VecDeque::new(),
+            mid_prices: VecDeque::new(),
+            candles: candles::INTERVALS
+                .iter()
+                .map(|(_, secs)| CandleSeries::new(*secs))
+                .collect(),
+            daily: RollingStats::default(),
+            last_trade_price: None,
+            status: MarketStatus::Open,
+            now: 0,
+            next_trade_id: 1,
+            changed_bids: BTreeSet::new(),
+            changed_asks: BTreeSet::new(),
+            sequence: 0,
+            feeds,
         }
     }
 
-    fn price_to_key(price: f64) -> u64 {
-        (price * 100000.0) as u64
+    /// Counts on `counter` every order this book closes as filled, whether on
+    /// arrival or while resting. Set once recovery is done, so replayed fills
+    /// are not counted again.
+    pub fn count_fills(&mut self, counter: IntCounter) {
+        self.history.filled = Some(counter);
     }
 
-    fn key_to_price(cent: u64) -> f64 {
-        cent as f64 / 100000.0
+    /// Only called with prices `validate` has accepted, which are at most `max_price`.
+    fn price_to_key(price: Decimal, tick_size: Decimal) -> u64 {
+        (price / tick_size).trunc().to_u64().unwrap_or(u64::MAX)
     }
 
-    pub fn add_order(&mut self, mut order: Order) -> OrderResponse {
-        let mut trades = Vec::new();
-        let original_quantity = order.quantity;
-
-        match order.order_type {
-            OrderType::MarketOrder => {
-                trades = self.match_market_order(&mut order);
+    /// Highest price a book key can hold at this market's tick size.
+    fn max_price(&self) -> Decimal {
+        Self::key_to_price(u64::MAX, self.market.tick_size)
+    }
 
-                if order.remaining_quantity > 0.0 {
-                    return OrderResponse::Error {
-                        message: "Insufficient liquidity for market order".to_string(),
-                    };
-                }
+    fn key_to_price(key: u64, tick_size: Decimal) -> Decimal {
+        (Decimal::from(key) * tick_size).normalize()
+    }
 
-                if trades.is_empty() {
-                    return OrderResponse::Error {
-                        message: "No matching orders available".to_string(),
-                    };
-                }
+    pub fn add_order(&mut self, order: Order, now: u64) -> OrderResponse {
+        self.now = now;
+        let key = order
+            .client_order_id
+            .clone()
+            .map(|id| (order.user_id.clone(), id));
 
-                OrderResponse::Filled {
-                    order_id: order.id.clone(),
-                    filled_quantity: original_quantity,
-                    trades,
-                }
+        let response = match self.validate(&order) {
+            Err(response) => response,
+            Ok(()) if order.activates_at.is_some_and(|at| at > now) => {
+                Self::count_placed(&mut self.accounts.lock().unwrap(), &order.user_id, 1, now);
+                self.delay(order)
             }
-            OrderType::LimitOrder => {
-                if order.price.is_none() {
-                    return OrderResponse::Error {
-                        message: "limit order must have price".to_string(),
-                    };
+            Ok(()) => {
+                let accounts = Arc::clone(&self.accounts);
+                let mut accounts = accounts.lock().unwrap();
+                let user_id = order.user_id.clone();
+                let response = self.execute(&mut accounts, order);
+                if response.accepted() {
+                    Self::count_placed(&mut accounts, &user_id, 1, now);
                 }
+                self.trigger_stops(&mut accounts);
+                self.repeg(&mut accounts);
+                self.publish_updates();
+                response
+            }
+        };
+        if let Some(key) = key {
+            self.client_order_ids.record(key, response.clone());
+        }
+        response
+    }
 
-                trades = self.match_limit_order(&mut order);
+    /// Holds a good-after-time order back until `activate_orders` reaches its
+    /// activation time. It has only been checked against the market's rules;
+    /// funds are checked when it goes in.
+    fn delay(&mut self, order: Order) -> OrderResponse {
+        let order_id = order.id.clone();
+        let activates_at = order.activates_at.unwrap_or_default();
+        self.delayed
+            .insert((activates_at, order.sequence, order_id.clone()));
+        self.open_orders.opened(&order.user_id);
+        self.orders.insert(order_id.clone(), order);
+        OrderResponse::Placed { order_id }
+    }
 
-                if order.remaining_quantity > 0.0 {
-                    self.add_to_book(order.clone());
+    fn is_delayed(&self, order: &Order) -> bool {
+        order.activates_at.is_some_and(|at| {
+            self.delayed
+                .contains(&(at, order.sequence, order.id.clone()))
+        })
+    }
 
-                    if trades.is_empty() {
-                        OrderResponse::Placed {
-                            order_id: order.id.clone(),
-                        }
-                    } else {
-                        OrderResponse::PartiallyFilled {
-                            order_id: order.id.clone(),
-                            filled_quantity: original_quantity - order.quantity,
-                            remaining_quantity: order.remaining_quantity,
-                            trades,
-                        }
-                    }
-                } else {
-                    OrderResponse::Filled {
-                        order_id: order.id.clone(),
-                        filled_quantity: order.quantity,
-                        trades,
-                    }
-                }
-            }
+    /// Why `order` breaks the market's rules, if it does; the answer is
+    /// remembered for its `client_order_id` as `add_order` would. The engine
+    /// asks before logging an order, so the log only holds orders that are
+    /// matched or placed when replayed.
+    fn rejected(&mut self, order: &Order) -> Option<OrderResponse> {
+        let rejected = self.validate(order).err()?;
+        if let Some(client_order_id) = &order.client_order_id {
+            let key = (order.user_id.clone(), client_order_id.clone());
+            self.client_order_ids.record(key, rejected.clone());
         }
+        Some(rejected)
     }
 
-    pub fn match_market_order(&mut self, order: &mut Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
-        let book = match order.side {
-            OrderSide::Buy => &mut self.asks,
-            OrderSide::Sell => &mut self.bids,
-        };
+    /// The response to an earlier order from the same user with the same
+    /// `client_order_id`, if it is still remembered.
+    pub fn repeated(&self, order: &Order) -> Option<OrderResponse> {
+        let client_order_id = order.client_order_id.as_deref()?;
+        self.client_order_ids
+            .get(&order.user_id, client_order_id)
+            .cloned()
+    }
 
-        let keys: Vec<u64> = match order.side {
-            OrderSide::Buy => book.keys().copied().collect(),
-            OrderSide::Sell => book.keys().copied().rev().collect(),
-        };
+    /// Places `first` and `second` linked to each other. A stop leg goes in
+    /// first, since it holds no funds and the other leg may need all of them.
+    /// The second leg to go in is only placed if the first is still resting
+    /// untouched, and is refused when the first is rejected; if it is rejected
+    /// itself, the first is cancelled again.
+    pub fn add_oco(&mut self, first: Order, second: Order, now: u64) -> OcoResponse {
+        self.now = now;
+        let accounts = Arc::clone(&self.accounts);
+        let mut accounts = accounts.lock().unwrap();
+        let response = self.place_oco(&mut accounts, first, second);
+        self.trigger_stops(&mut accounts);
+        self.repeg(&mut accounts);
+        self.publish_updates();
+        response
+    }
 
-        for price_key in keys {
-            if (order.remaining_quantity <= 0.0) {
-                break;
+    fn place_oco(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        mut first: Order,
+        mut second: Order,
+    ) -> OcoResponse {
+        first.linked_order_id = Some(second.id.clone());
+        second.linked_order_id = Some(first.id.clone());
+
+        let mut checked = [&first, &second].map(|leg| self.validate_oco_leg(leg));
+        // Each leg fits on its own; check there is room for both.
+        if let Some(max_open_orders) = self.market.max_open_orders {
+            if checked.iter().all(Result::is_ok)
+                && self.open_orders.get(&first.user_id) + 2 > max_open_orders
+            {
+                checked[1] = Err(self.too_many_open_orders(max_open_orders));
             }
+        }
+        if checked.iter().any(Result::is_err) {
+            let [first, second] = checked.map(|result| match result {
+                Ok(()) => OrderResponse::Error {
+                    code: OrderError::LinkedOrderRejected,
+                    message: "the other leg of the OCO was rejected".to_string(),
+                },
+                Err(response) => response,
+            });
+            return OcoResponse { first, second };
+        }
+        let user_id = first.user_id.clone();
 
-            if let Some(order_at_price) = book.get_mut(&price_key) {
-                while let Some(mut matching_order) = order_at_price.pop_front() {
-                    let trade_quantity = order
-                        .remaining_quantity
-                        .min(matching_order.remaining_quantity);
-                    let trade_price = matching_order.price.unwrap();
+        let stop_first = matches!(second.order_type, OrderType::StopMarket { .. });
+        let (leading, trailing) = match stop_first {
+            true => (second, first),
+            false => (first, second),
+        };
+        let leading_id = leading.id.clone();
+        let trailing_id = trailing.id.clone();
 
-                    let trade = Trade {
-                        id: Uuid::new_v4().to_string(),
-                        buy_order_id: match order.side {
-                            OrderSide::Buy => order.id.clone(),
-                            OrderSide::Sell => matching_order.id.clone(),
-                        },
-                        sell_order_id: match order.side {
-                            OrderSide::Buy => matching_order.id.clone(),
-                            OrderSide::Sell => order.id.clone(),
-                        },
-                        price: trade_price,
-                        quantity: trade_quantity,
-                        timestamp: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                    };
+        let mut leading_response = self.execute(accounts, leading);
+        let untouched = self
+            .orders
+            .get(&leading_id)
+            .is_some_and(|o| o.remaining_quantity == o.quantity);
 
-                    trades.push(trade);
+        let trailing_response = if untouched {
+            let response = self.execute(accounts, trailing);
+            if matches!(response, OrderResponse::Error { .. }) {
+                self.remove_order(accounts, &leading_id);
+                leading_response = OrderResponse::Cancelled {
+                    order_id: leading_id,
+                };
+            }
+            response
+        } else if matches!(leading_response, OrderResponse::Error { .. }) {
+            OrderResponse::Error {
+                code: OrderError::LinkedOrderRejected,
+                message: "the other leg of the OCO was rejected".to_string(),
+            }
+        } else {
+            OrderResponse::Cancelled {
+                order_id: trailing_id,
+            }
+        };
 
-                    order.remaining_quantity -= trade_quantity;
-                    matching_order.remaining_quantity -= trade_quantity;
+        let (first, second) = match stop_first {
+            true => (trailing_response, leading_response),
+            false => (leading_response, trailing_response),
+        };
+        let accepted = [&first, &second].iter().filter(|r| r.accepted()).count();
+        Self::count_placed(accounts, &user_id, accepted as u64, self.now);
+        OcoResponse { first, second }
+    }
 
-                    if matching_order.remaining_quantity > 0.0 {
-                        order_at_price.push_front(matching_order);
-                    } else {
-                        self.orders.remove(&matching_order.id);
-                    }
+    /// OCO legs must be able to wait on the book, each on its own trigger.
+    fn validate_oco_leg(&self, leg: &Order) -> Result<(), OrderResponse> {
+        let waits = matches!(
+            leg.order_type,
+            OrderType::LimitOrder
+                | OrderType::PostOnly
+                | OrderType::Iceberg { .. }
+                | OrderType::StopMarket { .. }
+        );
+        if !waits {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidOco,
+                message: "OCO legs must be limit, post-only, iceberg or stop orders".to_string(),
+            });
+        }
+        if leg.activates_at.is_some() {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidActivation,
+                message: "OCO legs cannot be delayed".to_string(),
+            });
+        }
+        self.validate(leg)
+    }
 
-                    if order.remaining_quantity <= 0.0 {
-                        break;
-                    }
+    /// Places `entry` and keeps `exits` until it is done trading. The exits
+    /// are checked now as if placed at the entry's full quantity, and again
+    /// when they go in.
+    pub fn add_bracket(&mut self, entry: Order, exits: Bracket, now: u64) -> OrderResponse {
+        self.now = now;
+        if let Err(response) = self.validate_bracket(&entry, &exits) {
+            return response;
+        }
+        let entry_id = entry.id.clone();
+        self.brackets.push(exits);
+        let response = self.add_order(entry, now);
+        // An entry rejected outright leaves nothing to exit.
+        if matches!(response, OrderResponse::Error { .. }) {
+            self.brackets.retain(|b| b.entry_id != entry_id);
+        }
+        response
+    }
+
+    fn validate_bracket(&self, entry: &Order, exits: &Bracket) -> Result<(), OrderResponse> {
+        let invalid = |message: &str| {
+            Err(OrderResponse::Error {
+                code: OrderError::InvalidBracket,
+                message: message.to_string(),
+            })
+        };
+        if entry.quote_quantity.is_some() {
+            return invalid("a bracket entry must give its quantity");
+        }
+        let (Some(take_profit), Some(stop)) =
+            (exits.take_profit.price, exits.stop.order_type.stop_price())
+        else {
+            return invalid("a bracket needs a take-profit price and a stop price");
+        };
+        // Read as the range the price must stay in: (low, high).
+        let (low, high) = match entry.side {
+            OrderSide::Buy => (stop, take_profit),
+            OrderSide::Sell => (take_profit, stop),
+        };
+        if low >= high
+            || entry
+                .price
+                .is_some_and(|price| price <= low || price >= high)
+        {
+            return invalid(match entry.side {
+                OrderSide::Buy => {
+                    "a buy's take-profit must be above its stop, and its price between them"
                 }
+                OrderSide::Sell => {
+                    "a sell's take-profit must be below its stop, and its price between them"
+                }
+            });
+        }
+        self.validate_oco_leg(&exits.take_profit)?;
+        self.validate_oco_leg(&exits.stop)
+    }
 
-                if order_at_price.is_empty() {
-                    book.remove(&price_key);
+    /// Places the exits of each bracket whose entry is done, sized to what it
+    /// filled; an entry that never traded takes its exits with it. Returns
+    /// whether any were placed.
+    fn release_brackets(&mut self, accounts: &mut HashMap<String, Account>) -> bool {
+        let (done, open): (Vec<Bracket>, Vec<Bracket>) = std::mem::take(&mut self.brackets)
+            .into_iter()
+            .partition(|b| !self.orders.contains_key(&b.entry_id));
+        self.brackets = open;
+
+        let mut placed = false;
+        for Bracket {
+            entry_id,
+            mut take_profit,
+            mut stop,
+        } in done
+        {
+            let filled = self
+                .history
+                .get(&entry_id)
+                .map_or(Decimal::ZERO, |o| o.quantity - o.remaining_quantity);
+            if filled.is_zero() {
+                continue;
+            }
+            for exit in [&mut take_profit, &mut stop] {
+                exit.quantity = filled;
+                exit.remaining_quantity = filled;
+            }
+            self.join_back_of_level(&mut take_profit);
+            let response = self.place_oco(accounts, take_profit, stop);
+            for leg in [&response.first, &response.second] {
+                if let OrderResponse::Error { code, .. } = leg {
+                    tracing::info!(entry_id, code = ?code, "bracket exit rejected");
                 }
             }
+            placed = true;
         }
-        trades
+        placed
     }
 
-    pub fn match_limit_order(&mut self, order: &mut Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
-        let order_price = order.price.unwrap();
-
-        let book = match order.side {
-            OrderSide::Buy => &mut self.asks,
-            OrderSide::Sell => &mut self.bids,
-        };
+    fn too_many_open_orders(&self, max_open_orders: usize) -> OrderResponse {
+        OrderResponse::Error {
+            code: OrderError::TooManyOpenOrders,
+            message: format!(
+                "already at the maximum of {max_open_orders} open orders on {}",
+                self.market.symbol
+            ),
+        }
+    }
 
-        let keys: Vec<u64> = match order.side {
-            OrderSide::Buy => book.keys().copied().collect(),
-            OrderSide::Sell => book.keys().copied().rev().collect(),
-        };
+    /// Checks an incoming or amended order against the market's rules before
+    /// anything is matched.
+    fn validate(&self, order: &Order) -> Result<(), OrderResponse> {
+        if !matches!(self.status, MarketStatus::Open | MarketStatus::Auction) {
+            return Err(OrderResponse::Error {
+                code: OrderError::MarketClosed,
+                message: format!("market {} is closed to new orders", self.market.symbol),
+            });
+        }
 
-        for price_key in keys {
-            let matching_price = Self::key_to_price(price_key);
+        if self.status == MarketStatus::Auction {
+            if !matches!(order.order_type, OrderType::LimitOrder) {
+                return Err(OrderResponse::Error {
+                    code: OrderError::UnsupportedOrderType,
+                    message: "only limit orders are accepted during the auction".to_string(),
+                });
+            }
+            // The uncross price is only known once the auction ends, so no
+            // average can be held to a collar while it runs.
+            if order.worst_average_price.is_some() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: "worst average price is not accepted during the auction".to_string(),
+                });
+            }
+            // Both sides of a self-trade would have to cross the uncross price.
+            let crosses_own = order.price.is_some_and(|price| {
+                self.orders.values().any(|o| {
+                    o.user_id == order.user_id
+                        && o.id != order.id
+                        && !self.is_delayed(o)
+                        && match (&order.side, &o.side, o.price) {
+                            (OrderSide::Buy, OrderSide::Sell, Some(ask)) => price >= ask,
+                            (OrderSide::Sell, OrderSide::Buy, Some(bid)) => price <= bid,
+                            _ => false,
+                        }
+                })
+            });
+            if crosses_own {
+                return Err(OrderResponse::Error {
+                    code: OrderError::WouldSelfTrade,
+                    message: "order crosses your own order in the auction".to_string(),
+                });
+            }
+        }
 
-            let should_match = match order.side {
-                OrderSide::Buy => order_price >= matching_price,
-                OrderSide::Sell => order_price <= matching_price,
-            };
+        if order
+            .client_order_id
+            .as_ref()
+            .is_some_and(|id| id.is_empty() || id.len() > MAX_CLIENT_ORDER_ID_LEN)
+        {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidClientOrderId,
+                message: format!(
+                    "client_order_id must be 1 to {MAX_CLIENT_ORDER_ID_LEN} bytes long"
+                ),
+            });
+        }
 
-            if !should_match {
-                break;
+        if let Some(quote_quantity) = order.quote_quantity {
+            let market_buy = matches!(order.order_type, OrderType::MarketOrder { .. })
+                && matches!(order.side, OrderSide::Buy);
+            if !market_buy || !order.quantity.is_zero() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidQuantity,
+                    message: "quote_quantity is only for market buys, in place of quantity"
+                        .to_string(),
+                });
             }
-
-            if order.remaining_quantity <= 0.0 {
-                break;
+            if quote_quantity <= Decimal::ZERO {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidQuantity,
+                    message: "quote quantity must be positive".to_string(),
+                });
             }
+        }
 
-            if let Some(order_at_price) = book.get_mut(&price_key) {
-                while let Some(mut matching_order) = order_at_price.pop_front() {
-                    let trading_quantity = order
-                        .remaining_quantity
-                        .min(matching_order.remaining_quantity);
-                    let trade_price = matching_order.price.unwrap();
+        // Decimal has no NaN or infinity, so non-finite input is already rejected
+        // when the request is parsed; only the sign needs checking here.
+        if order.quantity <= Decimal::ZERO && order.quote_quantity.is_none() {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidQuantity,
+                message: "quantity must be positive".to_string(),
+            });
+        }
 
-                    let trade = Trade {
-                        id: Uuid::new_v4().to_string(),
-                        buy_order_id: match order.side {
-                            OrderSide::Buy => order.id.clone(),
-                            OrderSide::Sell => matching_order.id.clone(),
-                        },
-                        sell_order_id: match order.side {
-                            OrderSide::Buy => matching_order.id.clone(),
-                            OrderSide::Sell => order.id.clone(),
-                        },
-                        price: trade_price,
-                        quantity: trading_quantity,
-                        timestamp: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                    };
+        let display_quantity = match order.order_type {
+            OrderType::Iceberg { display_quantity } => Some(display_quantity),
+            _ => None,
+        };
+        let base_decimals = self.market.base_decimals;
+        if std::iter::once(order.quantity)
+            .chain(display_quantity)
+            .any(|quantity| quantity.normalize().scale() > base_decimals)
+        {
+            return Err(OrderResponse::Error {
+                code: OrderError::QuantityPrecision,
+                message: format!("quantity has more than {base_decimals} decimal places"),
+            });
+        }
+        let quote_decimals = self.market.quote_decimals;
+        if order
+            .quote_quantity
+            .is_some_and(|quote| quote.normalize().scale() > quote_decimals)
+        {
+            return Err(OrderResponse::Error {
+                code: OrderError::QuantityPrecision,
+                message: format!("quote quantity has more than {quote_decimals} decimal places"),
+            });
+        }
 
-                    trades.push(trade);
+        if order.price.is_some_and(|p| p <= Decimal::ZERO) {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidPrice,
+                message: "price must be positive".to_string(),
+            });
+        }
 
-                    order.remaining_quantity -= trading_quantity;
-                    matching_order.remaining_quantity -= trading_quantity;
+        if order
+            .worst_average_price
+            .is_some_and(|p| p <= Decimal::ZERO)
+        {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidPrice,
+                message: "worst average price must be positive".to_string(),
+            });
+        }
 
-                    if (matching_order.remaining_quantity > 0.0) {
-                        order_at_price.push_front(matching_order);
-                    }
+        if order.worst_average_price.is_some() && !order.order_type.takes_on_arrival() {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidPrice,
+                message:
+                    "worst average price is only for orders that take from the book as they arrive"
+                        .to_string(),
+            });
+        }
 
-                    if order.remaining_quantity <= 0.0 {
-                        break;
-                    }
-                }
-                if order_at_price.is_empty() {
-                    book.remove(&price_key);
-                }
+        if let OrderType::MarketOrder {
+            protection_price: Some(protection_price),
+        } = order.order_type
+        {
+            if protection_price <= Decimal::ZERO {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: "protection price must be positive".to_string(),
+                });
             }
         }
-        trades
-    }
 
-    fn add_to_book(&mut self, order: Order) {
-        let price = order.price.unwrap();
-        let price_key = Self::price_to_key(price);
+        if let OrderType::StopMarket { stop_price } = order.order_type {
+            if stop_price <= Decimal::ZERO || stop_price > self.max_price() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: format!(
+                        "stop price must be positive and at most {}",
+                        self.max_price()
+                    ),
+                });
+            }
+        }
 
-        let book = match order.side {
-            OrderSide::Buy => &mut self.bids,
-            OrderSide::Sell => &mut self.asks,
-        };
+        if let OrderType::MarketIfTouched { touch_price } = order.order_type {
+            if touch_price <= Decimal::ZERO || touch_price > self.max_price() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: format!(
+                        "touch price must be positive and at most {}",
+                        self.max_price()
+                    ),
+                });
+            }
+        }
 
-        book.entry(price_key)
-            .or_insert_with(VecDeque::new)
-            .push_back(order);
-    }
+        if let OrderType::TrailingStop {
+            trail_amount,
+            stop_price,
+        } = order.order_type
+        {
+            if trail_amount <= Decimal::ZERO || trail_amount > self.max_price() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: format!(
+                        "trail amount must be positive and at most {}",
+                        self.max_price()
+                    ),
+                });
+            }
+            if stop_price.is_some() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: "a trailing stop's stop price is set by the engine".to_string(),
+                });
+            }
+        }
 
-    pub fn get_snapshot(&mut self) -> OrderbookSnapshot {
-        let mut bids = Vec::new();
-        for (price_key, orders) in self.bids.iter().rev() {
-            let total_quantity = orders.iter().map(|o| o.remaining_quantity).sum();
-            bids.push((Self::key_to_price(*price_key), total_quantity));
+        if let OrderType::Peg { offset, .. } = order.order_type {
+            // Amendments carry the price the engine gave the order.
+            if order.price.is_some() && !self.orders.contains_key(&order.id) {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: "a peg's price is set by the engine".to_string(),
+                });
+            }
+            if !(offset % self.market.tick_size).is_zero() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::TickSizeViolation,
+                    message: "peg offset not aligned to tick size".to_string(),
+                });
+            }
         }
 
-        let mut asks = Vec::new();
-        for (price_key, orders) in self.asks.iter() {
-            let total_quantity = orders.iter().map(|o| o.remaining_quantity).sum();
-            asks.push((Self::key_to_price(*price_key), total_quantity));
+        if order.order_type.requires_price() && order.price.is_none() {
+            return Err(OrderResponse::Error {
+                code: OrderError::MissingPrice,
+                message: "limit order must have price".to_string(),
+            });
         }
 
-        OrderbookSnapshot { bids, asks }
-    }
+        if let Some(price) = order.price.filter(|_| order.order_type.requires_price()) {
+            if price > self.max_price() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidPrice,
+                    message: format!("price must be at most {}", self.max_price()),
+                });
+            }
+            if !(price % self.market.tick_size).is_zero() {
+                return Err(OrderResponse::Error {
+                    code: OrderError::TickSizeViolation,
+                    message: "price not aligned to tick size".to_string(),
+                });
+            }
+        }
 
-    pub async fn run_orderbook_engine(mut rx: tokio::sync::mpsc::Receiver<OrderbookCommand>) {
-        let mut orderbook = Orderbook::new();
+        // `Decimal` arithmetic panics on overflow, so whatever the order could
+        // trade, at its own price or else at the highest one the book holds,
+        // must leave room to work out fees in basis points.
+        let reach = order
+            .price
+            .filter(|_| order.order_type.requires_price())
+            .unwrap_or_else(|| self.max_price());
+        let headroom = Decimal::from(10_000);
+        let too_large = reach
+            .checked_mul(order.quantity)
+            .and_then(|notional| notional.checked_mul(headroom))
+            .is_none()
+            || order
+                .quote_quantity
+                .is_some_and(|quote| quote.checked_mul(headroom).is_none());
+        if too_large {
+            return Err(OrderResponse::Error {
+                code: OrderError::InvalidQuantity,
+                message: "order is too large".to_string(),
+            });
+        }
 
-        while let Some(command) = rx.recv().await {
-            match command {
-                OrderbookCommand::AddOrder { order, response } => {
-                    let result = orderbook.add_order(order);
-                    let _ = response.send(result);
-                }
-                OrderbookCommand::GetSnapshot { response } => {
-                    let snapshot = orderbook.get_snapshot();
-                    let _ = response.send(snapshot);
+        if let Some(price) = order.price.filter(|_| order.order_type.requires_price()) {
+            if let Some((low, high)) = self.price_band() {
+                if price < low || price > high {
+                    return Err(OrderResponse::Error {
+                        code: OrderError::PriceOutsideBand,
+                        message: format!("price {price} is outside the price band {low} to {high}"),
+                    });
                 }
             }
         }
+
+        if order.quantity < self.market.min_quantity && order.quote_quantity.is_none() {
+            return Err(OrderResponse::Error {
+                code: OrderError::BelowMinimumQuantity,
+                message: format!(
+                    "quantity {} is below the minimum of {}",
+                    order.quantity, self.market.min_quantity
+                ),
+            });
+        }
+
+        if let Some(max_quantity) = self.market.max_quantity {
+            if order.quantity > max_quantity {
+                return Err(OrderResponse::Error {
+                    code: OrderError::AboveMaximumQuantity,
+                    message: format!(
+                        "quantity {} is above the maximum of {max_quantity}",
+                        order.quantity
+                    ),
+                });
+            }
+        }
+
+        if let Some(price) = order.price.filter(|_| order.order_type.requires_price()) {
+            let notional = price * order.quantity;
+            if notional < self.market.min_notional {
+                return Err(OrderResponse::Error {
+                    code: OrderError::BelowMinimumNotional,
+                    message: format!(
+                        "notional {notional} is below the minimum of {}",
+                        self.market.min_notional
+                    ),
+                });
+            }
+        }
+
+        if let OrderType::Iceberg { display_quantity } = order.order_type {
+            if self.market.matching_mode == MatchingMode::ProRata {
+                return Err(OrderResponse::Error {
+                    code: OrderError::UnsupportedOrderType,
+                    message: "iceberg orders need price-time matching".to_string(),
+                });
+            }
+            if display_quantity <= Decimal::ZERO || display_quantity > order.quantity {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidQuantity,
+                    message: "display quantity must be positive and at most the order quantity"
+                        .to_string(),
+                });
+            }
+        }
+
+        // An amendment replaces an order that is already counted.
+        let opens = (order.order_type.stays_open()
+            || order.activates_at.is_some_and(|at| at > self.now))
+            && !self.orders.contains_key(&order.id);
+        if let Some(max_open_orders) = self.market.max_open_orders.filter(|_| opens) {
+            if self.open_orders.get(&order.user_id) >= max_open_orders {
+                return Err(self.too_many_open_orders(max_open_orders));
+            }
+        }
+
+        if let Some(expires_at) = order.expires_at {
+            if !matches!(
+                order.order_type,
+                OrderType::LimitOrder
+                    | OrderType::PostOnly
+                    | OrderType::Iceberg { .. }
+                    | OrderType::Peg { .. }
+            ) {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidExpiry,
+                    message: "expiry only applies to orders that rest on the book".to_string(),
+                });
+            }
+            if expires_at <= self.now {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidExpiry,
+                    message: "expiry must be in the future".to_string(),
+                });
+            }
+            if order.activates_at.is_some_and(|at| at >= expires_at) {
+                return Err(OrderResponse::Error {
+                    code: OrderError::InvalidActivation,
+                    message: "activation must come before the expiry".to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Broadcasts the current quantity of every level changed since the last
+    /// update, and samples the mid-price. Nothing is sent if the operation
+    /// left the book untouched.
+    fn publish_updates(&mut self) {
+        if self.changed_bids.is_empty() && self.changed_asks.is_empty() {
+            return;
+        }
+        self.sample_mid_price();
+
+        let tick_size = self.market.tick_size;
+        let level = |book: &BTreeMap<u64, VecDeque<Order>>, key: u64| {
+            let quantity = book.get(&key).map_or(Decimal::ZERO, |orders| {
+                orders.iter().map(Order::visible).sum()
+            });
+            (Self::key_to_price(key, tick_size), quantity)
+        };
+
+        let bids = std::mem::take(&mut self.changed_bids)
+            .into_iter()
+            .rev()
+            .map(|key| level(&self.bids, key))
+            .collect();
+        let asks = std::mem::take(&mut self.changed_asks)
+            .into_iter()
+            .map(|key| level(&self.asks, key))
+            .collect();
+
+        self.sequence += 1;
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.feeds.book_updates.send(BookUpdate {
+            symbol: self.market.symbol.clone(),
+            sequence: self.sequence,
+            bids,
+            asks,
+            checksum: self.checksum(),
+        });
+    }
+
+    /// `book_checksum` of the book as it stands.
+    fn checksum(&self) -> u32 {
+        let tick_size = self.market.tick_size;
+        let top = |levels: &mut dyn Iterator<Item = (&u64, &VecDeque<Order>)>| {
+            levels
+                .take(CHECKSUM_DEPTH)
+                .map(|(key, orders)| {
+                    let quantity = orders.iter().map(Order::visible).sum();
+                    (Self::key_to_price(*key, tick_size), quantity)
+                })
+                .collect::<Vec<_>>()
+        };
+        book_checksum(
+            &top(&mut self.bids.iter().rev()),
+            &top(&mut self.asks.iter()),
+        )
+    }
+
+    /// Records the mid-price and spread unless they are what was last
+    /// recorded, so changes deeper in the book add nothing to the series.
+    fn sample_mid_price(&mut self) {
+        let top = self.best_bid().zip(self.best_ask());
+        let sample = MidPriceSample {
+            mid_price: top.map(|(bid, ask)| (bid + ask) / Decimal::TWO),
+            spread: top.map(|(bid, ask)| ask - bid),
+            timestamp: self.now,
+        };
+        let unchanged = self
+            .mid_prices
+            .back()
+            .is_some_and(|last| last.mid_price == sample.mid_price && last.spread == sample.spread);
+        if unchanged {
+            return;
+        }
+        if self.mid_prices.len() >= MID_PRICE_HISTORY_LIMIT {
+            self.mid_prices.pop_front();
+        }
+        self.mid_prices.push_back(sample);
+    }
+
+    /// Fires every pending stop or market-if-touched order whose trigger has
+    /// been reached, executing it as a market order. Fills from one can move
+    /// the price far enough to trigger the next, so this repeats until none
+    /// is eligible. Brackets whose entry is done have their exits placed in
+    /// between, as those can trade and trigger stops too.
+    fn trigger_stops(&mut self, accounts: &mut HashMap<String, Account>) {
+        loop {
+            while let Some(last_price) = self.last_trade_price {
+                let position = self
+                    .stops
+                    .iter()
+                    .position(|stop| stop.order_type.triggers_at(&stop.side, last_price));
+                let Some(position) = position else {
+                    break;
+                };
+
+                let mut order = self.stops.remove(position);
+                self.orders.remove(&order.id);
+                self.open_orders.closed(&order.user_id);
+                // Cancelled before the stop runs so it can use what the other leg held.
+                if let Some(linked) = &order.linked_order_id {
+                    self.remove_order(accounts, linked);
+                }
+                order.order_type = OrderType::MarketOrder {
+                    protection_price: None,
+                };
+                self.execute(accounts, order);
+            }
+            if !self.release_brackets(accounts) {
+                break;
+            }
+        }
+    }
+
+    /// Runs `order` and, if it did not end up resting, moves it to the history
+    /// with its final status. Rejected orders that never traded are not kept.
+    fn execute(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        mut order: Order,
+    ) -> OrderResponse {
+        if order.reduce_only {
+            if let Err(response) = self.reduce_to_holding(accounts, &mut order) {
+                return response;
+            }
+        }
+        if let Some(budget) = order.quote_quantity {
+            order.quantity = self.quote_order_quantity(&order, budget);
+            order.remaining_quantity = order.quantity;
+        }
+        let response = self.execute_order(accounts, &mut order);
+
+        let status = match &response {
+            OrderResponse::Filled { .. } => Some(OrderStatus::Filled),
+            OrderResponse::Cancelled { .. }
+            | OrderResponse::PartiallyFilled {
+                cancelled_quantity: Some(_),
+                ..
+            } => Some(OrderStatus::Cancelled),
+            OrderResponse::Error { .. } if order.remaining_quantity < order.quantity => {
+                Some(OrderStatus::Cancelled)
+            }
+            _ => None,
+        };
+        if let Some(status) = status {
+            self.history.record(order, status);
+        }
+
+        response
+    }
+
+    /// Cuts a reduce-only sell down to what its owner holds and has not
+    /// already set aside for other orders.
+    fn reduce_to_holding(
+        &self,
+        accounts: &HashMap<String, Account>,
+        order: &mut Order,
+    ) -> Result<(), OrderResponse> {
+        let held = accounts
+            .get(&order.user_id)
+            .and_then(|a| a.assets.get(&self.market.base_asset))
+            .copied()
+            .unwrap_or_default();
+        if matches!(order.side, OrderSide::Buy) || held <= Decimal::ZERO {
+            return Err(OrderResponse::Error {
+                code: OrderError::NothingToReduce,
+                message: format!(
+                    "reduce-only order has no {} holding to reduce",
+                    self.market.base_asset
+                ),
+            });
+        }
+
+        let excess = (order.remaining_quantity - held).max(Decimal::ZERO);
+        order.quantity -= excess;
+        order.remaining_quantity -= excess;
+        Ok(())
+    }
+
+    fn execute_order(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        order: &mut Order,
+    ) -> OrderResponse {
+        let original_quantity = order.quantity;
+
+        if let OrderType::TrailingStop {
+            trail_amount,
+            stop_price,
+        } = &mut order.order_type
+        {
+            *stop_price = self
+                .last_trade_price
+                .map(|price| Self::trail(&order.side, price, *trail_amount));
+        }
+
+        if let OrderType::Peg { .. } = order.order_type {
+            order.price = self.peg_price(order);
+            if order.price.is_none() {
+                return OrderResponse::Error {
+                    code: OrderError::NothingToPeg,
+                    message: "no price for the peg to follow".to_string(),
+                };
+            }
+        }
+
+        if let Err(message) = self.check_funds(accounts, order) {
+            return OrderResponse::Error {
+                code: OrderError::InsufficientFunds,
+                message,
+            };
+        }
+
+        if self.status == MarketStatus::Auction {
+            self.add_to_book(order.clone());
+            self.reserve(accounts, order, order.remaining_quantity);
+            return OrderResponse::Placed {
+                order_id: order.id.clone(),
+            };
+        }
+
+        if let Some(collar) = order.worst_average_price {
+            let breached = self
+                .average_fill_price(order)
+                .is_some_and(|average| match order.side {
+                    OrderSide::Buy => average > collar,
+                    OrderSide::Sell => average < collar,
+                });
+            if breached {
+                return OrderResponse::Error {
+                    code: OrderError::PriceCollarBreached,
+                    message: format!("order would fill at an average price beyond {collar}"),
+                };
+            }
+        }
+
+        match order.order_type {
+            OrderType::MarketOrder { protection_price } => {
+                let MatchOutcome {
+                    trades,
+                    taker_cancelled,
+                } = self.match_market_order(accounts, order);
+                self.settle(accounts, &trades, &order.side);
+
+                if taker_cancelled {
+                    return Self::self_trade_cancelled(order, trades);
+                }
+
+                // Anything left while the book still has liquidity was stopped
+                // by the protection price or the price band, and is cancelled.
+                let opposite_best = match order.side {
+                    OrderSide::Buy => self.best_ask(),
+                    OrderSide::Sell => self.best_bid(),
+                };
+                let limited = protection_price.is_some() || opposite_best.is_some();
+                if order.remaining_quantity > Decimal::ZERO && limited {
+                    if trades.is_empty() {
+                        let band_edge = self.band_edge(&order.side);
+                        let banded = Self::tighter(&order.side, protection_price, band_edge)
+                            != protection_price;
+                        return if banded {
+                            OrderResponse::Error {
+                                code: OrderError::PriceOutsideBand,
+                                message: "no liquidity within the price band".to_string(),
+                            }
+                        } else {
+                            OrderResponse::Error {
+                                code: OrderError::PriceProtection,
+                                message: "no liquidity within the protection price".to_string(),
+                            }
+                        };
+                    }
+                    return OrderResponse::PartiallyFilled {
+                        order_id: order.id.clone(),
+                        filled_quantity: original_quantity - order.remaining_quantity,
+                        remaining_quantity: order.remaining_quantity,
+                        cancelled_quantity: Some(order.remaining_quantity),
+                        trades,
+                    };
+                }
+
+                if order.remaining_quantity > Decimal::ZERO {
+                    return OrderResponse::Error {
+                        code: OrderError::InsufficientLiquidity,
+                        message: "Insufficient liquidity for market order".to_string(),
+                    };
+                }
+
+                if trades.is_empty() {
+                    return OrderResponse::Error {
+                        code: OrderError::NoMatchingOrders,
+                        message: "No matching orders available".to_string(),
+                    };
+                }
+
+                OrderResponse::Filled {
+                    order_id: order.id.clone(),
+                    filled_quantity: original_quantity,
+                    trades,
+                }
+            }
+            OrderType::LimitOrder | OrderType::Iceberg { .. } => {
+                let MatchOutcome {
+                    trades,
+                    taker_cancelled,
+                } = self.match_limit_order(accounts, order);
+                self.settle(accounts, &trades, &order.side);
+
+                if taker_cancelled {
+                    return Self::self_trade_cancelled(order, trades);
+                }
+
+                if order.remaining_quantity > Decimal::ZERO {
+                    if let OrderType::Iceberg { display_quantity } = order.order_type {
+                        order.visible_quantity =
+                            Some(display_quantity.min(order.remaining_quantity));
+                    }
+                    self.add_to_book(order.clone());
+                    self.reserve(accounts, order, order.remaining_quantity);
+
+                    if trades.is_empty() {
+                        OrderResponse::Placed {
+                            order_id: order.id.clone(),
+                        }
+                    } else {
+                        OrderResponse::PartiallyFilled {
+                            order_id: order.id.clone(),
+                            filled_quantity: original_quantity - order.remaining_quantity,
+                            remaining_quantity: order.remaining_quantity,
+                            cancelled_quantity: None,
+                            trades,
+                        }
+                    }
+                } else {
+                    OrderResponse::Filled {
+                        order_id: order.id.clone(),
+                        filled_quantity: order.quantity,
+                        trades,
+                    }
+                }
+            }
+            OrderType::StopMarket { .. }
+            | OrderType::TrailingStop { .. }
+            | OrderType::MarketIfTouched { .. } => {
+                self.orders.insert(order.id.clone(), order.clone());
+                self.open_orders.opened(&order.user_id);
+                self.stops.push(order.clone());
+                OrderResponse::Placed {
+                    order_id: order.id.clone(),
+                }
+            }
+            OrderType::Peg { .. } => {
+                self.add_to_book(order.clone());
+                self.reserve(accounts, order, order.remaining_quantity);
+                OrderResponse::Placed {
+                    order_id: order.id.clone(),
+                }
+            }
+            OrderType::PostOnly => {
+                let order_price = order.price.unwrap();
+                let crosses = match order.side {
+                    OrderSide::Buy => self.best_ask().is_some_and(|ask| order_price >= ask),
+                    OrderSide::Sell => self.best_bid().is_some_and(|bid| order_price <= bid),
+                };
+
+                if crosses {
+                    return OrderResponse::Error {
+                        code: OrderError::WouldCross,
+                        message: "post-only order would cross the book".to_string(),
+                    };
+                }
+
+                self.add_to_book(order.clone());
+                self.reserve(accounts, order, order.remaining_quantity);
+                OrderResponse::Placed {
+                    order_id: order.id.clone(),
+                }
+            }
+            OrderType::FillOrKill => {
+                if self.crossable_quantity(order) < order.quantity {
+                    return OrderResponse::Error {
+                        code: OrderError::FillOrKillUnfilled,
+                        message: "FOK could not be fully filled".to_string(),
+                    };
+                }
+
+                let trades = self.match_limit_order(accounts, order).trades;
+                self.settle(accounts, &trades, &order.side);
+
+                OrderResponse::Filled {
+                    order_id: order.id.clone(),
+                    filled_quantity: order.quantity,
+                    trades,
+                }
+            }
+            OrderType::ImmediateOrCancel => {
+                let trades = self.match_limit_order(accounts, order).trades;
+                self.settle(accounts, &trades, &order.side);
+
+                if trades.is_empty() {
+                    return OrderResponse::Error {
+                        code: OrderError::NoMatchingOrders,
+                        message: "No matching orders available".to_string(),
+                    };
+                }
+
+                if order.remaining_quantity > Decimal::ZERO {
+                    OrderResponse::PartiallyFilled {
+                        order_id: order.id.clone(),
+                        filled_quantity: original_quantity - order.remaining_quantity,
+                        remaining_quantity: order.remaining_quantity,
+                        cancelled_quantity: Some(order.remaining_quantity),
+                        trades,
+                    }
+                } else {
+                    OrderResponse::Filled {
+                        order_id: order.id.clone(),
+                        filled_quantity: order.quantity,
+                        trades,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Response for an incoming order whose remainder was cancelled because it
+    /// would have traded against the same user's resting order.
+    fn self_trade_cancelled(order: &Order, trades: Vec<Trade>) -> OrderResponse {
+        if trades.is_empty() {
+            return OrderResponse::Cancelled {
+                order_id: order.id.clone(),
+            };
+        }
+
+        OrderResponse::PartiallyFilled {
+            order_id: order.id.clone(),
+            filled_quantity: order.quantity - order.remaining_quantity,
+            remaining_quantity: order.remaining_quantity,
+            cancelled_quantity: Some(order.remaining_quantity),
+            trades,
+        }
+    }
+
+    /// Rejects a buy whose notional plus fees exceeds the user's balance, or a sell larger
+    /// than the user's holding of the base asset. Market buys are costed by
+    /// walking the asks they would consume.
+    fn check_funds(
+        &self,
+        accounts: &HashMap<String, Account>,
+        order: &Order,
+    ) -> Result<(), String> {
+        let account = accounts.get(&order.user_id);
+
+        match order.side {
+            OrderSide::Buy => {
+                let cost = match order.order_type {
+                    OrderType::MarketOrder { protection_price } => {
+                        let limit_price = Self::tighter(
+                            &order.side,
+                            protection_price,
+                            self.band_edge(&order.side),
+                        );
+                        self.market_buy_cost(order, limit_price)
+                    }
+                    OrderType::LimitOrder
+                    | OrderType::Iceberg { .. }
+                    | OrderType::ImmediateOrCancel
+                    | OrderType::FillOrKill
+                    | OrderType::PostOnly
+                    | OrderType::Peg { .. } => order.price.unwrap() * order.remaining_quantity,
+                    OrderType::StopMarket { .. } | OrderType::TrailingStop { .. } => {
+                        let stop_price = order.order_type.stop_price().unwrap_or_default();
+                        stop_price * order.remaining_quantity
+                    }
+                    OrderType::MarketIfTouched { touch_price } => {
+                        touch_price * order.remaining_quantity
+                    }
+                };
+                let fee_bps = self.market.maker_fee_bps.max(self.market.taker_fee_bps);
+                let cost = cost + MarketConfig::fee(cost, fee_bps);
+                let balance = account.map_or(Decimal::ZERO, |a| a.balance);
+                if cost > balance {
+                    return Err(format!(
+                        "insufficient balance: order requires {cost}, available {balance}"
+                    ));
+                }
+            }
+            OrderSide::Sell => {
+                let held = account
+                    .and_then(|a| a.assets.get(&self.market.base_asset))
+                    .copied()
+                    .unwrap_or(Decimal::ZERO);
+                if order.remaining_quantity > held {
+                    return Err(format!(
+                        "insufficient {}: order requires {}, available {held}",
+                        self.market.base_asset, order.remaining_quantity
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets aside what `quantity` of the resting `order` needs from its owner's
+    /// available funds.
+    fn reserve(&self, accounts: &mut HashMap<String, Account>, order: &Order, quantity: Decimal) {
+        let (asset, amount) = self
+            .market
+            .reservation(&order.side, order.price.unwrap(), quantity);
+        let reserved = accounts
+            .entry(order.user_id.clone())
+            .or_default()
+            .reserve(asset, amount);
+        if !reserved {
+            tracing::error!(order_id = order.id, %amount, "reservation would overflow");
+        }
+    }
+
+    /// Hands back what `quantity` of the resting `order` had set aside.
+    fn release(&self, accounts: &mut HashMap<String, Account>, order: &Order, quantity: Decimal) {
+        let (asset, amount) = self
+            .market
+            .reservation(&order.side, order.price.unwrap(), quantity);
+        let released = accounts
+            .entry(order.user_id.clone())
+            .or_default()
+            .release(asset, amount);
+        if !released {
+            tracing::error!(order_id = order.id, %amount, "release would overflow");
+        }
+    }
+
+    /// Moves quote and base between the two counterparties of each trade and
+    /// charges fees to each side. `taker_side` is the side of the incoming order.
+    fn settle(
+        &self,
+        accounts: &mut HashMap<String, Account>,
+        trades: &[Trade],
+        taker_side: &OrderSide,
+    ) {
+        for trade in trades {
+            let notional = self.market.settled_notional(trade.price, trade.quantity);
+            let (buyer_fee, seller_fee) = match taker_side {
+                OrderSide::Buy => (trade.taker_fee, trade.maker_fee),
+                OrderSide::Sell => (trade.maker_fee, trade.taker_fee),
+            };
+
+            let base = Some(self.market.base_asset.as_str());
+            let legs = [
+                (&trade.buy_user_id, -notional, buyer_fee, trade.quantity),
+                (&trade.sell_user_id, notional, seller_fee, -trade.quantity),
+            ];
+            for (user_id, quote, fee, quantity) in legs {
+                let account = accounts.entry(user_id.clone()).or_default();
+                let id = Some(trade.id.as_str());
+                // No account holds more than was deposited across all of
+                // them, so this only fails once one holds close to
+                // `Decimal::MAX`; the leg is then reported and left unsettled
+                // rather than half applied. The fee is a debit and the base
+                // only moves one way, so neither can fail once this passes.
+                if !account.can_credit(None, quote) || !account.can_credit(base, quantity) {
+                    tracing::error!(trade_id = trade.id, user_id, "settlement would overflow");
+                    continue;
+                }
+                let _ = account.credit(LedgerKind::Trade, None, quote, id, trade.timestamp);
+                if !fee.is_zero() {
+                    let _ = account.credit(LedgerKind::Fee, None, -fee, id, trade.timestamp);
+                }
+                let _ = account.credit(LedgerKind::Trade, base, quantity, id, trade.timestamp);
+                account.record_volume(notional, trade.timestamp);
+                account.record_activity(trade.timestamp, |a| a.fills += 1);
+            }
+        }
+    }
+
+    /// Counts `orders` accepted for `user_id` towards their order activity.
+    fn count_placed(accounts: &mut HashMap<String, Account>, user_id: &str, orders: u64, now: u64) {
+        accounts
+            .entry(user_id.to_string())
+            .or_default()
+            .record_activity(now, |a| a.placed += orders);
+    }
+
+    /// Maker and taker fee `user_id` pays on `market` for a trade at `now`,
+    /// by their trailing volume. Trades only count once settled, so every
+    /// fill of one order is charged the tier it started in.
+    fn fee_bps(
+        market: &MarketConfig,
+        accounts: &HashMap<String, Account>,
+        user_id: &str,
+        now: u64,
+    ) -> (Decimal, Decimal) {
+        let volume = accounts
+            .get(user_id)
+            .map_or(Decimal::ZERO, |a| a.trailing_volume(now));
+        let (_, maker_fee_bps, taker_fee_bps) = market.fee_tier(volume);
+        (maker_fee_bps, taker_fee_bps)
+    }
+
+    /// What `user_id` pays here as of `now`.
+    pub fn fees(&self, user_id: &str, now: u64) -> MarketFees {
+        let volume = self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .map_or(Decimal::ZERO, |a| a.trailing_volume(now));
+        self.market.fees_for(volume)
+    }
+
+    /// Quote needed to buy what is left of the market buy `order` from the
+    /// asks, ignoring levels above `limit_price` when one is given. The
+    /// buyer's own asks are passed over as matching would, since self-trade
+    /// prevention never lets them fill the order.
+    fn market_buy_cost(&self, order: &Order, limit_price: Option<Decimal>) -> Decimal {
+        let mut remaining = order.remaining_quantity;
+        let mut cost = Decimal::ZERO;
+
+        for (price_key, orders) in self.asks.iter() {
+            let price = Self::key_to_price(*price_key, self.market.tick_size);
+            if limit_price.is_some_and(|limit| price > limit) {
+                return cost;
+            }
+            for resting in orders {
+                if remaining <= Decimal::ZERO {
+                    return cost;
+                }
+                if resting.user_id == order.user_id {
+                    match order.self_trade_prevention {
+                        SelfTradePrevention::Resting => continue,
+                        _ => return cost,
+                    }
+                }
+                let fill = remaining.min(resting.remaining_quantity);
+                cost += fill * price;
+                remaining -= fill;
+            }
+        }
+        cost
+    }
+
+    /// Base quantity a quote-amount market buy affords, spending at most
+    /// `budget` on notional. The asks are walked the way `match_market_order`
+    /// would take them, and the level the budget runs out in is bought down to
+    /// the market's `base_decimals`.
+    fn quote_order_quantity(&self, order: &Order, budget: Decimal) -> Decimal {
+        let protection_price = match order.order_type {
+            OrderType::MarketOrder { protection_price } => protection_price,
+            _ => None,
+        };
+        let limit_price = Self::tighter(&order.side, protection_price, self.band_edge(&order.side));
+        let mut budget = budget;
+        let mut quantity = Decimal::ZERO;
+
+        for (price_key, orders) in self.asks.iter() {
+            let price = Self::key_to_price(*price_key, self.market.tick_size);
+            if limit_price.is_some_and(|limit| price > limit) {
+                break;
+            }
+            let mut available = Decimal::ZERO;
+            let mut stopped = false;
+            for resting in orders {
+                if resting.user_id == order.user_id {
+                    match order.self_trade_prevention {
+                        SelfTradePrevention::Resting => continue,
+                        _ => {
+                            stopped = true;
+                            break;
+                        }
+                    }
+                }
+                available += resting.remaining_quantity;
+            }
+
+            let affordable = (budget / price)
+                .round_dp_with_strategy(self.market.base_decimals, RoundingStrategy::ToZero);
+            let fill = available.min(affordable);
+            quantity += fill;
+            budget -= fill * price;
+            if stopped || fill < available {
+                break;
+            }
+        }
+        quantity
+    }
+
+    /// Lowest and highest prices the price band currently allows.
+    fn price_band(&self) -> Option<(Decimal, Decimal)> {
+        let band_bps = self.market.price_band_bps?;
+        let reference = self.last_trade_price?;
+        let offset = reference * band_bps / Decimal::from(10_000);
+        Some((reference - offset, reference + offset))
+    }
+
+    /// Furthest price an order on `side` may trade at under the price band.
+    fn band_edge(&self, side: &OrderSide) -> Option<Decimal> {
+        let (low, high) = self.price_band()?;
+        Some(match side {
+            OrderSide::Buy => high,
+            OrderSide::Sell => low,
+        })
+    }
+
+    /// The more restrictive of two price limits for an order on `side`.
+    fn tighter(side: &OrderSide, a: Option<Decimal>, b: Option<Decimal>) -> Option<Decimal> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(match side {
+                OrderSide::Buy => a.min(b),
+                OrderSide::Sell => a.max(b),
+            }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bids
+            .keys()
+            .next_back()
+            .map(|key| Self::key_to_price(*key, self.market.tick_size))
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.asks
+            .keys()
+            .next()
+            .map(|key| Self::key_to_price(*key, self.market.tick_size))
+    }
+
+    /// Best price on `side` among orders that are not pegs.
+    fn unpegged_best(&self, side: &OrderSide) -> Option<Decimal> {
+        let unpegged = |level: &&VecDeque<Order>| {
+            level
+                .iter()
+                .any(|o| !matches!(o.order_type, OrderType::Peg { .. }))
+        };
+        let key = match side {
+            OrderSide::Buy => self.bids.iter().rev().find(|(_, level)| unpegged(level)),
+            OrderSide::Sell => self.asks.iter().find(|(_, level)| unpegged(level)),
+        }
+        .map(|(key, _)| *key)?;
+        Some(Self::key_to_price(key, self.market.tick_size))
+    }
+
+    /// Where the peg `order` belongs now: its reference price plus the
+    /// offset, held a tick short of the other side's best. `None` when there
+    /// is nothing to follow or the price would not be positive, or would be
+    /// beyond what the book can hold.
+    fn peg_price(&self, order: &Order) -> Option<Decimal> {
+        let OrderType::Peg { reference, offset } = order.order_type else {
+            return None;
+        };
+        let followed = match (reference, &order.side) {
+            (PegReference::Primary, side) => side.clone(),
+            (PegReference::Market, OrderSide::Buy) => OrderSide::Sell,
+            (PegReference::Market, OrderSide::Sell) => OrderSide::Buy,
+        };
+        let price = self.unpegged_best(&followed)?.checked_add(offset)?;
+        let tick_size = self.market.tick_size;
+        let price = match order.side {
+            OrderSide::Buy => self
+                .best_ask()
+                .map_or(price, |ask| price.min(ask - tick_size)),
+            OrderSide::Sell => self
+                .best_bid()
+                .map_or(price, |bid| price.max(bid + tick_size)),
+        };
+        (price > Decimal::ZERO && price <= self.max_price()).then_some(price)
+    }
+
+    /// Moves each resting peg whose price no longer matches the book to the
+    /// back of its new level. A peg left with nothing to follow stays where
+    /// it is; a buy its owner can no longer fund at the new price is cancelled.
+    fn repeg(&mut self, accounts: &mut HashMap<String, Account>) {
+        for (sequence, id) in self.pegs.clone() {
+            let Some(order) = self.orders.get(&id).filter(|o| o.sequence == sequence) else {
+                self.pegs.remove(&(sequence, id));
+                continue;
+            };
+            let order = order.clone();
+            let Some(price) = self.peg_price(&order).filter(|p| Some(*p) != order.price) else {
+                continue;
+            };
+            let mut moved = order.clone();
+            moved.price = Some(price);
+
+            self.release(accounts, &order, order.remaining_quantity);
+            let funded = self.check_funds(accounts, &moved).is_ok();
+            self.reserve(accounts, &order, order.remaining_quantity);
+            if !funded {
+                self.remove_order(accounts, &id);
+                continue;
+            }
+
+            self.take_from_level(&order);
+            self.orders.remove(&id);
+            self.open_orders.closed(&order.user_id);
+            self.pegs.remove(&(sequence, id.clone()));
+            self.events
+                .publish(BookEvent::OrderCancelled { order_id: id });
+            self.release(accounts, &order, order.remaining_quantity);
+
+            self.join_back_of_level(&mut moved);
+            self.add_to_book(moved.clone());
+            self.reserve(accounts, &moved, moved.remaining_quantity);
+        }
+    }
+
+    /// Moves `order` behind everything already queued at its price, for an
+    /// order that goes on the book later than its sequence says.
+    fn join_back_of_level(&self, order: &mut Order) {
+        let Some(price) = order.price else {
+            return;
+        };
+        let price_key = Self::price_to_key(price, self.market.tick_size);
+        let book = match order.side {
+            OrderSide::Buy => &self.bids,
+            OrderSide::Sell => &self.asks,
+        };
+        if let Some(last) = book.get(&price_key).and_then(VecDeque::back) {
+            order.sequence = order.sequence.max(last.sequence);
+        }
+    }
+
+    /// Quantity shown at the first of `levels`, best price first.
+    fn top_size<'a>(mut levels: impl Iterator<Item = &'a VecDeque<Order>>) -> Option<Decimal> {
+        levels
+            .next()
+            .map(|orders| orders.iter().map(Order::visible).sum())
+    }
+
+    pub fn market_info(&self) -> MarketInfo {
+        let market = &self.market;
+        MarketInfo {
+            symbol: market.symbol.clone(),
+            base_asset: market.base_asset.clone(),
+            quote_asset: QUOTE_ASSET.to_string(),
+            tick_size: market.tick_size,
+            min_quantity: market.min_quantity,
+            max_quantity: market.max_quantity,
+            min_notional: market.min_notional,
+            max_open_orders: market.max_open_orders,
+            maker_fee_bps: market.maker_fee_bps,
+            taker_fee_bps: market.taker_fee_bps,
+            fee_tiers: market.fee_tiers.clone(),
+            base_decimals: market.base_decimals,
+            quote_decimals: market.quote_decimals,
+            status: self.status,
+        }
+    }
+
+    pub fn ticker(&self) -> Ticker {
+        let best_bid = self.best_bid();
+        let best_ask = self.best_ask();
+        let best_bid_size = Self::top_size(self.bids.values().rev());
+        let best_ask_size = Self::top_size(self.asks.values());
+        let top = best_bid
+            .zip(best_ask)
+            .zip(best_bid_size.zip(best_ask_size))
+            .filter(|(_, (bid_size, ask_size))| !(*bid_size + *ask_size).is_zero());
+        let indicative = match self.status {
+            MarketStatus::Auction => self.equilibrium(),
+            _ => None,
+        };
+        Ticker {
+            last_price: self.last_trade_price,
+            best_bid,
+            best_ask,
+            spread: best_bid.zip(best_ask).map(|(bid, ask)| ask - bid),
+            best_bid_size,
+            best_ask_size,
+            imbalance: top.map(|(_, (bid_size, ask_size))| bid_size / (bid_size + ask_size)),
+            microprice: top.map(|((bid, ask), (bid_size, ask_size))| {
+                (ask * bid_size + bid * ask_size) / (bid_size + ask_size)
+            }),
+            indicative_price: indicative.map(|(price, _)| price),
+            indicative_volume: indicative.map(|(_, volume)| volume),
+        }
+    }
+
+    /// Dry run of `match_limit_order`: how much of `order` could fill right now
+    /// at its limit price, without touching the book. Stops counting once the
+    /// order's full quantity is covered or self-trade prevention would cancel it.
+    fn crossable_quantity(&self, order: &Order) -> Decimal {
+        let order_price = order.price.unwrap();
+        let book = match order.side {
+            OrderSide::Buy => &self.asks,
+            OrderSide::Sell => &self.bids,
+        };
+
+        let keys: Vec<u64> = match order.side {
+            OrderSide::Buy => book.keys().copied().collect(),
+            OrderSide::Sell => book.keys().copied().rev().collect(),
+        };
+
+        let mut available = Decimal::ZERO;
+        for price_key in keys {
+            let level_price = Self::key_to_price(price_key, self.market.tick_size);
+            let crosses = match order.side {
+                OrderSide::Buy => order_price >= level_price,
+                OrderSide::Sell => order_price <= level_price,
+            };
+            if !crosses {
+                break;
+            }
+            for resting in &book[&price_key] {
+                if available >= order.quantity {
+                    return available;
+                }
+                if resting.user_id == order.user_id {
+                    match order.self_trade_prevention {
+                        SelfTradePrevention::Resting => continue,
+                        _ => return available,
+                    }
+                }
+                available += resting.remaining_quantity;
+            }
+        }
+        available
+    }
+
+    /// Dry run of the matching `order` would do now: the average price of what
+    /// it would take from the book, or `None` if it would take nothing. Only
+    /// market, limit, iceberg, IOC and FOK orders take liquidity; stops are
+    /// priced once they trigger.
+    fn average_fill_price(&self, order: &Order) -> Option<Decimal> {
+        let limit_price = match order.order_type {
+            OrderType::MarketOrder { protection_price } => {
+                Self::tighter(&order.side, protection_price, self.band_edge(&order.side))
+            }
+            OrderType::LimitOrder
+            | OrderType::Iceberg { .. }
+            | OrderType::ImmediateOrCancel
+            | OrderType::FillOrKill => order.price,
+            _ => return None,
+        };
+        let book = match order.side {
+            OrderSide::Buy => &self.asks,
+            OrderSide::Sell => &self.bids,
+        };
+        let keys: Vec<u64> = match order.side {
+            OrderSide::Buy => book.keys().copied().collect(),
+            OrderSide::Sell => book.keys().copied().rev().collect(),
+        };
+
+        let mut wanted = order.remaining_quantity;
+        let mut filled = Decimal::ZERO;
+        let mut notional = Decimal::ZERO;
+        'levels: for price_key in keys {
+            let level_price = Self::key_to_price(price_key, self.market.tick_size);
+            let crosses = limit_price.is_none_or(|limit| match order.side {
+                OrderSide::Buy => limit >= level_price,
+                OrderSide::Sell => limit <= level_price,
+            });
+            if !crosses {
+                break;
+            }
+            for resting in &book[&price_key] {
+                if wanted <= Decimal::ZERO {
+                    break 'levels;
+                }
+                if resting.user_id == order.user_id {
+                    match order.self_trade_prevention {
+                        SelfTradePrevention::Resting => continue,
+                        _ => break 'levels,
+                    }
+                }
+                let quantity = wanted.min(resting.remaining_quantity);
+                wanted -= quantity;
+                filled += quantity;
+                notional += quantity * level_price;
+            }
+        }
+        (!filled.is_zero()).then(|| notional / filled)
+    }
+
+    /// Matches at any price, or only up to the order's protection price or the
+    /// edge of the price band, whichever is tighter.
+    pub fn match_market_order(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        order: &mut Order,
+    ) -> MatchOutcome {
+        let protection_price = match order.order_type {
+            OrderType::MarketOrder { protection_price } => protection_price,
+            _ => None,
+        };
+        let limit_price = Self::tighter(&order.side, protection_price, self.band_edge(&order.side));
+        self.match_order(accounts, order, limit_price)
+    }
+
+    pub fn match_limit_order(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        order: &mut Order,
+    ) -> MatchOutcome {
+        let limit_price = order.price;
+        self.match_order(accounts, order, limit_price)
+    }
+
+    /// Walks the opposite side of the book best price first, filling `order`
+    /// against resting orders in time priority. With a `limit_price`, stops at
+    /// the first level that no longer crosses it. Resting orders give up the
+    /// funds they had reserved as they fill or are cancelled.
+    fn match_order(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        order: &mut Order,
+        limit_price: Option<Decimal>,
+    ) -> MatchOutcome {
+        let mut trades = Vec::new();
+        let mut taker_cancelled = false;
+        let tick_size = self.market.tick_size;
+        let (_, taker_fee_bps) = Self::fee_bps(&self.market, accounts, &order.user_id, self.now);
+
+        let book = match order.side {
+            OrderSide::Buy => &mut self.asks,
+            OrderSide::Sell => &mut self.bids,
+        };
+
+        let keys: Vec<u64> = match order.side {
+            OrderSide::Buy => book.keys().copied().collect(),
+            OrderSide::Sell => book.keys().copied().rev().collect(),
+        };
+        let mut touched = Vec::new();
+
+        for price_key in keys {
+            if order.remaining_quantity <= Decimal::ZERO || taker_cancelled {
+                break;
+            }
+
+            if let Some(limit_price) = limit_price {
+                let matching_price = Self::key_to_price(price_key, tick_size);
+                let should_match = match order.side {
+                    OrderSide::Buy => limit_price >= matching_price,
+                    OrderSide::Sell => limit_price <= matching_price,
+                };
+
+                if !should_match {
+                    break;
+                }
+            }
+
+            if let Some(order_at_price) = book.get_mut(&price_key) {
+                touched.push(price_key);
+                // Under pro-rata every order is visited once and filled up to
+                // its share. Visited orders go to the back, and the level is
+                // turned back into time order once the pass is over.
+                let shares = match self.market.matching_mode {
+                    MatchingMode::ProRata => Some(Self::pro_rata_shares(order, order_at_price)),
+                    MatchingMode::PriceTime => None,
+                };
+                let mut unvisited = order_at_price.len();
+                let mut rotated = 0;
+                while let Some(mut matching_order) = order_at_price.pop_front() {
+                    if shares.is_some() {
+                        if unvisited == 0 {
+                            order_at_price.push_front(matching_order);
+                            break;
+                        }
+                        unvisited -= 1;
+                    }
+
+                    if matching_order.user_id == order.user_id {
+                        let (asset, reserved) = self.market.reservation(
+                            &matching_order.side,
+                            matching_order.price.unwrap(),
+                            matching_order.remaining_quantity,
+                        );
+                        match order.self_trade_prevention {
+                            SelfTradePrevention::Resting => {
+                                accounts
+                                    .entry(matching_order.user_id.clone())
+                                    .or_default()
+                                    .release(asset, reserved);
+                                self.orders.remove(&matching_order.id);
+                                self.open_orders.closed(&matching_order.user_id);
+                                self.events.publish(BookEvent::OrderCancelled {
+                                    order_id: matching_order.id.clone(),
+                                });
+                                self.history.record(matching_order, OrderStatus::Cancelled);
+                                continue;
+                            }
+                            SelfTradePrevention::Taker => {
+                                order_at_price.push_front(matching_order);
+                            }
+                            SelfTradePrevention::Both => {
+                                accounts
+                                    .entry(matching_order.user_id.clone())
+                                    .or_default()
+                                    .release(asset, reserved);
+                                self.orders.remove(&matching_order.id);
+                                self.open_orders.closed(&matching_order.user_id);
+                                self.events.publish(BookEvent::OrderCancelled {
+                                    order_id: matching_order.id.clone(),
+                                });
+                                self.history.record(matching_order, OrderStatus::Cancelled);
+                            }
+                        }
+                        taker_cancelled = true;
+                        break;
+                    }
+
+                    let mut trade_quantity = order.remaining_quantity.min(matching_order.visible());
+                    if let Some(shares) = &shares {
+                        let share = shares.get(&matching_order.id).copied();
+                        trade_quantity = trade_quantity.min(share.unwrap_or_default());
+                        if trade_quantity.is_zero() {
+                            order_at_price.push_back(matching_order);
+                            rotated += 1;
+                            continue;
+                        }
+                    }
+                    let trade_price = matching_order.price.unwrap();
+                    let notional = self.market.settled_notional(trade_price, trade_quantity);
+
+                    let trade = Trade {
+                        id: format!("{}-{}", self.market.symbol, self.next_trade_id),
+                        buy_order_id: match order.side {
+                            OrderSide::Buy => order.id.clone(),
+                            OrderSide::Sell => matching_order.id.clone(),
+                        },
+                        sell_order_id: match order.side {
+                            OrderSide::Buy => matching_order.id.clone(),
+                            OrderSide::Sell => order.id.clone(),
+                        },
+                        buy_user_id: match order.side {
+                            OrderSide::Buy => order.user_id.clone(),
+                            OrderSide::Sell => matching_order.user_id.clone(),
+                        },
+                        sell_user_id: match order.side {
+                            OrderSide::Buy => matching_order.user_id.clone(),
+                            OrderSide::Sell => order.user_id.clone(),
+                        },
+                        price: trade_price,
+                        quantity: trade_quantity,
+                        aggressor_side: Some(order.side.clone()),
+                        maker_fee: self.market.settled_fee(
+                            notional,
+                            Self::fee_bps(
+                                &self.market,
+                                accounts,
+                                &matching_order.user_id,
+                                self.now,
+                            )
+                            .0,
+                        ),
+                        taker_fee: self.market.settled_fee(notional, taker_fee_bps),
+                        timestamp: self.now,
+                    };
+
+                    self.next_trade_id += 1;
+                    trades.push(trade);
+
+                    order.remaining_quantity -= trade_quantity;
+                    matching_order.remaining_quantity -= trade_quantity;
+                    let (asset, reserved) =
+                        self.market
+                            .reservation(&matching_order.side, trade_price, trade_quantity);
+                    accounts
+                        .entry(matching_order.user_id.clone())
+                        .or_default()
+                        .release(asset, reserved);
+
+                    if matching_order.remaining_quantity > Decimal::ZERO {
+                        let mut requeue = false;
+                        if let Some(visible) = matching_order.visible_quantity.as_mut() {
+                            *visible -= trade_quantity;
+                            // A used-up iceberg slice is refilled behind the
+                            // orders already waiting at this price.
+                            if visible.is_zero() {
+                                if let OrderType::Iceberg { display_quantity } =
+                                    matching_order.order_type
+                                {
+                                    *visible =
+                                        display_quantity.min(matching_order.remaining_quantity);
+                                }
+                                if let Some(last) = order_at_price.back() {
+                                    matching_order.sequence =
+                                        matching_order.sequence.max(last.sequence);
+                                }
+                                requeue = true;
+                            }
+                        }
+                        if let Some(resting) = self.orders.get_mut(&matching_order.id) {
+                            resting.remaining_quantity = matching_order.remaining_quantity;
+                            resting.visible_quantity = matching_order.visible_quantity;
+                            resting.sequence = matching_order.sequence;
+                        }
+                        if requeue {
+                            order_at_price.push_back(matching_order);
+                        } else if shares.is_some() {
+                            order_at_price.push_back(matching_order);
+                            rotated += 1;
+                        } else {
+                            order_at_price.push_front(matching_order);
+                        }
+                    } else {
+                        self.orders.remove(&matching_order.id);
+                        self.open_orders.closed(&matching_order.user_id);
+                        self.history.record(matching_order, OrderStatus::Filled);
+                    }
+
+                    if order.remaining_quantity <= Decimal::ZERO {
+                        break;
+                    }
+                }
+                order_at_price.rotate_right(rotated);
+
+                if order_at_price.is_empty() {
+                    book.remove(&price_key);
+                }
+            }
+        }
+
+        match order.side {
+            OrderSide::Buy => self.changed_asks.extend(touched),
+            OrderSide::Sell => self.changed_bids.extend(touched),
+        }
+        self.record_trades(&trades);
+
+        let linked: Vec<String> = trades
+            .iter()
+            .flat_map(|t| [&t.buy_order_id, &t.sell_order_id])
+            .filter_map(|id| match id == &order.id {
+                true => order.linked_order_id.clone(),
+                false => self.get_order(id).and_then(|o| o.linked_order_id),
+            })
+            .collect();
+        for id in linked {
+            self.remove_order(accounts, &id);
+        }
+
+        MatchOutcome {
+            trades,
+            taker_cancelled,
+        }
+    }
+
+    /// Adds `trades` to the tape, candles and daily statistics and tells the
+    /// users involved.
+    fn record_trades(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            if self.recent_trades.len() >= RECENT_TRADES_LIMIT {
+                self.recent_trades.pop_front();
+            }
+            self.recent_trades.push_back(trade.clone());
+            for series in &mut self.candles {
+                series.record(trade);
+            }
+            self.daily.record(trade);
+            let _ = self.feeds.user_events.send(UserEvent::Fill {
+                symbol: self.market.symbol.clone(),
+                trade: trade.clone(),
+            });
+            self.events.publish(BookEvent::Trade {
+                trade: trade.clone(),
+            });
+            self.follow_price(trade.price);
+        }
+        if let Some(trade) = trades.last() {
+            self.last_trade_price = Some(trade.price);
+        }
+    }
+
+    /// Trigger a trailing stop on `side` has when the best price so far is `price`.
+    fn trail(side: &OrderSide, price: Decimal, trail_amount: Decimal) -> Decimal {
+        match side {
+            OrderSide::Buy => price + trail_amount,
+            OrderSide::Sell => price - trail_amount,
+        }
+    }
+
+    /// Moves the trigger of every trailing stop that `price` is an improvement
+    /// for. Triggers only ever move towards the market, never back.
+    fn follow_price(&mut self, price: Decimal) {
+        for stop in &mut self.stops {
+            let OrderType::TrailingStop {
+                trail_amount,
+                stop_price,
+            } = &mut stop.order_type
+            else {
+                continue;
+            };
+            let candidate = Self::trail(&stop.side, price, *trail_amount);
+            let improved = stop_price.is_none_or(|current| match stop.side {
+                OrderSide::Buy => candidate < current,
+                OrderSide::Sell => candidate > current,
+            });
+            if improved {
+                *stop_price = Some(candidate);
+                if let Some(order) = self.orders.get_mut(&stop.id) {
+                    order.order_type = stop.order_type.clone();
+                }
+            }
+        }
+    }
+
+    /// Changes whether the market takes orders. Opening uncrosses whatever
+    /// the auction, if there was one, left crossed.
+    pub fn set_status(&mut self, status: MarketStatus, now: u64) {
+        self.now = now;
+        self.status = status;
+        if status == MarketStatus::Open {
+            let accounts = Arc::clone(&self.accounts);
+            let mut accounts = accounts.lock().unwrap();
+            self.uncross(&mut accounts);
+            self.trigger_stops(&mut accounts);
+            self.repeg(&mut accounts);
+            self.publish_updates();
+        }
+    }
+
+    /// Price a crossed book would uncross at and the volume that would trade
+    /// there: the price matching the most, then leaving the least unmatched,
+    /// then nearest the last trade, then the lowest.
+    fn equilibrium(&self) -> Option<(Decimal, Decimal)> {
+        let tick_size = self.market.tick_size;
+        let bid_key = *self.bids.keys().next_back()?;
+        let ask_key = *self.asks.keys().next()?;
+        if bid_key < ask_key {
+            return None;
+        }
+
+        let candidates: BTreeSet<u64> = self
+            .bids
+            .range(ask_key..)
+            .chain(self.asks.range(..=bid_key))
+            .map(|(key, _)| *key)
+            .collect();
+        let quantity = |levels: std::collections::btree_map::Range<u64, VecDeque<Order>>| {
+            levels
+                .flat_map(|(_, orders)| orders)
+                .map(|o| o.remaining_quantity)
+                .sum::<Decimal>()
+        };
+
+        let mut best: Option<(u64, Decimal, Decimal)> = None;
+        for key in candidates {
+            let demand = quantity(self.bids.range(key..));
+            let supply = quantity(self.asks.range(..=key));
+            let volume = demand.min(supply);
+            let imbalance = (demand - supply).abs();
+            let distance = |key: u64| {
+                self.last_trade_price
+                    .map(|last| (Self::key_to_price(key, tick_size) - last).abs())
+            };
+            let better = best.is_none_or(|(best_key, best_volume, best_imbalance)| {
+                (volume, best_imbalance, distance(best_key))
+                    > (best_volume, imbalance, distance(key))
+            });
+            if better {
+                best = Some((key, volume, imbalance));
+            }
+        }
+        best.map(|(key, volume, _)| (Self::key_to_price(key, tick_size), volume))
+    }
+
+    /// Trades everything that crosses at the equilibrium price, best-priced
+    /// and then oldest orders first. Neither side took liquidity, so both pay
+    /// the maker fee.
+    fn uncross(&mut self, accounts: &mut HashMap<String, Account>) {
+        let Some((price, volume)) = self.equilibrium() else {
+            return;
+        };
+        let mut trades = Vec::new();
+        let mut left = volume;
+
+        while left > Decimal::ZERO {
+            let (Some(mut bids), Some(mut asks)) =
+                (self.bids.last_entry(), self.asks.first_entry())
+            else {
+                break;
+            };
+            self.changed_bids.insert(*bids.key());
+            self.changed_asks.insert(*asks.key());
+            let (Some(bid), Some(ask)) = (bids.get_mut().front_mut(), asks.get_mut().front_mut())
+            else {
+                break;
+            };
+
+            let quantity = bid.remaining_quantity.min(ask.remaining_quantity).min(left);
+            let notional = self.market.settled_notional(price, quantity);
+            // Both sides rested, so each pays its own maker fee. Settled with
+            // the buy as taker, the buyer's goes in `taker_fee`.
+            let fee = |user_id: &str| {
+                let (maker_fee_bps, _) = Self::fee_bps(&self.market, accounts, user_id, self.now);
+                self.market.settled_fee(notional, maker_fee_bps)
+            };
+            let (buyer_fee, seller_fee) = (fee(&bid.user_id), fee(&ask.user_id));
+            trades.push(Trade {
+                id: format!("{}-{}", self.market.symbol, self.next_trade_id),
+                buy_order_id: bid.id.clone(),
+                sell_order_id: ask.id.clone(),
+                buy_user_id: bid.user_id.clone(),
+                sell_user_id: ask.user_id.clone(),
+                price,
+                quantity,
+                aggressor_side: None,
+                maker_fee: seller_fee,
+                taker_fee: buyer_fee,
+                timestamp: self.now,
+            });
+            self.next_trade_id += 1;
+            left -= quantity;
+
+            for level in [bids.get_mut(), asks.get_mut()] {
+                let order = level.front_mut().unwrap();
+                order.remaining_quantity -= quantity;
+                let (asset, reserved) =
+                    self.market
+                        .reservation(&order.side, order.price.unwrap(), quantity);
+                accounts
+                    .entry(order.user_id.clone())
+                    .or_default()
+                    .release(asset, reserved);
+
+                if order.remaining_quantity.is_zero() {
+                    let order = level.pop_front().unwrap();
+                    self.orders.remove(&order.id);
+                    self.open_orders.closed(&order.user_id);
+                    self.history.record(order, OrderStatus::Filled);
+                } else if let Some(resting) = self.orders.get_mut(&order.id) {
+                    resting.remaining_quantity = order.remaining_quantity;
+                }
+            }
+            if bids.get().is_empty() {
+                bids.remove();
+            }
+            if asks.get().is_empty() {
+                asks.remove();
+            }
+        }
+
+        self.settle(accounts, &trades, &OrderSide::Buy);
+        self.record_trades(&trades);
+        let linked: Vec<String> = trades
+            .iter()
+            .flat_map(|t| [&t.buy_order_id, &t.sell_order_id])
+            .filter_map(|id| self.get_order(id).and_then(|o| o.linked_order_id))
+            .collect();
+        for id in linked {
+            self.remove_order(accounts, &id);
+        }
+    }
+
+    /// Splits what `taker` can still trade at one level between the other
+    /// users' orders there, in proportion to their remaining quantity. Shares
+    /// are rounded down to the finest precision among the quantities involved,
+    /// and the units left over go one each to the oldest orders.
+    fn pro_rata_shares(taker: &Order, level: &VecDeque<Order>) -> HashMap<String, Decimal> {
+        let makers: Vec<&Order> = level
+            .iter()
+            .filter(|o| o.user_id != taker.user_id)
+            .collect();
+        let total: Decimal = makers.iter().map(|o| o.remaining_quantity).sum();
+        let quantity = taker.remaining_quantity;
+        if quantity >= total {
+            return makers
+                .iter()
+                .map(|o| (o.id.clone(), o.remaining_quantity))
+                .collect();
+        }
+
+        let scale = makers
+            .iter()
+            .map(|o| o.remaining_quantity.scale())
+            .fold(quantity.scale(), u32::max);
+        let unit = Decimal::new(1, scale);
+        let mut shares: Vec<Decimal> = makers
+            .iter()
+            .map(|o| {
+                (quantity * o.remaining_quantity / total)
+                    .round_dp_with_strategy(scale, RoundingStrategy::ToZero)
+            })
+            .collect();
+        // Each share lost less than one unit to rounding, so a single pass
+        // hands out the rest, and no share ends up above its order's quantity.
+        let mut left = quantity - shares.iter().sum::<Decimal>();
+        for share in &mut shares {
+            if left <= Decimal::ZERO {
+                break;
+            }
+            *share += unit;
+            left -= unit;
+        }
+
+        makers
+            .iter()
+            .zip(shares)
+            .map(|(o, share)| (o.id.clone(), share))
+            .collect()
+    }
+
+    fn add_to_book(&mut self, order: Order) {
+        let price = order.price.unwrap();
+        let price_key = Self::price_to_key(price, self.market.tick_size);
+
+        let (book, changed) = match order.side {
+            OrderSide::Buy => (&mut self.bids, &mut self.changed_bids),
+            OrderSide::Sell => (&mut self.asks, &mut self.changed_asks),
+        };
+
+        changed.insert(price_key);
+        if let Some(expires_at) = order.expires_at {
+            self.expiries.insert((expires_at, order.id.clone()));
+        }
+        if let OrderType::Peg { .. } = order.order_type {
+            self.pegs.insert((order.sequence, order.id.clone()));
+        }
+        self.orders.insert(order.id.clone(), order.clone());
+        self.open_orders.opened(&order.user_id);
+        self.events.publish(BookEvent::OrderPlaced {
+            order: order.clone(),
+        });
+        // Orders normally arrive in sequence, but restored ones might not.
+        // Equal sequences keep their arrival order, as refilled icebergs can
+        // share one with the order ahead of them.
+        let level = book.entry(price_key).or_insert_with(VecDeque::new);
+        let position = level.partition_point(|o| o.sequence <= order.sequence);
+        level.insert(position, order);
+    }
+
+    /// Whether a live order has reached its expiry by `now`. Stale index
+    /// entries met along the way are dropped.
+    fn has_due_expiry(&mut self, now: u64) -> bool {
+        while let Some((expires_at, id)) = self.expiries.first() {
+            if *expires_at > now {
+                return false;
+            }
+            if self.orders.contains_key(id) {
+                return true;
+            }
+            self.expiries.pop_first();
+        }
+        false
+    }
+
+    /// Takes every order whose expiry is at or before `now` off the book.
+    pub fn expire_orders(&mut self, now: u64) {
+        self.now = now;
+        while let Some((expires_at, id)) = self.expiries.pop_first() {
+            if expires_at > now {
+                self.expiries.insert((expires_at, id));
+                break;
+            }
+            let Some(order) = self.orders.remove(&id) else {
+                continue;
+            };
+            self.open_orders.closed(&order.user_id);
+            self.take_from_level(&order);
+            self.events.publish(BookEvent::OrderCancelled {
+                order_id: order.id.clone(),
+            });
+            self.release(
+                &mut self.accounts.lock().unwrap(),
+                &order,
+                order.remaining_quantity,
+            );
+            let _ = self
+                .feeds
+                .user_events
+                .send(UserEvent::Expired(order.clone()));
+            self.history.record(order, OrderStatus::Expired);
+        }
+        let accounts = Arc::clone(&self.accounts);
+        let mut accounts = accounts.lock().unwrap();
+        // A partly filled bracket entry that expired still gets its exits.
+        self.trigger_stops(&mut accounts);
+        self.repeg(&mut accounts);
+        self.publish_updates();
+    }
+
+    /// Whether a delayed order is due to go in by `now`.
+    fn has_due_activation(&self, now: u64) -> bool {
+        self.delayed
+            .first()
+            .is_some_and(|(activates_at, ..)| *activates_at <= now)
+    }
+
+    /// Puts in every delayed order whose activation time is at or before
+    /// `now`, soonest first. Each is checked again as if newly placed, funds
+    /// included; one that no longer passes is cancelled.
+    pub fn activate_orders(&mut self, now: u64) {
+        self.now = now;
+        let accounts = Arc::clone(&self.accounts);
+        let mut accounts = accounts.lock().unwrap();
+        while let Some((activates_at, sequence, id)) = self.delayed.pop_first() {
+            if activates_at > now {
+                self.delayed.insert((activates_at, sequence, id));
+                break;
+            }
+            let Some(order) = self.orders.remove(&id) else {
+                continue;
+            };
+            self.open_orders.closed(&order.user_id);
+
+            let response = match self.validate(&order) {
+                Err(response) => response,
+                Ok(()) => self.execute(&mut accounts, order.clone()),
+            };
+            if let OrderResponse::Error { code, .. } = &response {
+                tracing::info!(
+                    order_id = id,
+                    user_id = order.user_id,
+                    code = ?code,
+                    "delayed order rejected on activation"
+                );
+                if self.history.get(&id).is_none() {
+                    self.history.record(order, OrderStatus::Cancelled);
+                }
+            }
+            self.trigger_stops(&mut accounts);
+        }
+        self.repeg(&mut accounts);
+        self.publish_updates();
+    }
+
+    pub fn cancel_order(&mut self, order_id: &str) -> OrderResponse {
+        let accounts = Arc::clone(&self.accounts);
+        let mut accounts = accounts.lock().unwrap();
+        let response = self.remove_order(&mut accounts, order_id);
+        self.trigger_stops(&mut accounts);
+        self.repeg(&mut accounts);
+        self.publish_updates();
+        response
+    }
+
+    /// Cancels every open order of `user_id`, resting or pending on a stop,
+    /// and returns their ids.
+    pub fn cancel_all_for_user(&mut self, user_id: &str, now: u64) -> Vec<String> {
+        self.now = now;
+        let ids: Vec<String> = self
+            .orders
+            .values()
+            .filter(|o| o.user_id == user_id)
+            .map(|o| o.id.clone())
+            .collect();
+
+        let accounts = Arc::clone(&self.accounts);
+        let mut accounts = accounts.lock().unwrap();
+        for id in &ids {
+            self.remove_order(&mut accounts, id);
+        }
+        if !ids.is_empty() {
+            let cancelled = ids.len() as u64;
+            accounts
+                .entry(user_id.to_string())
+                .or_default()
+                .record_activity(self.now, |a| a.cancelled += cancelled);
+        }
+        self.trigger_stops(&mut accounts);
+        self.repeg(&mut accounts);
+        self.publish_updates();
+        ids
+    }
+
+    /// Takes an open order off the book without publishing the change.
+    fn remove_order(
+        &mut self,
+        accounts: &mut HashMap<String, Account>,
+        order_id: &str,
+    ) -> OrderResponse {
+        let order = match self.orders.remove(order_id) {
+            Some(o) => o,
+            None => {
+                return OrderResponse::Error {
+                    code: OrderError::OrderNotFound,
+                    message: "order not found or already filled".to_string(),
+                };
+            }
+        };
+        self.open_orders.closed(&order.user_id);
+
+        if let Some(position) = self.stops.iter().position(|o| o.id == order.id) {
+            self.stops.remove(position);
+            let order_id = order.id.clone();
+            self.history.record(order, OrderStatus::Cancelled);
+            return OrderResponse::Cancelled { order_id };
+        }
+        if let Some(activates_at) = order.activates_at {
+            if self
+                .delayed
+                .remove(&(activates_at, order.sequence, order.id.clone()))
+            {
+                let order_id = order.id.clone();
+                self.history.record(order, OrderStatus::Cancelled);
+                return OrderResponse::Cancelled { order_id };
+            }
+        }
+
+        if !self.take_from_level(&order) {
+            return OrderResponse::Error {
+                code: OrderError::OrderNotFound,
+                message: "order has already been filled".to_string(),
+            };
+        }
+        self.release(accounts, &order, order.remaining_quantity);
+
+        let order_id = order.id.clone();
+        self.events.publish(BookEvent::OrderCancelled {
+            order_id: order_id.clone(),
+        });
+        self.history.record(order, OrderStatus::Cancelled);
+        OrderResponse::Cancelled { order_id }
+    }
+
+    /// Removes a resting order from its price level, dropping the level if it
+    /// empties. Returns false if the order was not queued there.
+    fn take_from_level(&mut self, order: &Order) -> bool {
+        let price_key = Self::price_to_key(order.price.unwrap(), self.market.tick_size);
+        let (book, changed) = match order.side {
+            OrderSide::Buy => (&mut self.bids, &mut self.changed_bids),
+            OrderSide::Sell => (&mut self.asks, &mut self.changed_asks),
+        };
+
+        let Some(order_at_price) = book.get_mut(&price_key) else {
+            return false;
+        };
+        let Some(position) = order_at_price.iter().position(|o| o.id == order.id) else {
+            return false;
+        };
+
+        order_at_price.remove(position);
+        if order_at_price.is_empty() {
+            book.remove(&price_key);
+        }
+        changed.insert(price_key);
+        true
+    }
+
+    /// Cuts a resting order's total quantity to `quantity` without moving it
+    /// in its level's queue. Unlike an amendment this never reprices or
+    /// requeues: anything but a cut that leaves some quantity open is refused,
+    /// and cutting to the filled amount is a cancel.
+    pub fn reduce_order(&mut self, order_id: &str, quantity: Decimal, now: u64) -> OrderResponse {
+        self.now = now;
+
+        let Some(current) = self.orders.get(order_id).cloned() else {
+            return OrderResponse::Error {
+                code: OrderError::OrderNotFound,
+                message: "order not found or already filled".to_string(),
+            };
+        };
+        if self.stops.iter().any(|o| o.id == order_id) || self.is_delayed(&current) {
+            return OrderResponse::Error {
+                code: OrderError::NotAmendable,
+                message: "only resting orders can be reduced".to_string(),
+            };
+        }
+
+        let filled = current.quantity - current.remaining_quantity;
+        if quantity >= current.quantity {
+            return OrderResponse::Error {
+                code: OrderError::InvalidQuantity,
+                message: format!(
+                    "quantity must be less than the current {}",
+                    current.quantity
+                ),
+            };
+        }
+        if quantity <= filled {
+            return OrderResponse::Error {
+                code: OrderError::InvalidQuantity,
+                message: format!(
+                    "quantity must be greater than the {filled} already filled; cancel the order instead"
+                ),
+            };
+        }
+
+        let mut reduced = current.clone();
+        reduced.quantity = quantity;
+        reduced.remaining_quantity = quantity - filled;
+        reduced.visible_quantity = current
+            .visible_quantity
+            .map(|visible| visible.min(reduced.remaining_quantity));
+        if let Err(response) = self.validate(&reduced) {
+            return response;
+        }
+        self.shrink_in_place(&current, reduced)
+    }
+
+    /// Replaces resting `current` with `smaller`, the same order at the same
+    /// price with less left open, keeping its place in the queue and releasing
+    /// what the difference had reserved.
+    fn shrink_in_place(&mut self, current: &Order, smaller: Order) -> OrderResponse {
+        let price_key = Self::price_to_key(current.price.unwrap(), self.market.tick_size);
+        let (book, changed) = match current.side {
+            OrderSide::Buy => (&mut self.bids, &mut self.changed_bids),
+            OrderSide::Sell => (&mut self.asks, &mut self.changed_asks),
+        };
+        if let Some(queued) = book
+            .get_mut(&price_key)
+            .and_then(|level| level.iter_mut().find(|o| o.id == current.id))
+        {
+            queued.quantity = smaller.quantity;
+            queued.remaining_quantity = smaller.remaining_quantity;
+            queued.visible_quantity = smaller.visible_quantity;
+        }
+        changed.insert(price_key);
+        self.release(
+            &mut self.accounts.lock().unwrap(),
+            current,
+            current.remaining_quantity - smaller.remaining_quantity,
+        );
+        self.orders.insert(smaller.id.clone(), smaller);
+        self.publish_updates();
+        OrderResponse::Placed {
+            order_id: current.id.clone(),
+        }
+    }
+
+    /// Changes the price and/or total quantity of a resting order. Shrinking
+    /// the quantity at the same price keeps the order's place in the queue;
+    /// anything else cancels and replaces it under `sequence`, so it goes to
+    /// the back of its level and may trade immediately if it now crosses.
+    pub fn amend_order(
+        &mut self,
+        order_id: &str,
+        new_price: Option<Decimal>,
+        new_quantity: Option<Decimal>,
+        sequence: u64,
+        now: u64,
+    ) -> OrderResponse {
+        self.now = now;
+
+        let Some(current) = self.orders.get(order_id).cloned() else {
+            return OrderResponse::Error {
+                code: OrderError::OrderNotFound,
+                message: "order not found or already filled".to_string(),
+            };
+        };
+        if self.stops.iter().any(|o| o.id == order_id) || self.is_delayed(&current) {
+            return OrderResponse::Error {
+                code: OrderError::NotAmendable,
+                message: "only resting orders can be amended".to_string(),
+            };
+        }
+        if matches!(current.order_type, OrderType::Peg { .. }) && new_price.is_some() {
+            return OrderResponse::Error {
+                code: OrderError::NotAmendable,
+                message: "a peg's price follows the book".to_string(),
+            };
+        }
+
+        let filled = current.quantity - current.remaining_quantity;
+        let mut amended = current.clone();
+        amended.price = new_price.or(current.price);
+        amended.quantity = new_quantity.unwrap_or(current.quantity);
+        if amended.quantity <= filled {
+            return OrderResponse::Error {
+                code: OrderError::InvalidQuantity,
+                message: format!("quantity must be greater than the {filled} already filled"),
+            };
+        }
+        amended.remaining_quantity = amended.quantity - filled;
+        amended.visible_quantity = current
+            .visible_quantity
+            .map(|visible| visible.min(amended.remaining_quantity));
+
+        if let Err(response) = self.validate(&amended) {
+            return response;
+        }
+
+        if amended.price == current.price && amended.quantity <= current.quantity {
+            return self.shrink_in_place(&current, amended);
+        }
+
+        let accounts = Arc::clone(&self.accounts);
+        let mut accounts = accounts.lock().unwrap();
+        // The original's reservation counts towards the replacement. Funds are
+        // checked before it is pulled, so a rejected amendment leaves it
+        // resting untouched.
+        self.release(&mut accounts, &current, current.remaining_quantity);
+        if let Err(message) = self.check_funds(&accounts, &amended) {
+            self.reserve(&mut accounts, &current, current.remaining_quantity);
+            return OrderResponse::Error {
+                code: OrderError::InsufficientFunds,
+                message,
+            };
+        }
+
+        self.take_from_level(&current);
+        self.orders.remove(order_id);
+        self.open_orders.closed(&current.user_id);
+        self.events.publish(BookEvent::OrderCancelled {
+            order_id: order_id.to_string(),
+        });
+        amended.sequence = sequence;
+        let response = self.execute(&mut accounts, amended);
+        self.trigger_stops(&mut accounts);
+        self.repeg(&mut accounts);
+        self.publish_updates();
+        response
+    }
+
+    /// Looks an order up among open orders first, then recently closed ones.
+    pub fn get_order(&self, order_id: &str) -> Option<Order> {
+        self.orders
+            .get(order_id)
+            .or_else(|| self.history.get(order_id))
+            .cloned()
+    }
+
+    /// Open orders for `user_id`, resting or waiting on a stop trigger.
+    pub fn user_orders(&self, user_id: &str) -> Vec<Order> {
+        self.orders
+            .values()
+            .filter(|o| o.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    /// The last `limit` mid-price samples, oldest first.
+    pub fn mid_prices(&self, limit: usize) -> Vec<MidPriceSample> {
+        let skip = self.mid_prices.len().saturating_sub(limit);
+        self.mid_prices.iter().skip(skip).cloned().collect()
+    }
+
+    /// Up to `limit` of the most recent trades, newest first, starting after
+    /// the trade with id `before` if one is given. A `before` no longer on the
+    /// tape was older than everything still on it, so nothing is returned.
+    pub fn recent_trades(&self, limit: usize, before: Option<&str>) -> Vec<Trade> {
+        let newer = match before {
+            Some(id) => match self.recent_trades.iter().rposition(|t| t.id == id) {
+                Some(position) => self.recent_trades.len() - position,
+                None => return Vec::new(),
+            },
+            None => 0,
+        };
+        self.recent_trades
+            .iter()
+            .rev()
+            .skip(newer)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Volume and price range over the 24 hours up to `now`.
+    pub fn daily_stats(&self, now: u64) -> DailyStats {
+        self.daily
+            .summary(&self.market.symbol, self.last_trade_price, now)
+    }
+
+    /// Up to `limit` candles of width `interval_secs`, oldest first, or `None`
+    /// if that width is not aggregated.
+    pub fn candles(&self, interval_secs: u64, limit: usize) -> Option<Vec<Candle>> {
+        self.candles
+            .iter()
+            .find(|series| series.interval_secs == interval_secs)
+            .map(|series| series.latest(limit))
+    }
+
+    /// Captures the open orders so the book can be rebuilt with `restore`.
+    pub fn export(&self) -> BookState {
+        BookState {
+            symbol: self.market.symbol.clone(),
+            bids: self.bids.values().rev().flatten().cloned().collect(),
+            asks: self.asks.values().flatten().cloned().collect(),
+            stops: self.stops.clone(),
+            delayed: self
+                .delayed
+                .iter()
+                .filter_map(|(.., id)| self.orders.get(id).cloned())
+                .collect(),
+            brackets: self.brackets.clone(),
+            last_trade_price: self.last_trade_price,
+            next_trade_id: self.next_trade_id,
+            status: self.status,
+        }
+    }
+
+    /// What placing `order` now would answer, worked out on a copy of the book
+    /// so nothing real changes and nothing is published. Only the balances an
+    /// order here can move are copied: its user's and those of users with
+    /// orders in this book, so the copy costs time in the size of the book.
+    pub fn simulate_order(&self, order: Order, now: u64) -> OrderResponse {
+        let accounts = {
+            let accounts = self.accounts.lock().unwrap();
+            let users: HashSet<&str> = self
+                .orders
+                .values()
+                .map(|o| o.user_id.as_str())
+                .chain([order.user_id.as_str()])
+                .collect();
+            let touched = users
+                .into_iter()
+                .filter_map(|user| Some((user.to_string(), accounts.get(user)?.clone())))
+                .collect();
+            Arc::new(Mutex::new(touched))
+        };
+        let mut scratch = Orderbook::new(self.market.clone(), accounts, Feeds::new(1));
+        scratch.restore(self.export());
+        scratch.add_order(order, now)
+    }
+
+    /// Puts back the orders from a previous `export`. Balances are restored
+    /// separately, so nothing is re-checked or matched here.
+    pub fn restore(&mut self, state: BookState) {
+        for order in state.bids.into_iter().chain(state.asks) {
+            self.add_to_book(order);
+        }
+        for stop in state.stops {
+            self.orders.insert(stop.id.clone(), stop.clone());
+            self.open_orders.opened(&stop.user_id);
+            self.stops.push(stop);
+        }
+        for order in state.delayed {
+            self.delay(order);
+        }
+        self.brackets = state.brackets;
+        self.last_trade_price = state.last_trade_price;
+        self.next_trade_id = state.next_trade_id.max(1);
+        self.status = state.status;
+        self.changed_bids.clear();
+        self.changed_asks.clear();
+    }
+
+    pub fn get_snapshot(&self) -> OrderbookSnapshot {
+        let tick_size = self.market.tick_size;
+        let level = |(price_key, orders): (&u64, &VecDeque<Order>)| PriceLevel {
+            price: Self::key_to_price(*price_key, tick_size),
+            quantity: orders.iter().map(Order::visible).sum(),
+            order_count: orders.len(),
+        };
+
+        OrderbookSnapshot {
+            sequence: self.sequence,
+            bids: self.bids.iter().rev().map(level).collect(),
+            asks: self.asks.iter().map(level).collect(),
+            checksum: self.checksum(),
+        }
+    }
+
+    pub fn get_full_snapshot(&self) -> FullSnapshot {
+        let tick_size = self.market.tick_size;
+        let level = |(price_key, orders): (&u64, &VecDeque<Order>)| {
+            let entries = orders
+                .iter()
+                .map(|o| BookEntry {
+                    order_id: o.id.clone(),
+                    user_id: Some(o.user_id.clone()),
+                    remaining_quantity: o.visible(),
+                    sequence: o.sequence,
+                })
+                .collect();
+            (Self::key_to_price(*price_key, tick_size), entries)
+        };
+
+        FullSnapshot {
+            sequence: self.sequence,
+            bids: self.bids.iter().rev().map(level).collect(),
+            asks: self.asks.iter().map(level).collect(),
+        }
+    }
+
+    /// Builds every market from the last save and replays the log records
+    /// written after it, in log order. Returns the books by symbol and the last
+    /// order sequence handed out.
+    pub fn recover(
+        markets: Vec<MarketConfig>,
+        accounts: &Accounts,
+        feeds: &Feeds,
+        recovery: Recovery,
+    ) -> (HashMap<String, Orderbook>, u64) {
+        let mut books: HashMap<String, Orderbook> = markets
+            .into_iter()
+            .map(|m| {
+                let symbol = m.symbol.clone();
+                (symbol, Orderbook::new(m, accounts.clone(), feeds.clone()))
+            })
+            .collect();
+
+        for state in recovery.books {
+            match books.get_mut(&state.symbol) {
+                Some(orderbook) => orderbook.restore(state),
+                None => {
+                    tracing::warn!(symbol = %state.symbol, "dropping saved orders for unknown symbol")
+                }
+            }
+        }
+
+        let mut next_sequence = recovery.order_sequence;
+        let replayed = recovery.records.len();
+        for record in recovery.records {
+            let now = record.timestamp;
+            match record.entry {
+                WalEntry::AddOrder(order) => {
+                    next_sequence = next_sequence.max(order.sequence);
+                    if let Some(orderbook) = books.get_mut(&order.symbol) {
+                        orderbook.add_order(*order, now);
+                    }
+                }
+                WalEntry::AddOco { first, second } => {
+                    next_sequence = next_sequence.max(first.sequence).max(second.sequence);
+                    if let Some(orderbook) = books.get_mut(&first.symbol) {
+                        orderbook.add_oco(*first, *second, now);
+                    }
+                }
+                WalEntry::AddBracket { entry, exits } => {
+                    next_sequence = next_sequence
+                        .max(entry.sequence)
+                        .max(exits.take_profit.sequence)
+                        .max(exits.stop.sequence);
+                    if let Some(orderbook) = books.get_mut(&entry.symbol) {
+                        orderbook.add_bracket(*entry, *exits, now);
+                    }
+                }
+                WalEntry::CancelOrder {
+                    order_id,
+                    user_id,
+                    cancelled_by,
+                } => {
+                    if let Some(orderbook) = Self::holding(&mut books, &order_id) {
+                        let cancelled_by = cancelled_by.as_deref().unwrap_or(&user_id);
+                        orderbook.cancel_owned(&order_id, &user_id, cancelled_by, now);
+                    }
+                }
+                WalEntry::CancelAllForUser { user_id, symbol } => {
+                    for orderbook in Self::selected(&mut books, symbol.as_deref()) {
+                        orderbook.cancel_all_for_user(&user_id, now);
+                    }
+                }
+                WalEntry::AmendOrder {
+                    order_id,
+                    user_id,
+                    new_price,
+                    new_quantity,
+                    sequence,
+                } => {
+                    next_sequence = next_sequence.max(sequence);
+                    if let Some(orderbook) = Self::holding(&mut books, &order_id) {
+                        orderbook.amend_owned(
+                            &order_id,
+                            &user_id,
+                            new_price,
+                            new_quantity,
+                            sequence,
+                            now,
+                        );
+                    }
+                }
+                WalEntry::ReduceOrder {
+                    order_id,
+                    user_id,
+                    quantity,
+                } => {
+                    if let Some(orderbook) = Self::holding(&mut books, &order_id) {
+                        orderbook.reduce_owned(&order_id, &user_id, quantity, now);
+                    }
+                }
+                WalEntry::Deposit {
+                    user_id,
+                    asset,
+                    amount,
+                } => {
+                    let _ = accounts
+                        .lock()
+                        .unwrap()
+                        .entry(user_id)
+                        .or_default()
+                        .deposit(asset.as_deref(), amount, now);
+                }
+                WalEntry::Withdraw {
+                    user_id,
+                    asset,
+                    amount,
+                } => {
+                    let _ = accounts
+                        .lock()
+                        .unwrap()
+                        .entry(user_id)
+                        .or_default()
+                        .withdraw(asset.as_deref(), amount, now);
+                }
+                WalEntry::ExpireOrders { symbol } => {
+                    for orderbook in Self::selected(&mut books, symbol.as_deref()) {
+                        orderbook.expire_orders(now);
+                    }
+                }
+                WalEntry::ActivateOrders { symbol } => {
+                    if let Some(orderbook) = books.get_mut(&symbol) {
+                        orderbook.activate_orders(now);
+                    }
+                }
+                WalEntry::SetMarketStatus { symbol, status } => {
+                    if let Some(orderbook) = books.get_mut(&symbol) {
+                        orderbook.set_status(status, now);
+                    }
+                }
+                WalEntry::Signup(_) => {}
+            }
+        }
+        if replayed > 0 {
+            tracing::info!(records = replayed, "replayed write-ahead log records");
+        }
+
+        (books, next_sequence)
+    }
+
+    /// The book `order_id` is open in, if any.
+    fn holding<'a>(
+        books: &'a mut HashMap<String, Orderbook>,
+        order_id: &str,
+    ) -> Option<&'a mut Orderbook> {
+        books.values_mut().find(|b| b.orders.contains_key(order_id))
+    }
+
+    /// The book for `symbol`, or every book if it is `None`.
+    fn selected<'a>(
+        books: &'a mut HashMap<String, Orderbook>,
+        symbol: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a mut Orderbook> {
+        books
+            .iter_mut()
+            .filter(move |(s, _)| symbol.is_none_or(|symbol| symbol == s.as_str()))
+            .map(|(_, orderbook)| orderbook)
+    }
+
+    /// Runs this market until every sender of `rx` is gone. Commands are
+    /// handled one at a time in arrival order, each logged before it is
+    /// applied; `order_sequence` is shared with the other markets so
+    /// sequences stay unique across all of them.
+    pub async fn run_orderbook_engine(
+        mut self,
+        mut rx: tokio::sync::mpsc::Receiver<OrderbookCommand>,
+        wal: SharedWal,
+        order_sequence: Arc<AtomicU64>,
+    ) {
+        let mut sweep =
+            tokio::time::interval(std::time::Duration::from_millis(EXPIRY_SWEEP_INTERVAL_MS));
+        sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            let command = tokio::select! {
+                command = rx.recv() => match command {
+                    Some(command) => command,
+                    None => break,
+                },
+                _ = sweep.tick() => {
+                    let now = now_millis();
+                    self.expire(&wal, now);
+                    self.activate(&wal, now);
+                    self.daily.age_out(now);
+                    continue;
+                }
+            };
+
+            // Expire first, so nothing matches against an order past its
+            // expiry, and let due delayed orders in ahead of the command.
+            // Checks made before logging see the command's time as well, so
+            // an order they pass is not then refused when applied.
+            let now = now_millis();
+            self.now = now;
+            self.expire(&wal, now);
+            self.activate(&wal, now);
+
+            match command {
+                OrderbookCommand::AddOrder {
+                    mut order,
+                    ack,
+                    response,
+                } => {
+                    // A retry is answered without being logged or applied again.
+                    if let Some(repeated) = self.repeated(&order) {
+                        let _ = response.send(repeated);
+                        continue;
+                    }
+                    if self.abandoned(&response, "order") {
+                        continue;
+                    }
+                    let (order_id, user_id) = (order.id.clone(), order.user_id.clone());
+                    if let Some(rejected) = self.rejected(&order) {
+                        Self::trace_outcome("order", &order_id, &user_id, &rejected);
+                        self.reply(response, rejected, "order");
+                        continue;
+                    }
+                    order.sequence = order_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                    let entry = WalEntry::AddOrder(order.clone());
+                    let sequence = order.sequence;
+                    let result = persistence::logged(&wal, now, entry, || {
+                        if let Some(ack) = ack {
+                            let acknowledged = OrderAck::Acknowledged {
+                                order_id: order_id.clone(),
+                                sequence,
+                            };
+                            let _ = ack.send(acknowledged);
+                        }
+                        self.add_order(*order, now)
+                    })
+                    .unwrap_or_else(Self::log_failed);
+                    Self::trace_outcome("order", &order_id, &user_id, &result);
+                    self.reply(response, result, "order");
+                }
+                OrderbookCommand::SimulateOrder {
+                    mut order,
+                    response,
+                } => {
+                    // Ranked behind every order placed so far, as it would be
+                    // if placed now, without using up a sequence number.
+                    order.sequence = order_sequence.load(Ordering::SeqCst) + 1;
+                    let _ = response.send(self.simulate_order(*order, now));
+                }
+                OrderbookCommand::AddOco {
+                    mut first,
+                    mut second,
+                    response,
+                } => {
+                    if self.abandoned(&response, "OCO order") {
+                        continue;
+                    }
+                    first.sequence = order_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                    second.sequence = order_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                    let entry = WalEntry::AddOco {
+                        first: first.clone(),
+                        second: second.clone(),
+                    };
+                    let ids = [
+                        (first.id.clone(), first.user_id.clone()),
+                        (second.id.clone(), second.user_id.clone()),
+                    ];
+                    let result = persistence::logged(&wal, now, entry, || {
+                        self.add_oco(*first, *second, now)
+                    })
+                    .unwrap_or_else(|message| OcoResponse {
+                        first: Self::log_failed(message.clone()),
+                        second: Self::log_failed(message),
+                    });
+                    for ((order_id, user_id), leg) in
+                        ids.iter().zip([&result.first, &result.second])
+                    {
+                        Self::trace_outcome("OCO order", order_id, user_id, leg);
+                    }
+                    self.reply(response, result, "OCO order");
+                }
+                OrderbookCommand::AddBracket {
+                    mut entry,
+                    mut exits,
+                    response,
+                } => {
+                    if let Some(repeated) = self.repeated(&entry) {
+                        let _ = response.send(repeated);
+                        continue;
+                    }
+                    if self.abandoned(&response, "bracket order") {
+                        continue;
+                    }
+                    if let Err(rejected) = self.validate_bracket(&entry, &exits) {
+                        let (order_id, user_id) = (&entry.id, &entry.user_id);
+                        Self::trace_outcome("bracket order", order_id, user_id, &rejected);
+                        self.reply(response, rejected, "bracket order");
+                        continue;
+                    }
+                    for order in [&mut *entry, &mut exits.take_profit, &mut exits.stop] {
+                        order.sequence = order_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                    }
+                    let (order_id, user_id) = (entry.id.clone(), entry.user_id.clone());
+                    let entry_log = WalEntry::AddBracket {
+                        entry: entry.clone(),
+                        exits: exits.clone(),
+                    };
+                    let result = persistence::logged(&wal, now, entry_log, || {
+                        self.add_bracket(*entry, *exits, now)
+                    })
+                    .unwrap_or_else(Self::log_failed);
+                    Self::trace_outcome("bracket order", &order_id, &user_id, &result);
+                    self.reply(response, result, "bracket order");
+                }
+                OrderbookCommand::CancelOrder {
+                    order_id,
+                    user_id,
+                    cancelled_by,
+                    response,
+                } => {
+                    let entry = WalEntry::CancelOrder {
+                        order_id: order_id.clone(),
+                        user_id: user_id.clone(),
+                        cancelled_by: Some(cancelled_by.clone()),
+                    };
+                    let result = persistence::logged(&wal, now, entry, || {
+                        self.cancel_owned(&order_id, &user_id, &cancelled_by, now)
+                    })
+                    .unwrap_or_else(Self::log_failed);
+                    Self::trace_outcome("cancel", &order_id, &user_id, &result);
+                    self.reply(response, result, "cancel");
+                }
+                OrderbookCommand::AmendOrder {
+                    order_id,
+                    user_id,
+                    new_price,
+                    new_quantity,
+                    response,
+                } => {
+                    if self.abandoned(&response, "amendment") {
+                        continue;
+                    }
+                    let sequence = order_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                    let entry = WalEntry::AmendOrder {
+                        order_id: order_id.clone(),
+                        user_id: user_id.clone(),
+                        new_price,
+                        new_quantity,
+                        sequence,
+                    };
+                    let result = persistence::logged(&wal, now, entry, || {
+                        self.amend_owned(
+                            &order_id,
+                            &user_id,
+                            new_price,
+                            new_quantity,
+                            sequence,
+                            now,
+                        )
+                    })
+                    .unwrap_or_else(Self::log_failed);
+                    Self::trace_outcome("amendment", &order_id, &user_id, &result);
+                    self.reply(response, result, "amendment");
+                }
+                OrderbookCommand::ReduceOrder {
+                    order_id,
+                    user_id,
+                    quantity,
+                    response,
+                } => {
+                    if self.abandoned(&response, "reduction") {
+                        continue;
+                    }
+                    let entry = WalEntry::ReduceOrder {
+                        order_id: order_id.clone(),
+                        user_id: user_id.clone(),
+                        quantity,
+                    };
+                    let result = persistence::logged(&wal, now, entry, || {
+                        self.reduce_owned(&order_id, &user_id, quantity, now)
+                    })
+                    .unwrap_or_else(Self::log_failed);
+                    Self::trace_outcome("reduction", &order_id, &user_id, &result);
+                    self.reply(response, result, "reduction");
+                }
+                OrderbookCommand::CancelAllForUser { user_id, response } => {
+                    let entry = WalEntry::CancelAllForUser {
+                        user_id: user_id.clone(),
+                        symbol: Some(self.market.symbol.clone()),
+                    };
+                    let result = persistence::logged(&wal, now, entry, || {
+                        self.cancel_all_for_user(&user_id, now)
+                    });
+                    match &result {
+                        Ok(cancelled) => {
+                            tracing::info!(user_id, cancelled = cancelled.len(), "cancel-all")
+                        }
+                        Err(error) => tracing::error!(user_id, error, "cancel-all not applied"),
+                    }
+                    self.reply(response, result, "cancel-all");
+                }
+                OrderbookCommand::GetOrder { order_id, response } => {
+                    let _ = response.send(self.get_order(&order_id));
+                }
+                OrderbookCommand::GetUserOrders { user_id, response } => {
+                    let _ = response.send(self.user_orders(&user_id));
+                }
+                OrderbookCommand::GetSnapshot { response } => {
+                    let _ = response.send(self.get_snapshot());
+                }
+                OrderbookCommand::GetFullSnapshot { response } => {
+                    let _ = response.send(self.get_full_snapshot());
+                }
+                OrderbookCommand::GetTicker { response } => {
+                    let _ = response.send(self.ticker());
+                }
+                OrderbookCommand::GetMarketInfo { response } => {
+                    let _ = response.send(self.market_info());
+                }
+                OrderbookCommand::GetFees { user_id, response } => {
+                    let _ = response.send(self.fees(&user_id, now));
+                }
+                OrderbookCommand::GetCandles {
+                    interval_secs,
+                    limit,
+                    response,
+                } => {
+                    let _ = response.send(self.candles(interval_secs, limit));
+                }
+                OrderbookCommand::GetStats { response } => {
+                    let _ = response.send(self.daily_stats(now));
+                }
+                OrderbookCommand::GetMidPrices { limit, response } => {
+                    let _ = response.send(self.mid_prices(limit));
+                }
+                OrderbookCommand::GetRecentTrades {
+                    limit,
+                    before,
+                    response,
+                } => {
+                    let _ = response.send(self.recent_trades(limit, before.as_deref()));
+                }
+                #[cfg(feature = "test-endpoints")]
+                OrderbookCommand::Reset { response } => {
+                    let accounts = Arc::clone(&self.accounts);
+                    self = Self::new(self.market.clone(), accounts, self.feeds.clone());
+                    let _ = response.send(());
+                }
+                OrderbookCommand::SetStatus { status, response } => {
+                    let entry = WalEntry::SetMarketStatus {
+                        symbol: self.market.symbol.clone(),
+                        status,
+                    };
+                    let result =
+                        persistence::logged(&wal, now, entry, || self.set_status(status, now));
+                    match &result {
+                        Ok(()) => tracing::info!(status = ?status, "status change"),
+                        Err(error) => tracing::error!(error, "status change not applied"),
+                    }
+                    self.reply(response, result, "status change");
+                }
+                OrderbookCommand::ExportState { response, resume } => {
+                    let _ = response.send(self.export());
+                    let _ = resume.await;
+                }
+            }
+        }
+
+        tracing::info!("orderbook engine stopped");
+    }
+
+    /// Whether the caller of a command that would add an order has already
+    /// given up, e.g. after a timeout. Such commands are skipped so nobody is
+    /// left with an order they were never told about; cancels are applied
+    /// regardless.
+    fn abandoned<T>(&self, response: &tokio::sync::oneshot::Sender<T>, command: &str) -> bool {
+        let abandoned = response.is_closed();
+        if abandoned {
+            tracing::warn!(command, "skipped: its caller had gone");
+        }
+        abandoned
+    }
+
+    /// Hands `result` back to the caller. A caller that has gone by now only
+    /// loses the reply: the change is logged and stands, like any other.
+    fn reply<T>(&self, response: tokio::sync::oneshot::Sender<T>, result: T, command: &str) {
+        if response.send(result).is_err() {
+            tracing::warn!(command, "applied after its caller had gone");
+        }
+    }
+
+    /// Records what a command made of one order. Rejections are routine, so
+    /// only a failure to log the command is reported as an error.
+    fn trace_outcome(command: &str, order_id: &str, user_id: &str, response: &OrderResponse) {
+        match response {
+            OrderResponse::Error {
+                code: OrderError::LogWriteFailed,
+                message,
+            } => tracing::error!(order_id, user_id, error = %message, "{command} not applied"),
+            OrderResponse::Error { code, .. } => {
+                tracing::info!(order_id, user_id, outcome = "error", code = ?code, "{command}")
+            }
+            _ => tracing::info!(order_id, user_id, outcome = response.status(), "{command}"),
+        }
+    }
+
+    /// The command must not be applied if it could not be logged, or a restart
+    /// would silently lose it.
+    fn log_failed(message: String) -> OrderResponse {
+        OrderResponse::Error {
+            code: OrderError::LogWriteFailed,
+            message,
+        }
+    }
+
+    /// Expires due orders. The sweep is logged first so a replay expires
+    /// exactly the same orders at the same point.
+    fn expire(&mut self, wal: &SharedWal, now: u64) {
+        if !self.has_due_expiry(now) {
+            return;
+        }
+        let entry = WalEntry::ExpireOrders {
+            symbol: Some(self.market.symbol.clone()),
+        };
+        if let Err(message) = persistence::logged(wal, now, entry, || self.expire_orders(now)) {
+            tracing::error!(error = %message, "expiry sweep not applied");
+        }
+    }
+
+    /// Puts in due delayed orders, logged first like the expiry sweep.
+    fn activate(&mut self, wal: &SharedWal, now: u64) {
+        if !self.has_due_activation(now) {
+            return;
+        }
+        let entry = WalEntry::ActivateOrders {
+            symbol: self.market.symbol.clone(),
+        };
+        if let Err(message) = persistence::logged(wal, now, entry, || self.activate_orders(now)) {
+            tracing::error!(error = %message, "activation sweep not applied");
+        }
+    }
+
+    fn check_owner(&self, order_id: &str, user_id: &str) -> Result<(), OrderResponse> {
+        match self.orders.get(order_id) {
+            Some(order) if order.user_id != user_id => Err(OrderResponse::Error {
+                code: OrderError::NotOrderOwner,
+                message: "order belongs to another user".to_string(),
+            }),
+            Some(_) => Ok(()),
+            None => Err(OrderResponse::Error {
+                code: OrderError::OrderNotFound,
+                message: "order not found or already filled".to_string(),
+            }),
+        }
+    }
+
+    /// Cancels `user_id`'s order at the request of `cancelled_by`. It only
+    /// counts towards the owner's cancels if they asked for it themselves.
+    fn cancel_owned(
+        &mut self,
+        order_id: &str,
+        user_id: &str,
+        cancelled_by: &str,
+        now: u64,
+    ) -> OrderResponse {
+        self.now = now;
+        if let Err(response) = self.check_owner(order_id, user_id) {
+            return response;
+        }
+        let response = self.cancel_order(order_id);
+        if matches!(response, OrderResponse::Cancelled { .. }) && cancelled_by == user_id {
+            self.accounts
+                .lock()
+                .unwrap()
+                .entry(user_id.to_string())
+                .or_default()
+                .record_activity(self.now, |a| a.cancelled += 1);
+        }
+        response
+    }
+
+    fn amend_owned(
+        &mut self,
+        order_id: &str,
+        user_id: &str,
+        new_price: Option<Decimal>,
+        new_quantity: Option<Decimal>,
+        sequence: u64,
+        now: u64,
+    ) -> OrderResponse {
+        match self.check_owner(order_id, user_id) {
+            Ok(()) => self.amend_order(order_id, new_price, new_quantity, sequence, now),
+            Err(response) => response,
+        }
+    }
+
+    fn reduce_owned(
+        &mut self,
+        order_id: &str,
+        user_id: &str,
+        quantity: Decimal,
+        now: u64,
+    ) -> OrderResponse {
+        match self.check_owner(order_id, user_id) {
+            Ok(()) => self.reduce_order(order_id, quantity, now),
+            Err(response) => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::WalRecord;
+
+    const SYMBOL: &str = "BTC-USD";
+    const TRADERS: [&str; 3] = ["alice", "bob", "carol"];
+
+    /// Quote and BTC each trader starts with.
+    fn funding() -> Decimal {
+        Decimal::from(1_000_000)
+    }
+
+    fn funded() -> Accounts {
+        let account = Account {
+            balance: funding(),
+            assets: HashMap::from([("BTC".to_string(), funding())]),
+            ..Account::default()
+        };
+        Arc::new(Mutex::new(
+            TRADERS
+                .iter()
+                .map(|trader| (trader.to_string(), account.clone()))
+                .collect(),
+        ))
+    }
+
+    /// A BTC market with a one-cent tick and no fees, bands or minimums.
+    fn market() -> MarketConfig {
+        MarketConfig::new(SYMBOL, "BTC", Decimal::new(1, 2))
+    }
+
+    fn book() -> (Orderbook, Accounts) {
+        let accounts = funded();
+        let book = Orderbook::new(market(), Arc::clone(&accounts), Feeds::new(16));
+        (book, accounts)
+    }
+
+    fn order(
+        user_id: &str,
+        side: OrderSide,
+        order_type: OrderType,
+        price: Option<Decimal>,
+        quantity: Decimal,
+    ) -> Order {
+        Order {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            symbol: SYMBOL.to_string(),
+            side,
+            order_type,
+            price,
+            quantity,
+            remaining_quantity: quantity,
+            quote_quantity: None,
+            visible_quantity: None,
+            linked_order_id: None,
+            client_order_id: None,
+            expires_at: None,
+            activates_at: None,
+            worst_average_price: None,
+            timestamp: 0,
+            sequence: 0,
+            self_trade_prevention: SelfTradePrevention::default(),
+            reduce_only: false,
+            status: OrderStatus::Open,
+        }
+    }
+
+    fn limit(user_id: &str, side: OrderSide, price: i64, quantity: i64) -> Order {
+        order(
+            user_id,
+            side,
+            OrderType::LimitOrder,
+            Some(Decimal::from(price)),
+            Decimal::from(quantity),
+        )
+    }
+
+    fn market_order(user_id: &str, side: OrderSide, quantity: i64) -> Order {
+        let order_type = OrderType::MarketOrder {
+            protection_price: None,
+        };
+        order(user_id, side, order_type, None, Decimal::from(quantity))
+    }
+
+    /// Why `response` rejected its order, if it did.
+    fn rejection(response: &OrderResponse) -> Option<OrderError> {
+        match response {
+            OrderResponse::Error { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Trades `response` reports; none unless the order traded.
+    fn trades(response: &OrderResponse) -> &[Trade] {
+        match response {
+            OrderResponse::Filled { trades, .. }
+            | OrderResponse::PartiallyFilled { trades, .. } => trades,
+            _ => &[],
+        }
+    }
+
+    /// Every order resting on a level is in `orders` as it is queued, rests
+    /// at its own price and still has something left; every other entry in
+    /// `orders` is a stop or a delayed order. Quote and BTC are neither made
+    /// nor lost, and no holding goes negative.
+    fn check_book(book: &Orderbook, accounts: &Accounts) {
+        let mut resting = 0;
+        for (bids, levels) in [(true, &book.bids), (false, &book.asks)] {
+            for (ticks, level) in levels {
+                assert!(!level.is_empty(), "empty level at {ticks}");
+                for queued in level {
+                    assert_eq!(matches!(queued.side, OrderSide::Buy), bids);
+                    assert!(queued.remaining_quantity > Decimal::ZERO);
+                    let price = queued.price.unwrap();
+                    assert_eq!(
+                        Orderbook::price_to_key(price, book.market.tick_size),
+                        *ticks
+                    );
+                    let tracked = &book.orders[&queued.id];
+                    assert_eq!(tracked.remaining_quantity, queued.remaining_quantity);
+                    resting += 1;
+                }
+            }
+        }
+        let waiting = book.stops.len() + book.delayed.len();
+        assert_eq!(book.orders.len(), resting + waiting);
+
+        let accounts = accounts.lock().unwrap();
+        let (mut quote, mut base) = (Decimal::ZERO, Decimal::ZERO);
+        for account in accounts.values() {
+            let held = account.assets.get("BTC").copied().unwrap_or_default();
+            let reserved = account
+                .reserved_assets
+                .get("BTC")
+                .copied()
+                .unwrap_or_default();
+            for amount in [account.balance, account.reserved, held, reserved] {
+                assert!(amount >= Decimal::ZERO, "negative holding: {account:?}");
+            }
+            quote += account.balance + account.reserved;
+            base += held + reserved;
+        }
+        let funded = funding() * Decimal::from(TRADERS.len());
+        assert_eq!(quote, funded, "quote not conserved");
+        assert_eq!(base, funded, "BTC not conserved");
+    }
+
+    #[test]
+    fn replay_skips_a_deposit_that_would_overflow() {
+        let accounts: Accounts = Arc::new(Mutex::new(HashMap::new()));
+        let deposit = |sequence| WalRecord {
+            sequence,
+            timestamp: sequence,
+            entry: WalEntry::Deposit {
+                user_id: "alice".to_string(),
+                asset: None,
+                amount: Decimal::MAX,
+            },
+        };
+        let recovery = Recovery {
+            books: Vec::new(),
+            records: vec![deposit(1), deposit(2)],
+            order_sequence: 0,
+        };
+
+        Orderbook::recover(default_markets(), &accounts, &Feeds::new(1), recovery);
+
+        assert_eq!(accounts.lock().unwrap()["alice"].balance, Decimal::MAX);
+    }
+
+    #[test]
+    fn settlement_that_would_overflow_leaves_the_leg_unsettled() {
+        let (mut book, accounts) = book();
+        let nearly_full = Decimal::MAX - Decimal::ONE;
+        accounts.lock().unwrap().get_mut("alice").unwrap().balance = nearly_full;
+
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+        let response = book.add_order(limit("bob", OrderSide::Buy, 100, 1), 2);
+
+        assert_eq!(response.status(), "filled");
+        let accounts = accounts.lock().unwrap();
+        assert_eq!(accounts["alice"].balance, nearly_full);
+        assert_eq!(accounts["bob"].balance, funding() - Decimal::from(100));
+        assert_eq!(accounts["bob"].assets["BTC"], funding() + Decimal::ONE);
+    }
+
+    #[test]
+    fn only_accepted_orders_count_as_placed() {
+        let (mut book, accounts) = book();
+        let placed = |accounts: &Accounts| {
+            accounts.lock().unwrap()["bob"]
+                .recent_activity(3, 60_000)
+                .placed
+        };
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+
+        let mut collared = limit("bob", OrderSide::Buy, 100, 1);
+        collared.worst_average_price = Some(Decimal::from(99));
+        let response = book.add_order(collared, 2);
+        assert_eq!(rejection(&response), Some(OrderError::PriceCollarBreached));
+        assert_eq!(placed(&accounts), 0);
+
+        let response = book.add_order(limit("bob", OrderSide::Buy, 100, 1), 3);
+        assert_eq!(response.status(), "filled");
+        assert_eq!(placed(&accounts), 1);
+    }
+
+    #[test]
+    fn trades_are_numbered_and_stamped_with_the_command_time() {
+        let (mut book, _) = book();
+        let now = 1_700_000_000_000;
+        for (price, n) in [(101, 2), (102, 3), (103, 4)] {
+            book.add_order(limit("alice", OrderSide::Sell, price, 1), now + n);
+        }
+
+        let sweep = book.add_order(market_order("bob", OrderSide::Buy, 3), now + 10);
+        book.add_order(limit("carol", OrderSide::Sell, 99, 1), now + 20);
+        let cross = book.add_order(limit("bob", OrderSide::Buy, 99, 1), now + 30);
+
+        let stamped: Vec<(&str, u64, Decimal)> = trades(&sweep)
+            .iter()
+            .chain(trades(&cross))
+            .map(|t| (t.id.as_str(), t.timestamp, t.price))
+            .collect();
+        assert_eq!(
+            stamped,
+            [
+                ("BTC-USD-1", now + 10, Decimal::from(101)),
+                ("BTC-USD-2", now + 10, Decimal::from(102)),
+                ("BTC-USD-3", now + 10, Decimal::from(103)),
+                ("BTC-USD-4", now + 30, Decimal::from(99)),
+            ]
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn matching_conserves_quantity(
+            commands in proptest::collection::vec(
+                (0..TRADERS.len(), proptest::bool::ANY, proptest::bool::ANY, 9_500..10_500i64, 1..5_000i64),
+                1..60,
+            ),
+        ) {
+            let (mut book, accounts) = book();
+            for (n, (trader, sell, market, cents, hundredths)) in commands.into_iter().enumerate() {
+                let side = if sell { OrderSide::Sell } else { OrderSide::Buy };
+                let quantity = Decimal::new(hundredths, 2);
+                let incoming = match market {
+                    true => {
+                        let order_type = OrderType::MarketOrder { protection_price: None };
+                        order(TRADERS[trader], side, order_type, None, quantity)
+                    }
+                    false => order(
+                        TRADERS[trader],
+                        side,
+                        OrderType::LimitOrder,
+                        Some(Decimal::new(cents, 2)),
+                        quantity,
+                    ),
+                };
+                let response = book.add_order(incoming, n as u64 + 1);
+
+                let traded: Decimal = trades(&response).iter().map(|t| t.quantity).sum();
+                match &response {
+                    OrderResponse::Filled { filled_quantity, .. } => {
+                        proptest::prop_assert_eq!(traded, *filled_quantity);
+                        proptest::prop_assert_eq!(traded, quantity);
+                    }
+                    OrderResponse::PartiallyFilled { filled_quantity, remaining_quantity, .. } => {
+                        proptest::prop_assert_eq!(traded, *filled_quantity);
+                        proptest::prop_assert!(*remaining_quantity > Decimal::ZERO);
+                        proptest::prop_assert_eq!(traded + remaining_quantity, quantity);
+                    }
+                    _ => proptest::prop_assert!(traded.is_zero()),
+                }
+                for trade in trades(&response) {
+                    proptest::prop_assert!(trade.quantity > Decimal::ZERO);
+                }
+                check_book(&book, &accounts);
+            }
+        }
+    }
+
+    #[test]
+    fn fully_filled_maker_leaves_the_orders_map() {
+        let (mut book, _) = book();
+        let maker = limit("alice", OrderSide::Sell, 100, 2);
+        let maker_id = maker.id.clone();
+        book.add_order(maker, 1);
+
+        let response = book.add_order(limit("bob", OrderSide::Buy, 100, 2), 2);
+
+        assert_eq!(response.status(), "filled");
+        assert!(!book.orders.contains_key(&maker_id));
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn resting_limit_order_can_be_looked_up_and_tracks_its_fills() {
+        let (mut book, _) = book();
+        let resting = limit("alice", OrderSide::Buy, 100, 5);
+        let id = resting.id.clone();
+
+        assert_eq!(book.add_order(resting, 1).status(), "placed");
+        let found = book.get_order(&id).unwrap();
+        assert_eq!(found.price, Some(Decimal::from(100)));
+        assert_eq!(found.remaining_quantity, Decimal::from(5));
+
+        book.add_order(limit("bob", OrderSide::Sell, 100, 2), 2);
+        assert_eq!(
+            book.get_order(&id).unwrap().remaining_quantity,
+            Decimal::from(3)
+        );
+    }
+
+    #[test]
+    fn a_thousand_partial_fills_of_a_tenth_empty_the_book_exactly() {
+        let (mut book, _) = book();
+        let resting = limit("alice", OrderSide::Sell, 100, 100);
+        let id = resting.id.clone();
+        book.add_order(resting, 1);
+
+        for n in 0..1_000 {
+            let tenth = order(
+                "bob",
+                OrderSide::Buy,
+                OrderType::LimitOrder,
+                Some(Decimal::from(100)),
+                Decimal::new(1, 1),
+            );
+            assert_eq!(book.add_order(tenth, n + 2).status(), "filled");
+        }
+
+        assert!(book.asks.is_empty());
+        assert!(book.orders.is_empty());
+        assert_eq!(
+            book.get_order(&id).unwrap().remaining_quantity,
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn orders_need_the_funds_or_asset_they_would_spend() {
+        let (mut book, accounts) = book();
+        {
+            let mut accounts = accounts.lock().unwrap();
+            let carol = accounts.get_mut("carol").unwrap();
+            carol.balance = Decimal::from(100);
+            carol.assets.clear();
+        }
+
+        let over = order(
+            "carol",
+            OrderSide::Buy,
+            OrderType::LimitOrder,
+            Some(Decimal::new(10_001, 2)),
+            Decimal::ONE,
+        );
+        let response = book.add_order(over, 1);
+        assert_eq!(rejection(&response), Some(OrderError::InsufficientFunds));
+
+        let sell = book.add_order(limit("carol", OrderSide::Sell, 100, 1), 2);
+        assert_eq!(rejection(&sell), Some(OrderError::InsufficientFunds));
+
+        let exact = book.add_order(limit("carol", OrderSide::Buy, 100, 1), 3);
+        assert_eq!(exact.status(), "placed");
+        let accounts = accounts.lock().unwrap();
+        assert_eq!(accounts["carol"].balance, Decimal::ZERO);
+        assert_eq!(accounts["carol"].reserved, Decimal::from(100));
+    }
+
+    fn typed(
+        user_id: &str,
+        side: OrderSide,
+        order_type: OrderType,
+        price: i64,
+        quantity: i64,
+    ) -> Order {
+        order(
+            user_id,
+            side,
+            order_type,
+            Some(Decimal::from(price)),
+            Decimal::from(quantity),
+        )
+    }
+
+    #[test]
+    fn immediate_or_cancel_takes_what_it_can_and_never_rests() {
+        let (mut book, _) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 3), 1);
+        let ioc = |quantity| {
+            typed(
+                "bob",
+                OrderSide::Buy,
+                OrderType::ImmediateOrCancel,
+                100,
+                quantity,
+            )
+        };
+
+        let full = book.add_order(ioc(1), 2);
+        assert_eq!(full.status(), "filled");
+        assert_eq!(trades(&full).len(), 1);
+
+        let partial = book.add_order(ioc(5), 3);
+        let OrderResponse::PartiallyFilled {
+            filled_quantity,
+            cancelled_quantity,
+            ..
+        } = &partial
+        else {
+            panic!("expected a partial fill, got {partial:?}");
+        };
+        assert_eq!(*filled_quantity, Decimal::TWO);
+        assert_eq!(*cancelled_quantity, Some(Decimal::from(3)));
+
+        let nothing = book.add_order(ioc(1), 4);
+        assert_eq!(rejection(&nothing), Some(OrderError::NoMatchingOrders));
+        assert!(book.bids.is_empty() && book.asks.is_empty());
+        assert!(book.orders.is_empty());
+    }
+
+    #[test]
+    fn rejected_fill_or_kill_leaves_the_book_exactly_as_it_was() {
+        let (mut book, accounts) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+        book.add_order(limit("carol", OrderSide::Sell, 101, 1), 2);
+        let state = |book: &Orderbook, accounts: &Accounts| {
+            let accounts = serde_json::to_string(&*accounts.lock().unwrap()).unwrap();
+            (serde_json::to_string(&book.export()).unwrap(), accounts)
+        };
+        let before = state(&book, &accounts);
+
+        let fok = typed("bob", OrderSide::Buy, OrderType::FillOrKill, 101, 3);
+        let response = book.add_order(fok, 3);
+
+        assert_eq!(rejection(&response), Some(OrderError::FillOrKillUnfilled));
+        assert_eq!(state(&book, &accounts), before);
+    }
+
+    #[test]
+    fn post_only_is_rejected_if_it_would_cross_and_rests_otherwise() {
+        let (mut book, _) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+
+        let crossing = typed("bob", OrderSide::Buy, OrderType::PostOnly, 100, 1);
+        assert_eq!(
+            rejection(&book.add_order(crossing, 2)),
+            Some(OrderError::WouldCross)
+        );
+        assert!(book.bids.is_empty());
+
+        let resting = typed("bob", OrderSide::Buy, OrderType::PostOnly, 99, 1);
+        assert_eq!(book.add_order(resting, 3).status(), "placed");
+        assert_eq!(book.best_bid(), Some(Decimal::from(99)));
+        assert_eq!(book.best_ask(), Some(Decimal::from(100)));
+    }
+
+    #[test]
+    fn stop_fires_once_an_unrelated_trade_reaches_its_price() {
+        let (mut book, _) = book();
+        book.add_order(limit("carol", OrderSide::Buy, 95, 1), 1);
+        book.add_order(limit("carol", OrderSide::Buy, 94, 2), 2);
+        let stop_type = OrderType::StopMarket {
+            stop_price: Decimal::from(95),
+        };
+        let stop = order("alice", OrderSide::Sell, stop_type, None, Decimal::ONE);
+        let stop_id = stop.id.clone();
+        assert_eq!(book.add_order(stop, 3).status(), "placed");
+        assert_eq!(book.stops.len(), 1);
+
+        book.add_order(limit("bob", OrderSide::Sell, 95, 1), 4);
+
+        assert!(book.stops.is_empty());
+        let fired = book.recent_trades(1, None);
+        assert_eq!(fired[0].sell_order_id, stop_id);
+        assert_eq!(fired[0].price, Decimal::from(94));
+        assert_eq!(fired[0].buy_user_id, "carol");
+    }
+
+    #[test]
+    fn self_trade_prevention_never_trades_a_user_with_themselves() {
+        let modes = [
+            (SelfTradePrevention::Resting, false, true),
+            (SelfTradePrevention::Taker, true, false),
+            (SelfTradePrevention::Both, false, false),
+        ];
+        for (mode, resting_left, incoming_left) in modes {
+            let (mut book, _) = book();
+            let resting = limit("alice", OrderSide::Sell, 100, 1);
+            let resting_id = resting.id.clone();
+            book.add_order(resting, 1);
+
+            let mut incoming = limit("alice", OrderSide::Buy, 100, 1);
+            incoming.self_trade_prevention = mode;
+            let incoming_id = incoming.id.clone();
+            let response = book.add_order(incoming, 2);
+
+            assert!(trades(&response).is_empty(), "{mode:?} traded");
+            assert!(book.recent_trades(10, None).is_empty(), "{mode:?} traded");
+            assert_eq!(
+                book.orders.contains_key(&resting_id),
+                resting_left,
+                "{mode:?}"
+            );
+            assert_eq!(
+                book.orders.contains_key(&incoming_id),
+                incoming_left,
+                "{mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn back_to_back_snapshots_are_identical() {
+        let (mut book, _) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 101, 1), 1);
+        book.add_order(limit("bob", OrderSide::Buy, 99, 2), 2);
+
+        let first = serde_json::to_value(book.get_snapshot()).unwrap();
+        let second = serde_json::to_value(book.get_snapshot()).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first["bids"].as_array().unwrap().len(), 1);
+        assert_eq!(first["asks"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn user_orders_lists_only_that_users_open_orders() {
+        let (mut book, _) = book();
+        let first = limit("alice", OrderSide::Buy, 99, 1);
+        let second = limit("alice", OrderSide::Sell, 102, 3);
+        let mut expected = vec![first.id.clone(), second.id.clone()];
+        book.add_order(first, 1);
+        book.add_order(second, 2);
+        book.add_order(limit("bob", OrderSide::Buy, 98, 1), 3);
+
+        let orders = book.user_orders("alice");
+        let mut listed: Vec<String> = orders.iter().map(|o| o.id.clone()).collect();
+        listed.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert!(orders.iter().all(|o| o.user_id == "alice"));
+        assert!(book.user_orders("carol").is_empty());
+    }
+
+    #[test]
+    fn order_lookup_reports_open_filled_and_cancelled_orders() {
+        let (mut book, _) = book();
+        let resting = limit("alice", OrderSide::Sell, 100, 3);
+        let resting_id = resting.id.clone();
+        book.add_order(resting, 1);
+        let taker = limit("bob", OrderSide::Buy, 100, 1);
+        let taker_id = taker.id.clone();
+        book.add_order(taker, 2);
+
+        let open = book.get_order(&resting_id).unwrap();
+        assert!(matches!(open.status, OrderStatus::Open));
+        assert_eq!(open.remaining_quantity, Decimal::TWO);
+        let filled = book.get_order(&taker_id).unwrap();
+        assert!(matches!(filled.status, OrderStatus::Filled));
+        assert_eq!(filled.remaining_quantity, Decimal::ZERO);
+
+        assert_eq!(book.cancel_order(&resting_id).status(), "cancelled");
+        let cancelled = book.get_order(&resting_id).unwrap();
+        assert!(matches!(cancelled.status, OrderStatus::Cancelled));
+        assert_eq!(cancelled.remaining_quantity, Decimal::TWO);
+
+        assert!(book.get_order("no-such-order").is_none());
+    }
+
+    #[test]
+    fn taker_fee_is_charged_to_the_incoming_side() {
+        let accounts = funded();
+        let market = market().with_fees(Decimal::ZERO, Decimal::TEN);
+        let mut book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(16));
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+        let response = book.add_order(limit("bob", OrderSide::Buy, 100, 1), 2);
+
+        let fee = Decimal::new(1, 1);
+        assert_eq!(trades(&response)[0].taker_fee, fee);
+        assert_eq!(trades(&response)[0].maker_fee, Decimal::ZERO);
+        let accounts = accounts.lock().unwrap();
+        let hundred = Decimal::ONE_HUNDRED;
+        assert_eq!(accounts["bob"].balance, funding() - hundred - fee);
+        assert_eq!(accounts["alice"].balance, funding() + hundred);
+    }
+
+    #[test]
+    fn limit_prices_must_sit_on_the_tick() {
+        let (mut book, _) = book();
+        let quantity = Decimal::ONE;
+        let aligned = Some(Decimal::new(10_001, 2));
+        let on_tick = order(
+            "alice",
+            OrderSide::Buy,
+            OrderType::LimitOrder,
+            aligned,
+            quantity,
+        );
+        assert_eq!(book.add_order(on_tick, 1).status(), "placed");
+
+        let misaligned = Some(Decimal::new(100_005, 3));
+        let off_tick = order(
+            "bob",
+            OrderSide::Buy,
+            OrderType::LimitOrder,
+            misaligned,
+            quantity,
+        );
+        assert_eq!(
+            rejection(&book.add_order(off_tick, 2)),
+            Some(OrderError::TickSizeViolation)
+        );
+        assert_eq!(book.bids.len(), 1);
+        assert_eq!(book.best_bid(), aligned);
+    }
+
+    #[test]
+    fn orders_below_the_minimum_quantity_or_notional_are_rejected() {
+        let accounts = funded();
+        let market = market().with_minimums(Decimal::ONE, Decimal::ONE_HUNDRED);
+        let mut book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(16));
+        let lot = |price: i64, quantity: Decimal| {
+            let price = Some(Decimal::from(price));
+            order(
+                "alice",
+                OrderSide::Buy,
+                OrderType::LimitOrder,
+                price,
+                quantity,
+            )
+        };
+
+        let small = lot(200, Decimal::new(5, 1));
+        assert_eq!(
+            rejection(&book.add_order(small, 1)),
+            Some(OrderError::BelowMinimumQuantity)
+        );
+        let cheap = lot(99, Decimal::ONE);
+        assert_eq!(
+            rejection(&book.add_order(cheap, 2)),
+            Some(OrderError::BelowMinimumNotional)
+        );
+        assert!(book.bids.is_empty());
+
+        let just_over = lot(101, Decimal::new(101, 2));
+        assert_eq!(book.add_order(just_over, 3).status(), "placed");
+    }
+
+    #[test]
+    fn trade_tape_is_newest_first_up_to_the_limit() {
+        let (mut book, _) = book();
+        for (now, price) in [(1, 100), (2, 101), (3, 102)] {
+            book.add_order(limit("alice", OrderSide::Sell, price, 1), now);
+            book.add_order(limit("bob", OrderSide::Buy, price, 1), now);
+        }
+
+        let prices = |trades: Vec<Trade>| trades.iter().map(|t| t.price).collect::<Vec<_>>();
+        assert_eq!(
+            prices(book.recent_trades(2, None)),
+            [Decimal::from(102), Decimal::from(101)]
+        );
+        assert_eq!(book.recent_trades(10, None).len(), 3);
+        let oldest = book.recent_trades(10, None).pop().unwrap();
+        assert_eq!(oldest.price, Decimal::ONE_HUNDRED);
+    }
+
+    #[test]
+    fn ticker_is_null_where_the_book_or_tape_is_empty() {
+        let (mut book, _) = book();
+        let empty = book.ticker();
+        assert_eq!(empty.last_price, None);
+        assert_eq!(empty.best_bid, None);
+        assert_eq!(empty.best_ask, None);
+        assert_eq!(empty.spread, None);
+
+        book.add_order(limit("alice", OrderSide::Buy, 99, 1), 1);
+        let one_sided = book.ticker();
+        assert_eq!(one_sided.best_bid, Some(Decimal::from(99)));
+        assert_eq!(one_sided.best_ask, None);
+        assert_eq!(one_sided.spread, None);
+
+        book.add_order(limit("bob", OrderSide::Sell, 101, 2), 2);
+        book.add_order(limit("carol", OrderSide::Buy, 101, 1), 3);
+        let traded = book.ticker();
+        assert_eq!(traded.last_price, Some(Decimal::from(101)));
+        assert_eq!(traded.best_bid, Some(Decimal::from(99)));
+        assert_eq!(traded.best_ask, Some(Decimal::from(101)));
+        assert_eq!(traded.spread, Some(Decimal::TWO));
+    }
+
+    #[test]
+    fn candles_bucket_trades_by_minute() {
+        let (mut book, _) = book();
+        let fills = [
+            (61_000, 100, 1),
+            (62_000, 103, 2),
+            (63_000, 99, 1),
+            (119_999, 102, 1),
+            (120_500, 101, 3),
+        ];
+        for (now, price, quantity) in fills {
+            book.add_order(limit("alice", OrderSide::Sell, price, quantity), now);
+            book.add_order(limit("bob", OrderSide::Buy, price, quantity), now);
+        }
+
+        let candles = book.candles(60, 10).unwrap();
+        assert_eq!(candles.len(), 2);
+        let [first, second] = [&candles[0], &candles[1]];
+        assert_eq!(first.open_time, 60_000);
+        assert_eq!(
+            [first.open, first.high, first.low, first.close, first.volume],
+            [100, 103, 99, 102, 5].map(Decimal::from)
+        );
+        assert_eq!(second.open_time, 120_000);
+        assert_eq!(
+            [
+                second.open,
+                second.high,
+                second.low,
+                second.close,
+                second.volume
+            ],
+            [101, 101, 101, 101, 3].map(Decimal::from)
+        );
+        assert_eq!(book.candles(60, 1).unwrap()[0].open_time, 120_000);
+        assert!(book.candles(61, 10).is_none());
+    }
+
+    /// An empty log in a temporary directory, and its path.
+    fn wal() -> (SharedWal, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("orderbook-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.jsonl");
+        let (wal, _) = persistence::Wal::open(&path, 0).unwrap();
+        (Arc::new(Mutex::new(wal)), path)
+    }
+
+    #[tokio::test]
+    async fn engine_answers_queued_commands_then_exits_once_its_senders_drop() {
+        let (book, _) = book();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let engine =
+            tokio::spawn(book.run_orderbook_engine(rx, wal().0, Arc::new(AtomicU64::new(0))));
+
+        let (response, reply) = tokio::sync::oneshot::channel();
+        let command = OrderbookCommand::AddOrder {
+            order: Box::new(limit("alice", OrderSide::Buy, 100, 1)),
+            ack: None,
+            response,
+        };
+        tx.send(command).await.unwrap();
+        drop(tx);
+
+        let finished = tokio::time::timeout(std::time::Duration::from_secs(5), engine).await;
+        assert!(finished.expect("engine still running").is_ok());
+        assert_eq!(reply.await.unwrap().status(), "placed");
+    }
+
+    #[test]
+    fn saved_book_reloads_to_an_identical_snapshot() {
+        let (mut book, accounts) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 101, 3), 1);
+        book.add_order(limit("alice", OrderSide::Sell, 102, 1), 2);
+        book.add_order(limit("bob", OrderSide::Buy, 99, 2), 3);
+        book.add_order(limit("carol", OrderSide::Buy, 101, 1), 4);
+
+        let path =
+            std::env::temp_dir().join(format!("orderbook-test-{}.json", uuid::Uuid::new_v4()));
+        let state = persistence::SavedState {
+            accounts: accounts.lock().unwrap().clone(),
+            books: vec![book.export()],
+            ..Default::default()
+        };
+        persistence::save(&path, &state).unwrap();
+        let loaded = persistence::load(&path).unwrap();
+
+        let reloaded_accounts: Accounts = Arc::new(Mutex::new(loaded.accounts));
+        let recovery = Recovery {
+            books: loaded.books,
+            records: Vec::new(),
+            order_sequence: loaded.order_sequence,
+        };
+        let (books, _) = Orderbook::recover(
+            vec![market()],
+            &reloaded_accounts,
+            &Feeds::new(16),
+            recovery,
+        );
+
+        // The feed sequence starts over with the process; subscribers take a
+        // fresh snapshot when they reconnect.
+        let snapshot = |book: &Orderbook| {
+            let mut snapshot = serde_json::to_value(book.get_full_snapshot()).unwrap();
+            snapshot["sequence"].take();
+            snapshot
+        };
+        assert_eq!(snapshot(&books[SYMBOL]), snapshot(&book));
+        let holdings =
+            |accounts: &Accounts| serde_json::to_value(&*accounts.lock().unwrap()).unwrap();
+        assert_eq!(holdings(&reloaded_accounts), holdings(&accounts));
+    }
+
+    #[tokio::test]
+    async fn replaying_the_log_rebuilds_the_book_as_it_was() {
+        let (book, accounts) = book();
+        let (wal, path) = wal();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let engine = tokio::spawn(book.run_orderbook_engine(rx, wal, Arc::new(AtomicU64::new(0))));
+
+        let mut placed = Vec::new();
+        for order in [
+            limit("alice", OrderSide::Sell, 101, 3),
+            limit("alice", OrderSide::Sell, 102, 1),
+            limit("bob", OrderSide::Buy, 99, 2),
+            limit("carol", OrderSide::Buy, 101, 1),
+        ] {
+            placed.push(order.id.clone());
+            let (response, reply) = tokio::sync::oneshot::channel();
+            let order = Box::new(order);
+            tx.send(OrderbookCommand::AddOrder {
+                order,
+                ack: None,
+                response,
+            })
+            .await
+            .unwrap();
+            reply.await.unwrap();
+        }
+        let (response, reply) = tokio::sync::oneshot::channel();
+        let cancel = OrderbookCommand::CancelOrder {
+            order_id: placed[1].clone(),
+            user_id: "alice".to_string(),
+            cancelled_by: "alice".to_string(),
+            response,
+        };
+        tx.send(cancel).await.unwrap();
+        assert_eq!(reply.await.unwrap().status(), "cancelled");
+        let (response, reply) = tokio::sync::oneshot::channel();
+        tx.send(OrderbookCommand::GetFullSnapshot { response })
+            .await
+            .unwrap();
+        let before = reply.await.unwrap();
+        drop(tx);
+        engine.await.unwrap();
+
+        let (_, records) = persistence::Wal::open(&path, 0).unwrap();
+        assert_eq!(records.len(), 5);
+        let recovery = Recovery {
+            books: Vec::new(),
+            records,
+            order_sequence: 0,
+        };
+        let replayed = funded();
+        let (books, _) = Orderbook::recover(vec![market()], &replayed, &Feeds::new(16), recovery);
+
+        let levels = |snapshot: &FullSnapshot| {
+            let mut levels = serde_json::to_value(snapshot).unwrap();
+            levels["sequence"].take();
+            levels
+        };
+        assert_eq!(levels(&books[SYMBOL].get_full_snapshot()), levels(&before));
+        let holdings =
+            |accounts: &Accounts| serde_json::to_value(&*accounts.lock().unwrap()).unwrap();
+        assert_eq!(holdings(&replayed), holdings(&accounts));
+    }
+
+    #[test]
+    fn orders_in_the_same_millisecond_match_in_arrival_order() {
+        let (mut book, _) = book();
+        let makers: Vec<Order> = ["bob", "carol", "bob"]
+            .iter()
+            .map(|trader| limit(trader, OrderSide::Sell, 100, 1))
+            .collect();
+        let ids: Vec<String> = makers.iter().map(|o| o.id.clone()).collect();
+        for maker in makers {
+            book.add_order(maker, 7);
+        }
+
+        for (filled, expected) in ids.iter().enumerate() {
+            let response = book.add_order(limit("alice", OrderSide::Buy, 100, 1), 7);
+            let trades = trades(&response);
+            assert_eq!(trades.len(), 1);
+            assert_eq!(&trades[0].sell_order_id, expected, "fill {filled}");
+        }
+    }
+
+    #[test]
+    fn each_rejection_reports_its_own_error() {
+        let limit_at = |price: Option<Decimal>, quantity: Decimal| {
+            order(
+                "alice",
+                OrderSide::Buy,
+                OrderType::LimitOrder,
+                price,
+                quantity,
+            )
+        };
+        let mut unnamed = limit("alice", OrderSide::Buy, 100, 1);
+        unnamed.client_order_id = Some(String::new());
+        let cases = [
+            (
+                limit_at(Some(Decimal::ONE_HUNDRED), Decimal::ZERO),
+                OrderError::InvalidQuantity,
+            ),
+            (
+                limit("alice", OrderSide::Buy, -1, 1),
+                OrderError::InvalidPrice,
+            ),
+            (limit_at(None, Decimal::ONE), OrderError::MissingPrice),
+            (
+                limit_at(Some(Decimal::new(100_005, 3)), Decimal::ONE),
+                OrderError::TickSizeViolation,
+            ),
+            (
+                limit_at(Some(Decimal::ONE_HUNDRED), Decimal::new(1, 9)),
+                OrderError::QuantityPrecision,
+            ),
+            (
+                limit("alice", OrderSide::Buy, 100, 100_000),
+                OrderError::InsufficientFunds,
+            ),
+            (
+                market_order("alice", OrderSide::Sell, 1),
+                OrderError::InsufficientLiquidity,
+            ),
+            (
+                typed("alice", OrderSide::Buy, OrderType::PostOnly, 101, 1),
+                OrderError::WouldCross,
+            ),
+            (
+                typed("alice", OrderSide::Buy, OrderType::FillOrKill, 101, 2),
+                OrderError::FillOrKillUnfilled,
+            ),
+            (unnamed, OrderError::InvalidClientOrderId),
+        ];
+
+        for (rejected, expected) in cases {
+            let (mut book, _) = book();
+            book.add_order(limit("bob", OrderSide::Sell, 101, 1), 1);
+            let response = book.add_order(rejected, 2);
+            assert_eq!(rejection(&response), Some(expected), "{response:?}");
+        }
+
+        let (mut book, _) = book();
+        book.set_status(MarketStatus::Paused, 1);
+        let response = book.add_order(limit("alice", OrderSide::Buy, 100, 1), 2);
+        assert_eq!(rejection(&response), Some(OrderError::MarketClosed));
+    }
+
+    #[test]
+    fn partial_fill_serializes_tagged_with_its_trades() {
+        let (mut book, _) = book();
+        let resting = limit("alice", OrderSide::Sell, 100, 1);
+        let maker_id = resting.id.clone();
+        book.add_order(resting, 1);
+        let taker = limit("bob", OrderSide::Buy, 100, 3);
+        let taker_id = taker.id.clone();
+        let response = book.add_order(taker, 2);
+        let trade_id = trades(&response)[0].id.clone();
+
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({
+                "status": "partially_filled",
+                "order_id": taker_id,
+                "filled_quantity": 1,
+                "remaining_quantity": 2,
+                "trades": [{
+                    "id": trade_id,
+                    "buy_order_id": taker_id,
+                    "sell_order_id": maker_id,
+                    "price": 100,
+                    "quantity": 1,
+                    "aggressor_side": "buy",
+                    "maker_fee": 0,
+                    "taker_fee": 0,
+                    "timestamp": 2,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn protection_price_stops_a_market_order_walking_a_thin_book() {
+        let (mut book, _) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+        book.add_order(limit("alice", OrderSide::Sell, 150, 5), 2);
+        let protected = OrderType::MarketOrder {
+            protection_price: Some(Decimal::from(110)),
+        };
+        let buy = order("bob", OrderSide::Buy, protected, None, Decimal::from(3));
+
+        let response = book.add_order(buy, 3);
+        let OrderResponse::PartiallyFilled {
+            filled_quantity,
+            cancelled_quantity,
+            ..
+        } = &response
+        else {
+            panic!("expected a partial fill, got {response:?}");
+        };
+        assert_eq!(*filled_quantity, Decimal::ONE);
+        assert_eq!(*cancelled_quantity, Some(Decimal::TWO));
+        assert!(trades(&response)
+            .iter()
+            .all(|t| t.price == Decimal::ONE_HUNDRED));
+        assert_eq!(book.best_ask(), Some(Decimal::from(150)));
+        assert!(book.bids.is_empty());
+    }
+
+    #[test]
+    fn cancel_all_removes_every_order_of_the_user_and_nobody_elses() {
+        let (mut book, accounts) = book();
+        let mut ids = Vec::new();
+        for (side, price) in [
+            (OrderSide::Buy, 98),
+            (OrderSide::Buy, 99),
+            (OrderSide::Sell, 103),
+        ] {
+            let order = limit("alice", side, price, 1);
+            ids.push(order.id.clone());
+            book.add_order(order, 1);
+        }
+        book.add_order(limit("bob", OrderSide::Buy, 99, 2), 2);
+
+        let mut cancelled = book.cancel_all_for_user("alice", 3);
+        cancelled.sort();
+        ids.sort();
+        assert_eq!(cancelled, ids);
+        assert!(book.user_orders("alice").is_empty());
+
+        let snapshot = book.get_snapshot();
+        assert!(snapshot.asks.is_empty());
+        assert_eq!(snapshot.bids.len(), 1);
+        assert_eq!(snapshot.bids[0].price, Decimal::from(99));
+        assert_eq!(snapshot.bids[0].quantity, Decimal::TWO);
+        assert_eq!(accounts.lock().unwrap()["alice"].reserved, Decimal::ZERO);
+        assert!(book.cancel_all_for_user("alice", 4).is_empty());
+    }
+
+    #[test]
+    fn amend_keeps_priority_only_when_downsizing() {
+        let first_to_fill = |book: &mut Orderbook, now| {
+            let response = book.add_order(limit("carol", OrderSide::Sell, 99, 1), now);
+            trades(&response)[0].buy_order_id.clone()
+        };
+
+        let (mut downsized, _) = book();
+        let alice = limit("alice", OrderSide::Buy, 100, 3);
+        let alice_id = alice.id.clone();
+        downsized.add_order(alice, 1);
+        downsized.add_order(limit("bob", OrderSide::Buy, 100, 3), 2);
+        let amended = downsized.amend_order(&alice_id, None, Some(Decimal::TWO), 3, 3);
+        assert_eq!(amended.status(), "placed");
+        let remaining = downsized.get_order(&alice_id).unwrap().remaining_quantity;
+        assert_eq!(remaining, Decimal::TWO);
+        assert_eq!(first_to_fill(&mut downsized, 4), alice_id);
+
+        let (mut repriced, _) = book();
+        let alice = limit("alice", OrderSide::Buy, 100, 3);
+        let alice_id = alice.id.clone();
+        repriced.add_order(alice, 1);
+        let bob = limit("bob", OrderSide::Buy, 101, 3);
+        let bob_id = bob.id.clone();
+        repriced.add_order(bob, 2);
+        let amended = repriced.amend_order(&alice_id, Some(Decimal::from(101)), None, 3, 3);
+        assert_eq!(amended.status(), "placed");
+        assert_eq!(first_to_fill(&mut repriced, 4), bob_id);
+    }
+
+    #[test]
+    fn sweep_removes_orders_once_their_expiry_passes() {
+        let (mut book, accounts) = book();
+        let mut expiring = limit("alice", OrderSide::Buy, 100, 1);
+        expiring.expires_at = Some(10);
+        let expiring_id = expiring.id.clone();
+        assert_eq!(book.add_order(expiring, 1).status(), "placed");
+        book.add_order(limit("bob", OrderSide::Buy, 99, 1), 2);
+
+        book.expire_orders(9);
+        assert!(book.orders.contains_key(&expiring_id));
+        book.expire_orders(10);
+        assert!(!book.orders.contains_key(&expiring_id));
+        let expired = book.get_order(&expiring_id).unwrap();
+        assert!(matches!(expired.status, OrderStatus::Expired));
+        assert_eq!(book.best_bid(), Some(Decimal::from(99)));
+        assert_eq!(accounts.lock().unwrap()["alice"].reserved, Decimal::ZERO);
+    }
+
+    #[test]
+    fn price_band_rejects_orders_far_from_the_last_trade() {
+        let accounts = funded();
+        let market = market().with_price_band(Decimal::ONE_THOUSAND);
+        let mut book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(16));
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+        book.add_order(limit("bob", OrderSide::Buy, 100, 1), 2);
+
+        for (side, price) in [(OrderSide::Buy, 150), (OrderSide::Sell, 50)] {
+            let response = book.add_order(limit("carol", side, price, 1), 3);
+            assert_eq!(rejection(&response), Some(OrderError::PriceOutsideBand));
+        }
+        assert!(book.bids.is_empty() && book.asks.is_empty());
+
+        let within = limit("carol", OrderSide::Buy, 95, 1);
+        assert_eq!(book.add_order(within, 4).status(), "placed");
+    }
+
+    #[test]
+    fn iceberg_shows_one_slice_and_replenishes_behind_the_queue() {
+        let (mut book, _) = book();
+        let display_quantity = Decimal::TEN;
+        let iceberg = typed(
+            "alice",
+            OrderSide::Sell,
+            OrderType::Iceberg { display_quantity },
+            100,
+            100,
+        );
+        let iceberg_id = iceberg.id.clone();
+        book.add_order(iceberg, 1);
+        let behind = limit("carol", OrderSide::Sell, 100, 5);
+        let behind_id = behind.id.clone();
+        book.add_order(behind, 2);
+        assert_eq!(book.get_snapshot().asks[0].quantity, Decimal::from(15));
+
+        let first = book.add_order(limit("bob", OrderSide::Buy, 100, 10), 3);
+        assert_eq!(trades(&first).len(), 1);
+        assert_eq!(trades(&first)[0].sell_order_id, iceberg_id);
+        let remaining = book.get_order(&iceberg_id).unwrap().remaining_quantity;
+        assert_eq!(remaining, Decimal::from(90));
+        assert_eq!(book.get_snapshot().asks[0].quantity, Decimal::from(15));
+
+        let next = book.add_order(limit("bob", OrderSide::Buy, 100, 5), 4);
+        assert_eq!(trades(&next)[0].sell_order_id, behind_id);
+        assert_eq!(book.get_snapshot().asks[0].quantity, display_quantity);
+    }
+
+    #[test]
+    fn full_snapshot_lists_a_level_in_time_priority() {
+        let (mut book, _) = book();
+        let orders: Vec<Order> = TRADERS
+            .iter()
+            .zip([3, 1, 2])
+            .map(|(trader, quantity)| limit(trader, OrderSide::Buy, 100, quantity))
+            .collect();
+        let expected: Vec<String> = orders.iter().map(|o| o.id.clone()).collect();
+        for order in orders {
+            book.add_order(order, 1);
+        }
+        book.add_order(limit("bob", OrderSide::Sell, 100, 1), 2);
+
+        let snapshot = book.get_full_snapshot();
+        assert_eq!(snapshot.bids.len(), 1);
+        let (price, entries) = &snapshot.bids[0];
+        assert_eq!(*price, Decimal::ONE_HUNDRED);
+        let ids: Vec<&String> = entries.iter().map(|e| &e.order_id).collect();
+        assert_eq!(ids, expected.iter().collect::<Vec<_>>());
+        let remaining: Vec<Decimal> = entries.iter().map(|e| e.remaining_quantity).collect();
+        assert_eq!(remaining, [2, 1, 2].map(Decimal::from));
+    }
+
+    #[test]
+    fn filling_one_oco_leg_cancels_the_other() {
+        let (mut book, _) = book();
+        let take_profit = limit("alice", OrderSide::Sell, 110, 1);
+        let stop_type = OrderType::StopMarket {
+            stop_price: Decimal::from(90),
+        };
+        let stop_loss = order("alice", OrderSide::Sell, stop_type, None, Decimal::ONE);
+        let (profit_id, stop_id) = (take_profit.id.clone(), stop_loss.id.clone());
+        let placed = book.add_oco(take_profit, stop_loss, 1);
+        assert_eq!(placed.first.status(), "placed");
+        assert_eq!(placed.second.status(), "placed");
+        assert_eq!(book.stops.len(), 1);
+
+        let response = book.add_order(limit("bob", OrderSide::Buy, 110, 1), 2);
+        assert_eq!(trades(&response)[0].sell_order_id, profit_id);
+
+        assert!(book.stops.is_empty());
+        assert!(book.user_orders("alice").is_empty());
+        let sibling = book.get_order(&stop_id).unwrap();
+        assert!(matches!(sibling.status, OrderStatus::Cancelled));
+    }
+
+    #[test]
+    fn pro_rata_splits_a_fill_in_proportion_to_resting_size() {
+        let accounts = funded();
+        let market = market().with_matching_mode(MatchingMode::ProRata);
+        let mut book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(16));
+        let resting: Vec<Order> = [("alice", 2), ("bob", 3), ("bob", 5)]
+            .iter()
+            .map(|(trader, quantity)| limit(trader, OrderSide::Sell, 100, *quantity))
+            .collect();
+        let ids: Vec<String> = resting.iter().map(|o| o.id.clone()).collect();
+        for order in resting {
+            book.add_order(order, 1);
+        }
+
+        let response = book.add_order(limit("carol", OrderSide::Buy, 100, 4), 2);
+        let filled = |id: &str| -> Decimal {
+            trades(&response)
+                .iter()
+                .filter(|t| t.sell_order_id == id)
+                .map(|t| t.quantity)
+                .sum()
+        };
+        // 0.8, 1.2 and 2 rounded down to whole units, as every quantity is
+        // whole, and the unit left over goes to the oldest order.
+        let expected = [Decimal::ONE, Decimal::ONE, Decimal::TWO];
+        for (id, quantity) in ids.iter().zip(expected) {
+            assert_eq!(filled(id), quantity);
+        }
+        let total: Decimal = trades(&response).iter().map(|t| t.quantity).sum();
+        assert_eq!(total, Decimal::from(4));
+        check_book(&book, &accounts);
+    }
+
+    #[test]
+    fn auction_uncrosses_at_the_price_matching_the_most() {
+        let (mut book, _) = book();
+        book.set_status(MarketStatus::Auction, 1);
+        for (price, quantity) in [(102, 3), (101, 2), (100, 1)] {
+            let response = book.add_order(limit("alice", OrderSide::Buy, price, quantity), 2);
+            assert_eq!(response.status(), "placed");
+        }
+        for (price, quantity) in [(99, 2), (100, 2), (101, 4)] {
+            book.add_order(limit("bob", OrderSide::Sell, price, quantity), 3);
+        }
+        assert!(book.recent_trades(10, None).is_empty());
+
+        // 99 matches 2, 100 matches 4, 101 matches 5 and 102 matches 3.
+        let uncross_price = Decimal::from(101);
+        let ticker = book.ticker();
+        assert_eq!(ticker.indicative_price, Some(uncross_price));
+        assert_eq!(ticker.indicative_volume, Some(Decimal::from(5)));
+
+        book.set_status(MarketStatus::Open, 4);
+        let trades = book.recent_trades(10, None);
+        assert!(trades.iter().all(|t| t.price == uncross_price));
+        let volume: Decimal = trades.iter().map(|t| t.quantity).sum();
+        assert_eq!(volume, Decimal::from(5));
+        assert_eq!(book.best_bid(), Some(Decimal::ONE_HUNDRED));
+        assert_eq!(book.best_ask(), Some(uncross_price));
+        assert_eq!(book.get_snapshot().asks[0].quantity, Decimal::from(3));
+    }
+
+    #[test]
+    fn reduce_only_sell_executes_no_more_than_is_held() {
+        let (mut book, accounts) = book();
+        let five = Decimal::from(5);
+        accounts
+            .lock()
+            .unwrap()
+            .get_mut("alice")
+            .unwrap()
+            .assets
+            .insert("BTC".to_string(), five);
+        book.add_order(limit("bob", OrderSide::Buy, 100, 10), 1);
+
+        let mut sell = limit("alice", OrderSide::Sell, 100, 10);
+        sell.reduce_only = true;
+        let response = book.add_order(sell, 2);
+        let sold: Decimal = trades(&response).iter().map(|t| t.quantity).sum();
+        assert_eq!(sold, five);
+        assert_eq!(
+            accounts.lock().unwrap()["alice"].assets["BTC"],
+            Decimal::ZERO
+        );
+        assert_eq!(book.get_snapshot().bids[0].quantity, five);
+        assert!(book.get_snapshot().asks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resubmitting_a_client_order_id_answers_without_placing_again() {
+        let (book, _) = book();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let engine =
+            tokio::spawn(book.run_orderbook_engine(rx, wal().0, Arc::new(AtomicU64::new(0))));
+
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            // A retry is a fresh request, so it arrives under a new order id.
+            let mut order = limit("alice", OrderSide::Buy, 100, 1);
+            order.client_order_id = Some("retried".to_string());
+            let (response, reply) = tokio::sync::oneshot::channel();
+            let order = Box::new(order);
+            tx.send(OrderbookCommand::AddOrder {
+                order,
+                ack: None,
+                response,
+            })
+            .await
+            .unwrap();
+            responses.push(serde_json::to_value(reply.await.unwrap()).unwrap());
+        }
+        assert_eq!(responses[0]["status"], "placed");
+        assert_eq!(responses[1], responses[0]);
+
+        let (response, reply) = tokio::sync::oneshot::channel();
+        tx.send(OrderbookCommand::GetFullSnapshot { response })
+            .await
+            .unwrap();
+        let snapshot = reply.await.unwrap();
+        let resting: Vec<_> = snapshot.bids.iter().flat_map(|(_, queue)| queue).collect();
+        assert_eq!(resting.len(), 1);
+        assert_eq!(resting[0].order_id, responses[0]["order_id"]);
+        drop(tx);
+        engine.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn order_whose_caller_gave_up_is_skipped_and_the_engine_carries_on() {
+        let (book, accounts) = book();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let engine =
+            tokio::spawn(book.run_orderbook_engine(rx, wal().0, Arc::new(AtomicU64::new(0))));
+
+        let (response, reply) = tokio::sync::oneshot::channel();
+        drop(reply);
+        let order = Box::new(limit("alice", OrderSide::Sell, 100, 1));
+        tx.send(OrderbookCommand::AddOrder {
+            order,
+            ack: None,
+            response,
+        })
+        .await
+        .unwrap();
+
+        let (response, reply) = tokio::sync::oneshot::channel();
+        let order = Box::new(limit("bob", OrderSide::Buy, 100, 1));
+        tx.send(OrderbookCommand::AddOrder {
+            order,
+            ack: None,
+            response,
+        })
+        .await
+        .unwrap();
+        // Nobody was told about the sell, so it was never placed.
+        assert_eq!(reply.await.unwrap().status(), "placed");
+        let (response, reply) = tokio::sync::oneshot::channel();
+        tx.send(OrderbookCommand::GetFullSnapshot { response })
+            .await
+            .unwrap();
+        let snapshot = reply.await.unwrap();
+        assert!(snapshot.asks.is_empty());
+        assert_eq!(snapshot.bids.len(), 1);
+        drop(tx);
+        engine.await.expect("engine panicked");
+
+        let accounts = accounts.lock().unwrap();
+        assert_eq!(accounts["alice"].assets["BTC"], funding());
+        let reserved = accounts["alice"].reserved_assets.get("BTC").copied();
+        assert_eq!(reserved.unwrap_or_default(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn quote_market_buy_spends_its_budget_across_levels() {
+        let asks = |book: &mut Orderbook| {
+            book.add_order(limit("alice", OrderSide::Sell, 100, 2), 1);
+            book.add_order(limit("alice", OrderSide::Sell, 101, 3), 2);
+            book.add_order(limit("alice", OrderSide::Sell, 102, 4), 3);
+        };
+        let buy = |budget: Decimal| {
+            let mut order = market_order("bob", OrderSide::Buy, 0);
+            order.quote_quantity = Some(budget);
+            order
+        };
+
+        // 2 at 100 and 3 at 101 cost exactly 503.
+        let (mut exact, accounts) = book();
+        asks(&mut exact);
+        let response = exact.add_order(buy(Decimal::from(503)), 4);
+        let bought: Decimal = trades(&response).iter().map(|t| t.quantity).sum();
+        assert_eq!(bought, Decimal::from(5));
+        assert_eq!(
+            accounts.lock().unwrap()["bob"].balance,
+            funding() - Decimal::from(503)
+        );
+        assert_eq!(exact.best_ask(), Some(Decimal::from(102)));
+
+        // 51 more buys half of 102's level.
+        let (mut partial, accounts) = book();
+        asks(&mut partial);
+        let response = partial.add_order(buy(Decimal::from(554)), 4);
+        let last = trades(&response).last().unwrap();
+        assert_eq!(
+            (last.price, last.quantity),
+            (Decimal::from(102), Decimal::new(5, 1))
+        );
+        assert_eq!(
+            accounts.lock().unwrap()["bob"].balance,
+            funding() - Decimal::from(554)
+        );
+        assert_eq!(partial.get_snapshot().asks[0].quantity, Decimal::new(35, 1));
+        check_book(&partial, &accounts);
+    }
+
+    #[test]
+    fn trades_record_the_incoming_side_as_the_aggressor() {
+        let (mut book, _) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 1);
+        let response = book.add_order(market_order("bob", OrderSide::Buy, 1), 2);
+        let trade = &trades(&response)[0];
+        assert!(matches!(trade.aggressor_side, Some(OrderSide::Buy)));
+        assert_eq!(trade.buy_user_id, "bob");
+
+        book.add_order(limit("alice", OrderSide::Buy, 99, 1), 3);
+        let response = book.add_order(market_order("bob", OrderSide::Sell, 1), 4);
+        let trade = &trades(&response)[0];
+        assert!(matches!(trade.aggressor_side, Some(OrderSide::Sell)));
+    }
+
+    #[test]
+    fn market_sell_takes_the_highest_bid_first() {
+        let (mut book, accounts) = book();
+        for price in [100, 101, 102] {
+            book.add_order(limit("alice", OrderSide::Buy, price, 1), 1);
+        }
+        let response = book.add_order(market_order("bob", OrderSide::Sell, 3), 2);
+        let prices: Vec<Decimal> = trades(&response).iter().map(|t| t.price).collect();
+        assert_eq!(prices, [102, 101, 100].map(Decimal::from));
+        assert!(book.get_snapshot().bids.is_empty());
+        check_book(&book, &accounts);
+    }
+
+    /// Fields of every event emitted while it is the subscriber, by name.
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Events {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Fields<'a>(&'a mut HashMap<String, String>);
+            impl tracing::field::Visit for Fields<'_> {
+                fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                    self.0.insert(field.name().to_string(), value.to_string());
+                }
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0
+                        .insert(field.name().to_string(), format!("{value:?}"));
+                }
+            }
+            let mut fields = HashMap::new();
+            event.record(&mut Fields(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    #[tokio::test]
+    async fn placed_order_is_traced_with_its_id_owner_and_outcome() {
+        use tracing_subscriber::layer::SubscriberExt as _;
+
+        let events = Events::default();
+        // The engine runs on this thread, so it reports to this subscriber.
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(events.clone()));
+        let (book, _) = book();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let engine =
+            tokio::spawn(book.run_orderbook_engine(rx, wal().0, Arc::new(AtomicU64::new(0))));
+
+        let order = limit("alice", OrderSide::Buy, 100, 1);
+        let order_id = order.id.clone();
+        let (response, reply) = tokio::sync::oneshot::channel();
+        let order = Box::new(order);
+        tx.send(OrderbookCommand::AddOrder {
+            order,
+            ack: None,
+            response,
+        })
+        .await
+        .unwrap();
+        assert_eq!(reply.await.unwrap().status(), "placed");
+        drop(tx);
+        engine.await.unwrap();
+
+        let events = events.0.lock().unwrap();
+        let traced = events
+            .iter()
+            .find(|fields| fields.get("order_id") == Some(&order_id))
+            .expect("no event for the order");
+        assert_eq!(traced["user_id"], "alice");
+        assert_eq!(traced["outcome"], "placed");
+        assert_eq!(traced["message"], "order");
+    }
+
+    #[test]
+    fn microprice_weighs_each_side_by_the_size_opposite() {
+        let (mut book, _) = book();
+        assert_eq!(book.ticker().microprice, None);
+        book.add_order(limit("alice", OrderSide::Buy, 100, 3), 1);
+        let one_sided = book.ticker();
+        assert_eq!((one_sided.imbalance, one_sided.microprice), (None, None));
+
+        book.add_order(limit("alice", OrderSide::Buy, 99, 5), 2);
+        book.add_order(limit("bob", OrderSide::Sell, 102, 1), 3);
+        let ticker = book.ticker();
+        // (102 * 3 + 100 * 1) / (3 + 1); the level behind the best bid is ignored.
+        assert_eq!(ticker.microprice, Some(Decimal::new(1015, 1)));
+        assert_eq!(ticker.imbalance, Some(Decimal::new(75, 2)));
+    }
+
+    #[test]
+    fn trailing_stop_follows_the_market_and_fires_on_the_reversal() {
+        // Alice and bob trade one unit at `price`.
+        let print = |book: &mut Orderbook, price: i64, now: u64| {
+            book.add_order(limit("alice", OrderSide::Sell, price, 1), now);
+            book.add_order(market_order("bob", OrderSide::Buy, 1), now);
+        };
+        let trailing = || OrderType::TrailingStop {
+            trail_amount: Decimal::from(5),
+            stop_price: None,
+        };
+        let trigger = |book: &Orderbook, id: &str| match book.orders[id].order_type {
+            OrderType::TrailingStop { stop_price, .. } => stop_price,
+            _ => unreachable!(),
+        };
+
+        let (mut selling, _) = book();
+        let stop = order("carol", OrderSide::Sell, trailing(), None, Decimal::ONE);
+        let stop_id = stop.id.clone();
+        assert_eq!(selling.add_order(stop, 1).status(), "placed");
+        assert_eq!(trigger(&selling, &stop_id), None);
+
+        print(&mut selling, 100, 2);
+        assert_eq!(trigger(&selling, &stop_id), Some(Decimal::from(95)));
+        print(&mut selling, 110, 3);
+        assert_eq!(trigger(&selling, &stop_id), Some(Decimal::from(105)));
+        print(&mut selling, 106, 4);
+        assert_eq!(trigger(&selling, &stop_id), Some(Decimal::from(105)));
+        assert_eq!(selling.stops.len(), 1);
+
+        selling.add_order(limit("bob", OrderSide::Buy, 104, 1), 5);
+        print(&mut selling, 105, 6);
+        assert!(selling.stops.is_empty());
+        let fired = selling.recent_trades(1, None);
+        assert_eq!(fired[0].sell_order_id, stop_id);
+        assert_eq!(fired[0].price, Decimal::from(104));
+
+        // A buy trails the lowest price by as much, above it.
+        let (mut buying, _) = book();
+        let stop = order("carol", OrderSide::Buy, trailing(), None, Decimal::ONE);
+        let stop_id = stop.id.clone();
+        buying.add_order(stop, 1);
+        print(&mut buying, 100, 2);
+        print(&mut buying, 90, 3);
+        print(&mut buying, 94, 4);
+        assert_eq!(trigger(&buying, &stop_id), Some(Decimal::from(95)));
+        assert_eq!(buying.stops.len(), 1);
+    }
+
+    #[test]
+    fn highest_representable_price_rests_and_one_tick_more_is_refused() {
+        let (mut book, _) = book();
+        let tick = market().tick_size;
+        let highest = book.max_price();
+        assert_eq!(Orderbook::price_to_key(highest, tick), u64::MAX);
+
+        let mut at_limit = limit("alice", OrderSide::Sell, 0, 1);
+        at_limit.price = Some(highest);
+        assert_eq!(book.add_order(at_limit, 1).status(), "placed");
+        assert_eq!(book.best_ask(), Some(highest));
+
+        let mut beyond = limit("alice", OrderSide::Sell, 0, 1);
+        beyond.price = Some(highest + tick);
+        let response = book.add_order(beyond, 2);
+        assert_eq!(rejection(&response), Some(OrderError::InvalidPrice));
+        assert_eq!(book.get_snapshot().asks.len(), 1);
+        assert_eq!(book.get_snapshot().asks[0].quantity, Decimal::ONE);
+    }
+
+    #[test]
+    fn placing_then_cancelling_publishes_two_consecutive_events() {
+        let feeds = Feeds::new(16);
+        let mut events = feeds.book_events.subscribe();
+        let mut book = Orderbook::new(market(), funded(), feeds);
+
+        let order = limit("alice", OrderSide::Buy, 100, 1);
+        let order_id = order.id.clone();
+        book.add_order(order, 1);
+        assert_eq!(book.cancel_order(&order_id).status(), "cancelled");
+
+        let placed = events.try_recv().unwrap();
+        let cancelled = events.try_recv().unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(matches!(&placed.event, BookEvent::OrderPlaced { order } if order.id == order_id));
+        assert!(matches!(
+            &cancelled.event,
+            BookEvent::OrderCancelled { order_id: cancelled } if *cancelled == order_id
+        ));
+        assert_eq!(cancelled.sequence, placed.sequence + 1);
+        assert_eq!(placed.symbol, SYMBOL);
+    }
+
+    #[test]
+    fn orders_past_the_size_or_open_order_caps_are_refused() {
+        let market = market().with_maximums(Decimal::from(10), 3);
+        let mut book = Orderbook::new(market, funded(), Feeds::new(16));
+
+        let response = book.add_order(limit("alice", OrderSide::Buy, 100, 11), 1);
+        assert_eq!(rejection(&response), Some(OrderError::AboveMaximumQuantity));
+        let response = book.add_order(limit("alice", OrderSide::Buy, 100, 10), 2);
+        assert_eq!(response.status(), "placed");
+
+        for price in [99, 98] {
+            let response = book.add_order(limit("alice", OrderSide::Buy, price, 1), 3);
+            assert_eq!(response.status(), "placed");
+        }
+        let response = book.add_order(limit("alice", OrderSide::Buy, 97, 1), 4);
+        assert_eq!(rejection(&response), Some(OrderError::TooManyOpenOrders));
+        // Orders that cannot rest do not count against it, and nor do others' orders.
+        assert_eq!(
+            rejection(&book.add_order(limit("bob", OrderSide::Buy, 97, 1), 5)),
+            None
+        );
+        let response = book.add_order(market_order("bob", OrderSide::Sell, 10), 6);
+        assert_eq!(response.status(), "filled");
+        // Filling alice's bid at 100 freed a slot.
+        let response = book.add_order(limit("alice", OrderSide::Buy, 97, 1), 7);
+        assert_eq!(response.status(), "placed");
+    }
+
+    #[test]
+    fn primary_peg_follows_the_best_bid_to_the_back_of_each_level() {
+        let (mut book, accounts) = book();
+        book.add_order(limit("alice", OrderSide::Buy, 100, 1), 1);
+        let peg_type = OrderType::Peg {
+            reference: PegReference::Primary,
+            offset: Decimal::ZERO,
+        };
+        let peg = order("carol", OrderSide::Buy, peg_type, None, Decimal::ONE);
+        let peg_id = peg.id.clone();
+        assert_eq!(book.add_order(peg, 2).status(), "placed");
+        assert_eq!(book.orders[&peg_id].price, Some(Decimal::ONE_HUNDRED));
+
+        let better = limit("bob", OrderSide::Buy, 101, 2);
+        let better_id = better.id.clone();
+        book.add_order(better, 3);
+        assert_eq!(book.orders[&peg_id].price, Some(Decimal::from(101)));
+        let full = book.get_full_snapshot();
+        let (price, queue) = &full.bids[0];
+        assert_eq!(*price, Decimal::from(101));
+        let queued: Vec<&str> = queue.iter().map(|entry| entry.order_id.as_str()).collect();
+        assert_eq!(queued, [better_id.as_str(), peg_id.as_str()]);
+        assert_eq!(full.bids[1].1.len(), 1);
+
+        book.cancel_order(&better_id);
+        assert_eq!(book.orders[&peg_id].price, Some(Decimal::ONE_HUNDRED));
+        assert_eq!(book.get_snapshot().bids[0].quantity, Decimal::from(2));
+        check_book(&book, &accounts);
+    }
+
+    #[test]
+    fn market_if_touched_sell_fires_once_the_price_rises_to_it() {
+        let (mut book, _) = book();
+        let touch_type = OrderType::MarketIfTouched {
+            touch_price: Decimal::from(105),
+        };
+        let touched = order("carol", OrderSide::Sell, touch_type, None, Decimal::ONE);
+        let touched_id = touched.id.clone();
+        assert_eq!(book.add_order(touched, 1).status(), "placed");
+
+        book.add_order(limit("alice", OrderSide::Sell, 103, 1), 2);
+        book.add_order(market_order("bob", OrderSide::Buy, 1), 3);
+        assert_eq!(book.stops.len(), 1);
+
+        book.add_order(limit("bob", OrderSide::Buy, 104, 1), 4);
+        book.add_order(limit("alice", OrderSide::Sell, 105, 1), 5);
+        book.add_order(market_order("bob", OrderSide::Buy, 1), 6);
+        assert!(book.stops.is_empty());
+        let fired = book.recent_trades(1, None);
+        assert_eq!(fired[0].sell_order_id, touched_id);
+        assert_eq!(fired[0].price, Decimal::from(104));
+        assert!(book.get_snapshot().bids.is_empty());
+    }
+
+    #[test]
+    fn snapshot_levels_count_their_orders() {
+        let (mut book, _) = book();
+        for (user_id, quantity) in [("alice", 1), ("bob", 2), ("carol", 4)] {
+            book.add_order(limit(user_id, OrderSide::Sell, 101, quantity), 1);
+        }
+        book.add_order(limit("alice", OrderSide::Sell, 102, 1), 2);
+
+        let asks = book.get_snapshot().asks;
+        assert_eq!(asks[0].price, Decimal::from(101));
+        assert_eq!(asks[0].quantity, Decimal::from(7));
+        assert_eq!(asks[0].order_count, 3);
+        assert_eq!(asks[1].order_count, 1);
+    }
+
+    #[test]
+    fn fractional_fill_settles_whole_cents() {
+        let accounts = funded();
+        let market = market()
+            .with_decimals(8, 2)
+            .with_fees(Decimal::ZERO, Decimal::TEN);
+        let mut book = Orderbook::new(market, Arc::clone(&accounts), Feeds::new(16));
+        let quantity = Decimal::new(333, 3);
+        let mut ask = limit("alice", OrderSide::Sell, 0, 0);
+        ask.price = Some(Decimal::new(10_001, 2));
+        (ask.quantity, ask.remaining_quantity) = (quantity, quantity);
+        book.add_order(ask, 1);
+        let mut buy = market_order("bob", OrderSide::Buy, 0);
+        (buy.quantity, buy.remaining_quantity) = (quantity, quantity);
+        assert_eq!(book.add_order(buy, 2).status(), "filled");
+
+        // 100.01 * 0.333 = 33.30333 and 10 bps of 33.30 is 0.0333, both cut to cents.
+        let accounts = accounts.lock().unwrap();
+        assert_eq!(
+            accounts["alice"].balance,
+            funding() + Decimal::new(3_330, 2)
+        );
+        assert_eq!(accounts["bob"].balance, funding() - Decimal::new(3_333, 2));
+        assert_eq!(accounts["bob"].assets["BTC"], funding() + quantity);
+        assert_eq!(accounts["alice"].assets["BTC"], funding() - quantity);
+        for user_id in ["alice", "bob"] {
+            assert!(accounts[user_id].balance.scale() <= 2, "{user_id}");
+            assert_eq!(accounts[user_id].reserved, Decimal::ZERO, "{user_id}");
+        }
+    }
+
+    #[test]
+    fn mid_price_series_follows_the_top_of_book_in_order() {
+        let (mut book, _) = book();
+        book.add_order(limit("alice", OrderSide::Buy, 99, 1), 1);
+        book.add_order(limit("bob", OrderSide::Sell, 102, 2), 2);
+        book.add_order(limit("bob", OrderSide::Sell, 101, 1), 3);
+        // Below the best bid, so neither the mid nor the spread moves.
+        book.add_order(limit("alice", OrderSide::Buy, 98, 1), 4);
+        book.add_order(market_order("carol", OrderSide::Buy, 1), 5);
+
+        let sample = |mid: Option<Decimal>, spread: Option<i64>, timestamp| MidPriceSample {
+            mid_price: mid,
+            spread: spread.map(Decimal::from),
+            timestamp,
+        };
+        let moving = Some(Decimal::new(1_005, 1));
+        let series = [
+            sample(None, None, 1),
+            sample(moving, Some(3), 2),
+            sample(Some(Decimal::ONE_HUNDRED), Some(2), 3),
+            sample(moving, Some(3), 5),
+        ];
+        assert_eq!(book.mid_prices(10), series);
+        assert_eq!(book.mid_prices(2), series[2..]);
+    }
+
+    #[test]
+    fn delayed_order_joins_the_book_once_its_start_time_comes() {
+        let (mut book, accounts) = book();
+        let mut delayed = limit("alice", OrderSide::Buy, 100, 1);
+        delayed.activates_at = Some(1_000);
+        let delayed_id = delayed.id.clone();
+        assert_eq!(book.add_order(delayed, 10).status(), "placed");
+        let mut unfunded = limit("bob", OrderSide::Buy, 99, 1);
+        unfunded.activates_at = Some(1_000);
+        let unfunded_id = unfunded.id.clone();
+        book.add_order(unfunded, 10);
+        assert!(book.get_snapshot().bids.is_empty());
+        assert_eq!(accounts.lock().unwrap()["alice"].reserved, Decimal::ZERO);
+
+        book.activate_orders(999);
+        assert!(book.get_snapshot().bids.is_empty());
+
+        // Funds are only checked once the order goes in.
+        accounts.lock().unwrap().get_mut("bob").unwrap().balance = Decimal::TEN;
+        book.activate_orders(1_000);
+        let bids = book.get_snapshot().bids;
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].price, Decimal::ONE_HUNDRED);
+        assert_eq!(
+            book.get_order(&delayed_id).unwrap().status,
+            OrderStatus::Open
+        );
+        assert_eq!(
+            accounts.lock().unwrap()["alice"].reserved,
+            Decimal::ONE_HUNDRED
+        );
+        assert_eq!(
+            book.get_order(&unfunded_id).unwrap().status,
+            OrderStatus::Cancelled
+        );
+        assert_eq!(accounts.lock().unwrap()["bob"].balance, Decimal::TEN);
+    }
+
+    #[test]
+    fn bracket_places_its_exits_on_entry_and_one_filling_cancels_the_other() {
+        let (mut book, accounts) = book();
+        let entry = limit("carol", OrderSide::Buy, 100, 1);
+        let exits = Bracket::new(&entry, Decimal::from(110), Decimal::from(90));
+        let (take_profit_id, stop_id) = (exits.take_profit.id.clone(), exits.stop.id.clone());
+        assert_eq!(book.add_bracket(entry, exits, 1).status(), "placed");
+        assert!(book.get_order(&take_profit_id).is_none());
+        assert!(book.stops.is_empty());
+
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 2);
+        let take_profit = book
+            .get_order(&take_profit_id)
+            .expect("take-profit not placed");
+        assert_eq!(take_profit.status, OrderStatus::Open);
+        assert_eq!(book.best_ask(), Some(Decimal::from(110)));
+        assert_eq!(book.stops.len(), 1);
+        assert_eq!(book.stops[0].id, stop_id);
+
+        let response = book.add_order(market_order("bob", OrderSide::Buy, 1), 3);
+        assert_eq!(trades(&response)[0].sell_order_id, take_profit_id);
+        assert_eq!(
+            book.get_order(&take_profit_id).unwrap().status,
+            OrderStatus::Filled
+        );
+        assert_eq!(
+            book.get_order(&stop_id).unwrap().status,
+            OrderStatus::Cancelled
+        );
+        assert!(book.stops.is_empty());
+        check_book(&book, &accounts);
+    }
+
+    #[test]
+    fn crossing_a_volume_tier_lowers_the_fees_on_later_trades() {
+        let tiers = vec![FeeTier::new(
+            Decimal::ONE_THOUSAND,
+            Decimal::ZERO,
+            Decimal::from(5),
+        )];
+        let market = market()
+            .with_fees(Decimal::ZERO, Decimal::TEN)
+            .with_fee_tiers(tiers);
+        let mut book = Orderbook::new(market, funded(), Feeds::new(16));
+
+        book.add_order(limit("alice", OrderSide::Sell, 100, 10), 1);
+        let response = book.add_order(market_order("bob", OrderSide::Buy, 10), 2);
+        // 10 bps of 1,000, the trade that takes bob to the tier.
+        assert_eq!(trades(&response)[0].taker_fee, Decimal::ONE);
+
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 3);
+        let response = book.add_order(market_order("bob", OrderSide::Buy, 1), 4);
+        assert_eq!(trades(&response)[0].taker_fee, Decimal::new(5, 2));
+        // Alice is at the tier too, as makers count towards it.
+        book.add_order(limit("carol", OrderSide::Sell, 100, 1), 5);
+        let response = book.add_order(market_order("alice", OrderSide::Buy, 1), 6);
+        assert_eq!(trades(&response)[0].taker_fee, Decimal::new(5, 2));
+        // Carol has traded 100, still short of it.
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 7);
+        let response = book.add_order(market_order("carol", OrderSide::Buy, 1), 8);
+        assert_eq!(trades(&response)[0].taker_fee, Decimal::new(10, 2));
+    }
+
+    #[test]
+    fn checksum_matches_one_computed_from_the_canonical_levels() {
+        let feeds = Feeds::new(16);
+        let mut updates = feeds.book_updates.subscribe();
+        let mut book = Orderbook::new(market(), funded(), feeds);
+        assert_eq!(book.get_snapshot().checksum, crc32fast::hash(b"|"));
+
+        book.add_order(limit("alice", OrderSide::Buy, 100, 2), 1);
+        let mut quarter = limit("alice", OrderSide::Buy, 0, 0);
+        quarter.price = Some(Decimal::new(995, 1));
+        (quarter.quantity, quarter.remaining_quantity) = (Decimal::new(25, 2), Decimal::new(25, 2));
+        book.add_order(quarter, 2);
+        book.add_order(limit("bob", OrderSide::Sell, 101, 1), 3);
+
+        let expected = crc32fast::hash(b"100:2,99.5:0.25|101:1");
+        assert_eq!(book.get_snapshot().checksum, expected);
+        let mut last = None;
+        while let Ok(update) = updates.try_recv() {
+            last = Some(update);
+        }
+        assert_eq!(last.expect("no book update").checksum, expected);
+    }
+
+    #[test]
+    fn reduce_keeps_the_order_ahead_of_later_ones_at_its_price() {
+        let (mut book, accounts) = book();
+        let first = limit("alice", OrderSide::Buy, 100, 5);
+        let first_id = first.id.clone();
+        book.add_order(first, 1);
+        let later = limit("bob", OrderSide::Buy, 100, 3);
+        let later_id = later.id.clone();
+        book.add_order(later, 2);
+        book.add_order(limit("carol", OrderSide::Sell, 100, 1), 3);
+
+        for refused in [1, 5, 6] {
+            let response = book.reduce_order(&first_id, Decimal::from(refused), 4);
+            assert_eq!(
+                rejection(&response),
+                Some(OrderError::InvalidQuantity),
+                "{refused}"
+            );
+        }
+        let response = book.reduce_order(&first_id, Decimal::from(3), 4);
+        assert_eq!(response.status(), "placed");
+        let reduced = book.get_order(&first_id).unwrap();
+        assert_eq!(
+            (reduced.quantity, reduced.remaining_quantity),
+            (Decimal::from(3), Decimal::TWO)
+        );
+        let full = book.get_full_snapshot();
+        let queued: Vec<&str> = full.bids[0]
+            .1
+            .iter()
+            .map(|entry| entry.order_id.as_str())
+            .collect();
+        assert_eq!(queued, [first_id.as_str(), later_id.as_str()]);
+        assert_eq!(
+            accounts.lock().unwrap()["alice"].reserved,
+            Decimal::from(200)
+        );
+
+        let response = book.add_order(limit("carol", OrderSide::Sell, 100, 2), 5);
+        assert!(trades(&response).iter().all(|t| t.buy_order_id == first_id));
+        assert_eq!(
+            book.get_order(&first_id).unwrap().status,
+            OrderStatus::Filled
+        );
+        assert_eq!(book.get_snapshot().bids[0].quantity, Decimal::from(3));
+        check_book(&book, &accounts);
+    }
+
+    #[tokio::test]
+    async fn ack_names_the_order_the_later_fill_reports() {
+        let (book, _) = book();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let engine =
+            tokio::spawn(book.run_orderbook_engine(rx, wal().0, Arc::new(AtomicU64::new(0))));
+        let (response, reply) = tokio::sync::oneshot::channel();
+        let order = Box::new(limit("alice", OrderSide::Sell, 100, 1));
+        tx.send(OrderbookCommand::AddOrder {
+            order,
+            ack: None,
+            response,
+        })
+        .await
+        .unwrap();
+        reply.await.unwrap();
+
+        let (ack, acked) = tokio::sync::oneshot::channel();
+        let (response, reply) = tokio::sync::oneshot::channel();
+        let order = Box::new(limit("bob", OrderSide::Buy, 100, 1));
+        let order_id = order.id.clone();
+        tx.send(OrderbookCommand::AddOrder {
+            order,
+            ack: Some(ack),
+            response,
+        })
+        .await
+        .unwrap();
+        let OrderAck::Acknowledged {
+            order_id: acked_id,
+            sequence,
+        } = acked.await.unwrap();
+        assert_eq!(acked_id, order_id);
+        assert_eq!(sequence, 2);
+
+        let filled = reply.await.unwrap();
+        assert_eq!(filled.status(), "filled");
+        assert_eq!(trades(&filled)[0].buy_order_id, acked_id);
+        drop(tx);
+        engine.await.unwrap();
+    }
+
+    #[test]
+    fn simulated_market_buy_reports_fills_and_leaves_book_alone() {
+        let (mut book, accounts) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 2), 0);
+        book.add_order(limit("bob", OrderSide::Sell, 101, 3), 0);
+        let before = serde_json::to_value(book.get_full_snapshot()).unwrap();
+        let balances = serde_json::to_value(&*accounts.lock().unwrap()).unwrap();
+
+        let response = book.simulate_order(market_order("carol", OrderSide::Buy, 4), 0);
+
+        assert!(
+            matches!(response, OrderResponse::Filled { .. }),
+            "{response:?}"
+        );
+        let fills: Vec<_> = trades(&response)
+            .iter()
+            .map(|t| (t.price, t.quantity))
+            .collect();
+        assert_eq!(
+            fills,
+            [
+                (Decimal::from(100), Decimal::from(2)),
+                (Decimal::from(101), Decimal::from(2))
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(book.get_full_snapshot()).unwrap(),
+            before
+        );
+        assert_eq!(
+            serde_json::to_value(&*accounts.lock().unwrap()).unwrap(),
+            balances
+        );
+    }
+
+    #[test]
+    fn daily_stats_drop_trades_older_than_the_window() {
+        let (mut book, _) = book();
+        let minute = 60 * 1000;
+        let start = 10 * minute;
+        for (price, at) in [(100, start), (110, start + 30 * minute)] {
+            book.add_order(limit("alice", OrderSide::Sell, price, 1), at);
+            book.add_order(limit("bob", OrderSide::Buy, price, 1), at);
+        }
+
+        let both = book.daily_stats(start + crate::stats::WINDOW_MILLIS - minute);
+        assert_eq!(both.trade_count, 2);
+        assert_eq!(both.open, Some(Decimal::from(100)));
+        assert_eq!(both.volume, Decimal::from(2));
+
+        let later = book.daily_stats(start + crate::stats::WINDOW_MILLIS + minute);
+        assert_eq!(later.trade_count, 1);
+        assert_eq!(later.open, Some(Decimal::from(110)));
+        assert_eq!(later.low, Some(Decimal::from(110)));
+        assert_eq!(later.quote_volume, Decimal::from(110));
+        assert_eq!(later.last_price, Some(Decimal::from(110)));
+
+        let empty = book.daily_stats(start + crate::stats::WINDOW_MILLIS + 32 * minute);
+        assert_eq!(empty.trade_count, 0);
+        assert_eq!(empty.open, None);
+        assert_eq!(empty.last_price, Some(Decimal::from(110)));
+    }
+
+    #[test]
+    fn collar_breached_on_a_thin_book_rejects_without_touching_it() {
+        let (mut book, accounts) = book();
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 0);
+        book.add_order(limit("bob", OrderSide::Sell, 110, 5), 0);
+        let before = serde_json::to_value(book.get_full_snapshot()).unwrap();
+        let balances = serde_json::to_value(&*accounts.lock().unwrap()).unwrap();
+
+        // 100 for one and 110 for two average 106.67.
+        let mut collared = market_order("carol", OrderSide::Buy, 3);
+        collared.worst_average_price = Some(Decimal::from(105));
+        let response = book.add_order(collared, 0);
+
+        assert_eq!(rejection(&response), Some(OrderError::PriceCollarBreached));
+        assert_eq!(
+            serde_json::to_value(book.get_full_snapshot()).unwrap(),
+            before
+        );
+        assert_eq!(
+            serde_json::to_value(&*accounts.lock().unwrap()).unwrap(),
+            balances
+        );
+        assert_eq!(book.last_trade_price, None);
+    }
+
+    #[test]
+    fn collar_on_an_order_that_does_not_take_on_arrival_is_rejected() {
+        let (mut book, _) = book();
+        let collar = Some(Decimal::from(100));
+        for order_type in [
+            OrderType::PostOnly,
+            OrderType::StopMarket {
+                stop_price: Decimal::from(100),
+            },
+            OrderType::MarketIfTouched {
+                touch_price: Decimal::from(100),
+            },
+            OrderType::TrailingStop {
+                trail_amount: Decimal::ONE,
+                stop_price: None,
+            },
+            OrderType::Peg {
+                reference: PegReference::Primary,
+                offset: Decimal::ZERO,
+            },
+        ] {
+            let price = order_type.requires_price().then_some(Decimal::from(99));
+            let mut order = order("alice", OrderSide::Buy, order_type, price, Decimal::ONE);
+            order.worst_average_price = collar;
+            let response = book.add_order(order, 0);
+            assert_eq!(
+                rejection(&response),
+                Some(OrderError::InvalidPrice),
+                "{response:?}"
+            );
+        }
+        assert!(book.orders.is_empty() && book.stops.is_empty());
+    }
+
+    #[test]
+    fn exit_released_by_an_expiring_entry_trades_at_the_expiry_time() {
+        let (mut book, accounts) = book();
+        let mut entry = limit("carol", OrderSide::Buy, 100, 2);
+        entry.expires_at = Some(1_000);
+        let exits = Bracket::new(&entry, Decimal::from(105), Decimal::from(90));
+        let take_profit_id = exits.take_profit.id.clone();
+        book.add_bracket(entry, exits, 1);
+        book.add_order(limit("alice", OrderSide::Sell, 100, 1), 2);
+        book.add_order(limit("bob", OrderSide::Buy, 106, 1), 3);
+
+        book.expire_orders(1_000);
+
+        let trade = book.recent_trades.back().unwrap();
+        assert_eq!(trade.sell_order_id, take_profit_id);
+        assert_eq!(trade.price, Decimal::from(106));
+        assert_eq!(trade.timestamp, 1_000);
+        check_book(&book, &accounts);
+    }
+
+    #[test]
+    fn collared_order_is_refused_during_the_auction() {
+        let (mut book, accounts) = book();
+        book.set_status(MarketStatus::Auction, 1);
+        book.add_order(limit("alice", OrderSide::Sell, 110, 1), 2);
+
+        let mut collared = limit("bob", OrderSide::Buy, 110, 1);
+        collared.worst_average_price = Some(Decimal::from(100));
+        let response = book.add_order(collared, 3);
+        assert_eq!(rejection(&response), Some(OrderError::InvalidPrice));
+
+        book.set_status(MarketStatus::Open, 4);
+        assert!(book.recent_trades(10, None).is_empty());
+        assert_eq!(book.best_ask(), Some(Decimal::from(110)));
+        assert_eq!(book.best_bid(), None);
+        check_book(&book, &accounts);
     }
 }
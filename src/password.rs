@@ -0,0 +1,214 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use uuid::Uuid;
+
+use crate::config::{env_or, invalid};
+
+/// Makes the hashes stored for new passwords. Passwords are verified against
+/// whichever supported scheme their stored hash uses, so changing the hasher
+/// only affects hashes made from then on, and those upgraded at signin.
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, password: &str) -> Option<String>;
+    /// Whether `hash` was made by this hasher with its current parameters.
+    fn is_current(&self, hash: &str) -> bool;
+}
+
+pub struct Bcrypt {
+    pub cost: u32,
+}
+
+impl PasswordHasher for Bcrypt {
+    fn hash(&self, password: &str) -> Option<String> {
+        bcrypt::hash(password, self.cost).ok()
+    }
+
+    fn is_current(&self, hash: &str) -> bool {
+        hash.parse::<bcrypt::HashParts>()
+            .is_ok_and(|parts| parts.get_cost() == self.cost)
+    }
+}
+
+/// Argon2id with the crate's recommended parameters.
+pub struct Argon2id {
+    params: Params,
+}
+
+impl Argon2id {
+    pub fn new() -> Self {
+        Self {
+            params: Params::default(),
+        }
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordHasher for Argon2id {
+    fn hash(&self, password: &str) -> Option<String> {
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).ok()?;
+        self.hasher()
+            .hash_password(password.as_bytes(), &salt)
+            .ok()
+            .map(|hash| hash.to_string())
+    }
+
+    /// Only the costs are compared: parameters read back from a hash also
+    /// carry its output length, which the defaults leave unset.
+    fn is_current(&self, hash: &str) -> bool {
+        PasswordHash::new(hash).is_ok_and(|parsed| {
+            parsed.algorithm == Algorithm::Argon2id.ident()
+                && Params::try_from(&parsed).is_ok_and(|params| {
+                    (params.m_cost(), params.t_cost(), params.p_cost())
+                        == (
+                            self.params.m_cost(),
+                            self.params.t_cost(),
+                            self.params.p_cost(),
+                        )
+                })
+        })
+    }
+}
+
+/// Costs bcrypt accepts.
+const BCRYPT_COSTS: std::ops::RangeInclusive<u32> = 4..=31;
+
+/// Hasher chosen by `PASSWORD_HASHER` (`bcrypt`, the default, or `argon2`, in
+/// any case), with the bcrypt cost taken from `BCRYPT_COST`. Any other hasher
+/// or a cost bcrypt would refuse stops startup.
+pub fn from_env() -> std::io::Result<Box<dyn PasswordHasher>> {
+    let hasher = std::env::var("PASSWORD_HASHER").unwrap_or_else(|_| "bcrypt".to_string());
+    match hasher.trim().to_lowercase().as_str() {
+        "argon2" => Ok(Box::new(Argon2id::new())),
+        "bcrypt" => {
+            let cost = env_or("BCRYPT_COST", bcrypt::DEFAULT_COST)?;
+            if !BCRYPT_COSTS.contains(&cost) {
+                return Err(invalid("BCRYPT_COST", &cost.to_string()));
+            }
+            Ok(Box::new(Bcrypt { cost }))
+        }
+        _ => Err(invalid("PASSWORD_HASHER", &hasher)),
+    }
+}
+
+/// Checks `password` against a bcrypt or Argon2 hash.
+pub fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Kind of character a password can be required to contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharClass {
+    Lower,
+    Upper,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "lower" => Some(Self::Lower),
+            "upper" => Some(Self::Upper),
+            "digit" => Some(Self::Digit),
+            "symbol" => Some(Self::Symbol),
+            _ => None,
+        }
+    }
+
+    fn matches(self, c: char) -> bool {
+        match self {
+            Self::Lower => c.is_lowercase(),
+            Self::Upper => c.is_uppercase(),
+            Self::Digit => c.is_ascii_digit(),
+            Self::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+
+    fn requirement(self) -> &'static str {
+        match self {
+            Self::Lower => "contain a lowercase letter",
+            Self::Upper => "contain an uppercase letter",
+            Self::Digit => "contain a digit",
+            Self::Symbol => "contain a symbol",
+        }
+    }
+}
+
+/// Passwords shorter than this are refused unless `PASSWORD_MIN_LENGTH` is set.
+pub const DEFAULT_MIN_LENGTH: usize = 8;
+
+/// What a new password must satisfy.
+pub struct PasswordPolicy {
+    /// In characters, not bytes.
+    pub min_length: usize,
+    pub required: Vec<CharClass>,
+}
+
+/// Character classes required unless `PASSWORD_CHAR_CLASSES` is set.
+pub const DEFAULT_CHAR_CLASSES: [CharClass; 3] =
+    [CharClass::Lower, CharClass::Upper, CharClass::Digit];
+
+impl Default for PasswordPolicy {
+    /// The policy used when neither setting is in the environment.
+    fn default() -> Self {
+        Self {
+            min_length: DEFAULT_MIN_LENGTH,
+            required: DEFAULT_CHAR_CLASSES.to_vec(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Policy from `PASSWORD_MIN_LENGTH` and `PASSWORD_CHAR_CLASSES`, a
+    /// comma-separated list of `lower`, `upper`, `digit` and `symbol` that
+    /// defaults to the first three. A length that does not parse or a class
+    /// name not on that list stops startup.
+    pub fn from_env() -> std::io::Result<Self> {
+        let min_length = env_or("PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH)?;
+        let required = match std::env::var("PASSWORD_CHAR_CLASSES") {
+            Ok(classes) => parse_classes(&classes)?,
+            Err(_) => DEFAULT_CHAR_CLASSES.to_vec(),
+        };
+        Ok(Self {
+            min_length,
+            required,
+        })
+    }
+
+    /// Every requirement `password` fails, phrased to follow "password must".
+    pub fn unmet(&self, password: &str) -> Vec<String> {
+        let mut unmet = Vec::new();
+        if password.chars().count() < self.min_length {
+            unmet.push(format!("be at least {} characters long", self.min_length));
+        }
+        for class in &self.required {
+            if !password.chars().any(|c| class.matches(c)) {
+                unmet.push(class.requirement().to_string());
+            }
+        }
+        unmet
+    }
+}
+
+/// Classes named in `classes`, a `PASSWORD_CHAR_CLASSES` value: comma-separated
+/// and in any case, with empty entries skipped.
+pub fn parse_classes(classes: &str) -> std::io::Result<Vec<CharClass>> {
+    classes
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            CharClass::parse(&name).ok_or_else(|| invalid("PASSWORD_CHAR_CLASSES", classes))
+        })
+        .collect()
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::dispatch::Shards;
+use crate::types::{
+    Account, Accounts, BookState, Bracket, EngineState, MarketStatus, Order, OrderbookCommand, User,
+};
+
+/// File the server state is saved to unless `STATE_FILE` is set.
+pub const DEFAULT_STATE_FILE: &str = "orderbook_state.json";
+
+/// Write-ahead log location unless `WAL_FILE` is set.
+pub const DEFAULT_WAL_FILE: &str = "orderbook_wal.jsonl";
+
+/// How often the running server saves its state.
+pub const SAVE_INTERVAL_SECS: u64 = 5;
+
+/// Everything needed to bring the server back after a restart.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SavedState {
+    pub users: Vec<User>,
+    pub accounts: HashMap<String, Account>,
+    pub books: Vec<BookState>,
+    /// Last log record included in this state; later records are replayed on load.
+    #[serde(default)]
+    pub wal_sequence: u64,
+    #[serde(default)]
+    pub order_sequence: u64,
+}
+
+/// A state change, logged before it is applied.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WalEntry {
+    Signup(User),
+    /// Checked to fit in the account before it was logged.
+    Deposit {
+        user_id: String,
+        asset: Option<String>,
+        amount: Decimal,
+    },
+    /// Checked against the available balance before it was logged.
+    Withdraw {
+        user_id: String,
+        asset: Option<String>,
+        amount: Decimal,
+    },
+    AddOrder(Box<Order>),
+    AddOco {
+        first: Box<Order>,
+        second: Box<Order>,
+    },
+    AddBracket {
+        entry: Box<Order>,
+        exits: Box<Bracket>,
+    },
+    CancelOrder {
+        order_id: String,
+        user_id: String,
+        /// Who asked for the cancel. Records written before it was kept leave
+        /// it out and count as the owner's.
+        #[serde(default)]
+        cancelled_by: Option<String>,
+    },
+    /// `symbol` is the market the entry applied to. Records written before
+    /// each market had its own engine leave it out and apply to every market.
+    CancelAllForUser {
+        user_id: String,
+        #[serde(default)]
+        symbol: Option<String>,
+    },
+    SetMarketStatus {
+        symbol: String,
+        status: MarketStatus,
+    },
+    /// Expiry sweep that found at least one order past its `expires_at`.
+    ExpireOrders {
+        #[serde(default)]
+        symbol: Option<String>,
+    },
+    /// Activation sweep that found at least one good-after-time order due.
+    ActivateOrders {
+        symbol: String,
+    },
+    AmendOrder {
+        order_id: String,
+        user_id: String,
+        new_price: Option<Decimal>,
+        new_quantity: Option<Decimal>,
+        /// Sequence the order takes if the amendment loses its priority.
+        sequence: u64,
+    },
+    ReduceOrder {
+        order_id: String,
+        user_id: String,
+        quantity: Decimal,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WalRecord {
+    pub sequence: u64,
+    /// Engine time the entry was applied at, reused when it is replayed.
+    pub timestamp: u64,
+    pub entry: WalEntry,
+}
+
+/// What the engine starts from: the last saved books plus the log records
+/// written after them.
+pub struct Recovery {
+    pub books: Vec<BookState>,
+    pub records: Vec<WalRecord>,
+    pub order_sequence: u64,
+}
+
+/// Append-only log of `WalRecord`s, one JSON object per line. Records already
+/// covered by a saved state are dropped with `truncate_through`.
+pub struct Wal {
+    path: PathBuf,
+    file: Arc<File>,
+    sequence: u64,
+    applied: Arc<Applied>,
+}
+
+pub type SharedWal = Arc<Mutex<Wal>>;
+
+/// Sequence number of the last record applied. Records written by `logged`
+/// are applied after the log is released, each waiting here for the one
+/// before it, so they still take effect in log order.
+struct Applied {
+    sequence: Mutex<u64>,
+    advanced: Condvar,
+}
+
+impl Applied {
+    fn new(sequence: u64) -> Self {
+        Self {
+            sequence: Mutex::new(sequence),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Blocks until every record up to and including `sequence` is applied.
+    fn wait_for(&self, sequence: u64) {
+        let mut applied = self.sequence.lock().unwrap();
+        while *applied < sequence {
+            applied = self.advanced.wait(applied).unwrap();
+        }
+    }
+
+    fn advance(&self, sequence: u64) {
+        *self.sequence.lock().unwrap() = sequence;
+        self.advanced.notify_all();
+    }
+}
+
+impl Wal {
+    /// Opens or creates the log at `path` and returns the records after
+    /// `after`, in order. A torn final line from a crash mid-write is ignored.
+    pub fn open(path: &Path, after: u64) -> io::Result<(Self, Vec<WalRecord>)> {
+        let records = Self::read(path)?;
+        let sequence = records.last().map_or(after, |r| r.sequence.max(after));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        // The records after `after` are replayed before anything new is
+        // written, so they count as applied from the start.
+        let wal = Self {
+            path: path.to_path_buf(),
+            file: Arc::new(file),
+            sequence,
+            applied: Arc::new(Applied::new(sequence)),
+        };
+        let pending = records.into_iter().filter(|r| r.sequence > after).collect();
+        Ok((wal, pending))
+    }
+
+    fn read(path: &Path) -> io::Result<Vec<WalRecord>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+        }
+        Ok(records)
+    }
+
+    /// Sequence number of the last record written.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Waits until every record written so far has been applied. Callers
+    /// that check balances before appending call this first, before locking
+    /// `Accounts`, so the check sees every change already logged.
+    pub fn settle(&self) {
+        self.applied.wait_for(self.sequence);
+    }
+
+    /// Writes `entry` through to the disk, for a caller that applies it while
+    /// it still holds the log. Waits for the records before it to be applied
+    /// first.
+    pub fn append(&mut self, timestamp: u64, entry: WalEntry) -> io::Result<()> {
+        self.settle();
+        let sequence = self.write(timestamp, entry)?;
+        let synced = self.file.sync_data();
+        // Counted as applied even if the sync failed, or every record after
+        // it would wait for it forever.
+        self.applied.advance(sequence);
+        synced
+    }
+
+    /// Adds the record to the file, but not necessarily to the disk yet.
+    fn write(&mut self, timestamp: u64, entry: WalEntry) -> io::Result<u64> {
+        let record = WalRecord {
+            sequence: self.sequence + 1,
+            timestamp,
+            entry,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        (&*self.file).write_all(&line)?;
+        self.sequence = record.sequence;
+        Ok(record.sequence)
+    }
+
+    /// Rewrites the log without the records up to and including `sequence`.
+    pub fn truncate_through(&mut self, sequence: u64) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut out = File::create(&tmp)?;
+        for record in Self::read(&self.path)? {
+            if record.sequence > sequence {
+                let mut line = serde_json::to_vec(&record)?;
+                line.push(b'\n');
+                out.write_all(&line)?;
+            }
+        }
+        out.flush()?;
+        out.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+        sync_parent(&self.path)?;
+        self.file = Arc::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+}
+
+/// Appends `entry` and, only once it is on the disk, runs `apply`. The log is
+/// held just to write the record: the sync and `apply` happen after it is
+/// released, so other markets can log and sync in the meantime. Changes to
+/// balances shared between markets all go through here, and `apply` waits
+/// for every earlier record to be applied, so they take effect in the order
+/// a replay applies them.
+pub fn logged<T>(
+    wal: &SharedWal,
+    timestamp: u64,
+    entry: WalEntry,
+    apply: impl FnOnce() -> T,
+) -> Result<T, String> {
+    let (sequence, file, applied) = {
+        let mut wal = wal.lock().unwrap();
+        let sequence = wal
+            .write(timestamp, entry)
+            .map_err(|e| format!("failed to write the order log: {e}"))?;
+        (sequence, Arc::clone(&wal.file), Arc::clone(&wal.applied))
+    };
+
+    // Syncs running at once share the disk flush, which also covers every
+    // record written before this one.
+    let synced = file.sync_data();
+    applied.wait_for(sequence - 1);
+    let result = synced.map(|()| apply());
+    applied.advance(sequence);
+    result.map_err(|e| format!("failed to write the order log: {e}"))
+}
+
+/// Reads state written by `save`. A missing file means a fresh start.
+pub fn load(path: &Path) -> io::Result<SavedState> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SavedState::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes to a temporary file and renames it over `path`, so a crash mid-write
+/// leaves the previous save intact. The file and the rename are both on the
+/// disk when this returns, so the log it covers can be truncated after it.
+pub fn save(path: &Path, state: &SavedState) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut out = File::create(&tmp)?;
+    out.write_all(&serde_json::to_vec(state)?)?;
+    out.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+/// Syncs the directory holding `path`, so a rename into it survives a crash.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+/// Captures every market and the balances at one point in the log. Each
+/// engine replies with its book and then waits; balances are read once all of
+/// them have stopped, and the engines carry on when the export returns.
+pub async fn export_engine(
+    shards: &Shards,
+    accounts: &Accounts,
+    wal: &SharedWal,
+    order_sequence: &AtomicU64,
+) -> io::Result<EngineState> {
+    let engine_gone = || io::Error::other("orderbook engine is not running");
+    let mut books = Vec::new();
+    let mut resumes = Vec::new();
+
+    for orderbook_tx in shards.values() {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let (resume_tx, resume_rx) = tokio::sync::oneshot::channel();
+        orderbook_tx
+            .send(OrderbookCommand::ExportState {
+                response: response_tx,
+                resume: resume_rx,
+            })
+            .await
+            .map_err(|_| engine_gone())?;
+        books.push(response_rx.await.map_err(|_| engine_gone())?);
+        resumes.push(resume_tx);
+    }
+
+    // Holding the log also keeps out deposits, which no engine applies.
+    let wal = wal.lock().unwrap();
+    wal.settle();
+    Ok(EngineState {
+        books,
+        accounts: accounts.lock().unwrap().clone(),
+        wal_sequence: wal.sequence(),
+        order_sequence: order_sequence.load(Ordering::SeqCst),
+    })
+}
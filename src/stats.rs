@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::types::Trade;
+
+/// Span the rolling statistics cover, in milliseconds.
+pub const WINDOW_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+/// Trades are summed per minute, so a trade leaves the window up to a minute
+/// after it turns 24 hours old.
+const BUCKET_MILLIS: u64 = 60 * 1000;
+
+/// Trading in a market over the last 24 hours. The window's prices are null
+/// while nothing has traded in it; `last_price` is the latest trade ever.
+#[derive(Debug, Clone, Serialize)]
+pub struct DailyStats {
+    pub symbol: String,
+    /// Base quantity traded.
+    pub volume: Decimal,
+    /// Quote value traded, price times quantity summed over the trades.
+    pub quote_volume: Decimal,
+    pub trade_count: u64,
+    /// Price of the first trade in the window.
+    pub open: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub last_price: Option<Decimal>,
+    /// `last_price` less `open`, and that as a percentage of `open`.
+    pub price_change: Option<Decimal>,
+    pub price_change_percent: Option<Decimal>,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    start: u64,
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    volume: Decimal,
+    quote_volume: Decimal,
+    trade_count: u64,
+}
+
+/// One market's trades over the last 24 hours, summed per minute, oldest
+/// first. Minutes without trades have no bucket.
+#[derive(Default)]
+pub struct RollingStats {
+    buckets: VecDeque<Bucket>,
+}
+
+impl RollingStats {
+    pub fn record(&mut self, trade: &Trade) {
+        let start = trade.timestamp - trade.timestamp % BUCKET_MILLIS;
+        let quote_volume = trade.price * trade.quantity;
+
+        if let Some(bucket) = self.buckets.back_mut() {
+            if bucket.start == start {
+                bucket.high = bucket.high.max(trade.price);
+                bucket.low = bucket.low.min(trade.price);
+                bucket.volume += trade.quantity;
+                bucket.quote_volume += quote_volume;
+                bucket.trade_count += 1;
+                return;
+            }
+        }
+
+        self.buckets.push_back(Bucket {
+            start,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            volume: trade.quantity,
+            quote_volume,
+            trade_count: 1,
+        });
+    }
+
+    /// Drops the minutes that have left the window by `now`.
+    pub fn age_out(&mut self, now: u64) {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| !Self::in_window(bucket, now))
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// The window ending at `now`. Minutes that have left it but not yet been
+    /// aged out are skipped.
+    pub fn summary(&self, symbol: &str, last_price: Option<Decimal>, now: u64) -> DailyStats {
+        let mut stats = DailyStats {
+            symbol: symbol.to_string(),
+            volume: Decimal::ZERO,
+            quote_volume: Decimal::ZERO,
+            trade_count: 0,
+            open: None,
+            high: None,
+            low: None,
+            last_price,
+            price_change: None,
+            price_change_percent: None,
+        };
+
+        for bucket in self.buckets.iter().filter(|b| Self::in_window(b, now)) {
+            stats.open.get_or_insert(bucket.open);
+            stats.high = Some(stats.high.map_or(bucket.high, |high| high.max(bucket.high)));
+            stats.low = Some(stats.low.map_or(bucket.low, |low| low.min(bucket.low)));
+            stats.volume += bucket.volume;
+            stats.quote_volume += bucket.quote_volume;
+            stats.trade_count += bucket.trade_count;
+        }
+
+        if let Some((open, last)) = stats.open.zip(last_price) {
+            let change = last - open;
+            stats.price_change = Some(change);
+            stats.price_change_percent = Some((change / open * Decimal::ONE_HUNDRED).round_dp(2));
+        }
+        stats
+    }
+
+    fn in_window(bucket: &Bucket, now: u64) -> bool {
+        bucket.start + BUCKET_MILLIS > now.saturating_sub(WINDOW_MILLIS)
+    }
+}
@@ -0,0 +1,204 @@
+//! Routes for integration tests, built only with the `test-endpoints`
+//! feature. They take no token, so the feature must stay off in any build
+//! that is deployed.
+
+use std::collections::HashMap;
+
+use actix_web::{http::StatusCode, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::dispatch;
+use crate::error::ApiError;
+use crate::orderbook;
+use crate::persistence::WalEntry;
+use crate::types::{self, OrderError, OrderResponse, OrderbookCommand, User};
+use crate::{deposit_refusal, new_order, save_state, AppState, OrderRequest};
+
+/// Clears users, revoked tokens, balances and every book, then saves the
+/// empty state so a restart does not bring any of it back. Meant for a quiet
+/// server: requests running alongside may land on either side of the reset.
+#[post("/admin/reset")]
+pub async fn reset(data: web::Data<AppState>) -> impl Responder {
+    let reset = dispatch::request_all(&data.shards, |response| OrderbookCommand::Reset {
+        response,
+    })
+    .await;
+    if let Err(e) = reset {
+        return e.response();
+    }
+
+    data.users.lock().unwrap().clear();
+    data.revoked_tokens.lock().unwrap().clear();
+    data.accounts.lock().unwrap().clear();
+    if let Err(e) = save_state(&data).await {
+        tracing::error!(error = %e, "failed to save the reset state");
+        return ApiError::internal("failed to save the reset state").response();
+    }
+
+    tracing::warn!("all users, balances and books reset");
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
+struct SeedUser {
+    username: String,
+    password: String,
+    /// Quote currency deposited on creation.
+    #[serde(default)]
+    balance: Decimal,
+    /// Base assets deposited on creation.
+    #[serde(default)]
+    assets: HashMap<String, Decimal>,
+}
+
+#[derive(Deserialize)]
+struct SeedOrder {
+    username: String,
+    order: OrderRequest,
+}
+
+/// Users to create, then orders to place on their behalf, in order.
+#[derive(Deserialize)]
+struct Fixture {
+    #[serde(default)]
+    users: Vec<SeedUser>,
+    #[serde(default)]
+    orders: Vec<SeedOrder>,
+}
+
+#[derive(Serialize)]
+struct SeedResponse {
+    /// Id of each created user, by username.
+    users: HashMap<String, String>,
+    /// Engine response to each order, in fixture order.
+    orders: Vec<OrderResponse>,
+}
+
+/// Loads a fixture through the same log as signups, deposits and orders, so
+/// the seeded state survives a restart. Password rules are not applied.
+#[post("/admin/seed")]
+pub async fn seed(data: web::Data<AppState>, body: web::Json<Fixture>) -> impl Responder {
+    let fixture = body.into_inner();
+    let mut ids = HashMap::new();
+
+    for seeded in fixture.users {
+        let user = match create_user(&data, &seeded) {
+            Ok(user) => user,
+            Err(e) => return e.response(),
+        };
+        let now = orderbook::now_millis();
+        let deposits = std::iter::once((None, seeded.balance))
+            .chain(
+                seeded
+                    .assets
+                    .into_iter()
+                    .map(|(a, amount)| (Some(a), amount)),
+            )
+            .filter(|(_, amount)| !amount.is_zero());
+        for (asset, amount) in deposits {
+            let mut wal = data.wal.lock().unwrap();
+            wal.settle();
+            let mut accounts = data.accounts.lock().unwrap();
+            let account = accounts.entry(user.id.clone()).or_default();
+            if let Some(refused) = deposit_refusal(account, asset.as_deref(), amount) {
+                return refused.response();
+            }
+            let entry = WalEntry::Deposit {
+                user_id: user.id.clone(),
+                asset: asset.clone(),
+                amount,
+            };
+            if let Err(e) = wal.append(now, entry) {
+                return ApiError::internal(format!("failed to write the order log: {e}"))
+                    .response();
+            }
+            let _ = account.deposit(asset.as_deref(), amount, now);
+        }
+        ids.insert(user.username, user.id);
+    }
+
+    let mut responses = Vec::new();
+    for seeded in fixture.orders {
+        let username = types::canonical_username(&seeded.username);
+        let Some(user_id) = data
+            .users
+            .lock()
+            .unwrap()
+            .get(&username)
+            .map(|u| u.id.clone())
+        else {
+            return ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "unknown_user",
+                format!("unknown user {}", seeded.username),
+            )
+            .response();
+        };
+        let order = new_order(user_id, seeded.order);
+        let Some(orderbook_tx) = data.shards.get(&order.symbol) else {
+            responses.push(OrderResponse::Error {
+                code: OrderError::UnknownSymbol,
+                message: format!("unknown symbol {}", order.symbol),
+            });
+            continue;
+        };
+        match dispatch::request(orderbook_tx, |response| OrderbookCommand::AddOrder {
+            order: Box::new(order),
+            ack: None,
+            response,
+        })
+        .await
+        {
+            Ok(response) => responses.push(response),
+            Err(e) => return e.response(),
+        }
+    }
+
+    HttpResponse::Ok().json(SeedResponse {
+        users: ids,
+        orders: responses,
+    })
+}
+
+/// Signs `seeded` up the way `/signup` does, minus the password rules.
+fn create_user(data: &AppState, seeded: &SeedUser) -> Result<User, ApiError> {
+    let username = types::canonical_username(&seeded.username);
+    if username.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "empty_credentials",
+            "username cannot be empty",
+        ));
+    }
+    let mut users = data.users.lock().unwrap();
+    if users.contains_key(&username) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "user_exists",
+            format!("user {username} already exists"),
+        ));
+    }
+
+    let Some(password_hash) = data.password_hasher.hash(&seeded.password) else {
+        return Err(ApiError::internal("failed to hash the password"));
+    };
+    let mut user = User::new(
+        Uuid::new_v4().to_string(),
+        seeded.username.clone(),
+        password_hash,
+    );
+    user.is_admin = data.admins.contains(&username);
+    data.wal
+        .lock()
+        .unwrap()
+        .append(orderbook::now_millis(), WalEntry::Signup(user.clone()))
+        .map_err(|_| ApiError::internal("failed to record the new user"))?;
+    users.insert(username, user.clone());
+    data.accounts
+        .lock()
+        .unwrap()
+        .insert(user.id.clone(), Default::default());
+    Ok(user)
+}
@@ -1,94 +1,1359 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
 
-#[derive(Clone)]
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
+    /// Canonical form of the name, see `canonical_username`. Users are looked
+    /// up and tokens issued by it.
     pub username: String,
+    /// Name as given at signup, only trimmed.
+    #[serde(default)]
+    pub display_name: String,
     pub password_hash: String,
-    pub balance: f64,
-    pub assets: HashMap<String, f64>,
+    /// May use the `/admin` routes. Set from `ADMIN_USERS` at signup and
+    /// again each time the server starts.
+    #[serde(default)]
+    pub is_admin: bool,
 }
 
 impl User {
     pub fn new(id: String, username: String, password: String) -> Self {
         Self {
             id,
-            username,
+            display_name: username.trim().to_string(),
+            username: canonical_username(&username),
             password_hash: password,
-            balance: 0.0,
-            assets: HashMap::new(),
+            is_admin: false,
+        }
+    }
+
+    /// Brings a user saved before names were normalized to the canonical form.
+    pub fn canonicalize(mut self) -> Self {
+        if self.display_name.is_empty() {
+            self.display_name = self.username.clone();
         }
+        self.username = canonical_username(&self.username);
+        self
+    }
+}
+
+/// Usernames differing only in case or surrounding whitespace name the same user.
+pub fn canonical_username(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Currency every market is quoted and settled in.
+pub const QUOTE_ASSET: &str = "USD";
+
+/// Decimal places of base quantities, unless a market sets its own.
+pub const DEFAULT_BASE_DECIMALS: u32 = 8;
+
+/// Decimal places of quote amounts, unless a market sets its own.
+pub const DEFAULT_QUOTE_DECIMALS: u32 = 2;
+
+/// Funds held by a user: `balance` is denominated in the quote currency and
+/// `assets` maps a base asset (e.g. "BTC") to the quantity held. Both count
+/// only what is available; funds set aside by resting orders are moved to
+/// `reserved` and `reserved_assets` until the orders fill or are cancelled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: Decimal,
+    pub assets: HashMap<String, Decimal>,
+    #[serde(default)]
+    pub reserved: Decimal,
+    #[serde(default)]
+    pub reserved_assets: HashMap<String, Decimal>,
+    /// Latest changes to what the account holds, oldest first.
+    #[serde(default)]
+    pub ledger: VecDeque<LedgerEntry>,
+    /// Quote notional traded on each of the last `VOLUME_WINDOW_DAYS` days,
+    /// keyed by days since the Unix epoch. Fee tiers are chosen on it.
+    #[serde(default)]
+    pub daily_volume: BTreeMap<u64, Decimal>,
+    /// Orders placed and cancelled, and fills, in each minute of the last
+    /// `ORDER_ACTIVITY_RETENTION_MILLIS`, keyed by minutes since the Unix epoch.
+    #[serde(default)]
+    pub order_activity: BTreeMap<u64, OrderActivity>,
+}
+
+/// How far back `Account::order_activity` is kept, and so the longest span
+/// it can be summed over.
+pub const ORDER_ACTIVITY_RETENTION_MILLIS: u64 = 60 * 60 * 1_000;
+
+const MILLIS_PER_MINUTE: u64 = 60 * 1_000;
+
+/// What a user did with their orders over some span: how many they placed,
+/// how many of their own they cancelled and how many fills they got.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct OrderActivity {
+    pub placed: u64,
+    pub cancelled: u64,
+    pub fills: u64,
+}
+
+impl OrderActivity {
+    /// Cancels per order placed, or `None` before anything was placed. Orders
+    /// placed before the span and cancelled in it can take it above one.
+    pub fn cancel_ratio(&self) -> Option<Decimal> {
+        (self.placed > 0).then(|| Decimal::from(self.cancelled) / Decimal::from(self.placed))
+    }
+}
+
+/// Entries each account keeps in its ledger; older ones are dropped.
+pub const LEDGER_LIMIT: usize = 1_000;
+
+/// Largest amount of any asset a single deposit may bring in, in whole units.
+const MAX_DEPOSIT_UNITS: u64 = 1_000_000_000_000_000;
+
+/// `MAX_DEPOSIT_UNITS`, 10^15, as a `Decimal`: its low and high 32 bits.
+pub const MAX_DEPOSIT: Decimal = Decimal::from_parts(
+    MAX_DEPOSIT_UNITS as u32,
+    (MAX_DEPOSIT_UNITS >> 32) as u32,
+    0,
+    false,
+    0,
+);
+
+/// Days of trading that count towards a user's fee tier, today included.
+pub const VOLUME_WINDOW_DAYS: u64 = 30;
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1_000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerKind {
+    Deposit,
+    Withdrawal,
+    /// Quote paid or received, or base asset bought or sold, in a trade.
+    Trade,
+    Fee,
+}
+
+/// One change to an account's holding of one asset. Reserving funds for an
+/// order moves them within the holding and is not recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub kind: LedgerKind,
+    /// Base asset that changed, or `None` for the quote balance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>,
+    /// Negative for debits.
+    pub amount: Decimal,
+    /// What the account holds of the asset afterwards, reserved funds included.
+    pub balance: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trade_id: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Account {
+    /// Available and reserved amounts of the quote currency (`asset` of
+    /// `None`) or of a base asset.
+    fn holding_mut(&mut self, asset: Option<&str>) -> (&mut Decimal, &mut Decimal) {
+        match asset {
+            Some(asset) => (
+                self.assets.entry(asset.to_string()).or_default(),
+                self.reserved_assets.entry(asset.to_string()).or_default(),
+            ),
+            None => (&mut self.balance, &mut self.reserved),
+        }
+    }
+
+    /// Available and reserved amounts of the quote currency or a base asset.
+    fn holding(&self, asset: Option<&str>) -> (Decimal, Decimal) {
+        match asset {
+            Some(asset) => (
+                self.assets.get(asset).copied().unwrap_or_default(),
+                self.reserved_assets.get(asset).copied().unwrap_or_default(),
+            ),
+            None => (self.balance, self.reserved),
+        }
+    }
+
+    /// What is available and what is held in all after adding `amount`, or
+    /// `None` if either would overflow.
+    fn credited(&self, asset: Option<&str>, amount: Decimal) -> Option<(Decimal, Decimal)> {
+        let (available, reserved) = self.holding(asset);
+        let available = available.checked_add(amount)?;
+        Some((available, available.checked_add(reserved)?))
+    }
+
+    /// Whether `credit` can add `amount` without overflowing.
+    pub fn can_credit(&self, asset: Option<&str>, amount: Decimal) -> bool {
+        self.credited(asset, amount).is_some()
+    }
+
+    /// Adds `amount`, negative for a debit, to what is available and records
+    /// the change in the ledger. Returns the new available amount, or `None`,
+    /// with the account left as it is, if the holding would overflow.
+    pub fn credit(
+        &mut self,
+        kind: LedgerKind,
+        asset: Option<&str>,
+        amount: Decimal,
+        trade_id: Option<&str>,
+        timestamp: u64,
+    ) -> Option<Decimal> {
+        let (available, balance) = self.credited(asset, amount)?;
+        *self.holding_mut(asset).0 = available;
+
+        if self.ledger.len() >= LEDGER_LIMIT {
+            self.ledger.pop_front();
+        }
+        self.ledger.push_back(LedgerEntry {
+            kind,
+            asset: asset.map(str::to_string),
+            amount,
+            balance,
+            trade_id: trade_id.map(str::to_string),
+            timestamp,
+        });
+        Some(available)
+    }
+
+    /// Credits `amount` to what is available and returns the new total. If
+    /// that would overflow the account is left as it is and what is available
+    /// is returned as the error.
+    pub fn deposit(
+        &mut self,
+        asset: Option<&str>,
+        amount: Decimal,
+        timestamp: u64,
+    ) -> Result<Decimal, Decimal> {
+        self.credit(LedgerKind::Deposit, asset, amount, None, timestamp)
+            .ok_or(self.holding(asset).0)
+    }
+
+    /// Debits `amount` from what is available and returns the new total. If
+    /// that is not enough the account is left as it is and what is available
+    /// is returned as the error; reserved funds cannot be withdrawn.
+    pub fn withdraw(
+        &mut self,
+        asset: Option<&str>,
+        amount: Decimal,
+        timestamp: u64,
+    ) -> Result<Decimal, Decimal> {
+        let (available, _) = self.holding(asset);
+        if amount > available {
+            return Err(available);
+        }
+        self.credit(LedgerKind::Withdrawal, asset, -amount, None, timestamp)
+            .ok_or(available)
+    }
+
+    /// Moves `amount` from available to reserved. Returns false, with nothing
+    /// moved, if either side would overflow.
+    pub fn reserve(&mut self, asset: Option<&str>, amount: Decimal) -> bool {
+        self.shift(asset, -amount)
+    }
+
+    /// Moves `amount` from reserved back to available. Returns false, with
+    /// nothing moved, if either side would overflow.
+    pub fn release(&mut self, asset: Option<&str>, amount: Decimal) -> bool {
+        self.shift(asset, amount)
+    }
+
+    /// Adds `amount` to what is available and takes it from what is reserved.
+    fn shift(&mut self, asset: Option<&str>, amount: Decimal) -> bool {
+        let (available, reserved) = self.holding_mut(asset);
+        match (available.checked_add(amount), reserved.checked_sub(amount)) {
+            (Some(new_available), Some(new_reserved)) => {
+                *available = new_available;
+                *reserved = new_reserved;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds a trade's quote `notional` to the day of `timestamp`, dropping
+    /// days that have left the window. A day's volume stops at `Decimal::MAX`.
+    pub fn record_volume(&mut self, notional: Decimal, timestamp: u64) {
+        let day = timestamp / MILLIS_PER_DAY;
+        let volume = self.daily_volume.entry(day).or_default();
+        *volume = volume.saturating_add(notional);
+        let first_day = (day + 1).saturating_sub(VOLUME_WINDOW_DAYS);
+        self.daily_volume.retain(|d, _| *d >= first_day);
+    }
+
+    /// Adds to the minute of `timestamp` in `order_activity`, dropping minutes
+    /// past `ORDER_ACTIVITY_RETENTION_MILLIS`.
+    pub fn record_activity(&mut self, timestamp: u64, record: impl FnOnce(&mut OrderActivity)) {
+        let minute = timestamp / MILLIS_PER_MINUTE;
+        record(self.order_activity.entry(minute).or_default());
+        let first_minute =
+            (minute + 1).saturating_sub(ORDER_ACTIVITY_RETENTION_MILLIS / MILLIS_PER_MINUTE);
+        self.order_activity.retain(|m, _| *m >= first_minute);
+    }
+
+    /// Order activity over the `window_millis` up to `now`, counted by whole
+    /// minutes, the current one included.
+    pub fn recent_activity(&self, now: u64, window_millis: u64) -> OrderActivity {
+        let minute = now / MILLIS_PER_MINUTE;
+        let first_minute = (minute + 1).saturating_sub(window_millis.div_ceil(MILLIS_PER_MINUTE));
+        self.order_activity.range(first_minute..=minute).fold(
+            OrderActivity::default(),
+            |total, (_, a)| OrderActivity {
+                placed: total.placed + a.placed,
+                cancelled: total.cancelled + a.cancelled,
+                fills: total.fills + a.fills,
+            },
+        )
+    }
+
+    /// Quote notional traded over the `VOLUME_WINDOW_DAYS` days up to and
+    /// including that of `now`.
+    pub fn trailing_volume(&self, now: u64) -> Decimal {
+        let day = now / MILLIS_PER_DAY;
+        let first_day = (day + 1).saturating_sub(VOLUME_WINDOW_DAYS);
+        self.daily_volume
+            .range(first_day..=day)
+            .map(|(_, v)| *v)
+            .sum()
     }
 }
 
+/// Accounts keyed by user id, shared between the HTTP layer and the engine.
+pub type Accounts = Arc<Mutex<HashMap<String, Account>>>;
+
+/// Static parameters of a tradable market such as "BTC-USD".
 #[derive(Debug, Clone)]
+pub struct MarketConfig {
+    pub symbol: String,
+    /// Asset bought and sold; the quote side always settles against `Account::balance`.
+    pub base_asset: String,
+    /// Smallest price increment; book keys are prices expressed as a whole number of ticks.
+    pub tick_size: Decimal,
+    /// Fee charged to the resting side of a trade, in basis points of notional.
+    pub maker_fee_bps: Decimal,
+    /// Fee charged to the incoming side of a trade, in basis points of notional.
+    pub taker_fee_bps: Decimal,
+    /// Lower fees for users trading more, by ascending `min_volume`. Users
+    /// below the first pay `maker_fee_bps` and `taker_fee_bps`.
+    pub fee_tiers: Vec<FeeTier>,
+    /// Smallest order quantity accepted.
+    pub min_quantity: Decimal,
+    /// Smallest `price * quantity` accepted for orders that carry a price.
+    pub min_notional: Decimal,
+    /// Largest order quantity accepted, if any.
+    pub max_quantity: Option<Decimal>,
+    /// Most orders one user may have open at once, resting or waiting on a
+    /// stop, if there is a limit.
+    pub max_open_orders: Option<usize>,
+    /// How far, in basis points of the last traded price, an order may trade
+    /// or be priced. `None`, or a market that has not traded yet, has no band.
+    pub price_band_bps: Option<Decimal>,
+    pub matching_mode: MatchingMode,
+    /// Most decimal places an order quantity may have.
+    pub base_decimals: u32,
+    /// Decimal places trade notionals and fees are settled to.
+    pub quote_decimals: u32,
+}
+
+/// Fees for users whose trailing volume, in quote currency over
+/// `VOLUME_WINDOW_DAYS`, is at least `min_volume`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeTier {
+    pub min_volume: Decimal,
+    pub maker_fee_bps: Decimal,
+    pub taker_fee_bps: Decimal,
+}
+
+impl FeeTier {
+    pub fn new(min_volume: Decimal, maker_fee_bps: Decimal, taker_fee_bps: Decimal) -> Self {
+        Self {
+            min_volume,
+            maker_fee_bps,
+            taker_fee_bps,
+        }
+    }
+}
+
+/// The fees one user pays on one market, as listed by `/fees`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MarketFees {
+    pub symbol: String,
+    /// 0 for the market's base fees, then 1 onwards for each of its
+    /// `fee_tiers` reached.
+    pub tier: usize,
+    pub maker_fee_bps: Decimal,
+    pub taker_fee_bps: Decimal,
+    /// Trailing volume needed for the next tier, if there is one.
+    pub next_tier_volume: Option<Decimal>,
+}
+
+/// How the orders resting at the best price share an incoming order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchingMode {
+    /// Oldest order first.
+    #[default]
+    PriceTime,
+    /// In proportion to each order's size.
+    ProRata,
+}
+
+impl MarketConfig {
+    pub fn new(symbol: &str, base_asset: &str, tick_size: Decimal) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            base_asset: base_asset.to_string(),
+            tick_size,
+            maker_fee_bps: Decimal::ZERO,
+            taker_fee_bps: Decimal::ZERO,
+            fee_tiers: Vec::new(),
+            min_quantity: Decimal::ZERO,
+            min_notional: Decimal::ZERO,
+            max_quantity: None,
+            max_open_orders: None,
+            price_band_bps: None,
+            matching_mode: MatchingMode::PriceTime,
+            base_decimals: DEFAULT_BASE_DECIMALS,
+            quote_decimals: DEFAULT_QUOTE_DECIMALS,
+        }
+    }
+
+    pub fn with_matching_mode(mut self, matching_mode: MatchingMode) -> Self {
+        self.matching_mode = matching_mode;
+        self
+    }
+
+    pub fn with_price_band(mut self, price_band_bps: Decimal) -> Self {
+        self.price_band_bps = Some(price_band_bps);
+        self
+    }
+
+    pub fn with_minimums(mut self, min_quantity: Decimal, min_notional: Decimal) -> Self {
+        self.min_quantity = min_quantity;
+        self.min_notional = min_notional;
+        self
+    }
+
+    pub fn with_maximums(mut self, max_quantity: Decimal, max_open_orders: usize) -> Self {
+        self.max_quantity = Some(max_quantity);
+        self.max_open_orders = Some(max_open_orders);
+        self
+    }
+
+    pub fn with_decimals(mut self, base_decimals: u32, quote_decimals: u32) -> Self {
+        self.base_decimals = base_decimals;
+        self.quote_decimals = quote_decimals;
+        self
+    }
+
+    pub fn with_fees(mut self, maker_fee_bps: Decimal, taker_fee_bps: Decimal) -> Self {
+        self.maker_fee_bps = maker_fee_bps;
+        self.taker_fee_bps = taker_fee_bps;
+        self
+    }
+
+    pub fn with_fee_tiers(mut self, mut fee_tiers: Vec<FeeTier>) -> Self {
+        fee_tiers.sort_by_key(|tier| tier.min_volume);
+        self.fee_tiers = fee_tiers;
+        self
+    }
+
+    /// Tier number, maker fee and taker fee for a user with `volume` of
+    /// trailing volume. A tier never charges more than the base fees, which
+    /// is what buy orders reserve for.
+    pub fn fee_tier(&self, volume: Decimal) -> (usize, Decimal, Decimal) {
+        match self
+            .fee_tiers
+            .iter()
+            .rposition(|tier| volume >= tier.min_volume)
+        {
+            Some(index) => {
+                let tier = &self.fee_tiers[index];
+                (
+                    index + 1,
+                    tier.maker_fee_bps.min(self.maker_fee_bps),
+                    tier.taker_fee_bps.min(self.taker_fee_bps),
+                )
+            }
+            None => (0, self.maker_fee_bps, self.taker_fee_bps),
+        }
+    }
+
+    /// What a user with `volume` of trailing volume pays here.
+    pub fn fees_for(&self, volume: Decimal) -> MarketFees {
+        let (tier, maker_fee_bps, taker_fee_bps) = self.fee_tier(volume);
+        MarketFees {
+            symbol: self.symbol.clone(),
+            tier,
+            maker_fee_bps,
+            taker_fee_bps,
+            next_tier_volume: self.fee_tiers.get(tier).map(|next| next.min_volume),
+        }
+    }
+
+    /// What `quantity` of a resting order at `price` holds back from its owner:
+    /// quote for a buy, including the larger of the two fees, or the base
+    /// asset for a sell. The asset is `None` for the quote currency.
+    pub fn reservation(
+        &self,
+        side: &OrderSide,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> (Option<&str>, Decimal) {
+        match side {
+            OrderSide::Buy => {
+                let cost = price * quantity;
+                let fee_bps = self.maker_fee_bps.max(self.taker_fee_bps);
+                (None, cost + Self::fee(cost, fee_bps))
+            }
+            OrderSide::Sell => (Some(self.base_asset.as_str()), quantity),
+        }
+    }
+
+    /// Fee in quote currency for a trade of `notional` at `fee_bps`.
+    pub fn fee(notional: Decimal, fee_bps: Decimal) -> Decimal {
+        notional * fee_bps / Decimal::from(10_000)
+    }
+
+    /// Quote exchanged when `quantity` trades at `price`. Rounded down to
+    /// `quote_decimals`, as is every settled fee, so a fill never costs the
+    /// buyer more than its order set aside and balances carry no dust.
+    pub fn settled_notional(&self, price: Decimal, quantity: Decimal) -> Decimal {
+        (price * quantity).round_dp_with_strategy(self.quote_decimals, RoundingStrategy::ToZero)
+    }
+
+    /// Fee charged on a trade of `notional` at `fee_bps`, rounded down to
+    /// `quote_decimals`.
+    pub fn settled_fee(&self, notional: Decimal, fee_bps: Decimal) -> Decimal {
+        Self::fee(notional, fee_bps)
+            .round_dp_with_strategy(self.quote_decimals, RoundingStrategy::ToZero)
+    }
+}
+
+/// Written as "buy" or "sell"; read in any case.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OrderSide {
     Buy,
     Sell,
 }
 
-#[derive(Debug, Clone)]
+impl<'de> Deserialize<'de> for OrderSide {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let side = String::deserialize(deserializer)?;
+        match side.to_lowercase().as_str() {
+            "buy" => Ok(OrderSide::Buy),
+            "sell" => Ok(OrderSide::Sell),
+            _ => Err(de::Error::unknown_variant(&side, &["buy", "sell"])),
+        }
+    }
+}
+
+/// Tagged by `order_type`, written in snake case and read in any case. Only
+/// the case is ignored, so "Stop_Market" is read as `stop_market` but
+/// "StopMarket" is unknown. The derives only provide the exact-case forms,
+/// which the trait impls below wrap.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "order_type", remote = "Self")]
 pub enum OrderType {
+    #[serde(rename = "limit")]
     LimitOrder,
-    MarketOrder,
+    /// Takes liquidity at any price unless `protection_price` is set, in which
+    /// case levels beyond it (above for buys, below for sells) are not touched
+    /// and whatever is left unfilled is cancelled.
+    #[serde(rename = "market")]
+    MarketOrder {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protection_price: Option<Decimal>,
+    },
+    /// Matches like a limit order; whatever cannot fill immediately is cancelled
+    /// instead of resting on the book.
+    #[serde(rename = "ioc")]
+    ImmediateOrCancel,
+    /// Executes in full immediately or not at all; the book is left untouched
+    /// when there is not enough crossable liquidity.
+    #[serde(rename = "fok")]
+    FillOrKill,
+    /// Limit order that is rejected rather than matched if it would cross the
+    /// book, guaranteeing it only ever adds liquidity.
+    #[serde(rename = "post_only")]
+    PostOnly,
+    /// Held off the book until the last traded price reaches `stop_price`
+    /// (rising for buys, falling for sells), then executed as a market order.
+    #[serde(rename = "stop_market")]
+    StopMarket { stop_price: Decimal },
+    /// Stop whose trigger follows the market, `trail_amount` behind the best
+    /// price traded since it was placed: the highest for sells, the lowest for
+    /// buys. Once the price comes back by the trail it executes as a market
+    /// order.
+    #[serde(rename = "trailing_stop")]
+    TrailingStop {
+        trail_amount: Decimal,
+        /// Current trigger, kept by the engine. Unset until the market trades.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stop_price: Option<Decimal>,
+    },
+    /// Mirror of a stop: held off the book until the last traded price comes
+    /// to `touch_price` (falling for buys, rising for sells), then executed as
+    /// a market order.
+    #[serde(rename = "market_if_touched")]
+    MarketIfTouched { touch_price: Decimal },
+    /// Limit order that shows at most `display_quantity` on the book. Each
+    /// time the visible slice fills, the next one joins the back of its level.
+    #[serde(rename = "iceberg")]
+    Iceberg { display_quantity: Decimal },
+    /// Rests `offset` away from the best price it follows and is moved as
+    /// that price changes. The engine sets its `price`; it never takes
+    /// liquidity, so it is held a tick short of the other side.
+    #[serde(rename = "peg")]
+    Peg {
+        reference: PegReference,
+        offset: Decimal,
+    },
 }
 
-#[derive(Debug, Clone)]
+/// Best price a peg order follows. Other pegs are ignored when finding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PegReference {
+    /// Best price on the order's own side.
+    Primary,
+    /// Best price on the other side.
+    Market,
+}
+
+impl Serialize for OrderType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        OrderType::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields = serde_json::Map::deserialize(deserializer)?;
+        if let Some(serde_json::Value::String(tag)) = fields.get_mut("order_type") {
+            *tag = tag.to_lowercase();
+        }
+        OrderType::deserialize(serde_json::Value::Object(fields)).map_err(de::Error::custom)
+    }
+}
+
+impl OrderType {
+    pub fn requires_price(&self) -> bool {
+        !matches!(
+            self,
+            OrderType::MarketOrder { .. }
+                | OrderType::StopMarket { .. }
+                | OrderType::TrailingStop { .. }
+                | OrderType::MarketIfTouched { .. }
+                | OrderType::Peg { .. }
+        )
+    }
+
+    /// Whether an order of this type can stay open once placed, resting on
+    /// the book or waiting on its stop.
+    pub fn stays_open(&self) -> bool {
+        !matches!(
+            self,
+            OrderType::MarketOrder { .. } | OrderType::ImmediateOrCancel | OrderType::FillOrKill
+        )
+    }
+
+    /// Whether an order of this type takes from the book as it arrives, which
+    /// is all a `worst_average_price` collar is checked against.
+    pub fn takes_on_arrival(&self) -> bool {
+        matches!(
+            self,
+            OrderType::LimitOrder
+                | OrderType::MarketOrder { .. }
+                | OrderType::ImmediateOrCancel
+                | OrderType::FillOrKill
+                | OrderType::Iceberg { .. }
+        )
+    }
+
+    /// Last traded price at which a stop executes, once it has one.
+    pub fn stop_price(&self) -> Option<Decimal> {
+        match *self {
+            OrderType::StopMarket { stop_price } => Some(stop_price),
+            OrderType::TrailingStop { stop_price, .. } => stop_price,
+            _ => None,
+        }
+    }
+
+    /// Whether a pending order of this type on `side` fires once the market
+    /// trades at `last_price`.
+    pub fn triggers_at(&self, side: &OrderSide, last_price: Decimal) -> bool {
+        if let OrderType::MarketIfTouched { touch_price } = *self {
+            return match side {
+                OrderSide::Buy => last_price <= touch_price,
+                OrderSide::Sell => last_price >= touch_price,
+            };
+        }
+        self.stop_price().is_some_and(|stop_price| match side {
+            OrderSide::Buy => last_price >= stop_price,
+            OrderSide::Sell => last_price <= stop_price,
+        })
+    }
+}
+
+/// Which side is cancelled when an incoming order would trade against a
+/// resting order from the same user.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    /// Remove the resting order and keep matching.
+    #[default]
+    #[serde(rename = "cancel-resting")]
+    Resting,
+    /// Stop matching and cancel whatever is left of the incoming order.
+    #[serde(rename = "cancel-taker")]
+    Taker,
+    /// Remove the resting order and cancel the rest of the incoming order.
+    #[serde(rename = "cancel-both")]
+    Both,
+}
+
+/// Whether a market accepts new orders. Cancellations are allowed in every state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarketStatus {
+    #[default]
+    Open,
+    /// Closed for maintenance.
+    Paused,
+    /// Stopped by an operator after a disorderly market, e.g. a circuit breaker.
+    Halted,
+    /// Call auction: limit orders are collected without matching, and
+    /// everything that crosses trades at one price when the market opens.
+    Auction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    /// Resting on the book or waiting on a trigger.
+    Open,
+    Filled,
+    /// Cancelled by the user, or the unfilled remainder of an order that could
+    /// not rest (IOC, self-trade prevention, exhausted market order).
+    Cancelled,
+    /// Removed from the book when its `expires_at` passed.
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order {
     pub id: String,
     pub user_id: String,
+    pub symbol: String,
     pub side: OrderSide,
+    #[serde(flatten)]
     pub order_type: OrderType,
-    pub price: Option<f64>,
-    pub quantity: f64,
-    pub remaining_quantity: f64,
+    pub price: Option<Decimal>,
+    pub quantity: Decimal,
+    pub remaining_quantity: Decimal,
+    /// Quote a market buy spends instead of buying a set `quantity`. The
+    /// engine works out the quantity it affords when the order executes; fees
+    /// are charged on top.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote_quantity: Option<Decimal>,
+    /// Part of a resting iceberg's `remaining_quantity` currently shown on the
+    /// book. Other orders show all of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visible_quantity: Option<Decimal>,
+    /// Other leg of a one-cancels-the-other pair. Once either leg trades or
+    /// its stop triggers, the other is cancelled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_order_id: Option<String>,
+    /// Caller's own id for the order. Resubmitting an order with an id the
+    /// user recently placed on the same market returns the first response
+    /// instead of placing it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
+    /// Good-till-date expiry in milliseconds since the Unix epoch; the order is
+    /// cancelled once it passes. Only orders that rest on the book may set it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Good-after-time activation in milliseconds since the Unix epoch. Until
+    /// then the order waits off the book, holding no funds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activates_at: Option<u64>,
+    /// Collar on what the order takes from the book: the highest average
+    /// price a buy may fill at, the lowest for a sell. An order that would
+    /// breach it is rejected before anything trades.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worst_average_price: Option<Decimal>,
+    /// Milliseconds since the Unix epoch when the order was submitted.
     pub timestamp: u64,
+    /// Assigned by the engine in arrival order; breaks ties in time priority.
+    #[serde(default)]
+    pub sequence: u64,
+    pub self_trade_prevention: SelfTradePrevention,
+    /// Sell no more than the owner holds. Whatever exceeds the holding when
+    /// the order executes is cut from its quantity instead of being rejected.
+    #[serde(default)]
+    pub reduce_only: bool,
+    pub status: OrderStatus,
 }
 
-#[derive(Debug, Clone)]
+impl Order {
+    /// Quantity this order shows on the book.
+    pub fn visible(&self) -> Decimal {
+        self.visible_quantity.unwrap_or(self.remaining_quantity)
+    }
+}
+
+/// Exits waiting on a bracket's entry order: a take-profit limit and a stop
+/// on the other side, placed as an OCO pair once the entry is done trading
+/// and sized to what it filled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bracket {
+    pub entry_id: String,
+    pub take_profit: Order,
+    pub stop: Order,
+}
+
+impl Bracket {
+    /// Exits for `entry`, with ids derived from its own.
+    pub fn new(entry: &Order, take_profit_price: Decimal, stop_price: Decimal) -> Self {
+        let exit = |id: String, order_type: OrderType, price: Option<Decimal>| Order {
+            id,
+            user_id: entry.user_id.clone(),
+            symbol: entry.symbol.clone(),
+            side: match entry.side {
+                OrderSide::Buy => OrderSide::Sell,
+                OrderSide::Sell => OrderSide::Buy,
+            },
+            order_type,
+            price,
+            quantity: entry.quantity,
+            remaining_quantity: entry.quantity,
+            quote_quantity: None,
+            visible_quantity: None,
+            linked_order_id: None,
+            client_order_id: None,
+            expires_at: None,
+            activates_at: None,
+            worst_average_price: None,
+            timestamp: entry.timestamp,
+            sequence: 0,
+            self_trade_prevention: entry.self_trade_prevention,
+            reduce_only: false,
+            status: OrderStatus::Open,
+        };
+        Self {
+            entry_id: entry.id.clone(),
+            take_profit: exit(
+                Self::take_profit_id(&entry.id),
+                OrderType::LimitOrder,
+                Some(take_profit_price),
+            ),
+            stop: exit(
+                Self::stop_id(&entry.id),
+                OrderType::StopMarket { stop_price },
+                None,
+            ),
+        }
+    }
+
+    pub fn take_profit_id(entry_id: &str) -> String {
+        format!("{entry_id}-take-profit")
+    }
+
+    pub fn stop_id(entry_id: &str) -> String {
+        format!("{entry_id}-stop")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Trade {
     pub id: String,
     pub buy_order_id: String,
     pub sell_order_id: String,
-    pub price: f64,
-    pub quantity: f64,
+    #[serde(skip)]
+    pub buy_user_id: String,
+    #[serde(skip)]
+    pub sell_user_id: String,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    /// Side of the incoming order that took liquidity. Trades made when an
+    /// auction uncrosses have no aggressor and leave it out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggressor_side: Option<OrderSide>,
+    /// Quote charged to the resting order's owner.
+    pub maker_fee: Decimal,
+    /// Quote charged to the incoming order's owner.
+    pub taker_fee: Decimal,
+    /// Milliseconds since the Unix epoch.
     pub timestamp: u64,
 }
 
+#[derive(Serialize)]
 pub struct OrderbookSnapshot {
-    pub bids: Vec<(f64, f64)>,
-    pub asks: Vec<(f64, f64)>,
+    /// Sequence number of the last `BookUpdate` already reflected in this snapshot.
+    pub sequence: u64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    /// `orderbook::book_checksum` of the book, so a client can check its copy.
+    pub checksum: u32,
+}
+
+/// Visible quantity at one price of an `OrderbookSnapshot`.
+#[derive(Serialize)]
+pub struct PriceLevel {
+    pub price: Decimal,
+    pub quantity: Decimal,
+    /// Orders queued at the price.
+    pub order_count: usize,
+}
+
+/// One resting order in a `FullSnapshot`. Icebergs show only their visible slice.
+#[derive(Serialize)]
+pub struct BookEntry {
+    pub order_id: String,
+    /// Left out unless the order belongs to the user asking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub remaining_quantity: Decimal,
+    pub sequence: u64,
+}
+
+/// Level-3 view of a book: every resting order per price, in queue order.
+#[derive(Serialize)]
+pub struct FullSnapshot {
+    /// Sequence number of the last `BookUpdate` already reflected in this snapshot.
+    pub sequence: u64,
+    pub bids: Vec<(Decimal, Vec<BookEntry>)>,
+    pub asks: Vec<(Decimal, Vec<BookEntry>)>,
+}
+
+/// Open orders of one book, enough to rebuild it after a restart. Resting
+/// orders are listed best price first and in time priority within a level.
+#[derive(Serialize, Deserialize)]
+pub struct BookState {
+    pub symbol: String,
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+    pub stops: Vec<Order>,
+    /// Good-after-time orders not yet activated.
+    #[serde(default)]
+    pub delayed: Vec<Order>,
+    /// Exits of bracket entries still open.
+    #[serde(default)]
+    pub brackets: Vec<Bracket>,
+    pub last_trade_price: Option<Decimal>,
+    /// Number the next trade id is built from.
+    #[serde(default)]
+    pub next_trade_id: u64,
+    #[serde(default)]
+    pub status: MarketStatus,
+}
+
+/// Books and balances captured at the same point in the engine's command stream.
+pub struct EngineState {
+    pub books: Vec<BookState>,
+    pub accounts: HashMap<String, Account>,
+    /// Last write-ahead log record already reflected in `books` and `accounts`.
+    pub wal_sequence: u64,
+    /// Last `Order::sequence` handed out.
+    pub order_sequence: u64,
+}
+
+/// A market's trading rules and whether it is open, as listed by `/symbols`.
+/// Limits the market does not have are null.
+#[derive(Serialize)]
+pub struct MarketInfo {
+    pub symbol: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub tick_size: Decimal,
+    pub min_quantity: Decimal,
+    pub max_quantity: Option<Decimal>,
+    pub min_notional: Decimal,
+    pub max_open_orders: Option<usize>,
+    pub maker_fee_bps: Decimal,
+    pub taker_fee_bps: Decimal,
+    pub fee_tiers: Vec<FeeTier>,
+    pub base_decimals: u32,
+    pub quote_decimals: u32,
+    pub status: MarketStatus,
+}
+
+/// Mid-price and spread as of `timestamp`. Both are null while either side of
+/// the book is empty.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MidPriceSample {
+    pub mid_price: Option<Decimal>,
+    pub spread: Option<Decimal>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Top of book and last trade for a symbol; fields are null while the
+/// relevant side is empty or nothing has traded yet.
+#[derive(Serialize)]
+pub struct Ticker {
+    pub last_price: Option<Decimal>,
+    pub best_bid: Option<Decimal>,
+    pub best_ask: Option<Decimal>,
+    pub spread: Option<Decimal>,
+    /// Quantity shown at the best bid and best ask.
+    pub best_bid_size: Option<Decimal>,
+    pub best_ask_size: Option<Decimal>,
+    /// Share of the top-of-book quantity on the bid, from 0 to 1.
+    pub imbalance: Option<Decimal>,
+    /// Best bid and ask weighted by the size on the opposite side, which
+    /// leans towards the side more likely to give way.
+    pub microprice: Option<Decimal>,
+    /// During an auction, the price it would uncross at now and the volume
+    /// that would trade there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indicative_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indicative_volume: Option<Decimal>,
+}
+
+/// Price levels of one book that changed in a single operation, with their new
+/// total quantity. A quantity of zero means the level is now empty.
+#[derive(Debug, Clone, Serialize)]
+pub struct BookUpdate {
+    pub symbol: String,
+    /// Increases by one for every update published for `symbol`.
+    pub sequence: u64,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+    /// `orderbook::book_checksum` of the book once the update is applied.
+    pub checksum: u32,
+}
+
+/// Something that happened to users' orders, published to the per-user feed.
+#[derive(Debug, Clone)]
+pub enum UserEvent {
+    /// A trade, tagged with the market it happened in.
+    Fill { symbol: String, trade: Trade },
+    /// A good-till-date order reached its expiry and was taken off the book.
+    Expired(Order),
+    /// Outcome of an order whose placement was only acknowledged.
+    Result {
+        user_id: String,
+        symbol: String,
+        order_id: String,
+        response: OrderResponse,
+    },
 }
 
+/// Change to one book, as published on `Feeds::book_events`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BookEvent {
+    Trade {
+        trade: Trade,
+    },
+    /// An order went onto the book, or back onto it after an amendment that
+    /// lost its priority.
+    OrderPlaced {
+        order: Order,
+    },
+    /// A resting order left the book without filling: cancelled, expired or
+    /// removed by self-trade prevention.
+    OrderCancelled {
+        order_id: String,
+    },
+}
+
+/// A `BookEvent` numbered within its market. Each market counts from 1 every
+/// time the engine starts, so a subscriber that sees a number skipped has
+/// missed an event and should take a fresh snapshot.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequencedEvent {
+    pub symbol: String,
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub event: BookEvent,
+}
+
+/// Broadcast channels the engine publishes market data on. Slow subscribers
+/// lag rather than grow the buffer, since each channel has a fixed capacity.
+#[derive(Clone)]
+pub struct Feeds {
+    pub book_updates: tokio::sync::broadcast::Sender<BookUpdate>,
+    pub user_events: tokio::sync::broadcast::Sender<UserEvent>,
+    pub book_events: tokio::sync::broadcast::Sender<SequencedEvent>,
+}
+
+impl Feeds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            book_updates: tokio::sync::broadcast::channel(capacity).0,
+            user_events: tokio::sync::broadcast::channel(capacity).0,
+            book_events: tokio::sync::broadcast::channel(capacity).0,
+        }
+    }
+}
+
+/// Early answer to an order taken with an acknowledgement: it is accepted,
+/// numbered and logged, but not yet matched, so it may still be rejected.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OrderAck {
+    Acknowledged { order_id: String, sequence: u64 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
 pub enum OrderResponse {
     Placed {
         order_id: String,
     },
     PartiallyFilled {
         order_id: String,
-        filled_quantity: f64,
-        remaining_quantity: f64,
+        filled_quantity: Decimal,
+        remaining_quantity: Decimal,
+        /// Unfilled quantity that was cancelled rather than left resting.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cancelled_quantity: Option<Decimal>,
         trades: Vec<Trade>,
     },
     Filled {
         order_id: String,
-        filled_quantity: f64,
+        filled_quantity: Decimal,
         trades: Vec<Trade>,
     },
     Cancelled {
         order_id: String,
     },
     Error {
+        code: OrderError,
         message: String,
     },
 }
 
+impl OrderResponse {
+    /// The `status` this response is serialized with.
+    pub fn status(&self) -> &'static str {
+        match self {
+            OrderResponse::Placed { .. } => "placed",
+            OrderResponse::PartiallyFilled { .. } => "partially_filled",
+            OrderResponse::Filled { .. } => "filled",
+            OrderResponse::Cancelled { .. } => "cancelled",
+            OrderResponse::Error { .. } => "error",
+        }
+    }
+
+    /// Whether the order went in: it rests, waits on its trigger or traded.
+    pub fn accepted(&self) -> bool {
+        matches!(
+            self,
+            OrderResponse::Placed { .. }
+                | OrderResponse::PartiallyFilled { .. }
+                | OrderResponse::Filled { .. }
+        )
+    }
+
+    /// Id of the order the response is about; errors name none.
+    pub fn order_id(&self) -> Option<&str> {
+        match self {
+            OrderResponse::Placed { order_id }
+            | OrderResponse::PartiallyFilled { order_id, .. }
+            | OrderResponse::Filled { order_id, .. }
+            | OrderResponse::Cancelled { order_id } => Some(order_id),
+            OrderResponse::Error { .. } => None,
+        }
+    }
+}
+
+/// Outcome of each leg of an OCO pair, in the order they were submitted.
+#[derive(Serialize)]
+pub struct OcoResponse {
+    pub first: OrderResponse,
+    pub second: OrderResponse,
+}
+
+/// Machine-readable reason carried by `OrderResponse::Error`. The serialized
+/// names are part of the API and must not change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderError {
+    /// Quantity is zero or negative.
+    InvalidQuantity,
+    /// Limit or stop price is zero or negative.
+    InvalidPrice,
+    /// The order type needs a limit price and none was given.
+    MissingPrice,
+    TickSizeViolation,
+    /// A quantity has more decimal places than the market allows.
+    QuantityPrecision,
+    BelowMinimumQuantity,
+    BelowMinimumNotional,
+    AboveMaximumQuantity,
+    /// The user already has the market's maximum number of open orders.
+    TooManyOpenOrders,
+    /// There was no price for a peg order to follow.
+    NothingToPeg,
+    InsufficientFunds,
+    /// A market order ran out of resting liquidity before it was filled.
+    InsufficientLiquidity,
+    /// Nothing was available within a market order's protection price.
+    PriceProtection,
+    /// Price is outside the market's price band, or nothing was available
+    /// within it for a market order.
+    PriceOutsideBand,
+    /// The market is paused or halted.
+    MarketClosed,
+    /// Nothing on the book crossed a market or IOC order.
+    NoMatchingOrders,
+    /// Taking liquidity would have averaged a price beyond the order's
+    /// `worst_average_price`.
+    PriceCollarBreached,
+    /// A post-only order would have taken liquidity.
+    WouldCross,
+    /// The market does not take this order type.
+    UnsupportedOrderType,
+    /// During an auction, the order crosses one of the same user's orders.
+    WouldSelfTrade,
+    /// A reduce-only order found no holding to reduce. Buys never reduce one.
+    NothingToReduce,
+    /// `client_order_id` is empty or longer than `MAX_CLIENT_ORDER_ID_LEN`.
+    InvalidClientOrderId,
+    FillOrKillUnfilled,
+    UnknownSymbol,
+    /// No open order with that id; it may have filled or been cancelled.
+    OrderNotFound,
+    NotOrderOwner,
+    /// Stop orders waiting on their trigger and delayed orders cannot be amended.
+    NotAmendable,
+    /// Expiry is in the past, or set on an order type that never rests.
+    InvalidExpiry,
+    /// Activation is set on an OCO leg, or does not come before the expiry.
+    InvalidActivation,
+    /// The order could not be recorded in the write-ahead log, so it was not applied.
+    LogWriteFailed,
+    /// An OCO leg is of a type that never rests, or the legs name different markets.
+    InvalidOco,
+    /// The other leg of an OCO pair was rejected, so this one was not placed.
+    LinkedOrderRejected,
+    /// A bracket's take-profit and stop are on the wrong side of each other
+    /// or of the entry price, or its entry gives a quote quantity.
+    InvalidBracket,
+}
+
+/// Requests handled by the engine task of a single market.
 pub enum OrderbookCommand {
+    /// Places an order. When `ack` is given, it is answered as soon as the
+    /// order is sequenced and logged, before it is matched; `response` still
+    /// gets the outcome. A retry of an order already seen is not acknowledged.
     AddOrder {
-        order: Order,
+        order: Box<Order>,
+        ack: Option<tokio::sync::oneshot::Sender<OrderAck>>,
+        response: tokio::sync::oneshot::Sender<OrderResponse>,
+    },
+    /// Answers what `AddOrder` would for `order`, without placing it.
+    SimulateOrder {
+        order: Box<Order>,
+        response: tokio::sync::oneshot::Sender<OrderResponse>,
+    },
+    /// Places two orders as a one-cancels-the-other pair.
+    AddOco {
+        first: Box<Order>,
+        second: Box<Order>,
+        response: tokio::sync::oneshot::Sender<OcoResponse>,
+    },
+    /// Places an entry order whose exits go in once it has filled.
+    AddBracket {
+        entry: Box<Order>,
+        exits: Box<Bracket>,
+        response: tokio::sync::oneshot::Sender<OrderResponse>,
+    },
+    /// `cancelled_by` is the owner, or the admin force-cancelling the order.
+    CancelOrder {
+        order_id: String,
+        user_id: String,
+        cancelled_by: String,
+        response: tokio::sync::oneshot::Sender<OrderResponse>,
+    },
+    /// Changes the price and/or total quantity of a resting order.
+    AmendOrder {
+        order_id: String,
+        user_id: String,
+        new_price: Option<Decimal>,
+        new_quantity: Option<Decimal>,
+        response: tokio::sync::oneshot::Sender<OrderResponse>,
+    },
+    /// Cuts a resting order's total quantity in place, keeping its priority.
+    ReduceOrder {
+        order_id: String,
+        user_id: String,
+        quantity: Decimal,
         response: tokio::sync::oneshot::Sender<OrderResponse>,
     },
+    /// Cancels every open order of `user_id` in the market and replies with
+    /// the cancelled ids.
+    CancelAllForUser {
+        user_id: String,
+        response: tokio::sync::oneshot::Sender<Result<Vec<String>, String>>,
+    },
+    GetOrder {
+        order_id: String,
+        response: tokio::sync::oneshot::Sender<Option<Order>>,
+    },
+    GetUserOrders {
+        user_id: String,
+        response: tokio::sync::oneshot::Sender<Vec<Order>>,
+    },
     GetSnapshot {
         response: tokio::sync::oneshot::Sender<OrderbookSnapshot>,
     },
+    GetFullSnapshot {
+        response: tokio::sync::oneshot::Sender<FullSnapshot>,
+    },
+    GetTicker {
+        response: tokio::sync::oneshot::Sender<Ticker>,
+    },
+    GetMarketInfo {
+        response: tokio::sync::oneshot::Sender<MarketInfo>,
+    },
+    /// The fee tier `user_id` is in, by their trailing volume as of now.
+    GetFees {
+        user_id: String,
+        response: tokio::sync::oneshot::Sender<MarketFees>,
+    },
+    /// Replies with `None` if candles of `interval_secs` are not aggregated.
+    GetCandles {
+        interval_secs: u64,
+        limit: usize,
+        response: tokio::sync::oneshot::Sender<Option<Vec<crate::candles::Candle>>>,
+    },
+    /// Trading over the last 24 hours.
+    GetStats {
+        response: tokio::sync::oneshot::Sender<crate::stats::DailyStats>,
+    },
+    /// The last `limit` mid-price samples, oldest first.
+    GetMidPrices {
+        limit: usize,
+        response: tokio::sync::oneshot::Sender<Vec<MidPriceSample>>,
+    },
+    /// Only trades older than the one with id `before`, when it is given.
+    GetRecentTrades {
+        limit: usize,
+        before: Option<String>,
+        response: tokio::sync::oneshot::Sender<Vec<Trade>>,
+    },
+    /// Opens, pauses or halts the market.
+    SetStatus {
+        status: MarketStatus,
+        response: tokio::sync::oneshot::Sender<Result<(), String>>,
+    },
+    /// Replies with the book, then processes nothing else until `resume`
+    /// fires or is dropped, so every market can be captured at one point in
+    /// the write-ahead log.
+    ExportState {
+        response: tokio::sync::oneshot::Sender<BookState>,
+        resume: tokio::sync::oneshot::Receiver<()>,
+    },
+    /// Empties the book as if the market had just been created. Not logged.
+    #[cfg(feature = "test-endpoints")]
+    Reset {
+        response: tokio::sync::oneshot::Sender<()>,
+    },
 }
@@ -0,0 +1,183 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::dispatch;
+use crate::types::{
+    BookUpdate, OrderResponse, OrderSide, OrderbookCommand, OrderbookSnapshot, Trade, UserEvent,
+};
+use crate::{unknown_symbol, AppState, AuthenticatedUser};
+
+/// Messages sent over `/ws/orderbook/{symbol}`: one snapshot on connect, then
+/// an update for every change to the book.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BookFeedMessage<'a> {
+    Snapshot(&'a OrderbookSnapshot),
+    Update(&'a BookUpdate),
+}
+
+/// Messages sent over `/ws/user`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum UserFeedMessage<'a> {
+    /// One of the user's orders traded. `order_id` and `side` are the user's
+    /// own side of `trade`.
+    Fill {
+        symbol: &'a str,
+        order_id: &'a str,
+        side: OrderSide,
+        trade: &'a Trade,
+    },
+    /// A good-till-date order expired before it fully filled.
+    Expired { symbol: &'a str, order_id: &'a str },
+    /// What became of an order placed with `?ack=true`, as `POST /order`
+    /// would have answered it.
+    Result {
+        symbol: &'a str,
+        order_id: &'a str,
+        response: &'a OrderResponse,
+    },
+}
+
+/// Pumps `rx` into the socket until either end goes away. `render` turns each
+/// event into a text message, or `None` to skip it. Subscribers that fall too
+/// far behind are disconnected instead of being buffered for.
+async fn forward<T: Clone>(
+    mut session: Session,
+    mut messages: MessageStream,
+    mut rx: broadcast::Receiver<T>,
+    mut render: impl FnMut(&T) -> Option<String>,
+) {
+    let close_reason = loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(reason))) => break reason,
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break None,
+            },
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    if let Some(message) = render(&event) {
+                        if session.text(message).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {
+                    break Some(CloseReason {
+                        code: CloseCode::Again,
+                        description: Some("fell behind the event stream".into()),
+                    });
+                }
+                Err(RecvError::Closed) => break None,
+            },
+        }
+    };
+
+    let _ = session.close(close_reason).await;
+}
+
+/// Streams the book for `symbol`. Updates are only forwarded if their sequence
+/// is newer than the snapshot, so clients can apply them without gaps or
+/// duplicates. A client that is disconnected for lagging should reconnect for
+/// a fresh snapshot.
+#[get("/ws/orderbook/{symbol}")]
+async fn orderbook_feed(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Payload,
+    symbol: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let symbol = symbol.into_inner();
+
+    // Subscribe before asking for the snapshot so no update can slip in between.
+    let updates = data.feeds.book_updates.subscribe();
+
+    let Some(orderbook_tx) = data.shards.get(&symbol) else {
+        return Ok(unknown_symbol());
+    };
+    let snapshot = match dispatch::request(orderbook_tx, |response| OrderbookCommand::GetSnapshot {
+        response,
+    })
+    .await
+    {
+        Ok(snapshot) => snapshot,
+        Err(e) => return Ok(e.response()),
+    };
+
+    let (response, mut session, messages) = actix_ws::handle(&req, body)?;
+
+    actix_web::rt::spawn(async move {
+        let message = serde_json::to_string(&BookFeedMessage::Snapshot(&snapshot)).unwrap();
+        if session.text(message).await.is_err() {
+            return;
+        }
+
+        forward(session, messages, updates, |update: &BookUpdate| {
+            (update.symbol == symbol && update.sequence > snapshot.sequence)
+                .then(|| serde_json::to_string(&BookFeedMessage::Update(update)).unwrap())
+        })
+        .await;
+    });
+
+    Ok(response)
+}
+
+/// Streams fills, expiries and acknowledged order outcomes of the
+/// authenticated user's orders across all markets.
+#[get("/ws/user")]
+async fn user_feed(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    AuthenticatedUser(user): AuthenticatedUser,
+    body: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let events = data.feeds.user_events.subscribe();
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+
+    actix_web::rt::spawn(forward(session, messages, events, move |event| {
+        let message = match event {
+            UserEvent::Fill { symbol, trade } => {
+                let (order_id, side) = if trade.buy_user_id == user.id {
+                    (&trade.buy_order_id, OrderSide::Buy)
+                } else if trade.sell_user_id == user.id {
+                    (&trade.sell_order_id, OrderSide::Sell)
+                } else {
+                    return None;
+                };
+                UserFeedMessage::Fill {
+                    symbol,
+                    order_id,
+                    side,
+                    trade,
+                }
+            }
+            UserEvent::Expired(order) if order.user_id == user.id => UserFeedMessage::Expired {
+                symbol: &order.symbol,
+                order_id: &order.id,
+            },
+            UserEvent::Expired(_) => return None,
+            UserEvent::Result {
+                user_id,
+                symbol,
+                order_id,
+                response,
+            } if *user_id == user.id => UserFeedMessage::Result {
+                symbol,
+                order_id,
+                response,
+            },
+            UserEvent::Result { .. } => return None,
+        };
+        Some(serde_json::to_string(&message).unwrap())
+    }));
+
+    Ok(response)
+}